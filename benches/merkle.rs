@@ -0,0 +1,264 @@
+// Criterion benchmarks for tree building, proofs and the upload/download path.
+//
+// Leaf counts run from 10 up to MERKLE_BENCH_MAX_LEAVES (default 100000, set it to
+// 10000000 for the full range). Inputs are generated deterministically so runs are
+// comparable across commits:
+//   cargo bench -- --save-baseline main
+//   git checkout <branch> && cargo bench -- --baseline main
+use blake3::Hash;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use merkle_fileserver::cert::generate_self_signed;
+use merkle_fileserver::fileserver::audit::AuditLog;
+use merkle_fileserver::fileserver::auth::Auth;
+use merkle_fileserver::fileserver::routes::routes;
+use merkle_fileserver::merkletree::cache::TreeCache;
+use merkle_fileserver::merkletree::commitment::VectorCommitment;
+use merkle_fileserver::merkletree::kzg::{KzgCommitment, KzgSetup};
+use merkle_fileserver::merkletree::mmr::MerkleMountainRange;
+use merkle_fileserver::merkletree::sparse::SparseMerkleTree;
+use merkle_fileserver::merkletree::tree::{leaf_hash, verify_leaf_proof, FastMerkleTree};
+use merkle_fileserver::storage::memory::{MemoryBlobStore, MemoryMmrStore, MemoryTreeStore};
+use merkle_fileserver::storage::sled_store::SledTreeStore;
+use merkle_fileserver::storage::Store;
+use std::sync::Arc;
+
+type Proof = Vec<(Vec<u8>, bool)>;
+
+const LEAF_COUNTS: [usize; 7] = [10, 100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+// Reading files dominates build_merkle_tree, so it stops well short of the other groups
+const MAX_FILES: usize = 10_000;
+// KZG openings cost an MSM over the whole setup, which gets slow past this
+const KZG_MAX_LEAVES: usize = 10_000;
+const UPLOAD_FILES: usize = 16;
+const UPLOAD_FILE_SIZE: usize = 256 * 1024;
+
+fn leaf_counts() -> Vec<usize> {
+    let max = std::env::var("MERKLE_BENCH_MAX_LEAVES")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(100_000);
+    LEAF_COUNTS.into_iter().filter(|n| *n <= max).collect()
+}
+
+fn leaves(n: usize) -> Vec<Hash> {
+    (0..n).map(|i| leaf_hash(&i.to_le_bytes())).collect()
+}
+
+fn file_list(n: usize) -> Vec<String> {
+    (0..n).map(|i| format!("file{:08}.txt", i)).collect()
+}
+
+// Spread lookups over the whole tree instead of always proving leaf 0
+fn sample_indices(n: usize) -> Vec<usize> {
+    (0..64).map(|i| (i * 2654435761) % n).collect()
+}
+
+fn bench_build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("build");
+    group.sample_size(10);
+    for n in leaf_counts() {
+        let leaves = leaves(n);
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::new("from_leaves", n), &leaves, |b, leaves| {
+            b.iter(|| FastMerkleTree::from_leaves(leaves.clone()))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("get_root_hash_from_leaves", n),
+            &leaves,
+            |b, leaves| b.iter(|| FastMerkleTree::get_root_hash_from_leaves(leaves.clone())),
+        );
+    }
+    for n in leaf_counts().into_iter().filter(|n| *n <= MAX_FILES) {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<String> = file_list(n)
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let path = dir.path().join(name);
+                std::fs::write(&path, format!("contents of file {}", i)).unwrap();
+                path.to_str().unwrap().to_string()
+            })
+            .collect();
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(
+            BenchmarkId::new("build_merkle_tree", n),
+            &paths,
+            |b, paths| b.iter(|| FastMerkleTree::build_merkle_tree(paths.clone()).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+fn bench_proofs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("proof");
+    for n in leaf_counts() {
+        let tree = FastMerkleTree::from_leaves(leaves(n));
+        let files = file_list(n);
+        let samples: Vec<String> = sample_indices(n)
+            .into_iter()
+            .map(|i| files[i].clone())
+            .collect();
+
+        let dir = tempfile::tempdir().unwrap();
+        let sled_store = SledTreeStore::open(&sled::open(dir.path()).unwrap()).unwrap();
+        tree.commit_merkle_tree(&sled_store, "bench", files.clone(), &tree.root())
+            .unwrap();
+        group.bench_with_input(BenchmarkId::new("sled", n), &samples, |b, samples| {
+            let mut names = samples.iter().cycle();
+            b.iter(|| {
+                FastMerkleTree::get_merkle_proof_from_db(&sled_store, names.next().unwrap().clone())
+            })
+        });
+
+        let cache = TreeCache::new("bench", tree, files, SparseMerkleTree::new());
+        group.bench_with_input(BenchmarkId::new("memory", n), &samples, |b, samples| {
+            let mut names = samples.iter().cycle();
+            b.iter(|| cache.get_merkle_proof(names.next().unwrap()))
+        });
+
+        let root = cache.root().unwrap();
+        let proofs: Vec<(Hash, Proof)> = sample_indices(n)
+            .into_iter()
+            .zip(&samples)
+            .map(|(i, name)| {
+                let proof = cache.get_merkle_proof(name).unwrap();
+                (leaf_hash(&i.to_le_bytes()), proof)
+            })
+            .collect();
+        group.bench_with_input(BenchmarkId::new("verify", n), &proofs, |b, proofs| {
+            let mut proofs = proofs.iter().cycle();
+            b.iter(|| {
+                let (leaf, proof) = proofs.next().unwrap();
+                assert!(verify_leaf_proof(leaf, proof, root.as_bytes()))
+            })
+        });
+    }
+    group.finish();
+}
+
+// Proof size and verify time for each VectorCommitment backend over the same leaves
+fn bench_commitments(c: &mut Criterion) {
+    let mut group = c.benchmark_group("commitment");
+    let counts: Vec<usize> = leaf_counts()
+        .into_iter()
+        .filter(|n| *n <= KZG_MAX_LEAVES)
+        .collect();
+    let setup = Arc::new(KzgSetup::insecure_local(*counts.last().unwrap(), 1).unwrap());
+    for n in counts {
+        let leaves = leaves(n);
+        let index = n / 3;
+        let leaf = leaves[index];
+
+        let tree = FastMerkleTree::from_leaves(leaves.clone());
+        let tree_root = tree.commitment().unwrap();
+        let tree_proof = tree.prove(index).unwrap();
+        group.bench_function(BenchmarkId::new("verify_binary", n), |b| {
+            b.iter(|| FastMerkleTree::verify(&n, &tree_root, index, &leaf, &tree_proof))
+        });
+
+        let mmr = MerkleMountainRange::new(Arc::new(MemoryMmrStore::default()));
+        mmr.append(&leaves).unwrap();
+        let mmr_root = mmr.commitment().unwrap();
+        let mmr_proof = mmr.prove(index).unwrap();
+        group.bench_function(BenchmarkId::new("verify_mmr", n), |b| {
+            b.iter(|| MerkleMountainRange::verify(&(), &mmr_root, index, &leaf, &mmr_proof))
+        });
+
+        let kzg = KzgCommitment::new(setup.clone(), &leaves).unwrap();
+        let params = kzg.params();
+        let kzg_root = kzg.commitment().unwrap();
+        let kzg_proof = kzg.prove(index).unwrap();
+        group.bench_function(BenchmarkId::new("verify_kzg", n), |b| {
+            b.iter(|| KzgCommitment::verify(&params, &kzg_root, index, &leaf, &kzg_proof))
+        });
+
+        //criterion only measures time, so sizes go to stderr
+        eprintln!(
+            "proof size, {} leaves: binary {} bytes, mmr {} bytes, kzg {} bytes",
+            n,
+            tree_proof
+                .iter()
+                .map(|(hash, _)| hash.len() + 1)
+                .sum::<usize>(),
+            8 + 32 * (mmr_proof.path.len() + mmr_proof.peaks.len()),
+            kzg_proof.len()
+        );
+    }
+    group.finish();
+}
+
+fn bench_end_to_end(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _guard = runtime.enter();
+    let store = Store::new(
+        Arc::new(MemoryBlobStore::default()),
+        Arc::new(MemoryTreeStore::default()),
+    )
+    .unwrap();
+    let cert = generate_self_signed(&["localhost".to_string()], 1).unwrap();
+    let auth = Arc::new(Auth::disabled().unwrap());
+    let audit = Arc::new(AuditLog::temporary().unwrap());
+    let (addr, server) = warp::serve(routes(Arc::new(store), auth, audit))
+        .tls()
+        .cert(cert.cert_pem.as_bytes())
+        .key(cert.key_pem.as_bytes())
+        .bind_ephemeral(([127, 0, 0, 1], 0));
+    runtime.spawn(server);
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let url = format!("https://{}", addr);
+
+    let files: Vec<(String, Vec<u8>)> = (0..UPLOAD_FILES)
+        .map(|i| {
+            let data = (0..UPLOAD_FILE_SIZE).map(|j| (i * 31 + j) as u8).collect();
+            (format!("file{}.bin", i), data)
+        })
+        .collect();
+    let upload = || async {
+        let mut form = reqwest::multipart::Form::new();
+        for (name, data) in &files {
+            let part = reqwest::multipart::Part::bytes(data.clone()).file_name(name.clone());
+            form = form.part("file", part);
+        }
+        let response = client
+            .post(format!("{}/upload", url))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+    };
+
+    //download needs the files in place whichever benchmark is filtered in
+    runtime.block_on(upload());
+
+    let mut group = c.benchmark_group("end_to_end");
+    group.sample_size(20);
+    group.throughput(Throughput::Bytes((UPLOAD_FILES * UPLOAD_FILE_SIZE) as u64));
+    group.bench_function("upload", |b| b.to_async(&runtime).iter(upload));
+
+    group.throughput(Throughput::Bytes(UPLOAD_FILE_SIZE as u64));
+    group.bench_function("download", |b| {
+        b.to_async(&runtime).iter(|| async {
+            let response = client
+                .get(format!("{}/download/file0.bin", url))
+                .send()
+                .await
+                .unwrap();
+            assert!(response.headers().contains_key("X-File-Hash"));
+            response.bytes().await.unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_build,
+    bench_proofs,
+    bench_commitments,
+    bench_end_to_end
+);
+criterion_main!(benches);
@@ -1 +1,347 @@
+use crate::storage::env_or;
+use anyhow::{anyhow, bail, Context, Result};
+use rcgen::{CertificateParams, DnType, KeyPair};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use time::{Duration, OffsetDateTime};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
 
+// Where the server keeps the certificate it generates for itself when none is configured
+pub const DEFAULT_CERT_PATH: &str = "./certs/cert.pem";
+pub const DEFAULT_KEY_PATH: &str = "./certs/key.pem";
+const DEFAULT_DAYS: i64 = 365;
+
+pub const CERT_USAGE: &str =
+    "Usage: cargo run cert generate [--force] [--san <name>]... [--days <n>] [<cert.pem> <key.pem>]
+       cargo run cert show [<cert.pem>]";
+
+pub struct GeneratedCert {
+    pub cert_pem: String,
+    pub key_pem: String,
+    pub fingerprint: String,
+}
+
+// Names the certificate is valid for, from MERKLE_TLS_SANS (comma separated)
+pub fn default_sans() -> Vec<String> {
+    env_or("MERKLE_TLS_SANS", "localhost,127.0.0.1,::1")
+        .split(',')
+        .map(|san| san.trim().to_string())
+        .filter(|san| !san.is_empty())
+        .collect()
+}
+
+// SHA-256 of the DER certificate as lowercase hex, which is what clients pin
+pub fn fingerprint(der: &[u8]) -> String {
+    hex::encode(ring::digest::digest(&ring::digest::SHA256, der))
+}
+
+// Pins are accepted with or without colons and in either case, as tools print them
+pub fn normalize_fingerprint(pin: &str) -> String {
+    pin.replace(':', "").to_lowercase()
+}
+
+// A fresh P-256 key and a certificate for it, signed by itself. IP addresses in
+// sans become IP SANs, everything else DNS names.
+pub fn generate_self_signed(sans: &[String], days: i64) -> Result<GeneratedCert> {
+    if sans.is_empty() {
+        bail!("A certificate needs at least one subject alternative name");
+    }
+    let mut params = CertificateParams::new(sans.to_vec())?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, sans[0].as_str());
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now - Duration::minutes(5);
+    params.not_after = now + Duration::days(days);
+    let key = KeyPair::generate()?;
+    let cert = params.self_signed(&key)?;
+    Ok(GeneratedCert {
+        cert_pem: cert.pem(),
+        key_pem: key.serialize_pem(),
+        fingerprint: fingerprint(cert.der()),
+    })
+}
+
+// Write the pair, the key readable by its owner only. The key goes in first under
+// a temporary name so a server watching the files never pairs a new certificate
+// with the old key for longer than one reload.
+pub fn write_cert(cert: &GeneratedCert, cert_path: &Path, key_path: &Path) -> Result<()> {
+    for path in [cert_path, key_path] {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
+    let key_tmp = key_path.with_extension("pem.tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&key_tmp)?;
+    file.write_all(cert.key_pem.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&key_tmp, key_path)?;
+    std::fs::write(cert_path, &cert.cert_pem)?;
+    Ok(())
+}
+
+// Generate a certificate if neither file exists yet. Returns the new
+// certificate, or None when there already was one.
+pub fn ensure_cert(cert_path: &Path, key_path: &Path) -> Result<Option<GeneratedCert>> {
+    match (cert_path.exists(), key_path.exists()) {
+        (true, true) => Ok(None),
+        (false, false) => {
+            let cert = generate_self_signed(&default_sans(), DEFAULT_DAYS)?;
+            write_cert(&cert, cert_path, key_path)?;
+            Ok(Some(cert))
+        }
+        _ => bail!(
+            "Only one of {} and {} exists, remove it or run `cargo run cert generate --force`",
+            cert_path.display(),
+            key_path.display()
+        ),
+    }
+}
+
+pub fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let pem = std::fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice()).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        bail!("No certificates in {}", path.display());
+    }
+    Ok(certs)
+}
+
+pub fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let pem = std::fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+    rustls_pemfile::private_key(&mut pem.as_slice())?
+        .ok_or_else(|| anyhow!("No private key in {}", path.display()))
+}
+
+// What `cert show` prints about the first certificate in a file
+pub fn describe(path: &Path) -> Result<String> {
+    let der = read_certs(path)?.remove(0);
+    let (_, cert) = x509_parser::parse_x509_certificate(&der)
+        .map_err(|e| anyhow!("Invalid certificate in {}: {}", path.display(), e))?;
+    let sans = subject_alt_names(&cert);
+    let validity = cert.validity();
+    Ok(format!(
+        "Subject: {}\nIssuer: {}\nNames: {}\nValid from: {}\nValid until: {}{}\nSHA-256 fingerprint: {}",
+        cert.subject(),
+        cert.issuer(),
+        sans.join(", "),
+        validity.not_before,
+        validity.not_after,
+        if validity.is_valid() { "" } else { " (not valid now)" },
+        fingerprint(&der)
+    ))
+}
+
+fn subject_alt_names(cert: &X509Certificate) -> Vec<String> {
+    let mut sans = Vec::new();
+    if let Ok(Some(extension)) = cert.subject_alternative_name() {
+        for name in &extension.value.general_names {
+            match name {
+                GeneralName::DNSName(name) => sans.push(name.to_string()),
+                GeneralName::RFC822Name(email) => sans.push(email.to_string()),
+                GeneralName::IPAddress(bytes) => sans.push(ip_string(bytes)),
+                other => sans.push(other.to_string()),
+            }
+        }
+    }
+    sans
+}
+
+// Common names and subject alternative names of a DER certificate, which client
+// certificates are mapped to users by
+pub fn subject_names(der: &[u8]) -> Result<Vec<String>> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der)
+        .map_err(|e| anyhow!("Invalid certificate: {}", e))?;
+    let mut names: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|name| name.as_str().ok())
+        .map(str::to_string)
+        .collect();
+    names.extend(subject_alt_names(&cert));
+    Ok(names)
+}
+
+fn ip_string(bytes: &[u8]) -> String {
+    match bytes.len() {
+        4 => std::net::Ipv4Addr::from(<[u8; 4]>::try_from(bytes).unwrap()).to_string(),
+        16 => std::net::Ipv6Addr::from(<[u8; 16]>::try_from(bytes).unwrap()).to_string(),
+        _ => hex::encode(bytes),
+    }
+}
+
+// `cert generate` writes a new pair (the running server picks it up), `cert show` inspects one
+pub fn run(args: &[String]) -> Result<()> {
+    let Some((command, args)) = args.split_first() else {
+        bail!(CERT_USAGE);
+    };
+    let mut force = false;
+    let mut sans = Vec::new();
+    let mut days = DEFAULT_DAYS;
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--force" => force = true,
+            "--san" => sans.push(args.next().ok_or_else(|| anyhow!(CERT_USAGE))?.clone()),
+            "--days" => days = args.next().ok_or_else(|| anyhow!(CERT_USAGE))?.parse()?,
+            _ => positional.push(arg.clone()),
+        }
+    }
+    match (command.as_str(), positional.as_slice()) {
+        ("generate", []) => generate(DEFAULT_CERT_PATH, DEFAULT_KEY_PATH, sans, days, force),
+        ("generate", [cert_path, key_path]) => generate(cert_path, key_path, sans, days, force),
+        ("show", []) => show(DEFAULT_CERT_PATH),
+        ("show", [cert_path]) => show(cert_path),
+        _ => bail!(CERT_USAGE),
+    }
+}
+
+fn show(cert_path: &str) -> Result<()> {
+    println!("{}", describe(Path::new(cert_path))?);
+    Ok(())
+}
+
+fn generate(
+    cert_path: &str,
+    key_path: &str,
+    sans: Vec<String>,
+    days: i64,
+    force: bool,
+) -> Result<()> {
+    let (cert_path, key_path) = (Path::new(cert_path), Path::new(key_path));
+    if !force && (cert_path.exists() || key_path.exists()) {
+        bail!(
+            "{} already exists, pass --force to replace it",
+            cert_path.display()
+        );
+    }
+    let sans = if sans.is_empty() {
+        default_sans()
+    } else {
+        sans
+    };
+    let cert = generate_self_signed(&sans, days)?;
+    write_cert(&cert, cert_path, key_path)?;
+    println!("Wrote {} and {}", cert_path.display(), key_path.display());
+    println!("SHA-256 fingerprint: {}", cert.fingerprint);
+    Ok(())
+}
+
+// Accepts exactly one server certificate, by fingerprint, instead of checking
+// it against CAs. The handshake signature is still verified against it.
+#[derive(Debug)]
+pub struct PinnedCertVerifier {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedCertVerifier {
+    pub fn new(pin: &str, provider: Arc<CryptoProvider>) -> Self {
+        PinnedCertVerifier {
+            fingerprint: normalize_fingerprint(pin),
+            provider,
+        }
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match fingerprint(end_entity) == self.fingerprint {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(rustls::Error::General(
+                "Server certificate doesn't match the pinned fingerprint".into(),
+            )),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_generate_write_and_describe() {
+        use crate::cert::{describe, ensure_cert, generate_self_signed, read_certs, write_cert};
+        use crate::cert::{fingerprint, normalize_fingerprint};
+
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("certs/cert.pem");
+        let key_path = dir.path().join("certs/key.pem");
+        let sans = vec!["files.example".to_string(), "10.0.0.1".to_string()];
+        let cert = generate_self_signed(&sans, 30).unwrap();
+        write_cert(&cert, &cert_path, &key_path).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&key_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let der = read_certs(&cert_path).unwrap().remove(0);
+        assert_eq!(fingerprint(&der), cert.fingerprint);
+        let description = describe(&cert_path).unwrap();
+        assert!(description.contains("Names: files.example, 10.0.0.1"));
+        assert!(description.contains(&cert.fingerprint));
+
+        //existing pairs are kept, half a pair is an error
+        assert!(ensure_cert(&cert_path, &key_path).unwrap().is_none());
+        std::fs::remove_file(&key_path).unwrap();
+        assert!(ensure_cert(&cert_path, &key_path).is_err());
+
+        let pin = cert.fingerprint.to_uppercase();
+        let colons: Vec<String> = (0..pin.len() / 2)
+            .map(|i| pin[2 * i..2 * i + 2].to_string())
+            .collect();
+        assert_eq!(normalize_fingerprint(&colons.join(":")), cert.fingerprint);
+    }
+}
@@ -0,0 +1,138 @@
+use super::upload::TlsFlags;
+use crate::fileserver::fs::clean_file_name;
+use crate::merkletree::absence::{name_leaf, names_root, AbsenceProof, NeighbourProof};
+use crate::merkletree::tree::{leaf_position, verify_leaf_proof};
+use anyhow::{anyhow, bail, Result};
+use blake3::Hash;
+use reqwest::StatusCode;
+
+pub const ABSENT_USAGE: &str =
+    "Usage: cargo run prove-absent [--ca <cert.pem>] [--identity <cert-and-key.pem>] [--pin <sha256>] [--insecure] [--token <api-token>] <server-url> <file> <names-root>";
+
+// Ask the server to prove a file is not on it and check its answer against the
+// names root printed by upload, which commits to the sorted names and their count
+pub async fn prove_absent(args: &[String]) -> Result<()> {
+    let mut tls = TlsFlags::default();
+    let mut positional: Vec<String> = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !tls.parse(arg, &mut args, ABSENT_USAGE)? {
+            positional.push(arg.clone());
+        }
+    }
+    let [server_url, name, root] = positional.as_slice() else {
+        bail!(ABSENT_USAGE);
+    };
+    //the server proves the name the file would be stored under
+    let name = &clean_file_name(name);
+    let root = Hash::from_hex(root.trim()).map_err(|_| anyhow!("Invalid names root: {}", root))?;
+
+    let response = tls
+        .client()?
+        .get(format!(
+            "{}/prove-absent/{}",
+            server_url.trim_end_matches('/'),
+            name
+        ))
+        .send()
+        .await?;
+    let proof: AbsenceProof = match response.status() {
+        StatusCode::OK => response.json().await?,
+        StatusCode::CONFLICT => bail!("Server gave no proof: {}", response.text().await?),
+        status => bail!("Server gave no proof: {}", status),
+    };
+    if !verify_absence(&proof, name, &root) {
+        bail!("Verification Failed");
+    }
+    let neighbour = |neighbour: &Option<NeighbourProof>| match neighbour {
+        Some(neighbour) => format!("{} (leaf {})", neighbour.name, neighbour.index),
+        None => "nothing".to_string(),
+    };
+    println!("Verification Passed: {} is not in the tree", name);
+    println!(
+        "It would sort between {} and {}",
+        neighbour(&proof.left),
+        neighbour(&proof.right)
+    );
+    Ok(())
+}
+
+// Check the proof's names tree and count make up the names root, and the
+// neighbours prove against it at adjacent positions with the name sorting
+// strictly between them. Names compare by bytes, as the server sorts them.
+pub fn verify_absence(proof: &AbsenceProof, name: &str, root: &Hash) -> bool {
+    let leaf_count = proof.leaf_count;
+    let Ok(tree_root) = Hash::from_hex(&proof.names_tree_root) else {
+        return false;
+    };
+    if proof.name != name || leaf_count == 0 || names_root(&tree_root, leaf_count) != *root {
+        return false;
+    }
+    let valid = |neighbour: &NeighbourProof| {
+        neighbour.index < leaf_count
+            && leaf_position(&neighbour.proof) == Some(neighbour.index)
+            && verify_leaf_proof(
+                &name_leaf(&neighbour.name),
+                &neighbour.proof,
+                tree_root.as_bytes(),
+            )
+    };
+    match (&proof.left, &proof.right) {
+        (Some(left), Some(right)) => {
+            valid(left)
+                && valid(right)
+                && left.index + 1 == right.index
+                && left.name.as_str() < name
+                && name < right.name.as_str()
+        }
+        (Some(left), None) => {
+            valid(left) && left.index == leaf_count - 1 && left.name.as_str() < name
+        }
+        (None, Some(right)) => valid(right) && right.index == 0 && name < right.name.as_str(),
+        (None, None) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_verify_absence() {
+        use crate::client::absent::verify_absence;
+        use crate::merkletree::absence::{names_root, names_tree, AbsenceProof};
+
+        let file_list: Vec<String> = ["b", "d", "f", "h", "j"].map(String::from).to_vec();
+        let tree = names_tree(&file_list);
+        let root = names_root(&tree.root(), 5);
+
+        for name in ["a", "c", "e", "g", "i", "k"] {
+            let proof = AbsenceProof::build(&tree, &file_list, name).unwrap();
+            assert!(verify_absence(&proof, name, &root));
+            assert!(!verify_absence(&proof, "z", &root));
+            //the count is committed, so it can't be claimed smaller
+            let mut shorter = proof.clone();
+            shorter.leaf_count = 4;
+            assert!(!verify_absence(&shorter, name, &root));
+        }
+        assert!(AbsenceProof::build(&tree, &file_list, "d").is_none());
+
+        //neighbours that aren't adjacent don't prove anything
+        let mut proof = AbsenceProof::build(&tree, &file_list, "e").unwrap();
+        proof.right = AbsenceProof::build(&tree, &file_list, "i").unwrap().right;
+        assert!(!verify_absence(&proof, "e", &root));
+
+        //the last name has to really be last
+        let mut proof = AbsenceProof::build(&tree, &file_list, "k").unwrap();
+        proof.left = AbsenceProof::build(&tree, &file_list, "g").unwrap().left;
+        assert!(!verify_absence(&proof, "k", &root));
+
+        //names are committed, so a neighbour can't be renamed to fit
+        let mut proof = AbsenceProof::build(&tree, &file_list, "e").unwrap();
+        proof.left.as_mut().unwrap().name = "dd".to_string();
+        assert!(verify_absence(
+            &AbsenceProof::build(&tree, &file_list, "e").unwrap(),
+            "e",
+            &root
+        ));
+        assert!(!verify_absence(&proof, "e", &root));
+    }
+}
@@ -0,0 +1,135 @@
+use super::upload::TlsFlags;
+use crate::merkletree::zk::{read_key, verify_chain, AppendChain};
+use anyhow::{anyhow, bail, Result};
+use ark_bn254::{Bn254, Fr};
+use ark_groth16::VerifyingKey;
+use reqwest::StatusCode;
+use std::path::Path;
+use std::str::FromStr;
+
+pub const APPEND_USAGE: &str =
+    "Usage: cargo run verify-append [--ca <cert.pem>] [--identity <cert-and-key.pem>] [--pin <sha256>] [--insecure] [--token <api-token>] <server-url> <verifying-key> <file-count> <poseidon-root>";
+
+// Ask the server to prove the files it holds now only extend the ones the client
+// saw, given the file count and Poseidon root from then, and check every step
+// against a verifying key from `cargo run zk setup`
+pub async fn verify_append(args: &[String]) -> Result<()> {
+    let mut tls = TlsFlags::default();
+    let mut positional: Vec<String> = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !tls.parse(arg, &mut args, APPEND_USAGE)? {
+            positional.push(arg.clone());
+        }
+    }
+    let [server_url, vk_path, old_count, old_root] = positional.as_slice() else {
+        bail!(APPEND_USAGE);
+    };
+    let (depth, vk): (usize, VerifyingKey<Bn254>) = read_key(Path::new(vk_path))?;
+    let old_count: u64 = old_count.parse()?;
+    let old_root =
+        Fr::from_str(old_root.trim()).map_err(|_| anyhow!("Invalid root: {}", old_root))?;
+
+    let response = tls
+        .client()?
+        .get(format!(
+            "{}/prove-append/{}",
+            server_url.trim_end_matches('/'),
+            old_count
+        ))
+        .send()
+        .await?;
+    let chain: AppendChain = match response.status() {
+        StatusCode::OK => response.json().await?,
+        StatusCode::NOT_FOUND => bail!("Server has no append proving key"),
+        status => bail!("Server gave no proof: {}", status),
+    };
+    if chain.depth != depth {
+        bail!(
+            "Server proved a tree of depth {}, the key is for {}",
+            chain.depth,
+            depth
+        );
+    }
+    let new_root = verify_chain(&vk, &chain, &old_root, old_count)?;
+    println!("Verification Passed: {} files appended", chain.steps.len());
+    for step in &chain.steps {
+        println!("  {}", step.file);
+    }
+    println!("File count: {}", old_count + chain.steps.len() as u64);
+    println!("Poseidon root: {}", new_root);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_verify_append_from_server() {
+        use crate::fileserver::audit::AuditLog;
+        use crate::fileserver::auth::Auth;
+        use crate::fileserver::fs::tests::upload;
+        use crate::fileserver::routes::routes;
+        use crate::merkletree::poseidon::{field_leaf, PoseidonTree};
+        use crate::merkletree::tree::leaf_hash;
+        use crate::merkletree::zk::{setup, verify_chain, AppendChain, AppendKey};
+        use crate::storage::memory::{MemoryBlobStore, MemoryTreeStore};
+        use crate::storage::Store;
+        use ark_std::rand::rngs::StdRng;
+        use ark_std::rand::SeedableRng;
+        use std::sync::Arc;
+
+        let (pk, vk) = setup(3, 1, &mut StdRng::seed_from_u64(3)).unwrap();
+        let mut store = Store::new(
+            Arc::new(MemoryBlobStore::default()),
+            Arc::new(MemoryTreeStore::default()),
+        )
+        .unwrap();
+        store.append_key = Some(Arc::new(AppendKey { depth: 3, pk }));
+        let route = routes(
+            Arc::new(store),
+            Arc::new(Auth::disabled().unwrap()),
+            Arc::new(AuditLog::temporary().unwrap()),
+        );
+        let upload = |name: &str, contents: &str, mode: &str| {
+            upload(&format!("/upload{}", mode), &[(name, contents)]).reply(&route)
+        };
+        let root = |contents: &[&str]| {
+            let leaves: Vec<_> = contents
+                .iter()
+                .map(|contents| field_leaf(&leaf_hash(contents.as_bytes())))
+                .collect();
+            PoseidonTree::from_leaves(3, &leaves).unwrap().root()
+        };
+        let prove = |old_count: usize| {
+            warp::test::request()
+                .path(&format!("/prove-append/{}", old_count))
+                .reply(&route)
+        };
+
+        assert_eq!(upload("a.txt", "ay", "").await.status(), 200);
+        let old_root = root(&["ay"]);
+        assert_eq!(upload("c.txt", "sea", "?mode=add").await.status(), 200);
+        assert_eq!(upload("b.txt", "bee", "?mode=add").await.status(), 200);
+
+        let response = prove(1).await;
+        assert_eq!(response.status(), 200);
+        let chain: AppendChain = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(chain.steps.len(), 2);
+        assert_eq!(
+            verify_chain(&vk, &chain, &old_root, 1).unwrap(),
+            root(&["ay", "sea", "bee"])
+        );
+        //a root the client never had, or a step out of place, doesn't verify
+        assert!(verify_chain(&vk, &chain, &root(&["bee"]), 1).is_err());
+        let mut swapped = chain.clone();
+        swapped.steps.swap(0, 1);
+        assert!(verify_chain(&vk, &swapped, &old_root, 1).is_err());
+
+        //replacing a file isn't an append, so the old root no longer leads anywhere
+        assert_eq!(upload("a.txt", "eh", "?mode=add").await.status(), 200);
+        let chain: AppendChain = serde_json::from_slice(prove(1).await.body()).unwrap();
+        assert!(verify_chain(&vk, &chain, &old_root, 1).is_err());
+
+        assert_eq!(prove(4).await.status(), 400);
+    }
+}
@@ -1,8 +1,17 @@
-use crate::merkletree::tree::{FastMerkleTree, OFFSET_ONE, OFFSET_TWO};
+use crate::fileserver::fs::clean_file_name;
+use crate::fileserver::health::{check_dir_writable, Readiness};
+use crate::merkletree::absence::{names_root, names_tree};
+use crate::merkletree::sparse::SparseMerkleTree;
+use crate::merkletree::tree::{leaf_hash, verify_leaf_proof, FastMerkleTree, OFFSET_ONE};
 use blake3::Hash;
-use std::{fs::remove_file, path::PathBuf};
-use warp::filters::multipart::FormData;
+use serde::Serialize;
+use std::{fs::remove_file, path::Path, path::PathBuf};
+use tracing::field::Empty;
+use tracing::Span;
+use warp::filters::multipart::{FormData, Part};
+use warp::http::StatusCode;
 use warp::reject::Rejection;
+use warp::reply::Reply;
 
 use futures::TryStreamExt;
 use std::convert::Infallible;
@@ -10,41 +19,37 @@ use std::fs::File;
 use std::io::Write;
 use warp::{self, Buf};
 
-fn verify_proof(file_name: String, proof: Vec<(Vec<u8>, bool)>, root_hash: Vec<u8>) -> bool {
-    //convert proof to vector of hashes
-    let filepath = PathBuf::from(&file_name);
+fn verify_proof(file_name: &str, proof: Vec<(Vec<u8>, bool)>, root_hash: Vec<u8>) -> bool {
+    let filepath = PathBuf::from(file_name);
     if !filepath.exists() {
+        tracing::warn!("No file was uploaded to verify");
         return false;
     }
-    let bytes = std::fs::read(filepath.clone()).unwrap();
-    let mut hash_value = blake3::Hasher::new();
-    hash_value.update(&OFFSET_ONE);
-    hash_value.update(&bytes);
-    let hash_value = hash_value.finalize();
-    let mut current_hash = *hash_value.as_bytes();
-
-    for (sibling_hash, is_left) in proof {
-        let mut hasher = blake3::Hasher::new();
-        hasher.update(&OFFSET_TWO);
-        if is_left {
-            hasher.update(&sibling_hash);
-            hasher.update(&current_hash);
-        } else {
-            hasher.update(&current_hash);
-            hasher.update(&sibling_hash);
+    match std::fs::read(&filepath) {
+        Ok(bytes) => verify_leaf_proof(&leaf_hash(&bytes), &proof, &root_hash),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to read the uploaded file");
+            false
         }
-        current_hash = *hasher.finalize().as_bytes();
     }
-    let result = current_hash.to_vec() == root_hash;
-    //also delete the temp file
-    let _ = remove_file(file_name);
-    result
 }
 
+// The uploaded copy is only needed for one verification
+fn remove_upload(file_name: &str) {
+    if file_name.is_empty() {
+        return;
+    }
+    if let Err(e) = remove_file(file_name) {
+        tracing::warn!(error = %e, file = file_name, "Failed to delete the uploaded file");
+    }
+}
+
+#[tracing::instrument(name = "hash", skip_all, fields(files = Empty, root = Empty))]
 pub async fn handle_file_hash(mut form: FormData) -> Result<impl warp::Reply, Rejection> {
-    let mut file_hash_list: Vec<Hash> = Vec::new();
+    let mut files: Vec<(String, Hash)> = Vec::new();
     while let Ok(Some(part)) = form.try_next().await {
         if part.name() == "file" {
+            let file_name = clean_file_name(part.filename().unwrap_or("uploaded_file"));
             // Stream the uploaded file and calculate its hash
             let mut hasher = blake3::Hasher::new();
             hasher.update(&OFFSET_ONE);
@@ -56,17 +61,18 @@ pub async fn handle_file_hash(mut form: FormData) -> Result<impl warp::Reply, Re
 
             // Calculate the final hash and convert it to a hexadecimal string
             let hash = hasher.finalize();
-            file_hash_list.push(hash);
+            files.push((file_name, hash));
         }
     }
+    //in the order the fileserver stores them, whatever order the browser sent
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    let file_hash_list: Vec<Hash> = files.into_iter().map(|(_, hash)| hash).collect();
     //calculate the root hash
-    let root_hash = format!(
-        "{:?}",
-        FastMerkleTree::get_root_hash_from_leaves(file_hash_list)
-            .value
-            .as_bytes()
-            .to_vec()
-    );
+    Span::current().record("files", file_hash_list.len());
+    let root = FastMerkleTree::get_root_hash_from_leaves(file_hash_list).value;
+    Span::current().record("root", root.to_hex().as_str());
+    tracing::info!("Hashed files");
+    let root_hash = format!("{:?}", root.as_bytes().to_vec());
 
     let response = warp::http::response::Builder::new()
         .header("Content-Type", "text/plain")
@@ -76,9 +82,78 @@ pub async fn handle_file_hash(mut form: FormData) -> Result<impl warp::Reply, Re
     Ok(response)
 }
 
+// Root and leaves in the order the fileserver will store the files, so an upload can
+// carry what the client expects the server to compute
+#[derive(Serialize)]
+pub struct LeafHashes {
+    pub root: String,
+    pub files: Vec<String>,
+    pub leaves: Vec<String>,
+    // Root of the same leaves keyed by name, for checking non-membership proofs
+    pub sparse_root: String,
+    // Commits to the sorted names and their count, for checking absence proofs
+    pub names_root: String,
+}
+
+impl LeafHashes {
+    pub fn from_files(mut files: Vec<(String, Hash)>) -> Option<Self> {
+        if files.is_empty() {
+            return None;
+        }
+        //fileserver sorts by stored file name
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        let (files, leaves): (Vec<String>, Vec<Hash>) = files.into_iter().unzip();
+        let root = FastMerkleTree::get_root_hash_from_leaves(leaves.clone()).value;
+        let sparse = SparseMerkleTree::from_files(files.iter().cloned().zip(leaves.clone()));
+        Some(LeafHashes {
+            root: root.to_hex().to_string(),
+            sparse_root: sparse.root().to_hex().to_string(),
+            names_root: names_root(&names_tree(&files).root(), files.len())
+                .to_hex()
+                .to_string(),
+            files,
+            leaves: leaves
+                .iter()
+                .map(|leaf| leaf.to_hex().to_string())
+                .collect(),
+        })
+    }
+}
+
+#[tracing::instrument(name = "leaf_hashes", skip_all, fields(files = Empty, root = Empty))]
+pub async fn handle_leaf_hashes(mut form: FormData) -> Result<impl warp::Reply, Rejection> {
+    let mut files: Vec<(String, Hash)> = Vec::new();
+    while let Ok(Some(part)) = form.try_next().await {
+        if part.name() == "file" {
+            let file_name = clean_file_name(part.filename().unwrap_or("uploaded_file"));
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(&OFFSET_ONE);
+            let mut stream = part.stream();
+
+            while let Ok(Some(chunk)) = stream.try_next().await {
+                hasher.update(chunk.chunk());
+            }
+            files.push((file_name, hasher.finalize()));
+        }
+    }
+    Span::current().record("files", files.len());
+    match LeafHashes::from_files(files) {
+        Some(leaf_hashes) => {
+            Span::current().record("root", leaf_hashes.root.as_str());
+            tracing::info!("Hashed leaves");
+            Ok(warp::reply::json(&leaf_hashes))
+        }
+        None => {
+            tracing::warn!("No files to hash");
+            Err(warp::reject::not_found())
+        }
+    }
+}
+
+#[tracing::instrument(name = "verify", skip_all, fields(file = Empty, root = Empty, passed = Empty))]
 pub async fn handle_verify(
     mut form: warp::multipart::FormData,
-) -> Result<impl warp::Reply, Infallible> {
+) -> Result<warp::reply::Response, Infallible> {
     let mut root_hash = String::new();
     let mut merkle_proof = String::new();
     let mut file_name = String::new();
@@ -89,51 +164,85 @@ pub async fn handle_verify(
             "file" => {
                 // Handle the file upload
                 let file_path = format!("./{}", part.filename().unwrap_or("uploaded_file"));
-                let mut file = File::create(&file_path).unwrap();
-
-                let mut stream = part.stream();
-                while let Ok(Some(chunk)) = stream.try_next().await {
-                    file.write_all(chunk.chunk()).unwrap();
+                Span::current().record("file", file_path.as_str());
+                if let Err(e) = save_part(&file_path, part).await {
+                    tracing::error!(error = %e, "Failed to save the uploaded file");
+                    remove_upload(&file_path);
+                    return Ok(text_reply(
+                        "Could not save the file",
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    ));
                 }
-
                 file_name = file_path;
             }
-            "value1" => {
-                // Get the first string value
-                let mut data = Vec::new();
-                let mut stream = part.stream();
-                while let Ok(Some(chunk)) = stream.try_next().await {
-                    data.extend(chunk.chunk());
-                }
-                root_hash = String::from_utf8(data).unwrap();
-            }
-            "value2" => {
-                // Get the second string value
-                let mut data = Vec::new();
-                let mut stream = part.stream();
-                while let Ok(Some(chunk)) = stream.try_next().await {
-                    data.extend(chunk.chunk());
-                }
-                merkle_proof = String::from_utf8(data).unwrap();
-            }
+            // root hash
+            "value1" => root_hash = read_text(part).await,
+            // merkle proof
+            "value2" => merkle_proof = read_text(part).await,
             _ => {}
         }
     }
+    let result = check_inputs(&file_name, &root_hash, &merkle_proof);
+    remove_upload(&file_name);
+    Ok(match result {
+        Ok(true) => text_reply("Verification Passed", StatusCode::OK),
+        Ok(false) => text_reply("Verification Failed", StatusCode::OK),
+        Err(message) => text_reply(message, StatusCode::BAD_REQUEST),
+    })
+}
 
-    //format inputs into usable types for inner functions
-    let hash: Vec<u8> = serde_json::from_str(&root_hash).unwrap();
+//format inputs into usable types for inner functions
+fn check_inputs(
+    file_name: &str,
+    root_hash: &str,
+    merkle_proof: &str,
+) -> Result<bool, &'static str> {
+    let hash: Vec<u8> = serde_json::from_str(root_hash).map_err(|e| {
+        tracing::warn!(error = %e, "Root hash is not a byte list");
+        "Root hash is not a byte list"
+    })?;
+    Span::current().record("root", hex::encode(&hash).as_str());
     let formatted_string = merkle_proof
         .replace('(', "[") // Replace '(' with '['
         .replace(')', "]"); // Replace ')' with ']'
+    let proof: Vec<(Vec<u8>, bool)> = serde_json::from_str(&formatted_string).map_err(|e| {
+        tracing::warn!(error = %e, "Merkle proof is not a list of (hash, is_left) pairs");
+        "Merkle proof is not a list of (hash, is_left) pairs"
+    })?;
+    let passed = verify_proof(file_name, proof, hash);
+    Span::current().record("passed", passed);
+    tracing::info!("Verified proof");
+    Ok(passed)
+}
 
-    let proof: Vec<(Vec<u8>, bool)> = serde_json::from_str(&formatted_string).unwrap();
-    let res = verify_proof(file_name, proof, hash);
-    let result = match res {
-        true => "Verification Passed",
-        false => "Verification Failed",
-    };
+async fn save_part(file_path: &str, part: Part) -> std::io::Result<()> {
+    let mut file = File::create(file_path)?;
+    let mut stream = part.stream();
+    while let Ok(Some(chunk)) = stream.try_next().await {
+        file.write_all(chunk.chunk())?;
+    }
+    Ok(())
+}
 
-    Ok(warp::http::response::Builder::new()
-        .header("Content-Type", "text/plain")
-        .body(result.to_string()))
+async fn read_text(part: Part) -> String {
+    let mut data = Vec::new();
+    let mut stream = part.stream();
+    while let Ok(Some(chunk)) = stream.try_next().await {
+        data.extend(chunk.chunk());
+    }
+    String::from_utf8_lossy(&data).into_owned()
+}
+
+fn text_reply(body: &str, status: StatusCode) -> warp::reply::Response {
+    warp::reply::with_status(
+        warp::reply::with_header(body.to_string(), "Content-Type", "text/plain"),
+        status,
+    )
+    .into_response()
+}
+
+// Ready when files sent to verify can be saved in the working directory
+pub async fn handle_readyz() -> Result<warp::reply::Response, Infallible> {
+    let checks = vec![("working_dir", check_dir_writable(Path::new(".")).await)];
+    Ok(Readiness::new(checks).into_response())
 }
@@ -1,3 +1,7 @@
+pub mod absent;
+pub mod append;
 #[allow(clippy::module_inception)]
 pub mod client;
+pub mod nonmember;
 pub mod routes;
+pub mod upload;
@@ -0,0 +1,102 @@
+use super::upload::TlsFlags;
+use crate::fileserver::fs::NonMembership;
+use anyhow::{anyhow, bail, Result};
+use blake3::Hash;
+use reqwest::StatusCode;
+
+pub const NONMEMBER_USAGE: &str =
+    "Usage: cargo run prove-nonmember [--ca <cert.pem>] [--identity <cert-and-key.pem>] [--pin <sha256>] [--insecure] [--token <api-token>] <server-url> <file> <sparse-root>";
+
+// Ask the server to prove a file is not committed and check its answer against the
+// sparse root printed by upload. Slots are addressed by the hash of the name, so
+// unlike prove-absent this still holds after adds out of name order.
+pub async fn prove_nonmember(args: &[String]) -> Result<()> {
+    let mut tls = TlsFlags::default();
+    let mut positional: Vec<String> = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !tls.parse(arg, &mut args, NONMEMBER_USAGE)? {
+            positional.push(arg.clone());
+        }
+    }
+    let [server_url, name, sparse_root] = positional.as_slice() else {
+        bail!(NONMEMBER_USAGE);
+    };
+    let sparse_root = Hash::from_hex(sparse_root.trim())
+        .map_err(|_| anyhow!("Invalid sparse root: {}", sparse_root))?;
+
+    let response = tls
+        .client()?
+        .get(format!(
+            "{}/prove-nonmember/{}",
+            server_url.trim_end_matches('/'),
+            name
+        ))
+        .send()
+        .await?;
+    let proof: NonMembership = match response.status() {
+        StatusCode::OK => response.json().await?,
+        StatusCode::CONFLICT => bail!("{} is present on the server", name),
+        status => bail!("Server gave no proof: {}", status),
+    };
+    if !verify_nonmember(&proof, name, &sparse_root) {
+        bail!("Verification Failed");
+    }
+    println!("Verification Passed: {} is not in the tree", name);
+    Ok(())
+}
+
+// The proof is for this name and its slot is empty under the sparse root
+pub fn verify_nonmember(proof: &NonMembership, name: &str, sparse_root: &Hash) -> bool {
+    proof.file == name && proof.proof.verify_non_inclusion(sparse_root, name)
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_verify_nonmember_from_server() {
+        use crate::client::client::LeafHashes;
+        use crate::client::nonmember::verify_nonmember;
+        use crate::fileserver::fs::tests::{test_server, upload};
+        use crate::fileserver::fs::NonMembership;
+        use crate::merkletree::tree::leaf_hash;
+        use blake3::Hash;
+
+        let (_, route) = test_server();
+        let upload = |name: &str, contents: &str, mode: &str| {
+            upload(&format!("/upload{}", mode), &[(name, contents)]).reply(&route)
+        };
+        assert_eq!(upload("b.txt", "bee", "").await.status(), 200);
+        //added out of name order, which prove-absent can't cover
+        assert_eq!(upload("a.txt", "ay", "?mode=add").await.status(), 200);
+
+        let files = [("a.txt", "ay"), ("b.txt", "bee")]
+            .map(|(name, contents)| (name.to_string(), leaf_hash(contents.as_bytes())));
+        let sparse_root =
+            Hash::from_hex(LeafHashes::from_files(files.to_vec()).unwrap().sparse_root).unwrap();
+
+        let response = warp::test::request()
+            .path("/prove-nonmember/c.txt")
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), 200);
+        let proof: NonMembership = serde_json::from_slice(response.body()).unwrap();
+        assert!(verify_nonmember(&proof, "c.txt", &sparse_root));
+        assert!(!verify_nonmember(&proof, "a.txt", &sparse_root));
+        let old_root = Hash::from_hex(
+            LeafHashes::from_files(files[1..].to_vec())
+                .unwrap()
+                .sparse_root,
+        )
+        .unwrap();
+        assert!(!verify_nonmember(&proof, "c.txt", &old_root));
+
+        for present in ["a.txt", "b.txt"] {
+            let response = warp::test::request()
+                .path(&format!("/prove-nonmember/{}", present))
+                .reply(&route)
+                .await;
+            assert_eq!(response.status(), 409);
+        }
+    }
+}
@@ -1,4 +1,8 @@
-use super::client::{handle_file_hash, handle_verify};
+use super::client::{handle_file_hash, handle_leaf_hashes, handle_readyz, handle_verify};
+use crate::fileserver::health::{handle_healthz, VersionInfo};
+use crate::logging::{request_id, request_span};
+use crate::shutdown;
+use std::net::SocketAddr;
 use warp::Filter;
 
 pub async fn start_local_server() {
@@ -11,6 +15,13 @@ pub async fn start_local_server() {
         .and(warp::multipart::form().max_length(10_000_000))
         .and_then(handle_file_hash);
 
+    // called from the fileserver upload page, which is served from another origin
+    let leaves_route = warp::path("hashleaves")
+        .and(warp::post())
+        .and(warp::multipart::form().max_length(100_000_000))
+        .and_then(handle_leaf_hashes)
+        .with(warp::cors().allow_any_origin().allow_method("POST"));
+
     let verify_page = warp::path("verify")
         .and(warp::get())
         .and(warp::fs::file("./static/verify.html"));
@@ -20,7 +31,35 @@ pub async fn start_local_server() {
         .and(warp::multipart::form().max_length(10_000_000))
         .and_then(handle_verify);
 
-    let routes = hash_page.or(hash_route).or(verify_page).or(verify_route); //.or(static_files);
-                                                                            // Start the server
-    warp::serve(routes).run(([127, 0, 0, 1], 8081)).await;
+    let healthz_route = warp::path!("healthz")
+        .and(warp::get())
+        .and_then(handle_healthz);
+
+    let readyz_route = warp::path!("readyz")
+        .and(warp::get())
+        .and_then(handle_readyz);
+
+    //the client holds no tree, so there is no root to report
+    let version_route = warp::path!("version")
+        .and(warp::get())
+        .map(|| warp::reply::json(&VersionInfo::new(None)));
+
+    let routes = hash_page
+        .or(hash_route)
+        .or(leaves_route)
+        .or(verify_page)
+        .or(verify_route)
+        .or(healthz_route)
+        .or(readyz_route)
+        .or(version_route) //.or(static_files);
+        .with(warp::trace(|info| {
+            let id = request_id(info.request_headers());
+            request_span(&id, info.method().as_str(), info.path())
+        }));
+
+    // Start the server
+    let addr = SocketAddr::from(([127, 0, 0, 1], 8081));
+    tracing::info!(%addr, "Running the local client server");
+    let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(addr, shutdown::signal());
+    server.await;
 }
@@ -0,0 +1,252 @@
+use super::client::LeafHashes;
+use crate::cert::{read_certs, read_key, PinnedCertVerifier};
+use crate::fileserver::fs::clean_file_name;
+use crate::merkletree::sparse::SparseProof;
+use crate::merkletree::tree::{leaf_hash, leaf_position, verify_leaf_proof};
+use anyhow::{anyhow, bail, Result};
+use blake3::Hash;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::multipart::{Form, Part};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::Arc;
+
+pub const UPLOAD_USAGE: &str =
+    "Usage: cargo run upload [--ca <cert.pem>] [--identity <cert-and-key.pem>] [--pin <sha256>] [--insecure] [--token <api-token>] [--add] <server-url> <file>...";
+
+// Upload files from the command line, sending the root computed here as expected_root
+// so the server rejects the batch if it ends up with a different tree. With --add one
+// file goes in next to the ones already there and its proof under the new root is checked.
+pub async fn upload_files(args: &[String]) -> Result<()> {
+    let mut tls = TlsFlags::default();
+    let mut add = false;
+    let mut positional: Vec<String> = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--add" {
+            add = true;
+        } else if !tls.parse(arg, &mut args, UPLOAD_USAGE)? {
+            positional.push(arg.clone());
+        }
+    }
+    if positional.len() < 2 {
+        bail!(UPLOAD_USAGE);
+    }
+    let server_url = positional[0].trim_end_matches('/');
+    if add {
+        if positional.len() != 2 {
+            bail!("--add takes one file");
+        }
+        return add_file(&tls, server_url, &positional[1]).await;
+    }
+
+    let mut form = Form::new();
+    let mut files = Vec::new();
+    for path in &positional[1..] {
+        let data = std::fs::read(path)?;
+        let file_name = Path::new(path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("Not a file: {}", path))?
+            .to_string();
+        files.push((clean_file_name(&file_name), leaf_hash(&data)));
+        form = form.part("file", Part::bytes(data).file_name(file_name));
+    }
+    let leaf_hashes = LeafHashes::from_files(files).ok_or_else(|| anyhow!(UPLOAD_USAGE))?;
+    let form = form.text("expected_root", leaf_hashes.root.clone()).text(
+        "expected_leaves",
+        serde_json::to_string(&leaf_hashes.leaves)?,
+    );
+
+    let response = tls
+        .client()?
+        .post(format!("{}/upload", server_url))
+        .multipart(form)
+        .send()
+        .await?;
+
+    match response.status() {
+        StatusCode::OK => {
+            println!("Uploaded {} files", leaf_hashes.files.len());
+            println!("Root hash: {}", leaf_hashes.root);
+            println!("Sparse root: {}", leaf_hashes.sparse_root);
+            println!("Names root: {}", leaf_hashes.names_root);
+            Ok(())
+        }
+        StatusCode::CONFLICT => {
+            let report: serde_json::Value = response.json().await.unwrap_or_default();
+            eprintln!("{}", serde_json::to_string_pretty(&report)?);
+            bail!("Server computed a different root, upload rejected")
+        }
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            bail!(
+                "Upload failed: {}, check --token or MERKLE_TOKEN",
+                response.status()
+            )
+        }
+        status => bail!("Upload failed: {}", status),
+    }
+}
+
+// Body of a successful add
+#[derive(Deserialize)]
+struct AddedFile {
+    root: String,
+    index: usize,
+    proof: Vec<(String, bool)>,
+    sparse_root: String,
+    sparse_proof: Option<SparseProof>,
+    names_root: String,
+}
+
+async fn add_file(tls: &TlsFlags, server_url: &str, path: &str) -> Result<()> {
+    let data = std::fs::read(path)?;
+    let file_name = Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("Not a file: {}", path))?
+        .to_string();
+    let leaf = leaf_hash(&data);
+    let name = clean_file_name(&file_name);
+    let form = Form::new().part("file", Part::bytes(data).file_name(file_name));
+
+    let response = tls
+        .client()?
+        .post(format!("{}/upload?mode=add", server_url))
+        .multipart(form)
+        .send()
+        .await?;
+    match response.status() {
+        StatusCode::OK => {}
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            bail!(
+                "Upload failed: {}, check --token or MERKLE_TOKEN; replacing a file needs delete",
+                response.status()
+            )
+        }
+        status => bail!("Upload failed: {}", status),
+    }
+
+    //the root now covers files this client never saw, so check the new file is under it
+    let added: AddedFile = response.json().await?;
+    let proof = added
+        .proof
+        .iter()
+        .map(|(node, is_left)| Ok((hex::decode(node)?, *is_left)))
+        .collect::<Result<Vec<_>>>()?;
+    let root = hex::decode(&added.root)?;
+    if leaf_position(&proof) != Some(added.index) || !verify_leaf_proof(&leaf, &proof, &root) {
+        bail!(
+            "Server's proof doesn't put the file under root {}",
+            added.root
+        );
+    }
+    let sparse_root = Hash::from_hex(&added.sparse_root)?;
+    let in_sparse = added
+        .sparse_proof
+        .is_some_and(|proof| proof.verify_inclusion(&sparse_root, &name, &leaf));
+    if !in_sparse {
+        bail!(
+            "Server's proof doesn't put the file under sparse root {}",
+            added.sparse_root
+        );
+    }
+    println!("Added file {}", added.index);
+    println!("Root hash: {}", added.root);
+    println!("Sparse root: {}", added.sparse_root);
+    println!("Names root: {}", added.names_root);
+    Ok(())
+}
+
+// TLS flags shared by the CLI commands, and the API token they authenticate with
+#[derive(Default)]
+pub(crate) struct TlsFlags {
+    ca_cert: Option<String>,
+    identity: Option<String>,
+    pin: Option<String>,
+    insecure: bool,
+    token: Option<String>,
+}
+
+impl TlsFlags {
+    // Take a TLS flag and its value off the arguments; false if arg isn't one
+    pub(crate) fn parse(
+        &mut self,
+        arg: &str,
+        args: &mut std::slice::Iter<String>,
+        usage: &str,
+    ) -> Result<bool> {
+        let mut value = || {
+            Ok::<_, anyhow::Error>(
+                args.next()
+                    .ok_or_else(|| anyhow!(usage.to_string()))?
+                    .clone(),
+            )
+        };
+        match arg {
+            "--ca" => self.ca_cert = Some(value()?),
+            "--identity" => self.identity = Some(value()?),
+            "--pin" => self.pin = Some(value()?),
+            "--insecure" => self.insecure = true,
+            "--token" => self.token = Some(value()?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // Client trusting the given CA, or trusting any certificate with --insecure,
+    // and presenting the client certificate and key in --identity for mutual TLS.
+    // With --pin only the server certificate with that SHA-256 fingerprint is accepted.
+    // Requests carry --token, or MERKLE_TOKEN, as a bearer token.
+    pub(crate) fn client(&self) -> Result<reqwest::Client> {
+        let mut headers = HeaderMap::new();
+        if let Some(token) = self
+            .token
+            .clone()
+            .or_else(|| std::env::var("MERKLE_TOKEN").ok())
+        {
+            let mut value = HeaderValue::from_str(&format!("Bearer {}", token))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        let client = reqwest::Client::builder().default_headers(headers);
+        if let Some(pin) = &self.pin {
+            return Ok(client
+                .use_preconfigured_tls(self.pinned_config(pin)?)
+                .build()?);
+        }
+        let mut client = client;
+        if let Some(ca_cert) = &self.ca_cert {
+            let pem = std::fs::read(ca_cert)?;
+            client = client.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        if let Some(identity) = &self.identity {
+            let pem = std::fs::read(identity)?;
+            client = client.identity(reqwest::Identity::from_pem(&pem)?);
+        }
+        if self.insecure {
+            eprintln!("Warning: not verifying the server certificate");
+            client = client.danger_accept_invalid_certs(true);
+        }
+        Ok(client.build()?)
+    }
+
+    fn pinned_config(&self, pin: &str) -> Result<rustls::ClientConfig> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier::new(pin, provider)));
+        let mut config = match &self.identity {
+            Some(identity) => builder.with_client_auth_cert(
+                read_certs(Path::new(identity))?,
+                read_key(Path::new(identity))?,
+            )?,
+            None => builder.with_no_client_auth(),
+        };
+        //reqwest is built without http2 here
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
@@ -0,0 +1,464 @@
+use super::tls::TlsSettings;
+use crate::cert::{self, read_certs, write_cert, GeneratedCert};
+use crate::storage::env_or;
+use anyhow::{anyhow, bail, Context, Result};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount,
+    NewOrder, Order, OrderStatus, RetryPolicy,
+};
+use rcgen::{CertificateParams, CustomExtension, KeyPair};
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::ClientHello;
+use rustls::sign::{CertifiedKey, SingleCertAndKey};
+use rustls::ServerConfig;
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use warp::Filter;
+
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+pub const DEFAULT_ACCOUNT_PATH: &str = "./certs/acme-account.json";
+// Protocol a tls-alpn-01 validation connection offers, and the only one it accepts
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+// How often the certificate is checked for renewal, and how soon a failed order is retried
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Challenge {
+    // Answered on the TLS port itself, so nothing else has to listen
+    TlsAlpn01,
+    // Answered over plain HTTP, normally on port 80
+    Http01,
+}
+
+#[derive(Clone, Debug)]
+pub struct AcmeSettings {
+    pub domains: Vec<String>,
+    pub directory: String,
+    // mailto: URLs the CA sends expiry notices to
+    pub contact: Vec<String>,
+    pub challenge: Challenge,
+    pub http_addr: SocketAddr,
+    // Root to trust for the directory itself, such as Pebble's test CA
+    pub directory_ca: Option<PathBuf>,
+    pub account_path: PathBuf,
+    pub renew_before_days: i64,
+}
+
+impl AcmeSettings {
+    // ACME is on when MERKLE_ACME_DOMAINS names at least one domain
+    pub fn from_env() -> Result<Option<Self>> {
+        let domains: Vec<String> = env_or("MERKLE_ACME_DOMAINS", "")
+            .split(',')
+            .map(|domain| domain.trim().to_string())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+        if domains.is_empty() {
+            return Ok(None);
+        }
+        let challenge = match env_or("MERKLE_ACME_CHALLENGE", "tls-alpn-01").as_str() {
+            "tls-alpn-01" => Challenge::TlsAlpn01,
+            "http-01" => Challenge::Http01,
+            other => bail!(
+                "Unknown ACME challenge {}, expected tls-alpn-01 or http-01",
+                other
+            ),
+        };
+        Ok(Some(AcmeSettings {
+            domains,
+            directory: env_or("MERKLE_ACME_DIRECTORY", LETS_ENCRYPT_DIRECTORY),
+            contact: std::env::var("MERKLE_ACME_EMAIL")
+                .ok()
+                .filter(|email| !email.is_empty())
+                .map(|email| format!("mailto:{}", email))
+                .into_iter()
+                .collect(),
+            challenge,
+            http_addr: env_or("MERKLE_ACME_HTTP_ADDR", "0.0.0.0:80")
+                .parse()
+                .context("Invalid MERKLE_ACME_HTTP_ADDR")?,
+            directory_ca: std::env::var("MERKLE_ACME_CA")
+                .ok()
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from),
+            account_path: env_or("MERKLE_ACME_ACCOUNT", DEFAULT_ACCOUNT_PATH).into(),
+            renew_before_days: env_or("MERKLE_ACME_RENEW_DAYS", "30")
+                .parse()
+                .context("Invalid MERKLE_ACME_RENEW_DAYS")?,
+        }))
+    }
+}
+
+// Responses to the challenges of an order in flight. The HTTP-01 listener looks
+// up tokens, the TLS acceptor looks up validation certificates by server name.
+#[derive(Default)]
+pub struct Challenges {
+    http: RwLock<HashMap<String, String>>,
+    tls_alpn: RwLock<HashMap<String, Arc<ServerConfig>>>,
+}
+
+impl Challenges {
+    pub fn key_authorization(&self, token: &str) -> Option<String> {
+        self.http.read().unwrap().get(token).cloned()
+    }
+
+    // Only connections offering acme-tls/1 get the validation certificate
+    pub fn tls_alpn_config(&self, hello: &ClientHello) -> Option<Arc<ServerConfig>> {
+        let mut protocols = hello.alpn()?;
+        if !protocols.any(|protocol| protocol == ACME_TLS_ALPN) {
+            return None;
+        }
+        let server_name = hello.server_name()?;
+        self.tls_alpn.read().unwrap().get(server_name).cloned()
+    }
+
+    fn clear(&self) {
+        self.http.write().unwrap().clear();
+        self.tls_alpn.write().unwrap().clear();
+    }
+}
+
+// A self-signed certificate for the domain carrying the SHA-256 of the key
+// authorization in its acmeIdentifier extension (RFC 8737)
+pub fn tls_alpn_config(domain: &str, digest: &[u8]) -> Result<Arc<ServerConfig>> {
+    let mut params = CertificateParams::new(vec![domain.to_string()])?;
+    params
+        .custom_extensions
+        .push(CustomExtension::new_acme_identifier(digest));
+    let key = KeyPair::generate()?;
+    let cert = params.self_signed(&key)?;
+    //webpki rejects the critical acmeIdentifier extension, so the certificate goes in unparsed
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+    let certified = CertifiedKey::new(
+        vec![cert.der().clone()],
+        provider.key_provider.load_private_key(key)?,
+    );
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(SingleCertAndKey::from(certified)));
+    config.alpn_protocols = vec![ACME_TLS_ALPN.to_vec()];
+    Ok(Arc::new(config))
+}
+
+// Obtains a certificate for the configured domains and renews it before it
+// expires, writing it where TlsSettings points so the server reloads it
+pub struct AcmeManager {
+    settings: AcmeSettings,
+    tls: TlsSettings,
+    challenges: Arc<Challenges>,
+}
+
+impl AcmeManager {
+    pub fn new(settings: AcmeSettings, tls: TlsSettings, challenges: Arc<Challenges>) -> Self {
+        AcmeManager {
+            settings,
+            tls,
+            challenges,
+        }
+    }
+
+    // Start answering HTTP-01 challenges if they are used, and the renewal loop
+    pub fn spawn(self) -> Result<()> {
+        if self.settings.challenge == Challenge::Http01 {
+            self.serve_http_challenges()?;
+        }
+        tokio::spawn(async move {
+            loop {
+                let wait = match self.renew_if_needed().await {
+                    Ok(()) => CHECK_INTERVAL,
+                    Err(e) => {
+                        tracing::error!(
+                            error = format!("{:#}", e),
+                            "ACME certificate order failed"
+                        );
+                        RETRY_INTERVAL
+                    }
+                };
+                tokio::time::sleep(wait).await;
+            }
+        });
+        Ok(())
+    }
+
+    fn serve_http_challenges(&self) -> Result<()> {
+        let challenges = Arc::clone(&self.challenges);
+        let route =
+            warp::path!(".well-known" / "acme-challenge" / String).map(move |token: String| {
+                match challenges.key_authorization(&token) {
+                    Some(key_authorization) => warp::http::Response::builder()
+                        .header("content-type", "application/octet-stream")
+                        .body(key_authorization),
+                    None => warp::http::Response::builder()
+                        .status(404)
+                        .body(String::new()),
+                }
+            });
+        let (_, server) = warp::serve(route)
+            .try_bind_ephemeral(self.settings.http_addr)
+            .with_context(|| format!("Binding {}", self.settings.http_addr))?;
+        tokio::spawn(server);
+        Ok(())
+    }
+
+    pub async fn renew_if_needed(&self) -> Result<()> {
+        if self.needs_renewal() {
+            self.issue().await?;
+        }
+        Ok(())
+    }
+
+    // A certificate is due when it is missing, self-signed (the placeholder the
+    // server starts with), lacks one of the domains or expires soon
+    pub fn needs_renewal(&self) -> bool {
+        let Ok(certs) = read_certs(&self.tls.cert_path) else {
+            return true;
+        };
+        let Ok((_, cert)) = x509_parser::parse_x509_certificate(&certs[0]) else {
+            return true;
+        };
+        if cert.subject() == cert.issuer() {
+            return true;
+        }
+        let names: Vec<String> = match cert.subject_alternative_name() {
+            Ok(Some(extension)) => extension
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    x509_parser::extensions::GeneralName::DNSName(name) => Some(name.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        if !self
+            .settings
+            .domains
+            .iter()
+            .all(|domain| names.contains(domain))
+        {
+            return true;
+        }
+        let remaining = cert.validity().time_to_expiration();
+        remaining.is_none_or(|left| left < time::Duration::days(self.settings.renew_before_days))
+    }
+
+    // Run one order through to a certificate on disk
+    pub async fn issue(&self) -> Result<()> {
+        let account = self.account().await?;
+        let identifiers: Vec<Identifier> = self
+            .settings
+            .domains
+            .iter()
+            .map(|domain| Identifier::Dns(domain.clone()))
+            .collect();
+        let mut order = account.new_order(&NewOrder::new(&identifiers)).await?;
+        let ready = self.answer_challenges(&mut order).await;
+        self.challenges.clear();
+        ready?;
+
+        let key_pem = order.finalize().await?;
+        let cert_pem = order.poll_certificate(&RetryPolicy::default()).await?;
+        let der = rustls_pemfile::certs(&mut cert_pem.as_bytes())
+            .next()
+            .ok_or_else(|| anyhow!("The ACME server returned no certificate"))??;
+        let cert = GeneratedCert {
+            fingerprint: cert::fingerprint(&der),
+            cert_pem,
+            key_pem,
+        };
+        write_cert(&cert, &self.tls.cert_path, &self.tls.key_path)?;
+        tracing::info!(
+            domains = %self.settings.domains.join(", "),
+            directory = %self.settings.directory,
+            fingerprint = %cert.fingerprint,
+            "Obtained a certificate"
+        );
+        Ok(())
+    }
+
+    async fn answer_challenges(&self, order: &mut Order) -> Result<()> {
+        let kind = match self.settings.challenge {
+            Challenge::TlsAlpn01 => ChallengeType::TlsAlpn01,
+            Challenge::Http01 => ChallengeType::Http01,
+        };
+        let mut authorizations = order.authorizations();
+        while let Some(authorization) = authorizations.next().await {
+            let mut authorization = authorization?;
+            if authorization.status == AuthorizationStatus::Valid {
+                continue;
+            }
+            let mut challenge = authorization
+                .challenge(kind.clone())
+                .ok_or_else(|| anyhow!("The ACME server offered no {:?} challenge", kind))?;
+            let key_authorization = challenge.key_authorization();
+            match self.settings.challenge {
+                Challenge::TlsAlpn01 => {
+                    let domain = challenge.identifier().to_string();
+                    let config = tls_alpn_config(&domain, key_authorization.digest().as_ref())?;
+                    self.challenges
+                        .tls_alpn
+                        .write()
+                        .unwrap()
+                        .insert(domain, config);
+                }
+                Challenge::Http01 => {
+                    self.challenges.http.write().unwrap().insert(
+                        challenge.token.clone(),
+                        key_authorization.as_str().to_string(),
+                    );
+                }
+            }
+            challenge.set_ready().await?;
+        }
+        match order.poll_ready(&RetryPolicy::default()).await? {
+            OrderStatus::Ready => Ok(()),
+            status => bail!("ACME order ended up {:?}", status),
+        }
+    }
+
+    // The account is registered once and its key kept next to the certificate
+    async fn account(&self) -> Result<Account> {
+        //instant-acme's HTTP client uses the process-wide rustls provider
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let builder = match &self.settings.directory_ca {
+            Some(ca) => Account::builder_with_root(ca)?,
+            None => Account::builder()?,
+        };
+        let path = &self.settings.account_path;
+        if path.exists() {
+            let credentials: AccountCredentials = serde_json::from_slice(&std::fs::read(path)?)
+                .with_context(|| format!("Reading {}", path.display()))?;
+            return Ok(builder.from_credentials(credentials).await?);
+        }
+        let contact: Vec<&str> = self.settings.contact.iter().map(String::as_str).collect();
+        let (account, credentials) = builder
+            .create(
+                &NewAccount {
+                    contact: &contact,
+                    terms_of_service_agreed: true,
+                    only_return_existing: false,
+                },
+                self.settings.directory.clone(),
+                None,
+            )
+            .await?;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(path)?
+            .write_all(&serde_json::to_vec(&credentials)?)?;
+        Ok(account)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_renewal_and_tls_alpn_certificate() {
+        use crate::cert::{generate_self_signed, write_cert};
+        use crate::fileserver::acme::{tls_alpn_config, AcmeManager, AcmeSettings, Challenge};
+        use crate::fileserver::tls::TlsSettings;
+        use rcgen::{CertificateParams, IsCa, Issuer, KeyPair};
+        use time::{Duration, OffsetDateTime};
+
+        let dir = tempfile::tempdir().unwrap();
+        let tls = TlsSettings {
+            cert_path: dir.path().join("cert.pem"),
+            key_path: dir.path().join("key.pem"),
+            client_ca_path: None,
+            client_auth_optional: false,
+            generate_if_missing: true,
+        };
+        let settings = AcmeSettings {
+            domains: vec!["files.example".to_string()],
+            directory: "https://localhost:14000/dir".to_string(),
+            contact: Vec::new(),
+            challenge: Challenge::TlsAlpn01,
+            http_addr: ([127, 0, 0, 1], 0).into(),
+            directory_ca: None,
+            account_path: dir.path().join("account.json"),
+            renew_before_days: 30,
+        };
+        let manager = AcmeManager::new(settings, tls.clone(), Default::default());
+        assert!(manager.needs_renewal());
+
+        //the self-signed placeholder is replaced even though it names the domain
+        let placeholder = generate_self_signed(&["files.example".to_string()], 365).unwrap();
+        write_cert(&placeholder, &tls.cert_path, &tls.key_path).unwrap();
+        assert!(manager.needs_renewal());
+
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "Test CA");
+        ca_params.is_ca = IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let issuer = Issuer::new(ca_params, ca_key);
+        let issued = |name: &str, days: i64| {
+            let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            params.not_after = OffsetDateTime::now_utc() + Duration::days(days);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &issuer).unwrap();
+            let cert = crate::cert::GeneratedCert {
+                cert_pem: cert.pem(),
+                key_pem: key.serialize_pem(),
+                fingerprint: String::new(),
+            };
+            write_cert(&cert, &tls.cert_path, &tls.key_path).unwrap();
+        };
+        issued("files.example", 90);
+        assert!(!manager.needs_renewal());
+        issued("files.example", 10);
+        assert!(manager.needs_renewal());
+        issued("other.example", 90);
+        assert!(manager.needs_renewal());
+
+        let config = tls_alpn_config("files.example", &[7u8; 32]).unwrap();
+        assert_eq!(config.alpn_protocols, vec![b"acme-tls/1".to_vec()]);
+    }
+
+    // Needs a Pebble server answering for the domain, see the README:
+    //   MERKLE_ACME_DIRECTORY=https://localhost:14000/dir MERKLE_ACME_CA=pebble.minica.pem \
+    //   MERKLE_ACME_DOMAINS=files.example MERKLE_ACME_HTTP_ADDR=0.0.0.0:5002 cargo test -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn test_http01_order_against_pebble() {
+        use crate::cert::describe;
+        use crate::fileserver::acme::{AcmeManager, AcmeSettings, Challenge};
+        use crate::fileserver::tls::TlsSettings;
+
+        let dir = tempfile::tempdir().unwrap();
+        let tls = TlsSettings {
+            cert_path: dir.path().join("cert.pem"),
+            key_path: dir.path().join("key.pem"),
+            client_ca_path: None,
+            client_auth_optional: false,
+            generate_if_missing: true,
+        };
+        let settings = AcmeSettings {
+            challenge: Challenge::Http01,
+            account_path: dir.path().join("account.json"),
+            ..AcmeSettings::from_env().unwrap().unwrap()
+        };
+        let domains = settings.domains.clone();
+        let manager = AcmeManager::new(settings, tls.clone(), Default::default());
+        manager.serve_http_challenges().unwrap();
+        manager.renew_if_needed().await.unwrap();
+        assert!(!manager.needs_renewal());
+        let description = describe(&tls.cert_path).unwrap();
+        assert!(description.contains(&domains[0]));
+        assert!(description.contains("Pebble"));
+    }
+}
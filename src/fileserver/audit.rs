@@ -0,0 +1,490 @@
+use super::auth::{identity, Auth, Identity};
+use crate::fileserver::auth::session::now_secs;
+use crate::merkletree::mmr::{MerkleMountainRange, MmrProof};
+use crate::storage::env_or;
+use crate::storage::memory::MemoryMmrStore;
+use crate::storage::sled_mmr::SledMmrStore;
+use anyhow::{anyhow, bail, ensure, Result};
+use blake3::Hash;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io::Write;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use warp::http::StatusCode;
+use warp::reply::Reply;
+use warp::Filter;
+
+// Layout in the audit database:
+//   audit: sequence number (u64 big-endian) -> AuditEntry as JSON
+//   mmr_nodes: mountain range over the entry hashes, leaf n being entry n
+// Each entry holds the hash of the one before, so changing or removing an entry
+// breaks every hash after it, and the range root commits to the whole log.
+const AUDIT_TREE: &str = "audit";
+
+pub const AUDIT_USAGE: &str = "Usage: cargo run audit export [--since <seq>]
+       cargo run audit verify";
+
+// What happened. Hashes and roots are hex.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    // A batch swapped in, with the root it produced and the one the client expected
+    Upload {
+        files: Vec<String>,
+        root: String,
+        expected_root: Option<String>,
+    },
+    // A batch turned away because its root wasn't the one the client expected
+    UploadRejected {
+        files: Vec<String>,
+        expected_root: String,
+        computed_root: String,
+    },
+    // A file served, with the proof sent alongside it
+    Download {
+        file: String,
+        root: Option<String>,
+        proof: Vec<(String, bool)>,
+    },
+    // An absence, witness or solidity proof issued
+    Proof {
+        kind: String,
+        file: String,
+        root: Option<String>,
+    },
+    Login {
+        method: String,
+        success: bool,
+    },
+    Logout,
+    // A request refused for lack of a session, role or scope, with how many more
+    // refusals of the same actor went unrecorded since their last entry
+    Denied {
+        path: String,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        repeats: u64,
+    },
+    // A token or grant created or removed
+    Admin {
+        action: String,
+        target: String,
+    },
+}
+
+fn is_zero(count: &u64) -> bool {
+    *count == 0
+}
+
+// Refusals are written at most once a window per actor, so a client retrying
+// without credentials can't flood the log; the rest are counted into the next one
+const DENIED_WINDOW_SECS: u64 = 60;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    // Unix seconds
+    pub time: u64,
+    pub actor: String,
+    // Id of the API token the request came with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+    // Hash of the entry before, zeros for the first
+    pub prev: String,
+    // blake3 of this entry as JSON with hash empty
+    pub hash: String,
+}
+
+impl AuditEntry {
+    fn compute_hash(&self) -> Result<Hash> {
+        let unhashed = AuditEntry {
+            hash: String::new(),
+            ..self.clone()
+        };
+        Ok(blake3::hash(&serde_json::to_vec(&unhashed)?))
+    }
+}
+
+// Where the log stands: a root to publish or compare later copies against
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AuditHead {
+    pub entries: u64,
+    pub head: String,
+    pub root: Option<String>,
+}
+
+fn parse_hash(hex_hash: &str) -> Result<Hash> {
+    Ok(Hash::from_bytes(
+        hex::decode(hex_hash)?.as_slice().try_into()?,
+    ))
+}
+
+pub struct AuditLog {
+    entries: sled::Tree,
+    mmr: MerkleMountainRange,
+    // Next sequence number and the last entry's hash. Appends hold it so the
+    // chain and the range stay in step.
+    head: Mutex<(u64, Hash)>,
+    // Wakes the thread that flushes appends to disk, so requests never wait on it.
+    // Appends while a flush is pending are covered by that flush.
+    flush_requests: SyncSender<()>,
+    // Per actor: when their refusal window started and the refusals since not written
+    denied: Mutex<HashMap<String, (u64, u64)>>,
+    db: sled::Db,
+}
+
+impl AuditLog {
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        let entries = db.open_tree(AUDIT_TREE)?;
+        let mmr = MerkleMountainRange::new(Arc::new(SledMmrStore::open(db)?));
+        let head = match entries.last()? {
+            Some((_, value)) => {
+                let last: AuditEntry = serde_json::from_slice(&value)?;
+                (last.seq + 1, parse_hash(&last.hash)?)
+            }
+            None => (0, Hash::from_bytes([0; 32])),
+        };
+        //an entry written just before a crash may be missing from the range
+        for seq in mmr.leaf_count()?..head.0 {
+            let entry = entries
+                .get(seq.to_be_bytes())?
+                .ok_or_else(|| anyhow!("Audit entry {} is missing", seq))?;
+            let entry: AuditEntry = serde_json::from_slice(&entry)?;
+            mmr.append(&[parse_hash(&entry.hash)?])?;
+        }
+        let (flush_requests, pending) = mpsc::sync_channel::<()>(1);
+        let flushed = db.clone();
+        //ends once the log is dropped and the sender with it
+        std::thread::Builder::new()
+            .name("audit-flush".to_string())
+            .spawn(move || {
+                while pending.recv().is_ok() {
+                    if let Err(e) = flushed.flush() {
+                        tracing::error!(error = %e, "Failed to flush the audit log");
+                    }
+                }
+            })?;
+        Ok(AuditLog {
+            entries,
+            mmr,
+            head: Mutex::new(head),
+            flush_requests,
+            denied: Mutex::new(HashMap::new()),
+            db: db.clone(),
+        })
+    }
+
+    // Kept in MERKLE_AUDIT_DB (default ./audit_db)
+    pub fn from_env() -> Result<Self> {
+        AuditLog::open(&sled::open(env_or("MERKLE_AUDIT_DB", "audit_db"))?)
+    }
+
+    // Nothing persisted, for tests and benches
+    pub fn temporary() -> Result<Self> {
+        AuditLog::open(&sled::Config::new().temporary(true).open()?)
+    }
+
+    pub fn append(&self, identity: &Identity, event: AuditEvent) -> Result<AuditEntry> {
+        let mut head = self
+            .head
+            .lock()
+            .map_err(|_| anyhow!("Audit log poisoned"))?;
+        let (seq, prev) = *head;
+        let mut entry = AuditEntry {
+            seq,
+            time: now_secs(),
+            actor: identity.username.clone(),
+            token: identity.token.as_ref().map(|token| token.id.clone()),
+            event,
+            prev: prev.to_hex().to_string(),
+            hash: String::new(),
+        };
+        let hash = entry.compute_hash()?;
+        entry.hash = hash.to_hex().to_string();
+        self.entries
+            .insert(seq.to_be_bytes(), serde_json::to_vec(&entry)?)?;
+        self.mmr.append(&[hash])?;
+        *head = (seq + 1, hash);
+        //a full channel means a flush is already waiting to run
+        let _ = self.flush_requests.try_send(());
+        Ok(entry)
+    }
+
+    // Append, reporting failures rather than passing them on, so a broken log
+    // doesn't fail the request being recorded
+    pub fn record(&self, identity: &Identity, event: AuditEvent) {
+        if let Err(e) = self.append(identity, event) {
+            tracing::error!(error = format!("{:#}", e), "Failed to write the audit log");
+        }
+    }
+
+    // Record a refusal, or only count it if the actor had one recorded within the window
+    pub fn record_denied(&self, identity: &Identity, path: &str) {
+        let now = now_secs();
+        let repeats = {
+            let mut denied = self.denied.lock().unwrap();
+            match denied.get_mut(&identity.username) {
+                Some((start, skipped)) if now < *start + DENIED_WINDOW_SECS => {
+                    *skipped += 1;
+                    return;
+                }
+                Some((start, skipped)) => {
+                    *start = now;
+                    std::mem::take(skipped)
+                }
+                None => {
+                    denied.insert(identity.username.clone(), (now, 0));
+                    0
+                }
+            }
+        };
+        self.record(
+            identity,
+            AuditEvent::Denied {
+                path: path.to_string(),
+                repeats,
+            },
+        );
+    }
+
+    pub fn entries(&self, since: u64) -> impl Iterator<Item = Result<AuditEntry>> {
+        self.entries
+            .range(since.to_be_bytes()..)
+            .values()
+            .map(|value| Ok(serde_json::from_slice(&value?)?))
+    }
+
+    pub fn head(&self) -> Result<AuditHead> {
+        let head = self
+            .head
+            .lock()
+            .map_err(|_| anyhow!("Audit log poisoned"))?;
+        Ok(AuditHead {
+            entries: head.0,
+            head: head.1.to_hex().to_string(),
+            root: self.mmr.root()?.map(|root| root.to_hex().to_string()),
+        })
+    }
+
+    // An entry and the proof it is under the current root
+    pub fn proof(&self, seq: u64) -> Result<Option<(AuditEntry, MmrProof)>> {
+        let Some(value) = self.entries.get(seq.to_be_bytes())? else {
+            return Ok(None);
+        };
+        Ok(Some((
+            serde_json::from_slice(&value)?,
+            self.mmr.get_proof(seq)?,
+        )))
+    }
+
+    pub fn size_on_disk(&self) -> Result<u64> {
+        Ok(self.db.size_on_disk()?)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // Walk the whole log checking every hash, link and sequence number, and
+    // that the range root matches one rebuilt from the entries
+    pub fn verify(&self) -> Result<AuditHead> {
+        let rebuilt = MerkleMountainRange::new(Arc::new(MemoryMmrStore::default()));
+        let mut prev = Hash::from_bytes([0; 32]);
+        let mut count = 0;
+        for entry in self.entries(0) {
+            let entry = entry?;
+            ensure!(entry.seq == count, "Audit entry {} is missing", count);
+            ensure!(
+                parse_hash(&entry.prev)? == prev,
+                "Audit entry {} doesn't follow the one before",
+                entry.seq
+            );
+            let hash = entry.compute_hash()?;
+            ensure!(
+                parse_hash(&entry.hash)? == hash,
+                "Audit entry {} was changed",
+                entry.seq
+            );
+            rebuilt.append(&[hash])?;
+            prev = hash;
+            count += 1;
+        }
+        let head = self.head()?;
+        ensure!(
+            head.entries == count && rebuilt.root()? == self.mmr.root()?,
+            "Audit log and its mountain range disagree"
+        );
+        Ok(head)
+    }
+}
+
+// Records events for one request's identity
+#[derive(Clone)]
+pub struct Auditor {
+    log: Arc<AuditLog>,
+    identity: Identity,
+}
+
+impl Auditor {
+    pub fn new(log: Arc<AuditLog>, identity: Identity) -> Self {
+        Auditor { log, identity }
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        self.log.record(&self.identity, event);
+    }
+}
+
+// An Auditor for the request's identity, anonymous if it has none
+pub fn auditor(
+    log: Arc<AuditLog>,
+    auth: Arc<Auth>,
+) -> impl Filter<Extract = (Auditor,), Error = Infallible> + Clone {
+    identity(auth).map(move |identity: Option<Identity>| {
+        Auditor::new(
+            Arc::clone(&log),
+            identity.unwrap_or_else(Identity::anonymous),
+        )
+    })
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    since: Option<u64>,
+}
+
+// The log as JSON Lines, one entry per line in order
+pub async fn handle_export(
+    log: Arc<AuditLog>,
+    query: ExportQuery,
+) -> Result<warp::reply::Response, Infallible> {
+    let mut body = Vec::new();
+    for entry in log.entries(query.since.unwrap_or(0)) {
+        let line = entry.and_then(|entry| Ok(serde_json::to_vec(&entry)?));
+        match line {
+            Ok(line) => {
+                body.extend_from_slice(&line);
+                body.push(b'\n');
+            }
+            Err(_) => return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+        }
+    }
+    Ok(warp::reply::with_header(body, "Content-Type", "application/x-ndjson").into_response())
+}
+
+pub async fn handle_verify(log: Arc<AuditLog>) -> Result<warp::reply::Response, Infallible> {
+    Ok(match log.verify() {
+        Ok(head) => warp::reply::json(&head).into_response(),
+        Err(e) => {
+            warp::reply::with_status(format!("{:#}", e), StatusCode::CONFLICT).into_response()
+        }
+    })
+}
+
+#[derive(Serialize)]
+struct EntryProof {
+    entry: AuditEntry,
+    proof: MmrProof,
+}
+
+pub async fn handle_proof(
+    seq: u64,
+    log: Arc<AuditLog>,
+) -> Result<warp::reply::Response, Infallible> {
+    Ok(match log.proof(seq) {
+        Ok(Some((entry, proof))) => warp::reply::json(&EntryProof { entry, proof }).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    })
+}
+
+// `audit export` writes the log as JSON Lines to stdout and `audit verify`
+// checks it. The server keeps the database open, so stop it first.
+pub fn run(args: &[String]) -> Result<()> {
+    let log = AuditLog::from_env()?;
+    match args {
+        [command, rest @ ..] if command == "export" => {
+            let since = match rest {
+                [] => 0,
+                [flag, seq] if flag == "--since" => seq.parse()?,
+                _ => bail!(AUDIT_USAGE),
+            };
+            let mut out = std::io::stdout().lock();
+            for entry in log.entries(since) {
+                serde_json::to_writer(&mut out, &entry?)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        [command] if command == "verify" => {
+            let head = log.verify()?;
+            println!("{} entries, head {}", head.entries, head.head);
+            println!("Root: {}", head.root.unwrap_or_default());
+        }
+        _ => bail!(AUDIT_USAGE),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_audit_log_is_chained_and_committed() {
+        use crate::fileserver::audit::{AuditEvent, AuditLog};
+        use crate::fileserver::auth::Identity;
+        use crate::merkletree::commitment::VectorCommitment;
+        use crate::merkletree::mmr::MerkleMountainRange;
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let log = AuditLog::open(&db).unwrap();
+        let alice = Identity {
+            username: "alice".to_string(),
+            roles: Vec::new(),
+            token: None,
+        };
+        for i in 0..5 {
+            log.append(
+                &alice,
+                AuditEvent::Download {
+                    file: format!("file{}", i),
+                    root: None,
+                    proof: vec![("ab".to_string(), true)],
+                },
+            )
+            .unwrap();
+        }
+        log.append(&alice, AuditEvent::Logout).unwrap();
+        let head = log.verify().unwrap();
+        assert_eq!(head.entries, 6);
+        let entries: Vec<_> = log.entries(4).map(Result::unwrap).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].prev, entries[0].hash);
+
+        //each entry is provably under the root
+        let root = hex::decode(head.root.clone().unwrap()).unwrap();
+        let (entry, proof) = log.proof(2).unwrap().unwrap();
+        let leaf = blake3::Hash::from_hex(&entry.hash).unwrap();
+        assert!(MerkleMountainRange::verify(&(), &root, 2, &leaf, &proof));
+
+        //reopening picks up where the chain left off
+        drop(log);
+        let log = AuditLog::open(&db).unwrap();
+        assert_eq!(log.append(&alice, AuditEvent::Logout).unwrap().seq, 6);
+        log.verify().unwrap();
+
+        //editing an entry in place is caught
+        let tree = db.open_tree("audit").unwrap();
+        let edited = String::from_utf8(tree.get(1u64.to_be_bytes()).unwrap().unwrap().to_vec())
+            .unwrap()
+            .replace("file1", "other");
+        tree.insert(1u64.to_be_bytes(), edited.as_bytes()).unwrap();
+        assert!(log.verify().is_err());
+    }
+}
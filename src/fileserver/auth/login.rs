@@ -1,43 +1,231 @@
-// use warp::Filter;
-// use cookie::{Cookie, CookieJar};
-// use serde::Deserialize;
-// use std::collections::HashMap;
-// use std::sync::{Arc, Mutex};
-// use warp::Rejection;
-
-// // Simple in-memory storage for user sessions
-// pub type Sessions = Arc<Mutex<HashMap<String, String>>>;
-
-// #[derive(Deserialize)]
-// pub struct LoginRequest {
-//     username: String,
-//     password: String,
-// }
-
-// // Middleware to check if a user is logged in
-// pub async fn verify_session(sessions: Sessions, cookie_header: Option<String>) -> Result<(), Rejection> {
-//     let cookie_header = cookie_header.unwrap().clone();
-
-//         let mut cookie_jar = CookieJar::new();
-//         let parts: Vec<String> = cookie_header.split(' ').map(|s| s.to_string()).collect();      //  let parts: Vec<String> = cookie_header.split(' ');//.map(|s| s.to_string()).collect();
-//         for cookie in parts {
-//             let cookie: String = cookie.clone().trim().to_string();
-//             if let Ok(cookie) = Cookie::parse(cookie) {
-//                 cookie_jar.add_original(cookie.clone());
-//             }
-//         }
-
-//         if let Some(session_cookie) = cookie_jar.get("session") {
-//             let session_id = session_cookie.value();
-//             let sessions = sessions.lock().unwrap();
-//             if sessions.contains_key(session_id) {
-//                 return Ok(());
-//             }
-//         }
-
-//  //  let s = println!("{:?}",cookie_header);
-
-//     // Redirect to login page if session is invalid
-//     Err(warp::reject::not_found())
-
-// }
+use super::session::SessionStore;
+use super::{Auth, Identity};
+use crate::fileserver::audit::{AuditEvent, AuditLog};
+use crate::storage::env_or;
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use warp::http::{header, Response, StatusCode};
+use warp::hyper::Body;
+use warp::reply::Reply;
+
+// Cookie tying the OIDC callback to the browser that started the sign-in
+pub const OIDC_STATE_COOKIE: &str = "merkle_oidc_state";
+
+pub const USER_USAGE: &str =
+    "Usage: cargo run user add <name> [--role <role>]...   (password read from stdin)
+       cargo run user remove <name>
+       cargo run user list";
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+fn redirect(location: &str, cookies: &[String]) -> warp::reply::Response {
+    let mut response = Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, location);
+    for cookie in cookies {
+        response = response.header(header::SET_COOKIE, cookie);
+    }
+    response.body(Body::empty()).unwrap()
+}
+
+// Local username and password, as JSON from the login page
+// Sign-in attempts are recorded under the name they were for
+fn record_login(audit: &AuditLog, username: &str, method: &str, success: bool) {
+    let identity = Identity {
+        username: username.to_string(),
+        ..Identity::anonymous()
+    };
+    let method = method.to_string();
+    audit.record(&identity, AuditEvent::Login { method, success });
+}
+
+pub async fn handle_login(
+    auth: Arc<Auth>,
+    audit: Arc<AuditLog>,
+    request: LoginRequest,
+) -> Result<warp::reply::Response, Infallible> {
+    //password hashing is slow on purpose, so keep it off the async threads
+    let users = auth.users.clone();
+    let username = request.username.clone();
+    let user = tokio::task::spawn_blocking(move || {
+        users.authenticate(&request.username, &request.password)
+    })
+    .await;
+    let user = match user {
+        Ok(Ok(Some(user))) => user,
+        Ok(Ok(None)) => {
+            record_login(&audit, &username, "password", false);
+            return Ok(StatusCode::UNAUTHORIZED.into_response());
+        }
+        _ => return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+    };
+    record_login(&audit, &username, "password", true);
+    let Ok(token) = auth.sessions.create(&user.username) else {
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    };
+    let identity = Identity {
+        username: user.username,
+        roles: user.roles,
+        token: None,
+    };
+    Ok(warp::reply::with_header(
+        warp::reply::json(&identity),
+        header::SET_COOKIE,
+        auth.sessions.cookie(&token),
+    )
+    .into_response())
+}
+
+pub async fn handle_logout(
+    auth: Arc<Auth>,
+    audit: Arc<AuditLog>,
+    session: Option<String>,
+) -> Result<warp::reply::Response, Infallible> {
+    if let Some(token) = session {
+        if let Some(identity) = auth.session_identity(&token) {
+            audit.record(&identity, AuditEvent::Logout);
+        }
+        if let Err(e) = auth.sessions.remove(&token) {
+            tracing::error!(error = format!("{:#}", e), "Failed to end a session");
+        }
+    }
+    Ok(redirect("/login", &[SessionStore::clear_cookie()]))
+}
+
+pub async fn handle_whoami(
+    identity: Option<Identity>,
+) -> Result<warp::reply::Response, Infallible> {
+    Ok(match identity {
+        Some(identity) => warp::reply::json(&identity).into_response(),
+        None => StatusCode::UNAUTHORIZED.into_response(),
+    })
+}
+
+// Send the browser to the provider, remembering the state in a cookie
+pub async fn handle_oidc_login(auth: Arc<Auth>) -> Result<warp::reply::Response, Infallible> {
+    let Some(oidc) = &auth.oidc else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    match oidc.authorization_url() {
+        Ok((url, state)) => Ok(redirect(
+            &url,
+            &[format!(
+                "{}={}; Path=/auth/oidc; HttpOnly; Secure; SameSite=Lax; Max-Age=600",
+                OIDC_STATE_COOKIE, state
+            )],
+        )),
+        Err(_) => Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+    }
+}
+
+// The provider redirects here with a code. On success the user gets a session
+// and lands on the file list.
+pub async fn handle_oidc_callback(
+    auth: Arc<Auth>,
+    audit: Arc<AuditLog>,
+    query: CallbackQuery,
+    state_cookie: Option<String>,
+) -> Result<warp::reply::Response, Infallible> {
+    let Some(oidc) = &auth.oidc else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let unauthorized = |message: String| {
+        warp::reply::with_status(message, StatusCode::UNAUTHORIZED).into_response()
+    };
+    if let Some(error) = query.error {
+        return Ok(unauthorized(format!("Sign-in failed: {}", error)));
+    }
+    let (Some(code), Some(state)) = (query.code, query.state) else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+    if state_cookie.as_deref() != Some(state.as_str()) {
+        return Ok(unauthorized(
+            "Sign-in was started in another browser".to_string(),
+        ));
+    }
+    let login = match oidc.exchange(&code, &state).await {
+        Ok(login) => login,
+        Err(e) => {
+            record_login(&audit, &Identity::anonymous().username, "oidc", false);
+            return Ok(unauthorized(format!("Sign-in failed: {:#}", e)));
+        }
+    };
+    let user = match auth
+        .users
+        .upsert_oidc(&login.username, &login.subject, login.roles)
+    {
+        Ok(user) => user,
+        Err(e) => {
+            record_login(&audit, &login.username, "oidc", false);
+            return Ok(unauthorized(e.to_string()));
+        }
+    };
+    record_login(&audit, &user.username, "oidc", true);
+    let Ok(token) = auth.sessions.create(&user.username) else {
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    };
+    Ok(redirect(
+        "/list",
+        &[
+            auth.sessions.cookie(&token),
+            format!("{}=; Path=/auth/oidc; Max-Age=0", OIDC_STATE_COOKIE),
+        ],
+    ))
+}
+
+// `user add` creates or replaces a local user, `user remove` and `user list`
+// manage them. The server keeps the database open, so stop it first.
+pub fn run(args: &[String]) -> Result<()> {
+    let db = sled::open(env_or("MERKLE_AUTH_DB", "auth_db"))?;
+    let auth = Auth::open(&db, true)?;
+    match args {
+        [command, name, rest @ ..] if command == "add" => {
+            let mut roles = Vec::new();
+            let mut rest = rest.iter();
+            while let Some(arg) = rest.next() {
+                match (arg.as_str(), rest.next()) {
+                    ("--role", Some(role)) => roles.push(role.clone()),
+                    _ => bail!(USER_USAGE),
+                }
+            }
+            let mut password = String::new();
+            std::io::stdin().read_line(&mut password)?;
+            let user =
+                auth.users
+                    .add_local(name, password.trim_end_matches(['\r', '\n']), roles)?;
+            println!(
+                "Saved {} with roles [{}]",
+                user.username,
+                user.roles.join(", ")
+            );
+        }
+        [command, name] if command == "remove" => match auth.users.remove(name)? {
+            true => println!("Removed {}", name),
+            false => bail!("No user {}", name),
+        },
+        [command] if command == "list" => {
+            for user in auth.users.list()? {
+                let source = match user.oidc_subject {
+                    Some(subject) => format!("oidc {}", subject),
+                    None => "local".to_string(),
+                };
+                println!("{}\t{}\t{}", user.username, user.roles.join(","), source);
+            }
+        }
+        _ => bail!(USER_USAGE),
+    }
+    Ok(())
+}
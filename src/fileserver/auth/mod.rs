@@ -1 +1,342 @@
-pub(crate) mod login;
+pub mod login;
+pub mod oidc;
+pub mod rbac;
+pub mod session;
+pub mod tokens;
+pub mod users;
+
+use crate::cert::{self, normalize_fingerprint};
+use crate::fileserver::audit::AuditLog;
+use crate::fileserver::tls::Peer;
+use crate::metrics::metrics;
+use crate::storage::env_or;
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use oidc::{OidcProvider, OidcSettings};
+use rbac::{Action, GrantStore};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use session::{SessionStore, SESSION_COOKIE};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokens::{ApiToken, TokenStore};
+use users::UserStore;
+use warp::http::{header, HeaderMap, StatusCode};
+use warp::path::FullPath;
+use warp::{Filter, Rejection, Reply};
+
+// Who a request is from. With auth off every request is the anonymous user.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Identity {
+    pub username: String,
+    pub roles: Vec<String>,
+    // Set when the request came with an API token rather than a session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ApiToken>,
+}
+
+impl Identity {
+    pub fn anonymous() -> Self {
+        Identity {
+            username: "anonymous".to_string(),
+            roles: Vec::new(),
+            token: None,
+        }
+    }
+}
+
+// Users, sessions, API tokens and grants live in their own sled database,
+// separate from the tree, so they survive uploads and server restarts
+pub struct Auth {
+    pub users: UserStore,
+    pub sessions: SessionStore,
+    pub tokens: TokenStore,
+    pub grants: GrantStore,
+    pub oidc: Option<OidcProvider>,
+    // Local usernames by client certificate, keyed by SHA-256 fingerprint or by a
+    // subject common name or alternative name, for clients that sign in with mutual TLS
+    pub client_certs: HashMap<String, String>,
+    // Whether the guarded routes turn away requests without a session or token
+    pub required: bool,
+    // Name of the collection this server holds, which tokens and grants can be restricted to
+    pub collection: String,
+    db: sled::Db,
+}
+
+impl Auth {
+    pub fn open(db: &sled::Db, required: bool) -> Result<Self> {
+        let ttl_hours = env_or("MERKLE_SESSION_HOURS", "24").parse()?;
+        Ok(Auth {
+            users: UserStore::open(db)?,
+            sessions: SessionStore::open(db, ttl_hours)?,
+            tokens: TokenStore::open(db)?,
+            grants: GrantStore::open(db)?,
+            oidc: None,
+            client_certs: HashMap::new(),
+            required,
+            collection: env_or("MERKLE_COLLECTION", "default"),
+            db: db.clone(),
+        })
+    }
+
+    pub fn size_on_disk(&self) -> Result<u64> {
+        Ok(self.db.size_on_disk()?)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // MERKLE_AUTH=on guards uploads and downloads; users are kept in
+    // MERKLE_AUTH_DB (default ./auth_db), OIDC is on when MERKLE_OIDC_ISSUER is set
+    // and MERKLE_TLS_CLIENT_MAP maps client certificates to users
+    pub async fn from_env() -> Result<Self> {
+        let db = sled::open(env_or("MERKLE_AUTH_DB", "auth_db"))?;
+        let mut auth = Auth::open(&db, env_or("MERKLE_AUTH", "off") == "on")?;
+        auth.client_certs = client_cert_map(&env_or("MERKLE_TLS_CLIENT_MAP", ""))?;
+        if let Some(settings) = OidcSettings::from_env()? {
+            auth.oidc = Some(OidcProvider::discover(settings).await?);
+        }
+        Ok(auth)
+    }
+
+    // Auth off and nothing persisted, for tests and benches
+    pub fn disabled() -> Result<Self> {
+        Auth::open(&sled::Config::new().temporary(true).open()?, false)
+    }
+
+    // The user behind a session cookie, if the session is live and the user still exists
+    pub fn session_identity(&self, token: &str) -> Option<Identity> {
+        let session = self.sessions.get(token).ok()??;
+        let user = self.users.get(&session.username).ok()??;
+        Some(Identity {
+            username: user.username,
+            roles: user.roles,
+            token: None,
+        })
+    }
+
+    // The owner of an API token, limited to the token's scopes. Tokens stop
+    // working when their owner is removed.
+    pub fn token_identity(&self, bearer: &str) -> Option<Identity> {
+        let token = self.tokens.verify(bearer).ok()??;
+        let user = self.users.get(&token.owner).ok()??;
+        Some(Identity {
+            username: user.username,
+            roles: user.roles,
+            token: Some(token),
+        })
+    }
+
+    // The user a verified client certificate is mapped to, by fingerprint first
+    // and then by any of its names
+    pub fn cert_identity(&self, der: &[u8]) -> Option<Identity> {
+        let username = match self.client_certs.get(&cert::fingerprint(der)) {
+            Some(username) => username,
+            None => cert::subject_names(der)
+                .ok()?
+                .iter()
+                .find_map(|name| self.client_certs.get(name))?,
+        };
+        let user = self.users.get(username).ok()??;
+        Some(Identity {
+            username: user.username,
+            roles: user.roles,
+            token: None,
+        })
+    }
+}
+
+// Entries look like `<name or sha256 fingerprint>=<username>`, comma separated.
+// Fingerprints are kept in the form cert::fingerprint prints.
+pub fn client_cert_map(value: &str) -> Result<HashMap<String, String>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (key, username) = entry
+                .rsplit_once('=')
+                .ok_or_else(|| anyhow!("MERKLE_TLS_CLIENT_MAP entries look like name=user"))?;
+            let fingerprint = normalize_fingerprint(key);
+            let key = match fingerprint.len() == 64 && hex::decode(&fingerprint).is_ok() {
+                true => fingerprint,
+                false => key.to_string(),
+            };
+            Ok((key, username.to_string()))
+        })
+        .collect()
+}
+
+// Random bytes as unpadded base64url, for session ids, PKCE verifiers and states
+pub fn random_token(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    SystemRandom::new()
+        .fill(&mut buf)
+        .expect("System random source failed");
+    URL_SAFE_NO_PAD.encode(buf)
+}
+
+// Rejection for requests to guarded routes without a valid session or token.
+// Browsers asking for a page are sent to the login page instead.
+#[derive(Debug)]
+pub struct Unauthorized {
+    login_redirect: bool,
+}
+
+impl warp::reject::Reject for Unauthorized {}
+
+// Rejection for signed in requests whose role or token doesn't allow what they ask
+#[derive(Debug)]
+pub struct Forbidden;
+
+impl warp::reject::Reject for Forbidden {}
+
+// The request's identity from an `Authorization: Bearer` token, else the session
+// cookie or the client certificate, or None. Never rejects.
+pub fn identity(
+    auth: Arc<Auth>,
+) -> impl Filter<Extract = (Option<Identity>,), Error = std::convert::Infallible> + Clone {
+    warp::header::headers_cloned()
+        .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+        .and(warp::ext::optional::<Peer>())
+        .map(
+            move |headers: HeaderMap, cookie: Option<String>, peer: Option<Peer>| {
+                let bearer = headers
+                    .get(header::AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "));
+                match bearer {
+                    Some(bearer) => auth.token_identity(bearer.trim()),
+                    None => cookie
+                        .and_then(|token| auth.session_identity(&token))
+                        .or_else(|| auth.cert_identity(&peer?.client_cert?)),
+                }
+            },
+        )
+}
+
+// Check a request may do an action: Unauthorized if it needs to sign in first,
+// Forbidden if it is signed in without the role or scope
+pub fn check(
+    auth: &Auth,
+    identity: Option<&Identity>,
+    action: Action,
+    accept: Option<&str>,
+) -> Result<(), Rejection> {
+    match (auth.permits(identity, action), identity) {
+        (true, _) => Ok(()),
+        (false, None) => Err(warp::reject::custom(Unauthorized {
+            login_redirect: accept.is_some_and(|accept| accept.contains("text/html")),
+        })),
+        (false, Some(_)) => Err(warp::reject::custom(Forbidden)),
+    }
+}
+
+// Wraps all the server's routes, checking each request against the action its
+// path needs before any route runs. Refusals go in the audit log.
+pub fn authorize(
+    auth: Arc<Auth>,
+    audit: Arc<AuditLog>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path::full()
+        .and(identity(Arc::clone(&auth)))
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(
+            move |path: FullPath, identity: Option<Identity>, accept: Option<String>| {
+                let result = match Action::for_path(path.as_str()) {
+                    Some(action) => check(&auth, identity.as_ref(), action, accept.as_deref()),
+                    None => Ok(()),
+                };
+                if result.is_err() {
+                    audit.record_denied(
+                        &identity.unwrap_or_else(Identity::anonymous),
+                        path.as_str(),
+                    );
+                }
+                async move { result }
+            },
+        )
+        .untuple_one()
+}
+
+// Turn Unauthorized and Forbidden into responses, leaving other rejections to warp
+pub async fn recover_unauthorized(err: Rejection) -> Result<warp::reply::Response, Rejection> {
+    if err.find::<Forbidden>().is_some() {
+        metrics().reject("forbidden");
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    if err.find::<Unauthorized>().is_some() {
+        metrics().reject("unauthorized");
+    }
+    match err.find::<Unauthorized>() {
+        Some(Unauthorized {
+            login_redirect: true,
+        }) => Ok(
+            warp::reply::with_header(StatusCode::SEE_OTHER, header::LOCATION, "/login")
+                .into_response(),
+        ),
+        Some(_) => Ok(StatusCode::UNAUTHORIZED.into_response()),
+        None => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_requests_are_authorized_by_path() {
+        use crate::fileserver::audit::AuditLog;
+        use crate::fileserver::auth::tokens::Scope;
+        use crate::fileserver::auth::{authorize, recover_unauthorized, Auth};
+        use std::sync::Arc;
+        use warp::Filter;
+
+        let mut auth = Auth::disabled().unwrap();
+        auth.required = true;
+        auth.users
+            .add_local("ci", "secret", vec!["reader".to_string()])
+            .unwrap();
+        let (_, reader) = auth
+            .tokens
+            .create("ci", "reader", vec![Scope::Read], None, None)
+            .unwrap();
+        let (_, elsewhere) = auth
+            .tokens
+            .create(
+                "ci",
+                "other",
+                vec![Scope::Read],
+                Some("other".to_string()),
+                None,
+            )
+            .unwrap();
+        let audit = Arc::new(AuditLog::temporary().unwrap());
+        let route = authorize(Arc::new(auth), Arc::clone(&audit))
+            .map(warp::reply)
+            .recover(recover_unauthorized);
+
+        let status = |path: &str, bearer: Option<&str>, accept: &str| {
+            let mut request = warp::test::request().path(path).header("accept", accept);
+            if let Some(bearer) = bearer {
+                request = request.header("authorization", format!("Bearer {}", bearer));
+            }
+            let route = route.clone();
+            async move { request.reply(&route).await.status().as_u16() }
+        };
+        assert_eq!(status("/download/a", Some(&reader), "*/*").await, 200);
+        assert_eq!(status("/upload", Some(&reader), "*/*").await, 403);
+        assert_eq!(status("/admin/tokens", Some(&reader), "*/*").await, 403);
+        assert_eq!(status("/download/a", Some(&elsewhere), "*/*").await, 403);
+        assert_eq!(status("/download/a", Some("mfs_0_bogus"), "*/*").await, 401);
+        assert_eq!(status("/list", None, "text/html").await, 303);
+        assert_eq!(status("/login", None, "text/html").await, 200);
+        //one refusal per actor is in the audit log, the rest are counted for the next
+        assert_eq!(audit.head().unwrap().entries, 2);
+        assert_eq!(status("/upload", Some(&reader), "*/*").await, 403);
+        assert_eq!(audit.head().unwrap().entries, 2);
+        let denied = audit.entries(0).map(Result::unwrap);
+        assert!(denied.map(|entry| entry.actor).eq(["ci", "anonymous"]));
+    }
+}
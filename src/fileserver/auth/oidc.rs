@@ -0,0 +1,521 @@
+use super::random_token;
+use super::session::now_secs;
+use crate::storage::env_or;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use reqwest::Url;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+// How long a user has to finish signing in at the provider
+const PENDING_TTL: Duration = Duration::from_secs(10 * 60);
+// Leeway for clocks when checking ID token expiry
+const CLOCK_SKEW_SECS: u64 = 60;
+
+#[derive(Clone, Debug)]
+pub struct OidcSettings {
+    pub issuer: String,
+    pub client_id: String,
+    // Public clients rely on PKCE alone and have no secret
+    pub client_secret: Option<String>,
+    pub redirect_url: String,
+    pub scopes: String,
+    pub username_claim: String,
+    pub roles_claim: String,
+    // Values of the roles claim to local roles. When empty the values are used as roles.
+    pub role_map: HashMap<String, String>,
+    // Roles every OIDC user gets on top of the mapped ones
+    pub default_roles: Vec<String>,
+    // Root to trust for the provider, for test setups with their own CA
+    pub ca_path: Option<PathBuf>,
+}
+
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+impl OidcSettings {
+    // OIDC is on when MERKLE_OIDC_ISSUER is set
+    pub fn from_env() -> Result<Option<Self>> {
+        let issuer = env_or("MERKLE_OIDC_ISSUER", "");
+        if issuer.is_empty() {
+            return Ok(None);
+        }
+        let role_map = list(&env_or("MERKLE_OIDC_ROLE_MAP", ""))
+            .map(|pair| {
+                pair.split_once('=')
+                    .map(|(claim, role)| (claim.to_string(), role.to_string()))
+                    .ok_or_else(|| anyhow!("MERKLE_OIDC_ROLE_MAP entries look like group=role"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Some(OidcSettings {
+            issuer,
+            client_id: std::env::var("MERKLE_OIDC_CLIENT_ID")
+                .context("MERKLE_OIDC_CLIENT_ID must be set with MERKLE_OIDC_ISSUER")?,
+            client_secret: std::env::var("MERKLE_OIDC_CLIENT_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty()),
+            redirect_url: env_or(
+                "MERKLE_OIDC_REDIRECT_URL",
+                "https://localhost:8080/auth/oidc/callback",
+            ),
+            scopes: env_or("MERKLE_OIDC_SCOPES", "openid profile email"),
+            username_claim: env_or("MERKLE_OIDC_USERNAME_CLAIM", "preferred_username"),
+            roles_claim: env_or("MERKLE_OIDC_ROLES_CLAIM", "groups"),
+            role_map,
+            default_roles: list(&env_or("MERKLE_OIDC_DEFAULT_ROLES", ""))
+                .map(str::to_string)
+                .collect(),
+            ca_path: std::env::var("MERKLE_OIDC_CA")
+                .ok()
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from),
+        }))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+// What a sign-in started with, kept until the provider redirects back
+struct Pending {
+    verifier: String,
+    nonce: String,
+    started: Instant,
+}
+
+// Who an ID token says signed in, mapped to a local user
+#[derive(Clone, Debug, PartialEq)]
+pub struct OidcLogin {
+    // Issuer and subject, which identify the account at the provider for good
+    pub subject: String,
+    pub username: String,
+    pub roles: Vec<String>,
+}
+
+// A relying party for one provider, using the authorization code flow with PKCE
+pub struct OidcProvider {
+    settings: OidcSettings,
+    metadata: Metadata,
+    http: reqwest::Client,
+    jwks: RwLock<Vec<Jwk>>,
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+fn decode(part: &str) -> Result<Vec<u8>> {
+    Ok(URL_SAFE_NO_PAD.decode(part.trim_end_matches('='))?)
+}
+
+fn verify_signature(alg: &str, key: &Jwk, message: &[u8], sig: &[u8]) -> Result<()> {
+    let field =
+        |value: &Option<String>| decode(value.as_deref().ok_or_else(|| anyhow!("Incomplete JWK"))?);
+    let verified = match (alg, key.kty.as_str()) {
+        ("RS256", "RSA") => RsaPublicKeyComponents {
+            n: field(&key.n)?,
+            e: field(&key.e)?,
+        }
+        .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig),
+        ("ES256", "EC") if key.crv.as_deref() == Some("P-256") => {
+            //an uncompressed point: 0x04, then x and y
+            let mut point = vec![4u8];
+            point.extend(field(&key.x)?);
+            point.extend(field(&key.y)?);
+            UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point).verify(message, sig)
+        }
+        _ => bail!("Unsupported ID token algorithm {}", alg),
+    };
+    verified.map_err(|_| anyhow!("Invalid ID token signature"))
+}
+
+// A claim that may hold one string or a list of them
+fn claim_strings(value: Option<&Value>) -> Vec<String> {
+    match value {
+        Some(Value::String(value)) => vec![value.clone()],
+        Some(Value::Array(values)) => values
+            .iter()
+            .filter_map(|value| value.as_str().map(str::to_string))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+impl OidcProvider {
+    // Fetch the provider's metadata, which must name the configured issuer
+    pub async fn discover(settings: OidcSettings) -> Result<Self> {
+        let mut http = reqwest::Client::builder();
+        if let Some(ca_path) = &settings.ca_path {
+            let pem =
+                std::fs::read(ca_path).with_context(|| format!("Reading {}", ca_path.display()))?;
+            http = http.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        let http = http.build()?;
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            settings.issuer.trim_end_matches('/')
+        );
+        let metadata: Metadata = http
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("Reading OIDC metadata from {}", url))?;
+        if metadata.issuer != settings.issuer {
+            bail!(
+                "OIDC provider calls itself {}, expected {}",
+                metadata.issuer,
+                settings.issuer
+            );
+        }
+        Ok(OidcProvider {
+            settings,
+            metadata,
+            http,
+            jwks: RwLock::new(Vec::new()),
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Where to send the browser to sign in, and the state the callback must bring back
+    pub fn authorization_url(&self) -> Result<(String, String)> {
+        let state = random_token(16);
+        let verifier = random_token(32);
+        let nonce = random_token(16);
+        let challenge = URL_SAFE_NO_PAD.encode(ring::digest::digest(
+            &ring::digest::SHA256,
+            verifier.as_bytes(),
+        ));
+        let url = Url::parse_with_params(
+            &self.metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", self.settings.client_id.as_str()),
+                ("redirect_uri", self.settings.redirect_url.as_str()),
+                ("scope", self.settings.scopes.as_str()),
+                ("state", state.as_str()),
+                ("nonce", nonce.as_str()),
+                ("code_challenge", challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )?;
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, login| login.started.elapsed() < PENDING_TTL);
+        pending.insert(
+            state.clone(),
+            Pending {
+                verifier,
+                nonce,
+                started: Instant::now(),
+            },
+        );
+        Ok((url.to_string(), state))
+    }
+
+    // Trade the code from the callback for an ID token and check it. Each state
+    // works once.
+    pub async fn exchange(&self, code: &str, state: &str) -> Result<OidcLogin> {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|login| login.started.elapsed() < PENDING_TTL)
+            .ok_or_else(|| anyhow!("Unknown or expired sign-in"))?;
+        let mut request = self.http.post(&self.metadata.token_endpoint).form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.settings.redirect_url.as_str()),
+            ("code_verifier", pending.verifier.as_str()),
+            ("client_id", self.settings.client_id.as_str()),
+        ]);
+        if let Some(secret) = &self.settings.client_secret {
+            request = request.basic_auth(&self.settings.client_id, Some(secret));
+        }
+        let tokens: TokenResponse = request.send().await?.error_for_status()?.json().await?;
+        let claims = self
+            .verify_id_token(&tokens.id_token, &pending.nonce)
+            .await?;
+        self.login(&claims)
+    }
+
+    async fn verify_id_token(&self, token: &str, nonce: &str) -> Result<Map<String, Value>> {
+        let parts: Vec<&str> = token.split('.').collect();
+        let [header, payload, sig] = parts.as_slice() else {
+            bail!("Malformed ID token");
+        };
+        let header: JwtHeader = serde_json::from_slice(&decode(header)?)?;
+        let key = self.key(header.kid.as_deref()).await?;
+        let message = format!("{}.{}", parts[0], payload);
+        verify_signature(&header.alg, &key, message.as_bytes(), &decode(sig)?)?;
+
+        let claims: Map<String, Value> = serde_json::from_slice(&decode(payload)?)?;
+        if claims.get("iss").and_then(Value::as_str) != Some(self.metadata.issuer.as_str()) {
+            bail!("ID token is from another issuer");
+        }
+        if !claim_strings(claims.get("aud")).contains(&self.settings.client_id) {
+            bail!("ID token is for another client");
+        }
+        let expires = claims.get("exp").and_then(Value::as_u64).unwrap_or(0);
+        if expires + CLOCK_SKEW_SECS < now_secs() {
+            bail!("ID token has expired");
+        }
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            bail!("ID token nonce doesn't match the sign-in");
+        }
+        Ok(claims)
+    }
+
+    // The signing key by id, fetching the key set again when the provider has rotated
+    async fn key(&self, kid: Option<&str>) -> Result<Jwk> {
+        let find = |keys: &[Jwk]| match kid {
+            Some(kid) => keys
+                .iter()
+                .find(|key| key.kid.as_deref() == Some(kid))
+                .cloned(),
+            None if keys.len() == 1 => keys.first().cloned(),
+            None => None,
+        };
+        if let Some(key) = find(&self.jwks.read().unwrap()) {
+            return Ok(key);
+        }
+        let jwks: Jwks = self
+            .http
+            .get(&self.metadata.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let key = find(&jwks.keys);
+        *self.jwks.write().unwrap() = jwks.keys;
+        key.ok_or_else(|| anyhow!("No key {} in the provider's key set", kid.unwrap_or("")))
+    }
+
+    // Map the claims to a local username and roles
+    fn login(&self, claims: &Map<String, Value>) -> Result<OidcLogin> {
+        let subject = claims
+            .get("sub")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("ID token has no subject"))?;
+        let username = claims
+            .get(&self.settings.username_claim)
+            .and_then(Value::as_str)
+            .unwrap_or(subject);
+        let mut roles: Vec<String> = claim_strings(claims.get(&self.settings.roles_claim))
+            .into_iter()
+            //only mapped values count, so a group the provider happens to call "admin" grants nothing
+            .filter_map(|value| self.settings.role_map.get(&value).cloned())
+            .chain(self.settings.default_roles.iter().cloned())
+            .collect();
+        roles.sort();
+        roles.dedup();
+        Ok(OidcLogin {
+            subject: format!("{}|{}", self.metadata.issuer, subject),
+            username: username.to_string(),
+            roles,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    // Codes the mock provider has handed out: code -> (PKCE challenge, nonce)
+    type Codes = Arc<Mutex<HashMap<String, (String, String)>>>;
+
+    // Just enough of a provider for the code flow: discovery, keys and a token
+    // endpoint that checks the PKCE verifier and signs ES256 ID tokens
+    fn mock_provider(codes: Codes) -> SocketAddr {
+        use warp::Filter;
+
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key = Arc::new(
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                .unwrap(),
+        );
+        let point = key.public_key().as_ref().to_vec();
+        let jwks = json!({"keys": [{
+            "kty": "EC", "crv": "P-256", "kid": "k1",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        }]});
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let issuer = format!("http://{}", addr);
+        let metadata = json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+        });
+        let discovery = warp::path!(".well-known" / "openid-configuration")
+            .map(move || warp::reply::json(&metadata));
+        let keys = warp::path("jwks").map(move || warp::reply::json(&jwks));
+        let token = warp::path("token").and(warp::body::form()).map(
+            move |form: HashMap<String, String>| {
+                let (challenge, nonce) = codes.lock().unwrap().remove(&form["code"]).unwrap();
+                let verifier = form["code_verifier"].as_bytes();
+                let digest = ring::digest::digest(&ring::digest::SHA256, verifier);
+                assert_eq!(URL_SAFE_NO_PAD.encode(digest), challenge);
+                let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"ES256","kid":"k1"}"#);
+                let claims = json!({
+                    "iss": issuer, "aud": "merkle", "sub": "123", "nonce": nonce,
+                    "exp": crate::fileserver::auth::session::now_secs() + 300,
+                    "preferred_username": "alice", "groups": ["eng", "ops"],
+                });
+                let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+                let message = format!("{}.{}", header, payload);
+                let rng = ring::rand::SystemRandom::new();
+                let sig = key.sign(&rng, message.as_bytes()).unwrap();
+                let id_token = format!("{}.{}", message, URL_SAFE_NO_PAD.encode(sig));
+                warp::reply::json(&json!({"id_token": id_token, "token_type": "Bearer"}))
+            },
+        );
+        let (addr, server) = warp::serve(discovery.or(keys).or(token)).bind_ephemeral(addr);
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_code_flow_against_mock_provider() {
+        use crate::fileserver::auth::oidc::{OidcProvider, OidcSettings};
+        use reqwest::Url;
+
+        let codes = Codes::default();
+        let addr = mock_provider(codes.clone());
+        let settings = OidcSettings {
+            issuer: format!("http://{}", addr),
+            client_id: "merkle".to_string(),
+            client_secret: None,
+            redirect_url: "https://localhost:8080/auth/oidc/callback".to_string(),
+            scopes: "openid profile".to_string(),
+            username_claim: "preferred_username".to_string(),
+            roles_claim: "groups".to_string(),
+            role_map: HashMap::from([("eng".to_string(), "uploader".to_string())]),
+            default_roles: vec!["reader".to_string()],
+            ca_path: None,
+        };
+        let provider = OidcProvider::discover(settings.clone()).await.unwrap();
+
+        //play the browser: sign in at the provider, which redirects back with a code
+        let start = |code: &str| {
+            let (url, state) = provider.authorization_url().unwrap();
+            let query: HashMap<String, String> = Url::parse(&url)
+                .unwrap()
+                .query_pairs()
+                .into_owned()
+                .collect();
+            assert_eq!(query["state"], state);
+            assert_eq!(query["code_challenge_method"], "S256");
+            codes.lock().unwrap().insert(
+                code.to_string(),
+                (query["code_challenge"].clone(), query["nonce"].clone()),
+            );
+            state
+        };
+        let state = start("code1");
+        let login = provider.exchange("code1", &state).await.unwrap();
+        assert_eq!(login.username, "alice");
+        assert_eq!(login.subject, format!("http://{}|123", addr));
+        //ops has no mapping, reader comes from the defaults
+        assert_eq!(login.roles, vec!["reader", "uploader"]);
+
+        //a state is good for one sign-in
+        let state = start("code2");
+        assert!(provider.exchange("code2", "forged").await.is_err());
+        provider.exchange("code2", &state).await.unwrap();
+        assert!(provider.exchange("code2", &state).await.is_err());
+
+        //without a role map the provider's groups grant no roles
+        let unmapped = OidcProvider::discover(OidcSettings {
+            role_map: HashMap::new(),
+            ..settings.clone()
+        })
+        .await
+        .unwrap();
+        let (url, state) = unmapped.authorization_url().unwrap();
+        let query: HashMap<String, String> = Url::parse(&url)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect();
+        codes.lock().unwrap().insert(
+            "code4".to_string(),
+            (query["code_challenge"].clone(), query["nonce"].clone()),
+        );
+        let login = unmapped.exchange("code4", &state).await.unwrap();
+        assert_eq!(login.roles, vec!["reader"]);
+
+        //ID tokens for another client are refused
+        let other = OidcProvider::discover(OidcSettings {
+            client_id: "other".to_string(),
+            ..settings
+        })
+        .await
+        .unwrap();
+        let (url, state) = other.authorization_url().unwrap();
+        let query: HashMap<String, String> = Url::parse(&url)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect();
+        codes.lock().unwrap().insert(
+            "code3".to_string(),
+            (query["code_challenge"].clone(), query["nonce"].clone()),
+        );
+        let err = other.exchange("code3", &state).await.unwrap_err();
+        assert!(err.to_string().contains("another client"));
+    }
+}
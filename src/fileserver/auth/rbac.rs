@@ -0,0 +1,363 @@
+use super::tokens::Scope;
+use super::{Auth, Identity};
+use crate::fileserver::audit::{AuditEvent, Auditor};
+use anyhow::{anyhow, bail, Result};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::str::FromStr;
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::reply::Reply;
+
+// Layout in the auth database:
+//   grants: grant id -> Grant as JSON
+const GRANTS_TREE: &str = "grants";
+
+// Each role can do everything the ones before it can
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    // Download files and fetch proofs
+    Reader,
+    // Also upload, replacing the files already there
+    Uploader,
+    // Also manage tokens and grants
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reader" => Ok(Role::Reader),
+            "uploader" => Ok(Role::Uploader),
+            "admin" => Ok(Role::Admin),
+            _ => bail!("Unknown role {}, expected reader, uploader or admin", s),
+        }
+    }
+}
+
+// What a request does, as far as permissions go
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Read,
+    Upload,
+    // Replace or remove files already stored
+    Delete,
+    Admin,
+}
+
+impl Action {
+    pub fn minimum_role(self) -> Role {
+        match self {
+            Action::Read => Role::Reader,
+            Action::Upload | Action::Delete => Role::Uploader,
+            Action::Admin => Role::Admin,
+        }
+    }
+
+    // The token scope that covers it; tokens can't administer
+    pub fn scope(self) -> Option<Scope> {
+        match self {
+            Action::Read => Some(Scope::Read),
+            Action::Upload => Some(Scope::Upload),
+            Action::Delete => Some(Scope::Delete),
+            Action::Admin => None,
+        }
+    }
+
+    // The action a request needs, from its first path segment. Signing in is
+    // open to everyone, and anything not listed needs read.
+    pub fn for_path(path: &str) -> Option<Action> {
+        let segment = path
+            .trim_start_matches('/')
+            .split('/')
+            .next()
+            .unwrap_or_default();
+        match segment {
+            "" | "login" | "logout" | "auth" | "whoami" => None,
+            //probes from orchestrators, which have no credentials
+            "healthz" | "readyz" | "version" => None,
+            "admin" => Some(Action::Admin),
+            //the upload page too, so readers aren't shown a form they can't use
+            "upload" => Some(Action::Upload),
+            _ => Some(Action::Read),
+        }
+    }
+}
+
+// Who a grant is for: one user, or everyone with a value in their roles list,
+// such as a group mapped from OIDC
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Subject {
+    User(String),
+    Group(String),
+}
+
+// A role for a user or group, in one collection or, without one, in all of them
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Grant {
+    #[serde(default)]
+    pub id: String,
+    pub subject: Subject,
+    pub role: Role,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+}
+
+impl Grant {
+    fn applies_to(&self, identity: &Identity, collection: &str) -> bool {
+        let subject = match &self.subject {
+            Subject::User(username) => *username == identity.username,
+            Subject::Group(group) => identity.roles.contains(group),
+        };
+        subject
+            && self
+                .collection
+                .as_deref()
+                .is_none_or(|granted| granted == collection)
+    }
+}
+
+#[derive(Clone)]
+pub struct GrantStore {
+    grants: sled::Tree,
+}
+
+impl GrantStore {
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        Ok(GrantStore {
+            grants: db.open_tree(GRANTS_TREE)?,
+        })
+    }
+
+    // Save a grant, replacing any for the same subject and collection
+    pub fn put(&self, mut grant: Grant) -> Result<Grant> {
+        for existing in self.list()? {
+            if existing.subject == grant.subject && existing.collection == grant.collection {
+                self.grants.remove(existing.id.as_str())?;
+            }
+        }
+        let mut id = [0u8; 8];
+        SystemRandom::new()
+            .fill(&mut id)
+            .map_err(|_| anyhow!("System random source failed"))?;
+        grant.id = hex::encode(id);
+        self.grants
+            .insert(grant.id.as_str(), serde_json::to_vec(&grant)?)?;
+        self.grants.flush()?;
+        Ok(grant)
+    }
+
+    pub fn remove(&self, id: &str) -> Result<bool> {
+        let removed = self.grants.remove(id)?.is_some();
+        self.grants.flush()?;
+        Ok(removed)
+    }
+
+    pub fn list(&self) -> Result<Vec<Grant>> {
+        self.grants
+            .iter()
+            .values()
+            .map(|value| Ok(serde_json::from_slice(&value?)?))
+            .collect()
+    }
+}
+
+impl Auth {
+    // The highest role an identity has in this server's collection. Role names
+    // in a user's roles count everywhere; other values only through group grants.
+    pub fn role(&self, identity: &Identity) -> Option<Role> {
+        let direct = identity.roles.iter().filter_map(|role| role.parse().ok());
+        let granted = self
+            .grants
+            .list()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|grant| grant.applies_to(identity, &self.collection))
+            .map(|grant| grant.role);
+        direct.chain(granted).max()
+    }
+
+    // Whether a request may do something. Without auth required anyone may do
+    // anything but administer; a token also needs the matching scope.
+    pub fn permits(&self, identity: Option<&Identity>, action: Action) -> bool {
+        let Some(identity) = identity else {
+            return !self.required && action != Action::Admin;
+        };
+        let scoped = match (&identity.token, action.scope()) {
+            (None, _) => true,
+            (Some(token), Some(scope)) => token.allows(scope, &self.collection),
+            (Some(_), None) => false,
+        };
+        let by_role = self.role(identity) >= Some(action.minimum_role());
+        scoped && (by_role || (!self.required && action != Action::Admin))
+    }
+}
+
+#[derive(Serialize)]
+struct UserSummary {
+    username: String,
+    roles: Vec<String>,
+    // Role in this server's collection, from the roles and grants together
+    role: Option<Role>,
+    oidc: bool,
+}
+
+pub async fn handle_list_users(auth: Arc<Auth>) -> Result<warp::reply::Response, Infallible> {
+    let Ok(users) = auth.users.list() else {
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    };
+    let users: Vec<UserSummary> = users
+        .into_iter()
+        .map(|user| {
+            let identity = Identity {
+                username: user.username.clone(),
+                roles: user.roles.clone(),
+                token: None,
+            };
+            UserSummary {
+                role: auth.role(&identity),
+                username: user.username,
+                roles: user.roles,
+                oidc: user.oidc_subject.is_some(),
+            }
+        })
+        .collect();
+    Ok(warp::reply::json(&users).into_response())
+}
+
+pub async fn handle_list_grants(auth: Arc<Auth>) -> Result<warp::reply::Response, Infallible> {
+    Ok(match auth.grants.list() {
+        Ok(grants) => warp::reply::json(&grants).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    })
+}
+
+pub async fn handle_put_grant(
+    auth: Arc<Auth>,
+    auditor: Auditor,
+    grant: Grant,
+) -> Result<warp::reply::Response, Infallible> {
+    Ok(match auth.grants.put(grant) {
+        Ok(grant) => {
+            auditor.record(AuditEvent::Admin {
+                action: "grant".to_string(),
+                target: serde_json::to_string(&grant).unwrap_or_default(),
+            });
+            warp::reply::with_status(warp::reply::json(&grant), StatusCode::CREATED).into_response()
+        }
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    })
+}
+
+pub async fn handle_remove_grant(
+    id: String,
+    auth: Arc<Auth>,
+    auditor: Auditor,
+) -> Result<warp::reply::Response, Infallible> {
+    Ok(match auth.grants.remove(&id) {
+        Ok(true) => {
+            auditor.record(AuditEvent::Admin {
+                action: "remove_grant".to_string(),
+                target: id,
+            });
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_roles_from_users_groups_and_collections() {
+        use crate::fileserver::auth::rbac::{Action, Grant, Role, Subject};
+        use crate::fileserver::auth::tokens::Scope;
+        use crate::fileserver::auth::{Auth, Identity};
+
+        let mut auth = Auth::disabled().unwrap();
+        auth.required = true;
+        let user = |name: &str, roles: &[&str]| Identity {
+            username: name.to_string(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+            token: None,
+        };
+        let grant = |subject: Subject, role: Role, collection: Option<&str>| Grant {
+            id: String::new(),
+            subject,
+            role,
+            collection: collection.map(str::to_string),
+        };
+        auth.grants
+            .put(grant(Subject::Group("eng".to_string()), Role::Reader, None))
+            .unwrap();
+        auth.grants
+            .put(grant(
+                Subject::User("bob".to_string()),
+                Role::Uploader,
+                Some("default"),
+            ))
+            .unwrap();
+        auth.grants
+            .put(grant(
+                Subject::User("carol".to_string()),
+                Role::Admin,
+                Some("other"),
+            ))
+            .unwrap();
+
+        let admin = user("alice", &["admin"]);
+        let reader = user("dave", &["eng"]);
+        let bob = user("bob", &["eng"]);
+        assert_eq!(auth.role(&admin), Some(Role::Admin));
+        assert!(auth.permits(Some(&reader), Action::Read));
+        assert!(!auth.permits(Some(&reader), Action::Upload));
+        //the higher of group and user grants wins
+        assert_eq!(auth.role(&bob), Some(Role::Uploader));
+        assert!(!auth.permits(Some(&bob), Action::Admin));
+        //grants for another collection don't count here
+        assert_eq!(auth.role(&user("carol", &[])), None);
+        assert!(!auth.permits(None, Action::Read));
+
+        //a later grant for the same subject and collection replaces the earlier one
+        auth.grants
+            .put(grant(
+                Subject::Group("eng".to_string()),
+                Role::Uploader,
+                None,
+            ))
+            .unwrap();
+        assert_eq!(auth.grants.list().unwrap().len(), 3);
+        assert!(auth.permits(Some(&reader), Action::Upload));
+
+        //tokens are limited by both their owner's role and their scopes
+        auth.users
+            .add_local("dave", "pw", vec!["eng".to_string()])
+            .unwrap();
+        let (_, secret) = auth
+            .tokens
+            .create("dave", "ci", vec![Scope::Read, Scope::Upload], None, None)
+            .unwrap();
+        let token = auth.token_identity(&secret).unwrap();
+        assert!(auth.permits(Some(&token), Action::Upload));
+        assert!(!auth.permits(Some(&token), Action::Delete));
+        let (_, secret) = auth
+            .tokens
+            .create("dave", "admin", vec![Scope::Read], None, None)
+            .unwrap();
+        auth.users
+            .add_local("dave", "pw", vec!["admin".to_string()])
+            .unwrap();
+        assert!(!auth.permits(Some(&auth.token_identity(&secret).unwrap()), Action::Admin));
+
+        //without auth required, anyone but an administrator is let through
+        auth.required = false;
+        assert!(auth.permits(None, Action::Upload));
+        assert!(!auth.permits(None, Action::Admin));
+    }
+}
@@ -0,0 +1,130 @@
+use super::random_token;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Layout in the auth database:
+//   sessions: SHA-256 of the session id -> Session as JSON
+// Only hashes are stored, so a copy of the database can't be used to log in.
+const SESSIONS_TREE: &str = "sessions";
+
+pub const SESSION_COOKIE: &str = "merkle_session";
+
+// Roles are looked up on every request rather than kept here, so changes to a
+// user take effect on their open sessions
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub username: String,
+    // Unix seconds
+    pub expires: u64,
+}
+
+#[derive(Clone)]
+pub struct SessionStore {
+    sessions: sled::Tree,
+    ttl_secs: u64,
+}
+
+pub(crate) fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn session_key(token: &str) -> Vec<u8> {
+    ring::digest::digest(&ring::digest::SHA256, token.as_bytes())
+        .as_ref()
+        .to_vec()
+}
+
+impl SessionStore {
+    pub fn open(db: &sled::Db, ttl_hours: u64) -> Result<Self> {
+        Ok(SessionStore {
+            sessions: db.open_tree(SESSIONS_TREE)?,
+            ttl_secs: ttl_hours * 60 * 60,
+        })
+    }
+
+    // Start a session and return its id, which goes in the cookie
+    pub fn create(&self, username: &str) -> Result<String> {
+        self.remove_expired()?;
+        let token = random_token(32);
+        let session = Session {
+            username: username.to_string(),
+            expires: now_secs() + self.ttl_secs,
+        };
+        self.sessions
+            .insert(session_key(&token), serde_json::to_vec(&session)?)?;
+        self.sessions.flush()?;
+        Ok(token)
+    }
+
+    pub fn get(&self, token: &str) -> Result<Option<Session>> {
+        let Some(value) = self.sessions.get(session_key(token))? else {
+            return Ok(None);
+        };
+        let session: Session = serde_json::from_slice(&value)?;
+        Ok(Some(session).filter(|session| session.expires > now_secs()))
+    }
+
+    pub fn remove(&self, token: &str) -> Result<()> {
+        self.sessions.remove(session_key(token))?;
+        self.sessions.flush()?;
+        Ok(())
+    }
+
+    fn remove_expired(&self) -> Result<()> {
+        let now = now_secs();
+        for entry in self.sessions.iter() {
+            let (key, value) = entry?;
+            match serde_json::from_slice::<Session>(&value) {
+                Ok(session) if session.expires > now => {}
+                _ => {
+                    self.sessions.remove(key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Set-Cookie value for a new session. Lax rather than Strict so the session
+    // survives the redirect back from an OIDC provider.
+    pub fn cookie(&self, token: &str) -> String {
+        format!(
+            "{}={}; Path=/; HttpOnly; Secure; SameSite=Lax; Max-Age={}",
+            SESSION_COOKIE, token, self.ttl_secs
+        )
+    }
+
+    pub fn clear_cookie() -> String {
+        format!(
+            "{}=; Path=/; HttpOnly; Secure; SameSite=Lax; Max-Age=0",
+            SESSION_COOKIE
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_sessions_expire_and_are_stored_hashed() {
+        use crate::fileserver::auth::session::SessionStore;
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let sessions = SessionStore::open(&db, 1).unwrap();
+        let token = sessions.create("alice").unwrap();
+        assert_eq!(sessions.get(&token).unwrap().unwrap().username, "alice");
+        assert!(sessions.get("made-up").unwrap().is_none());
+        let tree = db.open_tree("sessions").unwrap();
+        assert!(tree.get(token.as_bytes()).unwrap().is_none());
+        assert!(sessions.cookie(&token).contains("HttpOnly; Secure"));
+
+        sessions.remove(&token).unwrap();
+        assert!(sessions.get(&token).unwrap().is_none());
+
+        let expired = SessionStore::open(&db, 0).unwrap();
+        let token = expired.create("alice").unwrap();
+        assert!(expired.get(&token).unwrap().is_none());
+    }
+}
@@ -0,0 +1,364 @@
+use super::session::now_secs;
+use super::{random_token, Auth};
+use crate::fileserver::audit::{AuditEvent, Auditor};
+use crate::storage::env_or;
+use anyhow::{anyhow, bail, Result};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::reply::Reply;
+
+// Layout in the auth database:
+//   tokens: token id -> StoredToken as JSON
+// Tokens look like mfs_<id>_<secret> and only the secret's SHA-256 is kept.
+const TOKENS_TREE: &str = "tokens";
+const TOKEN_PREFIX: &str = "mfs_";
+
+pub const TOKEN_USAGE: &str =
+    "Usage: cargo run token create <user> <name> --scope <read|upload|delete>... [--collection <name>] [--days <n>]
+       cargo run token revoke <id>
+       cargo run token list";
+
+// What a token may do. Uploads replace the files already on the server, so
+// uploading over existing files takes delete as well as upload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Upload,
+    Delete,
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "upload" => Ok(Scope::Upload),
+            "delete" => Ok(Scope::Delete),
+            _ => bail!("Unknown scope {}, expected read, upload or delete", s),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Scope::Read => "read",
+            Scope::Upload => "upload",
+            Scope::Delete => "delete",
+        })
+    }
+}
+
+// A token acts for its owner, limited to its scopes and, if set, one collection
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub owner: String,
+    pub scopes: Vec<Scope>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+    // Unix seconds
+    pub created: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+impl ApiToken {
+    pub fn allows(&self, scope: Scope, collection: &str) -> bool {
+        self.scopes.contains(&scope)
+            && self
+                .collection
+                .as_deref()
+                .is_none_or(|restricted| restricted == collection)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredToken {
+    #[serde(flatten)]
+    token: ApiToken,
+    // hex SHA-256 of the secret part
+    secret_hash: String,
+}
+
+fn secret_hash(secret: &str) -> String {
+    hex::encode(ring::digest::digest(
+        &ring::digest::SHA256,
+        secret.as_bytes(),
+    ))
+}
+
+#[derive(Clone)]
+pub struct TokenStore {
+    tokens: sled::Tree,
+}
+
+impl TokenStore {
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        Ok(TokenStore {
+            tokens: db.open_tree(TOKENS_TREE)?,
+        })
+    }
+
+    // Create a token and return it with the secret, which is not kept and
+    // can't be shown again
+    pub fn create(
+        &self,
+        owner: &str,
+        name: &str,
+        scopes: Vec<Scope>,
+        collection: Option<String>,
+        days: Option<u64>,
+    ) -> Result<(ApiToken, String)> {
+        if scopes.is_empty() {
+            bail!("A token needs at least one scope");
+        }
+        let mut id = [0u8; 8];
+        SystemRandom::new()
+            .fill(&mut id)
+            .map_err(|_| anyhow!("System random source failed"))?;
+        let id = hex::encode(id);
+        let secret = random_token(32);
+        let created = now_secs();
+        let token = ApiToken {
+            id: id.clone(),
+            name: name.to_string(),
+            owner: owner.to_string(),
+            scopes,
+            collection,
+            created,
+            expires: days.map(|days| created + days * 24 * 60 * 60),
+        };
+        let stored = StoredToken {
+            token: token.clone(),
+            secret_hash: secret_hash(&secret),
+        };
+        self.tokens
+            .insert(id.as_str(), serde_json::to_vec(&stored)?)?;
+        self.tokens.flush()?;
+        Ok((token, format!("{}{}_{}", TOKEN_PREFIX, id, secret)))
+    }
+
+    // The token a bearer value stands for, if it exists and hasn't expired
+    pub fn verify(&self, bearer: &str) -> Result<Option<ApiToken>> {
+        let Some((id, secret)) = bearer
+            .strip_prefix(TOKEN_PREFIX)
+            .and_then(|rest| rest.split_once('_'))
+        else {
+            return Ok(None);
+        };
+        let Some(value) = self.tokens.get(id)? else {
+            return Ok(None);
+        };
+        let stored: StoredToken = serde_json::from_slice(&value)?;
+        //comparing hashes, so timing says nothing about the secret
+        let valid = stored.secret_hash == secret_hash(secret)
+            && stored
+                .token
+                .expires
+                .is_none_or(|expires| expires > now_secs());
+        Ok(Some(stored.token).filter(|_| valid))
+    }
+
+    pub fn list(&self) -> Result<Vec<ApiToken>> {
+        self.tokens
+            .iter()
+            .values()
+            .map(|value| Ok(serde_json::from_slice::<StoredToken>(&value?)?.token))
+            .collect()
+    }
+
+    pub fn revoke(&self, id: &str) -> Result<bool> {
+        let removed = self.tokens.remove(id)?.is_some();
+        self.tokens.flush()?;
+        Ok(removed)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateTokenRequest {
+    name: String,
+    scopes: Vec<Scope>,
+    // Defaults to the admin creating it
+    owner: Option<String>,
+    collection: Option<String>,
+    days: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct CreatedToken {
+    #[serde(flatten)]
+    token: ApiToken,
+    secret: String,
+}
+
+pub async fn handle_list_tokens(auth: Arc<Auth>) -> Result<warp::reply::Response, Infallible> {
+    Ok(match auth.tokens.list() {
+        Ok(tokens) => warp::reply::json(&tokens).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    })
+}
+
+pub async fn handle_create_token(
+    auth: Arc<Auth>,
+    auditor: Auditor,
+    request: CreateTokenRequest,
+) -> Result<warp::reply::Response, Infallible> {
+    let owner = request
+        .owner
+        .unwrap_or_else(|| auditor.identity().username.clone());
+    if !matches!(auth.users.get(&owner), Ok(Some(_))) {
+        return Ok(
+            warp::reply::with_status(format!("No user {}", owner), StatusCode::BAD_REQUEST)
+                .into_response(),
+        );
+    }
+    match auth.tokens.create(
+        &owner,
+        &request.name,
+        request.scopes,
+        request.collection,
+        request.days,
+    ) {
+        Ok((token, secret)) => {
+            auditor.record(AuditEvent::Admin {
+                action: "create_token".to_string(),
+                target: format!("{} for {}", token.id, token.owner),
+            });
+            Ok(warp::reply::with_status(
+                warp::reply::json(&CreatedToken { token, secret }),
+                StatusCode::CREATED,
+            )
+            .into_response())
+        }
+        Err(e) => {
+            Ok(warp::reply::with_status(e.to_string(), StatusCode::BAD_REQUEST).into_response())
+        }
+    }
+}
+
+pub async fn handle_revoke_token(
+    id: String,
+    auth: Arc<Auth>,
+    auditor: Auditor,
+) -> Result<warp::reply::Response, Infallible> {
+    Ok(match auth.tokens.revoke(&id) {
+        Ok(true) => {
+            auditor.record(AuditEvent::Admin {
+                action: "revoke_token".to_string(),
+                target: id,
+            });
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    })
+}
+
+// `token create` prints a new token for an existing user, `token revoke` and
+// `token list` manage them. Like `user`, it needs the server stopped.
+pub fn run(args: &[String]) -> Result<()> {
+    let db = sled::open(env_or("MERKLE_AUTH_DB", "auth_db"))?;
+    let auth = Auth::open(&db, true)?;
+    match args {
+        [command, owner, name, rest @ ..] if command == "create" => {
+            if auth.users.get(owner)?.is_none() {
+                bail!("No user {}", owner);
+            }
+            let (mut scopes, mut collection, mut days) = (Vec::new(), None, None);
+            let mut rest = rest.iter();
+            while let Some(arg) = rest.next() {
+                match (arg.as_str(), rest.next()) {
+                    ("--scope", Some(scope)) => scopes.push(scope.parse()?),
+                    ("--collection", Some(name)) => collection = Some(name.clone()),
+                    ("--days", Some(n)) => days = Some(n.parse()?),
+                    _ => bail!(TOKEN_USAGE),
+                }
+            }
+            let (token, secret) = auth.tokens.create(owner, name, scopes, collection, days)?;
+            eprintln!(
+                "Created token {} for {}, it won't be shown again:",
+                token.id, owner
+            );
+            println!("{}", secret);
+        }
+        [command, id] if command == "revoke" => match auth.tokens.revoke(id)? {
+            true => println!("Revoked {}", id),
+            false => bail!("No token {}", id),
+        },
+        [command] if command == "list" => {
+            for token in auth.tokens.list()? {
+                let scopes: Vec<String> = token.scopes.iter().map(Scope::to_string).collect();
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    token.id,
+                    token.name,
+                    token.owner,
+                    scopes.join(","),
+                    token.collection.as_deref().unwrap_or("*")
+                );
+            }
+        }
+        _ => bail!(TOKEN_USAGE),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_tokens_are_scoped_and_revocable() {
+        use crate::fileserver::auth::tokens::{Scope, TokenStore};
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tokens = TokenStore::open(&db).unwrap();
+        let (token, secret) = tokens
+            .create(
+                "alice",
+                "ci",
+                vec![Scope::Upload],
+                Some("builds".to_string()),
+                None,
+            )
+            .unwrap();
+        let found = tokens.verify(&secret).unwrap().unwrap();
+        assert_eq!(found, token);
+        assert!(found.allows(Scope::Upload, "builds"));
+        assert!(!found.allows(Scope::Upload, "default"));
+        assert!(!found.allows(Scope::Read, "builds"));
+        //a wrong secret for a real id, and things that aren't tokens
+        let forged = format!("mfs_{}_{}", token.id, "x".repeat(43));
+        assert!(tokens.verify(&forged).unwrap().is_none());
+        assert!(tokens.verify("not-a-token").unwrap().is_none());
+        assert!(tokens
+            .create("alice", "none", Vec::new(), None, None)
+            .is_err());
+        //the secret is not stored
+        let stored = db
+            .open_tree("tokens")
+            .unwrap()
+            .get(&token.id)
+            .unwrap()
+            .unwrap();
+        assert!(!String::from_utf8_lossy(&stored)
+            .contains(&secret["mfs_".len() + token.id.len() + 1..]));
+
+        let (_, expired) = tokens
+            .create("alice", "old", vec![Scope::Read], None, Some(0))
+            .unwrap();
+        assert!(tokens.verify(&expired).unwrap().is_none());
+        assert_eq!(tokens.list().unwrap().len(), 2);
+
+        assert!(tokens.revoke(&token.id).unwrap());
+        assert!(tokens.verify(&secret).unwrap().is_none());
+        assert!(!tokens.revoke(&token.id).unwrap());
+    }
+}
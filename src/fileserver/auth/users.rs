@@ -0,0 +1,228 @@
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+use std::sync::OnceLock;
+
+// Layout in the auth database:
+//   users: username -> User as JSON
+const USERS_TREE: &str = "users";
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub username: String,
+    pub roles: Vec<String>,
+    // pbkdf2-sha256$<iterations>$<salt>$<hash>, None for users who only sign in through OIDC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+    // Issuer and subject of the OIDC account the user was created from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oidc_subject: Option<String>,
+}
+
+pub fn hash_password(password: &str) -> Result<String> {
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| anyhow!("System random source failed"))?;
+    Ok(encode_password(password, &salt))
+}
+
+fn encode_password(password: &str, salt: &[u8]) -> String {
+    let mut hash = [0u8; HASH_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    format!(
+        "pbkdf2-sha256${}${}${}",
+        PBKDF2_ITERATIONS,
+        STANDARD_NO_PAD.encode(salt),
+        STANDARD_NO_PAD.encode(hash)
+    )
+}
+
+// What passwords for unknown users are checked against, so the check costs one
+// PBKDF2 run like a real one. Made once, when the store opens.
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| encode_password("", &[0u8; SALT_LEN]))
+}
+
+// Constant time, and false for anything that doesn't parse
+pub fn verify_password(password: &str, encoded: &str) -> bool {
+    let parts: Vec<&str> = encoded.split('$').collect();
+    let [scheme, iterations, salt, hash] = parts.as_slice() else {
+        return false;
+    };
+    let (Ok(iterations), Ok(salt), Ok(hash)) = (
+        iterations.parse::<u32>(),
+        STANDARD_NO_PAD.decode(salt),
+        STANDARD_NO_PAD.decode(hash),
+    ) else {
+        return false;
+    };
+    let Some(iterations) = NonZeroU32::new(iterations) else {
+        return false;
+    };
+    *scheme == "pbkdf2-sha256"
+        && pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            password.as_bytes(),
+            &hash,
+        )
+        .is_ok()
+}
+
+#[derive(Clone)]
+pub struct UserStore {
+    users: sled::Tree,
+}
+
+impl UserStore {
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        dummy_hash();
+        Ok(UserStore {
+            users: db.open_tree(USERS_TREE)?,
+        })
+    }
+
+    pub fn get(&self, username: &str) -> Result<Option<User>> {
+        match self.users.get(username)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put(&self, user: &User) -> Result<()> {
+        self.users
+            .insert(user.username.as_str(), serde_json::to_vec(user)?)?;
+        self.users.flush()?;
+        Ok(())
+    }
+
+    pub fn remove(&self, username: &str) -> Result<bool> {
+        let removed = self.users.remove(username)?.is_some();
+        self.users.flush()?;
+        Ok(removed)
+    }
+
+    pub fn list(&self) -> Result<Vec<User>> {
+        self.users
+            .iter()
+            .values()
+            .map(|value| Ok(serde_json::from_slice(&value?)?))
+            .collect()
+    }
+
+    // Add or replace a local user with a password
+    pub fn add_local(&self, username: &str, password: &str, roles: Vec<String>) -> Result<User> {
+        if username.is_empty() || password.is_empty() {
+            bail!("Username and password must not be empty");
+        }
+        let user = User {
+            username: username.to_string(),
+            roles,
+            password_hash: Some(hash_password(password)?),
+            oidc_subject: None,
+        };
+        self.put(&user)?;
+        Ok(user)
+    }
+
+    // The user for a username and password, or None. Unknown users cost as much
+    // time as wrong passwords, so timing doesn't tell which names exist.
+    pub fn authenticate(&self, username: &str, password: &str) -> Result<Option<User>> {
+        let user = self.get(username)?;
+        let encoded = user.as_ref().and_then(|user| user.password_hash.clone());
+        let valid = match &encoded {
+            Some(encoded) => verify_password(password, encoded),
+            None => {
+                let _ = verify_password(password, dummy_hash());
+                false
+            }
+        };
+        Ok(user.filter(|_| valid))
+    }
+
+    // Create or update the user an OIDC login maps to. Roles come from the
+    // provider on every login; a local user of the same name from another
+    // source is not taken over.
+    pub fn upsert_oidc(&self, username: &str, subject: &str, roles: Vec<String>) -> Result<User> {
+        let mut user = match self.get(username)? {
+            Some(user) if user.oidc_subject.as_deref() == Some(subject) => user,
+            Some(_) => bail!(
+                "User {} exists and is not linked to this OIDC account",
+                username
+            ),
+            None => User {
+                username: username.to_string(),
+                roles: Vec::new(),
+                password_hash: None,
+                oidc_subject: Some(subject.to_string()),
+            },
+        };
+        user.roles = roles;
+        self.put(&user)?;
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_local_and_oidc_users() {
+        use crate::fileserver::auth::users::UserStore;
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let users = UserStore::open(&db).unwrap();
+        users
+            .add_local("alice", "correct horse", vec!["admin".to_string()])
+            .unwrap();
+        assert!(users.authenticate("alice", "wrong").unwrap().is_none());
+        assert!(users
+            .authenticate("bob", "correct horse")
+            .unwrap()
+            .is_none());
+        let alice = users
+            .authenticate("alice", "correct horse")
+            .unwrap()
+            .unwrap();
+        assert_eq!(alice.roles, vec!["admin"]);
+        assert!(users.add_local("", "x", Vec::new()).is_err());
+
+        let bob = users
+            .upsert_oidc("bob", "https://idp|123", vec!["reader".to_string()])
+            .unwrap();
+        assert!(bob.password_hash.is_none());
+        //roles follow the provider, and a different subject can't claim the name
+        let bob = users
+            .upsert_oidc("bob", "https://idp|123", vec!["uploader".to_string()])
+            .unwrap();
+        assert_eq!(bob.roles, vec!["uploader"]);
+        assert!(users
+            .upsert_oidc("bob", "https://idp|456", Vec::new())
+            .is_err());
+        assert!(users
+            .upsert_oidc("alice", "https://idp|789", Vec::new())
+            .is_err());
+        //OIDC users have no password to log in with
+        assert!(users.authenticate("bob", "").unwrap().is_none());
+
+        assert_eq!(users.list().unwrap().len(), 2);
+        assert!(users.remove("bob").unwrap());
+        assert!(users.get("bob").unwrap().is_none());
+    }
+}
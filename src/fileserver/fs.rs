@@ -1,98 +1,803 @@
-use crate::merkletree::tree::FastMerkleTree;
+use super::audit::{AuditEvent, Auditor};
+use crate::merkletree::hashing::{self, LeafHasher};
+use crate::merkletree::poseidon::{field_leaf, MAX_DEPTH};
+use crate::merkletree::sparse::{SparseMerkleTree, SparseProof};
+use crate::merkletree::tree::{
+    bytes32_hex, keccak_leaf, FastMerkleTree, KeccakMerkleTree, ROOT_FORMAT, ZERO,
+};
+use crate::merkletree::witness::MembershipWitness;
+use crate::merkletree::zk::prove_chain;
+use crate::metrics::metrics;
+use crate::storage::Store;
 use anyhow::Result;
-use futures::TryStreamExt;
+use blake3::Hash;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::{SinkExt, TryStreamExt};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::convert::Infallible;
 use std::fs::{self};
-use std::path::{Path, PathBuf};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use warp::filters::multipart::FormData;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_util::io::StreamReader;
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
+use warp::filters::multipart::{FormData, Part};
 use warp::reject::Rejection;
 use warp::reply::Reply;
 use warp::{self, http::StatusCode, Buf};
 
-const UPLOAD_DIR: &str = "filestore/";
+// Whether an upload replaces the whole store or adds to it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadMode {
+    // The batch becomes the new set of files and the tree is rebuilt
+    #[default]
+    Replace,
+    // One file goes in next to the others, or over the one with its name
+    Add,
+}
+
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    #[serde(default)]
+    pub mode: UploadMode,
+}
+
+// Query for /download: root_format 1 asks for a proof against the root the same
+// files had before the padding changed, tree::ROOT_FORMAT
+#[derive(Debug, Deserialize)]
+pub struct DownloadQuery {
+    root_format: Option<u32>,
+}
 
+// Sent back by an add: the new roots and the file's place under them
+#[derive(Serialize)]
+struct AddedFile {
+    root: String,
+    index: usize,
+    proof: Vec<(String, bool)>,
+    sparse_root: String,
+    sparse_proof: Option<SparseProof>,
+    names_root: String,
+}
+
+// Sent back by /prove-nonmember: the file's empty slot under the sparse root
+#[derive(Serialize, Deserialize)]
+pub struct NonMembership {
+    pub file: String,
+    pub sparse_root: String,
+    pub proof: SparseProof,
+}
+
+// Optional form fields sent alongside the files
+#[derive(Default)]
+struct UploadFields {
+    expected_root: Option<String>,
+    expected_leaves: Option<String>,
+}
+
+#[derive(Serialize)]
+struct LeafDiff {
+    index: usize,
+    file: Option<String>,
+    expected: Option<String>,
+    computed: Option<String>,
+}
+
+// Body of the rejection sent when the client expected a different root
+#[derive(Serialize)]
+struct RootMismatch {
+    expected_root: String,
+    computed_root: String,
+    computed_leaves: Vec<(String, String)>,
+    differing_leaves: Option<Vec<LeafDiff>>,
+}
+
+// What an upload staged: leaf hashes by cleaned file name, plus the optional fields
+#[derive(Default)]
+struct StagedUpload {
+    files: BTreeMap<String, Hash>,
+    fields: UploadFields,
+    bytes: u64,
+}
+
+#[tracing::instrument(name = "upload", skip_all, fields(batch = Empty, files = Empty, root = Empty))]
 pub async fn handle_file_upload(
-    db: Arc<sled::Db>,
+    mode: UploadMode,
+    may_delete: bool,
+    store: Arc<Store>,
+    auditor: Auditor,
+    form: FormData,
+) -> Result<warp::reply::Response, Infallible> {
+    //stage the new files where readers can't see them so a failed upload leaves the store untouched
+    let batch = new_batch_id();
+    Span::current().record("batch", batch.as_str());
+    let response = match stage_upload(&store, &batch, form).await {
+        Ok(Some(upload)) if mode == UploadMode::Add => {
+            add_upload(&store, &batch, upload, may_delete, &auditor).await
+        }
+        Ok(Some(upload)) => commit_upload(&store, &batch, upload, &auditor).await,
+        Ok(None) => {
+            metrics().reject("malformed_upload");
+            StatusCode::BAD_REQUEST.into_response()
+        }
+        Err(e) => {
+            tracing::error!(error = %format!("{:#}", e), "Staging the upload failed");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    };
+    if let Err(e) = store.blobs.discard(&batch).await {
+        tracing::warn!(error = %format!("{:#}", e), "Failed to clean up the staged batch");
+    }
+    Ok(response)
+}
+
+// Returns None if the form is malformed or ends early. A body that breaks off,
+// such as when the connection drops, must not commit the files that did arrive
+// as the whole new set.
+async fn stage_upload(
+    store: &Store,
+    batch: &str,
     mut form: FormData,
-) -> Result<impl warp::Reply, Infallible> {
-    //empty the current folder for new uploads since user can't have root hash for all files
-    let _ = empty_folder(format!("./{}", UPLOAD_DIR));
-
-    while let Ok(Some(part)) = form.try_next().await {
-        let filename = match part.filename() {
-            Some(filename) => filename.to_string(),
-            None => return Ok(StatusCode::BAD_REQUEST),
+) -> Result<Option<StagedUpload>> {
+    let mut upload = StagedUpload::default();
+    loop {
+        let part = match form.try_next().await {
+            Ok(Some(part)) => part,
+            Ok(None) => break,
+            Err(e) => return incomplete(e),
         };
-        let mut data = Vec::new();
+        let name = part.name().to_string();
+        match (name.as_str(), part.filename()) {
+            ("expected_root", None) => match read_field(part).await {
+                //an empty field is the same as leaving it out, for a full upload or an add
+                Ok(field) => {
+                    upload.fields.expected_root = field.filter(|root| !root.trim().is_empty())
+                }
+                Err(e) => return incomplete(e),
+            },
+            ("expected_leaves", None) => match read_field(part).await {
+                Ok(field) => upload.fields.expected_leaves = field,
+                Err(e) => return incomplete(e),
+            },
+            (_, Some(filename)) => {
+                //clean file name for storage (remove all spaces and special characters)
+                let clean_file_name = clean_file_name(filename);
+                let (leaf, size) = match stage_file(store, batch, &clean_file_name, part).await {
+                    Ok(staged) => staged,
+                    Err(e) => match e.downcast::<warp::Error>() {
+                        Ok(e) => return incomplete(e),
+                        Err(e) => return Err(e),
+                    },
+                };
+                upload.bytes += size;
+                upload.files.insert(clean_file_name, leaf);
+            }
+            (_, None) => {
+                tracing::warn!(field = name, "Upload form has a field without a file name");
+                return Ok(None);
+            }
+        }
+    }
+    Ok(Some(upload))
+}
+
+fn incomplete(e: warp::Error) -> Result<Option<StagedUpload>> {
+    tracing::warn!(error = %e, "Upload form ended early or is malformed");
+    Ok(None)
+}
+
+// Write one file into the batch as it arrives, hashing each chunk on the hashing
+// pool while the blob store writes it, and return its leaf hash and size
+async fn stage_file(store: &Store, batch: &str, name: &str, part: Part) -> Result<(Hash, u64)> {
+    let (mut tx, rx) = mpsc::channel::<std::io::Result<Bytes>>(16);
+    let hash = async move {
+        let mut hasher = LeafHasher::default();
+        let mut size = 0;
         let mut stream = part.stream();
+        while let Some(mut chunk) = stream.try_next().await? {
+            let chunk = chunk.copy_to_bytes(chunk.remaining());
+            size += chunk.len() as u64;
+            hasher.update(&chunk).await?;
+            //the blob store stopped reading, its error is returned instead
+            if tx.send(Ok(chunk)).await.is_err() {
+                break;
+            }
+        }
+        //closing the channel ends the file
+        drop(tx);
+        anyhow::Ok((hasher.finalize().await?, size))
+    };
+    let write = store
+        .blobs
+        .stage(batch, name, Box::pin(StreamReader::new(rx)));
+    let (leaf, ()) = tokio::try_join!(hash, write)?;
+    Ok(leaf)
+}
+
+// A small text field, None if it isn't UTF-8
+async fn read_field(part: Part) -> Result<Option<String>, warp::Error> {
+    let mut data = Vec::new();
+    let mut stream = part.stream();
+    while let Some(chunk) = stream.try_next().await? {
+        data.extend_from_slice(chunk.chunk());
+    }
+    Ok(String::from_utf8(data).ok())
+}
 
-        while let Ok(Some(chunk)) = stream.try_next().await {
-            data.extend_from_slice(chunk.chunk());
+async fn commit_upload(
+    store: &Arc<Store>,
+    batch: &str,
+    upload: StagedUpload,
+    auditor: &Auditor,
+) -> warp::reply::Response {
+    if upload.files.is_empty() {
+        tracing::warn!("Upload has no files");
+        metrics().reject("empty_upload");
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    //build merkle tree for the entire batch, leaves sorted by file name
+    let (file_list, leaves): (Vec<String>, Vec<Hash>) = upload.files.into_iter().unzip();
+    Span::current().record("files", file_list.len());
+    let files = file_list.clone();
+    let trees = hashing::spawn(move || {
+        let sparse = SparseMerkleTree::from_files(files.into_iter().zip(leaves.clone()));
+        let _timer = metrics().tree_build_seconds.start_timer();
+        (FastMerkleTree::from_leaves(leaves), sparse)
+    });
+    let (merkle_tree, sparse) = match trees.await {
+        Ok(trees) => trees,
+        Err(e) => {
+            tracing::error!(error = %e, "Building the merkle tree failed");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
-        //clean file name for storage (remove all spaces and special characters)
-        let clean_file_name = clean_file_name(&filename);
-        let save_path = PathBuf::from(format!("./{}{}", UPLOAD_DIR, clean_file_name));
-        if (tokio::fs::write(save_path, data).await).is_err() {
-            return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    Span::current().record("root", merkle_tree.root().to_hex().as_str());
+    let fields = upload.fields;
+
+    //reject the whole batch if the client computed a different root
+    if let Some(expected_root) = &fields.expected_root {
+        let expected_root = match parse_hash(expected_root) {
+            Some(root) => root,
+            None => {
+                tracing::warn!(expected_root, "Expected root is not a hash");
+                metrics().reject("malformed_upload");
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+        };
+        if expected_root != merkle_tree.root().as_bytes() {
+            tracing::warn!(
+                expected_root = %hex::encode(&expected_root),
+                "Upload rejected, the client computed a different root"
+            );
+            metrics().reject("root_mismatch");
+            auditor.record(AuditEvent::UploadRejected {
+                files: file_list.clone(),
+                expected_root: hex::encode(&expected_root),
+                computed_root: merkle_tree.root().to_hex().to_string(),
+            });
+            let expected_leaves = match fields.expected_leaves.as_deref().map(parse_hash_list) {
+                Some(None) => return StatusCode::BAD_REQUEST.into_response(),
+                Some(Some(leaves)) => Some(leaves),
+                None => None,
+            };
+            let report = root_mismatch(&merkle_tree, &file_list, expected_root, expected_leaves);
+            return warp::reply::with_status(warp::reply::json(&report), StatusCode::CONFLICT)
+                .into_response();
         }
     }
 
-    //clear old db entries before adding new ones
-    let _ = clear_db(db.clone());
-    //build merkle tree for the entire upload folder
-    let file_list = get_file_list(UPLOAD_DIR);
-    FastMerkleTree::build_merkle_tree(db, file_list);
-    Ok(StatusCode::OK)
+    //swap files and tree together; user can't have root hash for old and new files.
+    //it runs as its own task so a shutdown that drops this request can't stop it halfway
+    let file_count = file_list.len();
+    let commit = tokio::spawn(
+        {
+            let store = Arc::clone(store);
+            let batch = batch.to_string();
+            let auditor = auditor.clone();
+            async move {
+                let root = merkle_tree.root().to_hex().to_string();
+                let files = file_list.clone();
+                let mut cache = store.cache.write().await;
+                store
+                    .swap_in(&mut cache, &batch, merkle_tree, file_list, sparse)
+                    .await?;
+                auditor.record(AuditEvent::Upload {
+                    files,
+                    root,
+                    expected_root: fields.expected_root,
+                });
+                anyhow::Ok(())
+            }
+        }
+        .in_current_span(),
+    );
+    match commit.await.unwrap_or_else(|e| Err(e.into())) {
+        Ok(()) => {
+            tracing::info!("Upload committed");
+            metrics().upload_bytes.inc_by(upload.bytes);
+            metrics().upload_files.observe(file_count as f64);
+            StatusCode::OK.into_response()
+        }
+        Err(e) => {
+            tracing::error!(error = %format!("{:#}", e), "Committing the upload failed");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
 }
 
+// Put a single file into the live set, extending or updating the tree in place
+// instead of rebuilding it
+async fn add_upload(
+    store: &Arc<Store>,
+    batch: &str,
+    upload: StagedUpload,
+    may_delete: bool,
+    auditor: &Auditor,
+) -> warp::reply::Response {
+    //the root depends on the files already there, which the client can't compute
+    if upload.files.len() != 1 || upload.fields.expected_root.is_some() {
+        tracing::warn!("An add takes one file and no expected root");
+        metrics().reject("malformed_upload");
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let Some((filename, leaf)) = upload.files.into_iter().next() else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    Span::current().record("files", 1);
+
+    //runs as its own task for the same reason as commit_upload
+    let commit = tokio::spawn(
+        {
+            let store = Arc::clone(store);
+            let batch = batch.to_string();
+            let auditor = auditor.clone();
+            async move {
+                let mut cache = store.cache.write().await;
+                //replacing a file removes the old one, so it needs delete
+                if cache.file_index(&filename).is_some() && !may_delete {
+                    return Ok(None);
+                }
+                store.merge_in(&mut cache, &batch, &filename, leaf).await?;
+                let root = cache.root().unwrap_or(Hash::from_bytes(ZERO));
+                auditor.record(AuditEvent::Upload {
+                    files: vec![filename.clone()],
+                    root: root.to_hex().to_string(),
+                    expected_root: None,
+                });
+                anyhow::Ok(Some(AddedFile {
+                    root: root.to_hex().to_string(),
+                    index: cache.file_index(&filename).unwrap_or_default(),
+                    proof: FastMerkleTree::pretty_merkle_proof(
+                        cache.get_merkle_proof(&filename).unwrap_or_default(),
+                    ),
+                    sparse_root: cache.sparse_root().to_hex().to_string(),
+                    sparse_proof: cache.prove_member(&filename),
+                    names_root: cache.names_root().to_hex().to_string(),
+                }))
+            }
+        }
+        .in_current_span(),
+    );
+    match commit.await.unwrap_or_else(|e| Err(e.into())) {
+        Ok(Some(added)) => {
+            Span::current().record("root", added.root.as_str());
+            tracing::info!("Upload added");
+            metrics().upload_bytes.inc_by(upload.bytes);
+            metrics().upload_files.observe(1.0);
+            warp::reply::json(&added).into_response()
+        }
+        Ok(None) => {
+            tracing::warn!("Add would replace a file without delete permission");
+            metrics().reject("forbidden");
+            StatusCode::FORBIDDEN.into_response()
+        }
+        Err(e) => {
+            tracing::error!(error = %format!("{:#}", e), "Committing the upload failed");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn root_mismatch(
+    merkle_tree: &FastMerkleTree,
+    file_list: &[String],
+    expected_root: Vec<u8>,
+    expected_leaves: Option<Vec<Vec<u8>>>,
+) -> RootMismatch {
+    let computed_leaves = merkle_tree.leaves(file_list.len());
+    let differing_leaves = expected_leaves.map(|expected_leaves| {
+        let leaf_count = computed_leaves.len().max(expected_leaves.len());
+        (0..leaf_count)
+            .filter_map(|index| {
+                let computed = computed_leaves
+                    .get(index)
+                    .map(|leaf| leaf.as_bytes().to_vec());
+                let expected = expected_leaves.get(index).cloned();
+                if computed == expected {
+                    return None;
+                }
+                Some(LeafDiff {
+                    index,
+                    file: file_list.get(index).cloned(),
+                    expected: expected.map(hex::encode),
+                    computed: computed.map(hex::encode),
+                })
+            })
+            .collect()
+    });
+    RootMismatch {
+        expected_root: hex::encode(expected_root),
+        computed_root: merkle_tree.root().to_hex().to_string(),
+        computed_leaves: file_list
+            .iter()
+            .cloned()
+            .zip(computed_leaves.iter().map(|leaf| leaf.to_hex().to_string()))
+            .collect(),
+        differing_leaves,
+    }
+}
+
+// Accepts a hash either as hex or as the byte list printed by the client hash page
+pub(crate) fn parse_hash(value: &str) -> Option<Vec<u8>> {
+    let value = value.trim();
+    let bytes = match value.starts_with('[') {
+        true => serde_json::from_str::<Vec<u8>>(value).ok()?,
+        false => hex::decode(value).ok()?,
+    };
+    (bytes.len() == 32).then_some(bytes)
+}
+
+fn parse_hash_list(value: &str) -> Option<Vec<Vec<u8>>> {
+    let leaves: Vec<String> = serde_json::from_str(value).ok()?;
+    leaves.iter().map(|leaf| parse_hash(leaf)).collect()
+}
+
+pub(crate) fn new_batch_id() -> String {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    format!("{}-{}", nanos, id)
+}
+
+#[tracing::instrument(name = "download", skip(store, auditor), fields(file = %filename, root = Empty))]
 pub async fn handle_file_download(
-    db: Arc<sled::Db>,
+    store: Arc<Store>,
+    auditor: Auditor,
     filename: String,
-) -> Result<impl Reply, Rejection> {
+    query: DownloadQuery,
+) -> Result<warp::reply::Response, Rejection> {
     use tokio_util::io::ReaderStream;
-    let filepath = PathBuf::from(format!("./filestore/{}", filename));
-    if filepath.exists() {
+    let root_format = query.root_format.unwrap_or(ROOT_FORMAT);
+    if root_format != 1 && root_format != ROOT_FORMAT {
+        let reason = format!("root_format has to be 1 or {}", ROOT_FORMAT);
+        return Ok(warp::reply::with_status(reason, StatusCode::BAD_REQUEST).into_response());
+    }
+    //hold the lock until the file is open and the proof is read so both come from one upload
+    let cache = store.cache.read().await;
+    record_root(cache.root());
+    let file = match store.blobs.open(&filename).await {
+        Ok(file) => file,
+        Err(e) => {
+            tracing::error!(error = %format!("{:#}", e), "Failed to open the file");
+            None
+        }
+    };
+    if let Some(file) = file {
         let mut merkle_proof: Vec<(Vec<u8>, bool)> = Vec::new();
+        let mut root = cache.root();
 
-        // get merkle proof from db
-        if let Some(proof) =
-            FastMerkleTree::get_merkle_proof_from_db(db, format!("./filestore/{}", filename))
-        {
+        // get merkle proof from the in-memory tree
+        if root_format == ROOT_FORMAT {
+            if let Some(proof) = cache.get_merkle_proof(&filename) {
+                merkle_proof = proof;
+            };
+        } else if let Some((legacy_root, proof)) = cache.legacy_merkle_proof(&filename) {
+            root = Some(legacy_root);
             merkle_proof = proof;
-        };
-        let file = tokio::fs::File::open(filepath).await.unwrap();
+        }
+        auditor.record(AuditEvent::Download {
+            file: filename.clone(),
+            root: root.map(|root| root.to_hex().to_string()),
+            proof: merkle_proof
+                .iter()
+                .map(|(hash, is_left)| (hex::encode(hash), *is_left))
+                .collect(),
+        });
 
-        let stream = ReaderStream::new(file);
+        let stream = ReaderStream::new(file)
+            .inspect_ok(|chunk| metrics().download_bytes.inc_by(chunk.len() as u64));
         let response = warp::http::response::Builder::new()
             .header(
                 "Content-Disposition",
                 format!("attachment; filename=\"{}\"", filename),
             )
             .header("X-File-Hash", format!("{:?}", merkle_proof))
+            .header("X-Root-Format", root_format)
             .body(warp::hyper::Body::wrap_stream(stream))
             .unwrap();
         Ok(response)
     } else {
+        tracing::debug!("File not found");
+        metrics().reject("not_found");
         Err(warp::reject::not_found())
     }
 }
 
-// Handler to list files
-#[allow(clippy::manual_flatten)]
-pub async fn list_files_handler() -> Result<impl Reply, Rejection> {
-    // Read directory contents
-    let mut files = vec![];
-    if let Ok(entries) = fs::read_dir(UPLOAD_DIR) {
-        for entry in entries {
-            if let Ok(entry) = entry {
-                if let Ok(filename) = entry.file_name().into_string() {
-                    files.push(filename);
+// Proof that no file with this name is under the current root
+#[tracing::instrument(name = "prove_absent", skip(store, auditor), fields(file = %filename, root = Empty))]
+pub async fn handle_prove_absent(
+    store: Arc<Store>,
+    auditor: Auditor,
+    filename: String,
+) -> Result<warp::reply::Response, Infallible> {
+    //stored names are cleaned, so look up the name the file would have been stored under
+    let filename = clean_file_name(&filename);
+    let cache = store.cache.read().await;
+    record_root(cache.root());
+    if cache.get_merkle_proof(&filename).is_some() {
+        let reason = format!("{} is present", filename);
+        return Ok(warp::reply::with_status(reason, StatusCode::CONFLICT).into_response());
+    }
+    if !cache.sorted() {
+        let reason = "Files were added out of name order since the last full upload, so \
+                      absence can't be shown by neighbours; use /prove-nonmember";
+        return Ok(warp::reply::with_status(reason, StatusCode::CONFLICT).into_response());
+    }
+    match cache.prove_absent(&filename) {
+        Some(proof) => {
+            auditor.record(proof_event("absence", &filename, cache.root()));
+            Ok(warp::reply::json(&proof).into_response())
+        }
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+// Proof from the sparse tree that no file with this name is committed, which
+// holds whatever order the files went in
+#[tracing::instrument(name = "prove_nonmember", skip(store, auditor), fields(file = %filename, root = Empty))]
+pub async fn handle_prove_nonmember(
+    store: Arc<Store>,
+    auditor: Auditor,
+    filename: String,
+) -> Result<warp::reply::Response, Infallible> {
+    let cache = store.cache.read().await;
+    record_root(cache.root());
+    match cache.prove_nonmember(&filename) {
+        Some(proof) => {
+            auditor.record(proof_event("nonmember", &filename, cache.root()));
+            Ok(warp::reply::json(&NonMembership {
+                file: filename,
+                sparse_root: cache.sparse_root().to_hex().to_string(),
+                proof,
+            })
+            .into_response())
+        }
+        None => Ok(StatusCode::CONFLICT.into_response()),
+    }
+}
+
+// Most appends proven in one request; each is a Groth16 proof
+pub const MAX_APPEND_STEPS: usize = 64;
+
+// Proof that the Poseidon tree over the current files is the one over the first
+// old_count files with the others appended, for clients holding the old root
+#[tracing::instrument(name = "prove_append", skip(store, auditor), fields(root = Empty))]
+pub async fn handle_prove_append(
+    store: Arc<Store>,
+    auditor: Auditor,
+    old_count: usize,
+) -> Result<warp::reply::Response, Infallible> {
+    let Some(key) = store.append_key.clone() else {
+        tracing::debug!("No append proving key configured");
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let cache = store.cache.read().await;
+    record_root(cache.root());
+    let root = cache.root();
+    let files = cache.file_list().to_vec();
+    let leaves: Vec<_> = cache.leaves().iter().map(field_leaf).collect();
+    drop(cache);
+    if old_count > leaves.len()
+        || leaves.len() > 1 << key.depth
+        || leaves.len() - old_count > MAX_APPEND_STEPS
+    {
+        let reason = format!(
+            "count has to be at most {} files and within {} of it, in a tree of depth {}",
+            leaves.len(),
+            MAX_APPEND_STEPS,
+            key.depth
+        );
+        return Ok(warp::reply::with_status(reason, StatusCode::BAD_REQUEST).into_response());
+    }
+    let range = format!("{}..{}", old_count, leaves.len());
+    match hashing::spawn_proof(move || prove_chain(&key, &files, &leaves, old_count)).await {
+        Ok(Ok(chain)) => {
+            auditor.record(proof_event("append", &range, root));
+            Ok(warp::reply::json(&chain).into_response())
+        }
+        Ok(Err(e)) | Err(e) => {
+            tracing::error!(error = %format!("{:#}", e), "Proving the append failed");
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+// Query for /witness: tree is poseidon (default) or blake3, format is json
+// (circom input.json, default) or toml (noir Prover.toml)
+#[derive(Debug, Deserialize)]
+pub struct WitnessQuery {
+    tree: Option<String>,
+    format: Option<String>,
+    depth: Option<usize>,
+}
+
+const DEFAULT_WITNESS_DEPTH: usize = 20;
+
+// Membership inputs for an external circuit, from the in-memory tree
+#[tracing::instrument(name = "witness", skip(store, auditor), fields(file = %filename, root = Empty))]
+pub async fn handle_witness(
+    store: Arc<Store>,
+    auditor: Auditor,
+    filename: String,
+    query: WitnessQuery,
+) -> Result<warp::reply::Response, Infallible> {
+    let cache = store.cache.read().await;
+    record_root(cache.root());
+    let (Some(index), Some(root)) = (cache.file_index(&filename), cache.root()) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let kind = format!("witness-{}", query.tree.as_deref().unwrap_or("poseidon"));
+    let toml = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("toml") => true,
+        Some(_) => return Ok(StatusCode::BAD_REQUEST.into_response()),
+    };
+    let witness = match query.tree.as_deref() {
+        Some("blake3") => {
+            let proof = cache.get_merkle_proof(&filename).unwrap_or_default();
+            let leaf = cache.leaves()[index];
+            drop(cache);
+            witness_reply(&MembershipWitness::blake3(&leaf, &proof, &root), toml)
+        }
+        None | Some("poseidon") => {
+            let leaves = cache.leaves();
+            drop(cache);
+            //the depth sets how much hashing the request costs, so it is bounded before any
+            let depth = query.depth.unwrap_or(DEFAULT_WITNESS_DEPTH);
+            if !(1..=MAX_DEPTH).contains(&depth) || leaves.len() > 1 << depth {
+                tracing::warn!(depth, "Witness depth out of range");
+                let reason = format!(
+                    "depth has to be between 1 and {} and hold all {} files",
+                    MAX_DEPTH,
+                    leaves.len()
+                );
+                return Ok(
+                    warp::reply::with_status(reason, StatusCode::BAD_REQUEST).into_response()
+                );
+            }
+            //rebuilding the poseidon tree is real work, so keep it off the runtime threads
+            match hashing::spawn(move || MembershipWitness::poseidon(&leaves, index, depth)).await {
+                Ok(Ok(witness)) => witness_reply(&witness, toml),
+                Ok(Err(e)) => {
+                    tracing::warn!(error = %e, "Cannot build the poseidon witness");
+                    Ok(
+                        warp::reply::with_status(e.to_string(), StatusCode::BAD_REQUEST)
+                            .into_response(),
+                    )
                 }
+                Err(e) => Err(e),
             }
         }
+        Some(_) => return Ok(StatusCode::BAD_REQUEST.into_response()),
+    };
+    if witness
+        .as_ref()
+        .is_ok_and(|reply| reply.status().is_success())
+    {
+        auditor.record(proof_event(&kind, &filename, Some(root)));
+    }
+    Ok(witness.unwrap_or_else(|e| {
+        tracing::error!(error = %format!("{:#}", e), "Building the witness failed");
+        warp::reply::with_status(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
+    }))
+}
+
+fn witness_reply<T: Serialize>(
+    witness: &MembershipWitness<T>,
+    toml: bool,
+) -> Result<warp::reply::Response> {
+    match toml {
+        true => Ok(warp::reply::with_header(
+            witness.to_prover_toml()?,
+            "Content-Type",
+            "application/toml",
+        )
+        .into_response()),
+        false => Ok(warp::reply::json(witness).into_response()),
+    }
+}
+
+// Inclusion proof for OpenZeppelin's MerkleProof.verify(proof, root, leaf), where
+// leaf is keccak_leaf(value) and value is the file's blake3 leaf hash
+#[derive(Serialize)]
+struct SolidityProof {
+    value: String,
+    leaf: String,
+    proof: Vec<String>,
+    root: String,
+}
+
+#[tracing::instrument(name = "solidity_proof", skip(store, auditor), fields(file = %filename, root = Empty))]
+pub async fn handle_solidity_proof(
+    store: Arc<Store>,
+    auditor: Auditor,
+    filename: String,
+) -> Result<warp::reply::Response, Infallible> {
+    let cache = store.cache.read().await;
+    record_root(cache.root());
+    let Some(index) = cache.file_index(&filename) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let blake3_root = cache.root();
+    let leaves = cache.leaves();
+    drop(cache);
+    let value = *leaves[index].as_bytes();
+    let tree = match hashing::spawn(move || KeccakMerkleTree::from_file_leaves(&leaves)).await {
+        Ok(tree) => tree,
+        Err(e) => {
+            tracing::error!(error = %e, "Building the keccak tree failed");
+            return Ok(
+                warp::reply::with_status(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+                    .into_response(),
+            );
+        }
+    };
+    let leaf = keccak_leaf(&value);
+    let (Some(proof), Some(root)) = (tree.get_proof(&leaf), tree.root()) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    auditor.record(proof_event("solidity", &filename, blake3_root));
+    Ok(warp::reply::json(&SolidityProof {
+        value: bytes32_hex(&value),
+        leaf: bytes32_hex(&leaf),
+        proof: proof.iter().map(bytes32_hex).collect(),
+        root: bytes32_hex(&root),
+    })
+    .into_response())
+}
+
+// Proofs are recorded against the blake3 root they were made under
+fn proof_event(kind: &str, filename: &str, root: Option<Hash>) -> AuditEvent {
+    AuditEvent::Proof {
+        kind: kind.to_string(),
+        file: filename.to_string(),
+        root: root.map(|root| root.to_hex().to_string()),
     }
+}
+
+// Fills in the root field of the current handler span
+fn record_root(root: Option<Hash>) {
+    if let Some(root) = root {
+        Span::current().record("root", root.to_hex().as_str());
+    }
+}
+
+// Handler to list files
+pub async fn list_files_handler(store: Arc<Store>) -> Result<impl Reply, Rejection> {
+    let _cache = store.cache.read().await;
+    let files = store.blobs.list().await.unwrap_or_else(|e| {
+        tracing::error!(error = %format!("{:#}", e), "Failed to list the stored files");
+        Vec::new()
+    });
     // Respond with list of files
     Ok(warp::reply::json(&files))
 }
@@ -110,31 +815,175 @@ pub fn get_file_list(upload_dir: &str) -> Vec<String> {
     file_list
 }
 
-fn clear_db(db: Arc<sled::Db>) -> Result<()> {
-    for result in db.iter() {
-        let (key, _) = result?;
-        db.remove(key)?;
-    }
-    Ok(())
+pub(crate) fn clean_file_name(file_name: &str) -> String {
+    let invalid_chars = Regex::new(r"[^\w\.\-]").unwrap();
+    let trimmed = file_name.trim();
+    let clean_file_name = invalid_chars.replace_all(trimmed, "_");
+    clean_file_name.to_string()
 }
 
-fn empty_folder(path: String) -> Result<()> {
-    let folder_path = Path::new(&path);
-    for entry in fs::read_dir(folder_path)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_dir() {
-            fs::remove_dir_all(&path)?;
-        } else {
-            fs::remove_file(&path)?
+#[cfg(test)]
+pub(crate) mod tests {
+    #[test]
+    fn test_parse_hash_accepts_hex_and_byte_list() {
+        use crate::fileserver::fs::parse_hash;
+
+        let bytes: Vec<u8> = (0..32).collect();
+        let byte_list = format!("{:?}", bytes);
+        assert_eq!(parse_hash(&hex::encode(&bytes)), Some(bytes.clone()));
+        assert_eq!(parse_hash(&byte_list), Some(bytes));
+        assert_eq!(parse_hash("abcd"), None);
+    }
+
+    // In-memory store behind the full routes, with auth off
+    pub(crate) fn test_server() -> (
+        std::sync::Arc<crate::storage::Store>,
+        impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone,
+    ) {
+        use crate::fileserver::audit::AuditLog;
+        use crate::fileserver::auth::Auth;
+        use crate::fileserver::routes::routes;
+        use crate::storage::memory::{MemoryBlobStore, MemoryTreeStore};
+        use crate::storage::Store;
+        use std::sync::Arc;
+
+        let store = Arc::new(
+            Store::new(
+                Arc::new(MemoryBlobStore::default()),
+                Arc::new(MemoryTreeStore::default()),
+            )
+            .unwrap(),
+        );
+        let routes = routes(
+            Arc::clone(&store),
+            Arc::new(Auth::disabled().unwrap()),
+            Arc::new(AuditLog::temporary().unwrap()),
+        );
+        (store, routes)
+    }
+
+    // POST of a multipart form with one file part per (name, contents), shared by
+    // the route tests here and in the client
+    pub(crate) fn upload(path: &str, files: &[(&str, &str)]) -> warp::test::RequestBuilder {
+        let mut body = String::new();
+        for (name, contents) in files {
+            body += &format!(
+                "--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n{}\r\n",
+                name, contents
+            );
         }
+        body += "--X--\r\n";
+        warp::test::request()
+            .method("POST")
+            .path(path)
+            .header("content-type", "multipart/form-data; boundary=X")
+            .body(body)
     }
-    Ok(())
-}
 
-fn clean_file_name(file_name: &str) -> String {
-    let invalid_chars = Regex::new(r"[^\w\.\-]").unwrap();
-    let trimmed = file_name.trim();
-    let clean_file_name = invalid_chars.replace_all(trimmed, "_");
-    clean_file_name.to_string()
+    #[tokio::test]
+    async fn test_add_extends_the_tree() {
+        use crate::merkletree::tree::{leaf_hash, FastMerkleTree};
+
+        let (store, routes) = test_server();
+        let response = upload("/upload", &[("a", "one"), ("b", "two"), ("c", "three")])
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        let response = upload("/upload?mode=add", &[("d", "four")])
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        let added: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let leaves = ["one", "two", "three", "four"].map(|data| leaf_hash(data.as_bytes()));
+        let root = FastMerkleTree::from_leaves(leaves.to_vec()).root();
+        assert_eq!(added["root"], root.to_hex().as_str());
+        assert_eq!(added["index"], 3);
+        assert_eq!(store.blobs.list().await.unwrap().len(), 4);
+
+        //replacing a file keeps its slot, and an add takes exactly one file
+        let response = upload("/upload?mode=add", &[("b", "five")])
+            .reply(&routes)
+            .await;
+        let added: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(added["index"], 1);
+        let response = upload("/upload?mode=add", &[("e", "six"), ("f", "seven")])
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(store.cache.read().await.file_list().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_upload_leaves_the_tree() {
+        let (store, routes) = test_server();
+        let response = upload("/upload", &[("a", "one"), ("b", "two"), ("c", "three")])
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        let root = store.cache.read().await.root();
+
+        //a body that breaks off mid-form leaves the tree alone
+        let response = warp::test::request()
+            .method("POST")
+            .path("/upload")
+            .header("content-type", "multipart/form-data; boundary=X")
+            .body("--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a\"\r\n\r\none\r\n--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"b\"\r\n\r\ntw")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(store.cache.read().await.file_list().len(), 3);
+        assert_eq!(store.cache.read().await.root(), root);
+    }
+
+    #[tokio::test]
+    async fn test_witness_depth_is_bounded() {
+        let (_, routes) = test_server();
+        let response = upload("/upload", &[("a", "one"), ("b", "two"), ("c", "three")])
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        //a witness depth has to fit the files and stay within the limit
+        for (depth, status) in [(0, 400), (1, 400), (2, 200), (64, 400), (1000, 400)] {
+            let response = warp::test::request()
+                .path(&format!("/witness/a?depth={}", depth))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), status, "depth {}", depth);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prove_absent_needs_name_order() {
+        let (_, routes) = test_server();
+        let response = upload("/upload", &[("a", "one"), ("b", "two"), ("c", "three")])
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        let prove_absent = |name: &str| {
+            warp::test::request()
+                .path(&format!("/prove-absent/{}", name))
+                .reply(&routes)
+        };
+        assert_eq!(prove_absent("bb").await.status(), 200);
+        assert_eq!(prove_absent("b").await.status(), 409);
+        //looked up under the name it would be stored as
+        let response = prove_absent("b(b)").await;
+        let proof: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(proof["name"], "b_b_");
+
+        //absence holds while adds keep name order, and says why once they don't
+        let response = upload("/upload?mode=add", &[("d", "four")])
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(prove_absent("bb").await.status(), 200);
+        let response = upload("/upload?mode=add", &[("aa", "eight")])
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        let response = prove_absent("bb").await;
+        assert_eq!(response.status(), 409);
+        assert!(String::from_utf8_lossy(response.body()).contains("name order"));
+    }
 }
@@ -0,0 +1,219 @@
+use super::fs::new_batch_id;
+use crate::merkletree::tree::ROOT_FORMAT;
+use crate::storage::sled_store::SCHEMA_VERSION;
+use crate::storage::Store;
+use anyhow::{anyhow, ensure, Result};
+use blake3::Hash;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use warp::http::StatusCode;
+use warp::reply::Reply;
+
+// What /version reports on both servers
+#[derive(Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub hash: &'static str,
+    // Layout of the stored tree, sled_store::SCHEMA_VERSION
+    pub tree_format: u64,
+    // How the root pads the leaves, tree::ROOT_FORMAT
+    pub root_format: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<String>,
+}
+
+impl VersionInfo {
+    pub fn new(root: Option<Hash>) -> Self {
+        VersionInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            hash: "blake3",
+            tree_format: SCHEMA_VERSION,
+            root_format: ROOT_FORMAT,
+            root: root.map(|root| root.to_hex().to_string()),
+        }
+    }
+}
+
+// Result of each readiness check by name, sent with 503 if any failed
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, String>,
+}
+
+impl Readiness {
+    pub fn new(checks: Vec<(&'static str, Result<()>)>) -> Self {
+        let ready = checks.iter().all(|(_, result)| result.is_ok());
+        let checks = checks
+            .into_iter()
+            .map(|(name, result)| match result {
+                Ok(()) => (name, "ok".to_string()),
+                Err(e) => {
+                    tracing::warn!(check = name, error = %format!("{:#}", e), "Not ready");
+                    (name, format!("{:#}", e))
+                }
+            })
+            .collect();
+        Readiness { ready, checks }
+    }
+
+    pub fn into_response(self) -> warp::reply::Response {
+        let status = match self.ready {
+            true => StatusCode::OK,
+            false => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        warp::reply::with_status(warp::reply::json(&self), status).into_response()
+    }
+}
+
+// How long a blob store write probe's result is reused. /readyz is open to anyone
+// and polled by orchestrators, and against S3 every probe is a PUT, LIST and DELETE.
+const WRITE_PROBE_SECS: u64 = 5;
+
+// The last write probe's result, shared by every /readyz request
+#[derive(Default)]
+pub struct WriteProbe(Mutex<Option<(Instant, Result<(), String>)>>);
+
+impl WriteProbe {
+    // Run the probe unless one finished within the window. Requests arriving while
+    // it runs wait for its result rather than starting their own.
+    pub async fn check(&self, probe: impl Future<Output = Result<()>>) -> Result<()> {
+        let mut last = self.0.lock().await;
+        let result = match last.as_ref() {
+            Some((at, result)) if at.elapsed() < Duration::from_secs(WRITE_PROBE_SECS) => {
+                result.clone()
+            }
+            _ => {
+                let result = probe.await.map_err(|e| format!("{:#}", e));
+                *last = Some((Instant::now(), result.clone()));
+                result
+            }
+        };
+        result.map_err(|e| anyhow!(e))
+    }
+}
+
+// The process is up and serving requests
+pub async fn handle_healthz() -> Result<impl Reply, Infallible> {
+    Ok("ok")
+}
+
+pub async fn handle_readyz(
+    store: Arc<Store>,
+    write_probe: Arc<WriteProbe>,
+) -> Result<warp::reply::Response, Infallible> {
+    let checks = vec![
+        ("tree_store", store.tree.batch().map(drop)),
+        (
+            "blob_store",
+            write_probe.check(check_blobs_writable(&store)).await,
+        ),
+        ("tree", check_tree(&store).await),
+    ];
+    Ok(Readiness::new(checks).into_response())
+}
+
+pub async fn handle_version(store: Arc<Store>) -> Result<impl Reply, Infallible> {
+    let root = store.cache.read().await.root();
+    Ok(warp::reply::json(&VersionInfo::new(root)))
+}
+
+// Stage and discard an empty batch, the way an upload writes
+async fn check_blobs_writable(store: &Store) -> Result<()> {
+    let batch = new_batch_id();
+    let staged = store
+        .blobs
+        .stage(&batch, "readyz", Box::pin(tokio::io::empty()))
+        .await;
+    store.blobs.discard(&batch).await?;
+    staged
+}
+
+// The tree serving proofs is the one committed to the store
+async fn check_tree(store: &Store) -> Result<()> {
+    let cache = store.cache.read().await;
+    let stored_files = store.tree.num_of_files()?.unwrap_or(0);
+    ensure!(
+        cache.file_list().len() == stored_files,
+        "{} files loaded but {} in the tree store",
+        cache.file_list().len(),
+        stored_files
+    );
+    ensure!(
+        cache.root() == store.tree.node(0)?,
+        "Loaded root differs from the tree store"
+    );
+    ensure!(
+        cache.batch() == store.tree.batch()?.unwrap_or_default(),
+        "Loaded tree is from another upload than the tree store"
+    );
+    Ok(())
+}
+
+// Write and remove a file in dir
+pub async fn check_dir_writable(dir: &Path) -> Result<()> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let probe = dir.join(format!(
+        ".readyz-{}-{}",
+        std::process::id(),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+    tokio::fs::write(&probe, b"").await?;
+    tokio::fs::remove_file(&probe).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_readyz_checks_the_loaded_tree() {
+        use crate::fileserver::health::{handle_readyz, WriteProbe};
+        use crate::merkletree::tree::{leaf_hash, FastMerkleTree};
+        use crate::storage::memory::{MemoryBlobStore, MemoryTreeStore};
+        use crate::storage::{Store, TreeStore};
+        use std::sync::Arc;
+
+        let tree_store = Arc::new(MemoryTreeStore::default());
+        let store =
+            Arc::new(Store::new(Arc::new(MemoryBlobStore::default()), tree_store.clone()).unwrap());
+        let write_probe = Arc::new(WriteProbe::default());
+        let status = |store| {
+            let write_probe = Arc::clone(&write_probe);
+            async move { handle_readyz(store, write_probe).await.unwrap().status() }
+        };
+        assert_eq!(status(Arc::clone(&store)).await, 200);
+
+        //a tree committed behind the cache's back leaves it serving stale proofs
+        let tree = FastMerkleTree::from_leaves(vec![leaf_hash(b"a")]);
+        let nodes: Vec<_> = tree.0.iter().map(|node| node.value).collect();
+        tree_store
+            .commit("other", &nodes, &["a".to_string()], &tree.root())
+            .unwrap();
+        assert_eq!(status(Arc::clone(&store)).await, 503);
+    }
+
+    #[tokio::test]
+    async fn test_write_probe_result_is_reused() {
+        use crate::fileserver::health::WriteProbe;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let probe = WriteProbe::default();
+        let runs = AtomicUsize::new(0);
+        let failing = || async {
+            runs.fetch_add(1, Ordering::SeqCst);
+            anyhow::bail!("read-only")
+        };
+        //failures are reused too, so a broken store isn't hammered either
+        for _ in 0..3 {
+            assert!(probe.check(failing()).await.is_err());
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,3 +1,7 @@
-pub(crate) mod auth;
-pub(crate) mod fs;
-pub(crate) mod routes;
+pub mod acme;
+pub mod audit;
+pub mod auth;
+pub mod fs;
+pub mod health;
+pub mod routes;
+pub mod tls;
@@ -1,54 +1,353 @@
-use super::fs::{handle_file_download, handle_file_upload};
+use super::acme::{AcmeManager, AcmeSettings};
+use super::audit::{auditor, handle_export, handle_proof, handle_verify, AuditLog, ExportQuery};
+use super::auth::login::{
+    handle_login, handle_logout, handle_oidc_callback, handle_oidc_login, handle_whoami,
+    CallbackQuery, OIDC_STATE_COOKIE,
+};
+use super::auth::rbac::{
+    handle_list_grants, handle_list_users, handle_put_grant, handle_remove_grant, Action,
+};
+use super::auth::session::SESSION_COOKIE;
+use super::auth::tokens::{handle_create_token, handle_list_tokens, handle_revoke_token};
+use super::auth::{authorize, check, identity, recover_unauthorized, Auth, Identity};
+use super::fs::{
+    handle_file_download, handle_file_upload, handle_prove_absent, handle_prove_append,
+    handle_prove_nonmember, handle_solidity_proof, handle_witness, DownloadQuery, UploadMode,
+    UploadQuery, WitnessQuery,
+};
+use super::health::{handle_healthz, handle_readyz, handle_version, WriteProbe};
+use super::tls::{serve, ReloadableTls, TlsSettings};
 use crate::fileserver::fs::list_files_handler;
-use std::fs::{self};
+use crate::metrics::handle_metrics;
+use crate::shutdown;
+use crate::storage::{env_or, Store};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use warp::Filter;
-
-const UPLOAD_DIR: &str = "filestore/";
+use warp::{Filter, Rejection, Reply};
 
 pub async fn start_server() {
-    fs::create_dir_all(UPLOAD_DIR).unwrap();
-    let db = sled::open("merkle_tree_db").expect("Failed to open database");
+    let store = Store::from_env().await.expect("Failed to open storage");
 
     // put inside Arc for shared ownership
-    let db = Arc::new(db);
-    let db_filter = warp::any().map(move || Arc::clone(&db));
+    let store = Arc::new(store);
+    let auth = Arc::new(
+        Auth::from_env()
+            .await
+            .expect("Failed to open the auth database"),
+    );
+    let audit = Arc::new(AuditLog::from_env().expect("Failed to open the audit log"));
+
+    // Start the server with TLS, reloading certificates when they change
+    let mut settings = TlsSettings::from_env();
+    let acme = AcmeSettings::from_env().expect("Invalid ACME settings");
+    //ACME replaces whatever is there, so serve a self-signed placeholder until then
+    settings.generate_if_missing |= acme.is_some();
+    settings
+        .ensure_cert()
+        .expect("Failed to generate a TLS certificate");
+    let tls = ReloadableTls::new(settings.clone()).expect("Failed to load TLS certificates");
+    tracing::info!(
+        fingerprint = %settings.fingerprint().unwrap_or_default(),
+        "TLS certificate SHA-256 fingerprint"
+    );
+    tls.watch().expect("Failed to watch TLS certificates");
+    if let Some(acme) = acme {
+        AcmeManager::new(acme, settings, tls.challenges())
+            .spawn()
+            .expect("Failed to start ACME");
+    }
+    let addr: SocketAddr = env_or("MERKLE_LISTEN_ADDR", "127.0.0.1:8080")
+        .parse()
+        .expect("Invalid MERKLE_LISTEN_ADDR");
+    tracing::info!(%addr, "Running the https server");
+    let routes = routes(Arc::clone(&store), Arc::clone(&auth), Arc::clone(&audit));
+    serve(
+        routes,
+        addr,
+        tls,
+        shutdown::signal(),
+        shutdown::grace_period(),
+    )
+    .await
+    .expect("Server failed");
+
+    //an upload commit outlives its request, so wait for any still running and keep
+    //new ones out, then get everything onto disk before exiting
+    let _cache = store.cache.write().await;
+    let flushed = [
+        ("tree store", store.tree.flush()),
+        ("auth database", auth.flush()),
+        ("audit log", audit.flush()),
+    ];
+    for (name, result) in flushed {
+        if let Err(e) = result {
+            tracing::error!(error = %format!("{:#}", e), "Failed to flush the {}", name);
+        }
+    }
+    tracing::info!("Shut down");
+}
+
+// All server routes over one store, separate from start_server so benches can bind them elsewhere
+pub fn routes(
+    store: Arc<Store>,
+    auth: Arc<Auth>,
+    audit: Arc<AuditLog>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let store_filter = warp::any().map(move || Arc::clone(&store));
+    let auth_filter = warp::any().map({
+        let auth = Arc::clone(&auth);
+        move || Arc::clone(&auth)
+    });
+    let audit_filter = warp::any().map({
+        let audit = Arc::clone(&audit);
+        move || Arc::clone(&audit)
+    });
+    let current_identity = identity(Arc::clone(&auth));
+    //records events under the request's identity
+    let auditor = auditor(Arc::clone(&audit), Arc::clone(&auth));
+    //an upload replaces the files already there, so over a non-empty store it also takes
+    //delete. An add only replaces the file with its name, which is checked once the form
+    //is read, so whether the request may delete is passed on.
+    let may_replace =
+        warp::query::<UploadQuery>()
+            .and(store_filter.clone())
+            .and(current_identity.clone())
+            .and(auth_filter.clone())
+            .and_then(
+                |query: UploadQuery,
+                 store: Arc<Store>,
+                 identity: Option<Identity>,
+                 auth: Arc<Auth>| async move {
+                    let may_delete = auth.permits(identity.as_ref(), Action::Delete);
+                    let files = store.tree.num_of_files().ok().flatten().unwrap_or(0);
+                    if query.mode == UploadMode::Replace && files > 0 {
+                        check(&auth, identity.as_ref(), Action::Delete, None)?;
+                    }
+                    Ok::<_, Rejection>((query.mode, may_delete))
+                },
+            )
+            .untuple_one();
 
     let upload_page = warp::path("upload")
         .and(warp::get())
         .and(warp::fs::file("./static/upload.html"));
 
     let upload_route = warp::path("upload")
-        .and(db_filter.clone())
         .and(warp::post())
+        .and(may_replace)
+        .and(store_filter.clone())
+        .and(auditor.clone())
         .and(warp::multipart::form().max_length(100_000_000))
-        .and_then(handle_file_upload);
+        .and_then(handle_file_upload)
+        //boxed to keep the combined filter type within the compiler's recursion limit
+        .boxed();
 
     let download_route = warp::path("download")
-        .and(db_filter.clone())
+        .and(store_filter.clone())
+        .and(auditor.clone())
         .and(warp::path::param::<String>())
+        .and(warp::query::<DownloadQuery>())
         .and_then(handle_file_download);
 
+    let prove_absent_route = warp::path("prove-absent")
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and(auditor.clone())
+        .and(warp::path::param::<String>())
+        .and_then(handle_prove_absent);
+
+    let prove_nonmember_route = warp::path("prove-nonmember")
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and(auditor.clone())
+        .and(warp::path::param::<String>())
+        .and_then(handle_prove_nonmember);
+
+    let prove_append_route = warp::path("prove-append")
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and(auditor.clone())
+        .and(warp::path::param::<usize>())
+        .and_then(handle_prove_append);
+
+    let witness_route = warp::path("witness")
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and(auditor.clone())
+        .and(warp::path::param::<String>())
+        .and(warp::query::<WitnessQuery>())
+        .and_then(handle_witness);
+
+    let solidity_proof_route = warp::path("solidity-proof")
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and(auditor.clone())
+        .and(warp::path::param::<String>())
+        .and_then(handle_solidity_proof);
+
     let list_page = warp::path("list").and(warp::fs::file("./static/list.html"));
 
     let download_page = warp::path("downloads").and(warp::fs::file("./static/download.html"));
 
     let list_files = warp::path("files")
         .and(warp::get())
+        .and(store_filter.clone())
         .and_then(list_files_handler);
 
+    let login_page = warp::path("login")
+        .and(warp::get())
+        .and(warp::fs::file("./static/login.html"));
+
+    let login_route = warp::path("login")
+        .and(warp::post())
+        .and(auth_filter.clone())
+        .and(audit_filter.clone())
+        .and(warp::body::json())
+        .and_then(handle_login);
+
+    let logout_route = warp::path("logout")
+        .and(warp::post())
+        .and(auth_filter.clone())
+        .and(audit_filter.clone())
+        .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+        .and_then(handle_logout);
+
+    let whoami_route = warp::path("whoami")
+        .and(warp::get())
+        .and(current_identity)
+        .and_then(handle_whoami);
+
+    let oidc_login_route = warp::path!("auth" / "oidc" / "login")
+        .and(warp::get())
+        .and(auth_filter.clone())
+        .and_then(handle_oidc_login);
+
+    let oidc_callback_route = warp::path!("auth" / "oidc" / "callback")
+        .and(warp::get())
+        .and(auth_filter.clone())
+        .and(audit_filter.clone())
+        .and(warp::query::<CallbackQuery>())
+        .and(warp::cookie::optional::<String>(OIDC_STATE_COOKIE))
+        .and_then(handle_oidc_callback);
+
+    let admin_page = warp::path!("admin")
+        .and(warp::get())
+        .and(warp::fs::file("./static/admin.html"));
+
+    let list_users_route = warp::path!("admin" / "users")
+        .and(warp::get())
+        .and(auth_filter.clone())
+        .and_then(handle_list_users);
+
+    let list_grants_route = warp::path!("admin" / "grants")
+        .and(warp::get())
+        .and(auth_filter.clone())
+        .and_then(handle_list_grants);
+
+    let put_grant_route = warp::path!("admin" / "grants")
+        .and(warp::post())
+        .and(auth_filter.clone())
+        .and(auditor.clone())
+        .and(warp::body::json())
+        .and_then(handle_put_grant);
+
+    let remove_grant_route = warp::path!("admin" / "grants" / String)
+        .and(warp::delete())
+        .and(auth_filter.clone())
+        .and(auditor.clone())
+        .and_then(handle_remove_grant);
+
+    let list_tokens_route = warp::path!("admin" / "tokens")
+        .and(warp::get())
+        .and(auth_filter.clone())
+        .and_then(handle_list_tokens);
+
+    let create_token_route = warp::path!("admin" / "tokens")
+        .and(warp::post())
+        .and(auth_filter.clone())
+        .and(auditor.clone())
+        .and(warp::body::json())
+        .and_then(handle_create_token);
+
+    let revoke_token_route = warp::path!("admin" / "tokens" / String)
+        .and(warp::delete())
+        .and(auth_filter.clone())
+        .and(auditor)
+        .and_then(handle_revoke_token);
+
+    let audit_export_route = warp::path!("admin" / "audit")
+        .and(warp::get())
+        .and(audit_filter.clone())
+        .and(warp::query::<ExportQuery>())
+        .and_then(handle_export);
+
+    let audit_verify_route = warp::path!("admin" / "audit" / "verify")
+        .and(warp::get())
+        .and(audit_filter.clone())
+        .and_then(handle_verify);
+
+    let audit_proof_route = warp::path!("admin" / "audit" / u64)
+        .and(warp::get())
+        .and(audit_filter.clone())
+        .and_then(handle_proof);
+
+    let healthz_route = warp::path!("healthz")
+        .and(warp::get())
+        .and_then(handle_healthz);
+
+    let write_probe = Arc::new(WriteProbe::default());
+    let readyz_route = warp::path!("readyz")
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and(warp::any().map(move || Arc::clone(&write_probe)))
+        .and_then(handle_readyz);
+
+    let version_route = warp::path!("version")
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and_then(handle_version);
+
+    let metrics_route = warp::path!("metrics")
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and(auth_filter.clone())
+        .and(audit_filter)
+        .and_then(handle_metrics);
+
+    //every request is checked against the role and token scopes its path needs first
     let routes = list_page
         .or(list_files)
         .or(upload_page)
         .or(upload_route)
         .or(download_page)
-        .or(download_route);
-
-    // Start the server with TLS
-    warp::serve(routes)
-        .tls()
-        .cert_path("./demo_certs/cert.pem")
-        .key_path("./demo_certs/key.pem")
-        .run(([127, 0, 0, 1], 8080))
-        .await;
+        .or(download_route)
+        .or(prove_absent_route)
+        .or(prove_nonmember_route)
+        .or(prove_append_route)
+        .or(witness_route)
+        .or(solidity_proof_route)
+        .or(login_page)
+        .or(login_route)
+        .or(logout_route)
+        .or(whoami_route)
+        .or(oidc_login_route)
+        .or(oidc_callback_route)
+        .or(admin_page)
+        .or(list_users_route)
+        .or(list_grants_route)
+        .or(put_grant_route)
+        .or(remove_grant_route)
+        .or(list_tokens_route)
+        .or(create_token_route)
+        .or(revoke_token_route)
+        .or(audit_export_route)
+        .or(audit_verify_route)
+        .or(audit_proof_route)
+        .or(metrics_route)
+        .or(healthz_route)
+        .or(readyz_route)
+        .or(version_route);
+    authorize(auth, audit)
+        .and(routes)
+        .recover(recover_unauthorized)
 }
@@ -0,0 +1,491 @@
+use super::acme::Challenges;
+use crate::cert::{self, read_certs, read_key};
+use crate::logging::{request_id, request_span, REQUEST_ID_HEADER};
+use crate::metrics::metrics;
+use crate::storage::env_or;
+use anyhow::{Context, Result};
+use notify::{RecursiveMode, Watcher};
+use rustls::pki_types::CertificateDer;
+use rustls::server::{Acceptor, ClientHello, WebPkiClientVerifier};
+use rustls::{RootCertStore, ServerConfig};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio_rustls::LazyConfigAcceptor;
+use tracing::Instrument;
+use warp::hyper::server::conn::Http;
+use warp::hyper::service::{service_fn, Service};
+use warp::hyper::{Body, Request};
+use warp::{Filter, Rejection, Reply};
+
+// Where the server's certificate and key live, and the CA bundle client
+// certificates are checked against when mutual TLS is on
+#[derive(Clone, Debug)]
+pub struct TlsSettings {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub client_ca_path: Option<PathBuf>,
+    // Accept clients without a certificate too, leaving it to the routes
+    pub client_auth_optional: bool,
+    // No paths were configured, so a self-signed pair may be made at the defaults
+    pub generate_if_missing: bool,
+}
+
+// Who is on the other end of a connection. It is attached to every request on
+// the connection, so filters can read it with warp::ext::get::<Peer>().
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub remote_addr: SocketAddr,
+    // Leaf certificate the client authenticated with, under mutual TLS
+    pub client_cert: Option<CertificateDer<'static>>,
+}
+
+impl TlsSettings {
+    pub fn from_env() -> Self {
+        let configured = |key| std::env::var(key).is_ok_and(|path| !path.is_empty());
+        TlsSettings {
+            cert_path: env_or("MERKLE_TLS_CERT", cert::DEFAULT_CERT_PATH).into(),
+            key_path: env_or("MERKLE_TLS_KEY", cert::DEFAULT_KEY_PATH).into(),
+            client_ca_path: std::env::var("MERKLE_TLS_CLIENT_CA")
+                .ok()
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from),
+            client_auth_optional: env_or("MERKLE_TLS_CLIENT_AUTH", "required") == "optional",
+            generate_if_missing: !configured("MERKLE_TLS_CERT") && !configured("MERKLE_TLS_KEY"),
+        }
+    }
+
+    // Make a self-signed pair on first run when nothing was configured
+    pub fn ensure_cert(&self) -> Result<()> {
+        if !self.generate_if_missing {
+            return Ok(());
+        }
+        if cert::ensure_cert(&self.cert_path, &self.key_path)?.is_some() {
+            tracing::info!(
+                sans = %cert::default_sans().join(", "),
+                path = %self.cert_path.display(),
+                "Generated a self-signed certificate"
+            );
+        }
+        Ok(())
+    }
+
+    // Fingerprint of the server certificate, for clients to pin
+    pub fn fingerprint(&self) -> Result<String> {
+        Ok(cert::fingerprint(&read_certs(&self.cert_path)?[0]))
+    }
+
+    fn files(&self) -> Vec<&Path> {
+        let mut files = vec![self.cert_path.as_path(), self.key_path.as_path()];
+        files.extend(self.client_ca_path.as_deref());
+        files
+    }
+
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_ca_path {
+            Some(ca_path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(ca_path)? {
+                    roots.add(cert)?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider);
+                let verifier = match self.client_auth_optional {
+                    true => verifier.allow_unauthenticated().build()?,
+                    false => verifier.build()?,
+                };
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(read_certs(&self.cert_path)?, read_key(&self.key_path)?)
+            .context("Certificate and key don't match")?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+// The server's TLS config, replaced whole on reload. New connections get the
+// new certificate; open ones keep the one they were made with.
+pub struct ReloadableTls {
+    settings: TlsSettings,
+    current: RwLock<Arc<ServerConfig>>,
+    // Validation certificates for ACME tls-alpn-01 challenges in flight
+    challenges: Arc<Challenges>,
+}
+
+impl ReloadableTls {
+    pub fn new(settings: TlsSettings) -> Result<Arc<Self>> {
+        let config = settings.server_config()?;
+        Ok(Arc::new(ReloadableTls {
+            settings,
+            current: RwLock::new(config),
+            challenges: Arc::default(),
+        }))
+    }
+
+    // A broken file on disk leaves the old config serving
+    pub fn reload(&self) -> Result<()> {
+        let config = self.settings.server_config()?;
+        *self.current.write().unwrap() = config;
+        Ok(())
+    }
+
+    fn reload_logged(&self, reason: &str) {
+        match self.reload() {
+            Ok(()) => tracing::info!(
+                reason,
+                fingerprint = %self.settings.fingerprint().unwrap_or_default(),
+                "Reloaded TLS certificates"
+            ),
+            Err(e) => tracing::error!(
+                reason,
+                error = format!("{:#}", e),
+                "Keeping old TLS certificates, reload failed"
+            ),
+        }
+    }
+
+    pub fn challenges(&self) -> Arc<Challenges> {
+        Arc::clone(&self.challenges)
+    }
+
+    fn config_for(&self, hello: &ClientHello) -> Arc<ServerConfig> {
+        match self.challenges.tls_alpn_config(hello) {
+            Some(config) => config,
+            None => self.current.read().unwrap().clone(),
+        }
+    }
+
+    // Reload on SIGHUP and whenever one of the files is written. The directories
+    // are watched rather than the files, since tools often replace by renaming.
+    pub fn watch(self: &Arc<Self>) -> Result<()> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hangup = signal(SignalKind::hangup())?;
+            let tls = Arc::clone(self);
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    tls.reload_logged("SIGHUP");
+                }
+            });
+        }
+
+        let files = self
+            .settings
+            .files()
+            .into_iter()
+            .map(std::path::absolute)
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                //reading the files, as a reload does, must not trigger another one
+                match event {
+                    Ok(event) if !event.kind.is_access() => {
+                        //only fails once the reload task is gone
+                        tx.send(event.paths).ok();
+                    }
+                    Err(e) => tracing::warn!(error = %e, "Watching TLS certificates failed"),
+                    _ => {}
+                }
+            })?;
+        for file in &files {
+            let dir = file.parent().unwrap_or(Path::new("/"));
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
+        let tls = Arc::clone(self);
+        tokio::spawn(async move {
+            let _watcher = watcher;
+            while let Some(paths) = rx.recv().await {
+                if !paths.iter().any(|path| files.contains(path)) {
+                    continue;
+                }
+                //cert and key are usually written one after the other, so let both land
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                while rx.try_recv().is_ok() {}
+                tls.reload_logged("file changed");
+            }
+        });
+        Ok(())
+    }
+}
+
+// Serve the routes over TLS, taking the config afresh for each connection. Each
+// request runs in its own span with a request id, which is echoed back in X-Request-Id.
+// Once shutdown resolves no new connections are taken, and open ones finish the
+// requests they are on and close; whatever is still running after grace is dropped.
+pub async fn serve<F, R>(
+    routes: F,
+    addr: SocketAddr,
+    tls: Arc<ReloadableTls>,
+    shutdown: impl Future<Output = ()>,
+    grace: Duration,
+) -> Result<()>
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let listener = TcpListener::bind(addr).await?;
+    let service = warp::service(routes);
+    let (draining, drain) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        let (tcp, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(connection) => connection,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Failed to accept connection");
+                    continue;
+                }
+            },
+            () = &mut shutdown => break,
+        };
+        //forget connections that have closed
+        while connections.try_join_next().is_some() {}
+        let tls = Arc::clone(&tls);
+        let service = service.clone();
+        let mut drain = drain.clone();
+        connections.spawn(async move {
+            //the config is picked after the client hello, so ACME validation can get its own
+            let start = match LazyConfigAcceptor::new(Acceptor::default(), tcp).await {
+                Ok(start) => start,
+                Err(e) => {
+                    tracing::debug!(%remote_addr, error = %e, "TLS handshake failed");
+                    return;
+                }
+            };
+            let config = tls.config_for(&start.client_hello());
+            //clients that fail the handshake, such as without a valid client certificate, are dropped here
+            let stream = match start.into_stream(config).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::debug!(%remote_addr, error = %e, "TLS handshake failed");
+                    return;
+                }
+            };
+            let peer = Peer {
+                remote_addr,
+                client_cert: stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|certs| certs.first())
+                    .map(|cert| cert.clone().into_owned()),
+            };
+            let per_connection = service_fn(move |mut request: Request<Body>| {
+                let id = request_id(request.headers());
+                let span = request_span(&id, request.method().as_str(), request.uri().path());
+                request.headers_mut().insert(REQUEST_ID_HEADER, id.clone());
+                request.extensions_mut().insert(peer.clone());
+                let mut service = service.clone();
+                async move {
+                    let started = Instant::now();
+                    let mut response = service.call(request).await?;
+                    metrics().respond(response.status().as_u16());
+                    tracing::info!(
+                        status = response.status().as_u16(),
+                        elapsed_ms = started.elapsed().as_millis() as u64,
+                        "Request finished"
+                    );
+                    response.headers_mut().insert(REQUEST_ID_HEADER, id);
+                    Ok::<_, Infallible>(response)
+                }
+                .instrument(span)
+            });
+            let connection = Http::new().serve_connection(stream, per_connection);
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                //only ever changes to true
+                Ok(()) = drain.changed() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
+                //mostly clients going away mid-request
+                tracing::debug!(%remote_addr, error = %e, "Connection ended with an error");
+            }
+        });
+    }
+
+    drop(listener);
+    draining.send_replace(true);
+    tracing::info!(
+        connections = connections.len(),
+        grace_secs = grace.as_secs(),
+        "Stopped accepting connections, waiting for open ones to finish"
+    );
+    let drained = tokio::time::timeout(grace, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        tracing::warn!(
+            connections = connections.len(),
+            "Grace period over, dropping connections with requests still running"
+        );
+        connections.shutdown().await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_reload_keeps_old_config_on_bad_files() {
+        use crate::fileserver::tls::{ReloadableTls, TlsSettings};
+        use std::sync::Arc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cert = dir.path().join("cert.pem");
+        let key = dir.path().join("key.pem");
+        let settings = TlsSettings {
+            cert_path: cert.clone(),
+            key_path: key.clone(),
+            client_ca_path: None,
+            client_auth_optional: false,
+            generate_if_missing: true,
+        };
+        settings.ensure_cert().unwrap();
+        let key_pem = std::fs::read(&key).unwrap();
+        let tls = ReloadableTls::new(settings.clone()).unwrap();
+        let before = tls.current.read().unwrap().clone();
+
+        std::fs::write(&key, "not a key").unwrap();
+        assert!(tls.reload().is_err());
+        assert!(Arc::ptr_eq(&before, &tls.current.read().unwrap()));
+
+        std::fs::write(&key, key_pem).unwrap();
+        tls.reload().unwrap();
+        assert!(!Arc::ptr_eq(&before, &tls.current.read().unwrap()));
+
+        //a client CA bundle has to hold certificates
+        let mtls = TlsSettings {
+            client_ca_path: Some(cert),
+            ..settings.clone()
+        };
+        assert!(mtls.server_config().is_ok());
+        let mtls = TlsSettings {
+            client_ca_path: Some(key),
+            ..settings
+        };
+        assert!(mtls.server_config().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_signs_in_uploads() {
+        use crate::cert::{generate_self_signed, write_cert};
+        use crate::fileserver::audit::AuditLog;
+        use crate::fileserver::auth::Auth;
+        use crate::fileserver::routes::routes;
+        use crate::fileserver::tls::{serve, ReloadableTls, TlsSettings};
+        use crate::storage::memory::{MemoryBlobStore, MemoryTreeStore};
+        use crate::storage::Store;
+        use rcgen::{
+            BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer,
+            KeyPair,
+        };
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let dir = tempfile::tempdir().unwrap();
+        let server = generate_self_signed(&["localhost".to_string()], 1).unwrap();
+        write_cert(
+            &server,
+            &dir.path().join("cert.pem"),
+            &dir.path().join("key.pem"),
+        )
+        .unwrap();
+        let mut ca = CertificateParams::new(Vec::new()).unwrap();
+        ca.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca.distinguished_name.push(DnType::CommonName, "test ca");
+        let ca_key = KeyPair::generate().unwrap();
+        std::fs::write(
+            dir.path().join("ca.pem"),
+            ca.self_signed(&ca_key).unwrap().pem(),
+        )
+        .unwrap();
+        let issuer = Issuer::new(ca, ca_key);
+        let mut client = CertificateParams::new(Vec::new()).unwrap();
+        client
+            .distinguished_name
+            .push(DnType::CommonName, "ci-runner");
+        client.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client_key = KeyPair::generate().unwrap();
+        let client = client.signed_by(&client_key, &issuer).unwrap();
+        let identity = format!("{}{}", client.pem(), client_key.serialize_pem());
+
+        let mut auth = Auth::disabled().unwrap();
+        auth.required = true;
+        auth.users
+            .add_local("ci", "secret", vec!["uploader".to_string()])
+            .unwrap();
+        auth.client_certs
+            .insert("ci-runner".to_string(), "ci".to_string());
+        let store = Arc::new(
+            Store::new(
+                Arc::new(MemoryBlobStore::default()),
+                Arc::new(MemoryTreeStore::default()),
+            )
+            .unwrap(),
+        );
+        let routes = routes(
+            Arc::clone(&store),
+            Arc::new(auth),
+            Arc::new(AuditLog::temporary().unwrap()),
+        );
+        let tls = ReloadableTls::new(TlsSettings {
+            cert_path: dir.path().join("cert.pem"),
+            key_path: dir.path().join("key.pem"),
+            client_ca_path: Some(dir.path().join("ca.pem")),
+            client_auth_optional: true,
+            generate_if_missing: false,
+        })
+        .unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        tokio::spawn(serve(
+            routes,
+            addr,
+            tls,
+            std::future::pending(),
+            Duration::from_secs(1),
+        ));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let upload = |identity: Option<&str>| {
+            let mut client = reqwest::Client::builder().danger_accept_invalid_certs(true);
+            if let Some(identity) = identity {
+                client = client.identity(reqwest::Identity::from_pem(identity.as_bytes()).unwrap());
+            }
+            let form = reqwest::multipart::Form::new().part(
+                "file",
+                reqwest::multipart::Part::text("one").file_name("a.txt"),
+            );
+            let request = client
+                .build()
+                .unwrap()
+                .post(format!("https://localhost:{}/upload", addr.port()))
+                .multipart(form);
+            async move { request.send().await.unwrap().status().as_u16() }
+        };
+        assert_eq!(upload(None).await, 401);
+        assert_eq!(upload(Some(&identity)).await, 200);
+        assert_eq!(store.blobs.list().await.unwrap(), vec!["a.txt"]);
+    }
+}
@@ -0,0 +1,11 @@
+// the warp route futures nest deeper than the default limit once rust-s3 fails on error responses
+#![recursion_limit = "256"]
+
+pub mod cert;
+pub mod client;
+pub mod fileserver;
+pub mod logging;
+pub mod merkletree;
+pub mod metrics;
+pub mod shutdown;
+pub mod storage;
@@ -0,0 +1,79 @@
+use crate::storage::env_or;
+use ring::rand::{SecureRandom, SystemRandom};
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+use warp::http::{HeaderMap, HeaderValue};
+
+// Header carrying the request id, taken from the client when it sends a usable one
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Log to stderr for the server and client. MERKLE_LOG takes tracing filter
+// directives (default info, e.g. `merkle_fileserver=debug,warp=info`) and
+// MERKLE_LOG_FORMAT is text (one line per event, default), pretty or json.
+pub fn init() {
+    let filter = EnvFilter::try_new(env_or("MERKLE_LOG", "info")).unwrap_or_else(|e| {
+        eprintln!("Invalid MERKLE_LOG, using info: {}", e);
+        EnvFilter::new("info")
+    });
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    let result = match env_or("MERKLE_LOG_FORMAT", "text").as_str() {
+        "json" => builder.json().with_current_span(true).try_init(),
+        "pretty" => builder.pretty().try_init(),
+        "text" => builder.try_init(),
+        other => {
+            eprintln!("Unknown MERKLE_LOG_FORMAT {}, using text", other);
+            builder.try_init()
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Logging already set up: {}", e);
+    }
+}
+
+// The client's request id if it is short printable ASCII, otherwise a new one
+pub fn request_id(headers: &HeaderMap) -> HeaderValue {
+    let sent = headers
+        .get(REQUEST_ID_HEADER)
+        .filter(|id| !id.is_empty() && id.len() <= 64 && id.to_str().is_ok());
+    if let Some(id) = sent {
+        return id.clone();
+    }
+    let mut id = [0u8; 8];
+    if SystemRandom::new().fill(&mut id).is_err() {
+        tracing::warn!("System random source failed, request id is zeros");
+    }
+    HeaderValue::from_str(&hex::encode(id)).expect("hex is a valid header value")
+}
+
+// Span covering one request; handler spans and events nest inside it
+pub fn request_span(id: &HeaderValue, method: &str, path: &str) -> Span {
+    tracing::info_span!(
+        "request",
+        id = id.to_str().unwrap_or_default(),
+        method,
+        path
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_request_id_keeps_usable_client_ids() {
+        use crate::logging::{request_id, REQUEST_ID_HEADER};
+        use warp::http::{HeaderMap, HeaderValue};
+
+        let mut headers = HeaderMap::new();
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("abc-123"));
+        assert_eq!(request_id(&headers), "abc-123");
+
+        headers.insert(
+            REQUEST_ID_HEADER,
+            HeaderValue::from_str(&"x".repeat(65)).unwrap(),
+        );
+        let generated = request_id(&headers);
+        assert_eq!(generated.len(), 16);
+        assert_ne!(request_id(&HeaderMap::new()), generated);
+    }
+}
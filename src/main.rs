@@ -1,18 +1,43 @@
+use merkle_fileserver::{cert, client, fileserver, logging, merkletree};
 use std::env;
 use tokio::runtime::Runtime;
-mod cert;
-mod client;
-mod fileserver;
-mod merkletree;
 
 async fn run_server() {
     // run server
-    let _ = fileserver::routes::start_server().await;
+    fileserver::routes::start_server().await;
 }
 
 async fn run_client() {
     //run client
-    let _ = client::routes::start_local_server().await;
+    client::routes::start_local_server().await;
+}
+
+async fn run_upload(args: &[String]) {
+    //upload files from the cli
+    if let Err(e) = client::upload::upload_files(args).await {
+        eprintln!("{}", e);
+    }
+}
+
+async fn run_prove_absent(args: &[String]) {
+    //check a file is not on the server
+    if let Err(e) = client::absent::prove_absent(args).await {
+        eprintln!("{}", e);
+    }
+}
+
+async fn run_prove_nonmember(args: &[String]) {
+    //check a file is not on the server against the sparse root
+    if let Err(e) = client::nonmember::prove_nonmember(args).await {
+        eprintln!("{}", e);
+    }
+}
+
+async fn run_verify_append(args: &[String]) {
+    //check the server only appended since a known root
+    if let Err(e) = client::append::verify_append(args).await {
+        eprintln!("{:#}", e);
+    }
 }
 
 fn main() {
@@ -20,22 +45,64 @@ fn main() {
     let rt = Runtime::new().unwrap();
 
     if args.len() < 2 {
-        eprintln!("Usage: cargo run [client|server]");
+        eprintln!("Usage: cargo run [client|server|upload|prove-absent|prove-nonmember|verify-append|cert|zk|user|token|audit]");
         return;
     }
 
     match args[1].as_str() {
         "server" => {
-            println!("Running the https server on port 8080...");
+            logging::init();
             rt.block_on(run_server());
         }
         "client" => {
-            println!("Running the client on port 8081...");
+            logging::init();
             rt.block_on(run_client());
         }
+        "upload" => {
+            rt.block_on(run_upload(&args[2..]));
+        }
+        "prove-absent" => {
+            rt.block_on(run_prove_absent(&args[2..]));
+        }
+        "prove-nonmember" => {
+            rt.block_on(run_prove_nonmember(&args[2..]));
+        }
+        "verify-append" => {
+            rt.block_on(run_verify_append(&args[2..]));
+        }
+        "cert" => {
+            //generate or inspect the server certificate
+            if let Err(e) = cert::run(&args[2..]) {
+                eprintln!("{}", e);
+            }
+        }
+        "zk" => {
+            //make the key pair for append proofs
+            if let Err(e) = merkletree::zk::run(&args[2..]) {
+                eprintln!("{:#}", e);
+            }
+        }
+        "user" => {
+            //manage local users while the server is stopped
+            if let Err(e) = fileserver::auth::login::run(&args[2..]) {
+                eprintln!("{}", e);
+            }
+        }
+        "token" => {
+            //manage API tokens while the server is stopped
+            if let Err(e) = fileserver::auth::tokens::run(&args[2..]) {
+                eprintln!("{}", e);
+            }
+        }
+        "audit" => {
+            //export or check the audit log while the server is stopped
+            if let Err(e) = fileserver::audit::run(&args[2..]) {
+                eprintln!("{:#}", e);
+            }
+        }
         _ => {
             eprintln!("Unknown argument: {}", args[1]);
-            eprintln!("Usage: cargo run [client|server]");
+            eprintln!("Usage: cargo run [client|server|upload|prove-absent|prove-nonmember|verify-append|cert|zk|user|token|audit]");
         }
     }
 }
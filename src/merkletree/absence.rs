@@ -0,0 +1,71 @@
+use super::tree::{leaf_hash, FastMerkleTree};
+use blake3::Hash;
+use serde::{Deserialize, Serialize};
+
+// A name next to where the missing one would sort, with its proof in the names tree
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NeighbourProof {
+    pub name: String,
+    pub index: usize,
+    pub proof: Vec<(Vec<u8>, bool)>,
+}
+
+// Leaves are sorted by file name, so a name is absent when the names on either
+// side of where it would go are adjacent. At the ends of the list only one
+// neighbour exists and it has to be the first or last name.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AbsenceProof {
+    pub name: String,
+    pub leaf_count: usize,
+    // Root of the names tree, which with leaf_count makes up the names root
+    pub names_tree_root: String,
+    pub left: Option<NeighbourProof>,
+    pub right: Option<NeighbourProof>,
+}
+
+// The content root says nothing about names, so the sorted names get a tree of
+// their own, in the same order as the leaves. Its names root is a second
+// commitment next to the content root, not part of it: an absence proof only
+// means something to a client that got the names root from a source it trusts,
+// the way it got the content root, and the content root alone can't show absence.
+pub fn name_leaf(name: &str) -> Hash {
+    leaf_hash(name.as_bytes())
+}
+
+pub fn names_tree(file_list: &[String]) -> FastMerkleTree {
+    FastMerkleTree::from_leaves(file_list.iter().map(|name| name_leaf(name)).collect())
+}
+
+// Commits to the names tree and how many names are in it, so a proof can't pass
+// off a name in the middle as the last
+pub fn names_root(names_tree_root: &Hash, leaf_count: usize) -> Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&(leaf_count as u64).to_le_bytes());
+    hasher.update(names_tree_root.as_bytes());
+    hasher.finalize()
+}
+
+impl AbsenceProof {
+    // None if the name is in the list or the list is empty
+    pub fn build(names: &FastMerkleTree, file_list: &[String], name: &str) -> Option<Self> {
+        let leaf_count = file_list.len();
+        if leaf_count == 0 {
+            return None;
+        }
+        let position = file_list
+            .binary_search_by(|filename| filename.as_str().cmp(name))
+            .err()?;
+        let neighbour = |index: usize| NeighbourProof {
+            name: file_list[index].clone(),
+            index,
+            proof: names.get_merkle_proof(index),
+        };
+        Some(AbsenceProof {
+            name: name.to_string(),
+            leaf_count,
+            names_tree_root: names.root().to_hex().to_string(),
+            left: position.checked_sub(1).map(neighbour),
+            right: (position < leaf_count).then(|| neighbour(position)),
+        })
+    }
+}
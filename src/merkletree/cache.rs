@@ -0,0 +1,307 @@
+use super::absence::{self, AbsenceProof};
+use super::sparse::{SparseMerkleTree, SparseProof};
+use super::tree::{FastMerkleTree, MerkleProof, ZERO};
+use crate::metrics::metrics;
+use crate::storage::TreeStore;
+use anyhow::{anyhow, ensure, Result};
+use blake3::Hash;
+use std::collections::HashMap;
+
+// The committed tree held in memory so proofs don't touch the tree store.
+// Every change is written through to the store before it is applied here.
+pub struct TreeCache {
+    batch: String,
+    tree: FastMerkleTree,
+    files: HashMap<String, usize>,
+    file_list: Vec<String>,
+    // Uploads sort leaves by name but appends go on the end, which loses the order
+    sorted: bool,
+    // The same leaves keyed by file name, whose root is committed next to the tree's
+    sparse: SparseMerkleTree,
+    // File names in file order, for absence proofs
+    names: FastMerkleTree,
+}
+
+impl TreeCache {
+    pub fn new(
+        batch: &str,
+        tree: FastMerkleTree,
+        file_list: Vec<String>,
+        sparse: SparseMerkleTree,
+    ) -> Self {
+        let files = file_list
+            .iter()
+            .enumerate()
+            .map(|(i, filename)| (filename.clone(), i))
+            .collect();
+        TreeCache {
+            batch: batch.to_string(),
+            tree,
+            files,
+            names: absence::names_tree(&file_list),
+            sorted: file_list.is_sorted(),
+            file_list,
+            sparse,
+        }
+    }
+
+    // Read the whole committed tree out of the store
+    pub fn load(store: &dyn TreeStore) -> Result<Self> {
+        let tree = FastMerkleTree(
+            store
+                .nodes()?
+                .into_iter()
+                .map(|value| super::tree::FastMerkleNode { value })
+                .collect(),
+        );
+        let file_list = store.file_list()?;
+        let batch = store.batch()?.unwrap_or_default();
+        let sparse = SparseMerkleTree::from_files(
+            file_list.iter().cloned().zip(tree.leaves(file_list.len())),
+        );
+        if let Some(sparse_root) = store.sparse_root()? {
+            ensure!(
+                sparse_root == sparse.root(),
+                "Stored sparse root {} doesn't match the tree's files",
+                sparse_root
+            );
+        }
+        Ok(Self::new(&batch, tree, file_list, sparse))
+    }
+
+    pub fn root(&self) -> Option<Hash> {
+        self.tree.0.first().map(|node| node.value)
+    }
+
+    pub fn sparse_root(&self) -> Hash {
+        self.sparse.root()
+    }
+
+    pub fn names_root(&self) -> Hash {
+        absence::names_root(&self.names.root(), self.file_list.len())
+    }
+
+    // Upload batch the tree came from
+    pub fn batch(&self) -> &str {
+        &self.batch
+    }
+
+    pub fn file_list(&self) -> &[String] {
+        &self.file_list
+    }
+
+    pub fn file_index(&self, filename: &str) -> Option<usize> {
+        self.files.get(filename).copied()
+    }
+
+    pub fn leaves(&self) -> Vec<Hash> {
+        self.tree.leaves(self.file_list.len())
+    }
+
+    pub fn get_merkle_proof(&self, filename: &str) -> Option<Vec<(Vec<u8>, bool)>> {
+        let file_index = *self.files.get(filename)?;
+        let _timer = metrics().memory_proof_seconds.start_timer();
+        Some(self.tree.get_merkle_proof(file_index))
+    }
+
+    // Root and proof in root format 1, for clients holding a root from before the
+    // padding changed
+    pub fn legacy_merkle_proof(&self, filename: &str) -> Option<(Hash, MerkleProof)> {
+        let file_index = *self.files.get(filename)?;
+        FastMerkleTree::legacy_merkle_proof(&self.leaves(), file_index)
+    }
+
+    // Whether the leaves are still in name order, which absence proofs need
+    pub fn sorted(&self) -> bool {
+        self.sorted
+    }
+
+    // None if the file is present, the tree is empty or its leaves are out of order
+    pub fn prove_absent(&self, filename: &str) -> Option<AbsenceProof> {
+        if !self.sorted {
+            return None;
+        }
+        AbsenceProof::build(&self.names, &self.file_list, filename)
+    }
+
+    // Proof of the file's slot in the sparse tree, None if the file is present
+    pub fn prove_nonmember(&self, filename: &str) -> Option<SparseProof> {
+        if self.files.contains_key(filename) {
+            return None;
+        }
+        Some(self.sparse.prove(filename))
+    }
+
+    // Proof of the file's slot and leaf in the sparse tree, None if it isn't present
+    pub fn prove_member(&self, filename: &str) -> Option<SparseProof> {
+        self.files.get(filename)?;
+        Some(self.sparse.prove(filename))
+    }
+
+    // Replace the leaf for an existing file as part of upload batch, O(log n) nodes
+    // rehashed and written
+    pub fn update_leaf(
+        &mut self,
+        store: &dyn TreeStore,
+        batch: &str,
+        filename: &str,
+        leaf: Hash,
+    ) -> Result<()> {
+        let file_index = *self
+            .files
+            .get(filename)
+            .ok_or_else(|| anyhow!("No such file: {}", filename))?;
+        let old_leaf = self.tree.leaf(file_index);
+        let changed = self.tree.set_leaf(file_index, leaf);
+        self.sparse.insert(filename, leaf);
+        let sparse_root = self.sparse.root();
+        if let Err(e) = store.update(batch, &self.changed_nodes(&changed), &[], &sparse_root) {
+            self.tree.set_leaf(file_index, old_leaf);
+            self.sparse.insert(filename, old_leaf);
+            return Err(e);
+        }
+        self.batch = batch.to_string();
+        Ok(())
+    }
+
+    // Add a leaf for a new file after the existing ones, or replace the leaf of a file
+    // already there. The new leaf goes in the first empty slot, so only its path is
+    // rehashed; a full tree doubles first and is written out whole.
+    pub fn append_leaf(
+        &mut self,
+        store: &dyn TreeStore,
+        batch: &str,
+        filename: &str,
+        leaf: Hash,
+    ) -> Result<()> {
+        if self.files.contains_key(filename) {
+            return self.update_leaf(store, batch, filename, leaf);
+        }
+        let leaf_count = self.file_list.len();
+        let filename = filename.to_string();
+        self.sparse.insert(&filename, leaf);
+        let sparse_root = self.sparse.root();
+
+        if leaf_count < self.tree.capacity() {
+            let changed = self.tree.set_leaf(leaf_count, leaf);
+            if let Err(e) = store.update(
+                batch,
+                &self.changed_nodes(&changed),
+                std::slice::from_ref(&filename),
+                &sparse_root,
+            ) {
+                self.tree.set_leaf(leaf_count, Hash::from_bytes(ZERO));
+                self.sparse.remove(&filename);
+                return Err(e);
+            }
+        } else {
+            //built aside so a failed commit leaves the cache as it was
+            let mut tree = match leaf_count {
+                0 => FastMerkleTree::from_leaves(Vec::new()),
+                _ => FastMerkleTree(self.tree.0.clone()),
+            };
+            if leaf_count > 0 {
+                tree.grow();
+            }
+            tree.set_leaf(leaf_count, leaf);
+            let mut file_list = self.file_list.clone();
+            file_list.push(filename.clone());
+            if let Err(e) = tree.commit_merkle_tree(store, batch, file_list, &sparse_root) {
+                self.sparse.remove(&filename);
+                return Err(e);
+            }
+            self.tree = tree;
+        }
+        self.batch = batch.to_string();
+        if leaf_count >= self.names.capacity() {
+            self.names.grow();
+        }
+        self.names
+            .set_leaf(leaf_count, absence::name_leaf(&filename));
+        self.files.insert(filename.clone(), leaf_count);
+        self.sorted &= self.file_list.last().is_none_or(|last| *last < filename);
+        self.file_list.push(filename);
+        Ok(())
+    }
+
+    fn changed_nodes(&self, changed: &[usize]) -> Vec<(usize, Hash)> {
+        changed
+            .iter()
+            .map(|index| (*index, self.tree.0[*index].value))
+            .collect()
+    }
+}
+#[cfg(test)]
+mod tests {
+    fn leaf(i: usize) -> blake3::Hash {
+        crate::merkletree::tree::leaf_hash(&i.to_le_bytes())
+    }
+
+    #[test]
+    fn test_update_and_append_match_rebuild() {
+        use crate::merkletree::cache::TreeCache;
+        use crate::merkletree::sparse::SparseMerkleTree;
+        use crate::merkletree::tree::FastMerkleTree;
+        use crate::storage::memory::MemoryTreeStore;
+        use crate::storage::TreeStore;
+
+        let store = MemoryTreeStore::default();
+        let mut leaves: Vec<blake3::Hash> = (0..3).map(leaf).collect();
+        let mut file_list: Vec<String> = (0..3).map(|i| format!("f{}", i)).collect();
+        let sparse = |file_list: &[String], leaves: &[blake3::Hash]| {
+            SparseMerkleTree::from_files(file_list.iter().cloned().zip(leaves.to_vec())).root()
+        };
+        let tree = FastMerkleTree::from_leaves(leaves.clone());
+        tree.commit_merkle_tree(
+            &store,
+            "test",
+            file_list.clone(),
+            &sparse(&file_list, &leaves),
+        )
+        .unwrap();
+        let mut cache = TreeCache::load(&store).unwrap();
+
+        //appends fill the empty slots, then double the tree when it is full
+        for i in 3..10 {
+            cache
+                .append_leaf(&store, "add", &format!("f{}", i), leaf(i))
+                .unwrap();
+            leaves.push(leaf(i));
+            file_list.push(format!("f{}", i));
+            assert_eq!(
+                cache.root(),
+                Some(FastMerkleTree::from_leaves(leaves.clone()).root())
+            );
+        }
+        for i in [0, 4, 9] {
+            cache
+                .update_leaf(&store, "update", &format!("f{}", i), leaf(100 + i))
+                .unwrap();
+            leaves[i] = leaf(100 + i);
+            assert_eq!(
+                cache.root(),
+                Some(FastMerkleTree::from_leaves(leaves.clone()).root())
+            );
+        }
+
+        //store kept in sync with the cache, sparse root included
+        assert_eq!(cache.sparse_root(), sparse(&file_list, &leaves));
+        assert_eq!(store.sparse_root().unwrap(), Some(cache.sparse_root()));
+        assert!(cache.prove_nonmember("f3").is_none());
+        assert!(cache
+            .prove_nonmember("f10")
+            .unwrap()
+            .verify_non_inclusion(&cache.sparse_root(), "f10"));
+        let reloaded = TreeCache::load(&store).unwrap();
+        assert_eq!(reloaded.root(), cache.root());
+        assert_eq!(reloaded.file_list(), file_list.as_slice());
+        assert_eq!(reloaded.batch(), "update");
+        assert_eq!(reloaded.names_root(), cache.names_root());
+        for filename in &file_list {
+            assert_eq!(
+                cache.get_merkle_proof(filename),
+                FastMerkleTree::get_merkle_proof_from_db(&store, filename.clone())
+            );
+        }
+    }
+}
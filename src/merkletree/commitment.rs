@@ -0,0 +1,81 @@
+use super::tree::{leaf_position, verify_leaf_proof, FastMerkleTree};
+use anyhow::{ensure, Result};
+use blake3::Hash;
+
+// A short commitment to an ordered list of leaf hashes, with a proof per leaf that
+// it sits at its index. Every tree backend implements it so callers and
+// benchmarks can swap one for another.
+pub trait VectorCommitment {
+    type Proof;
+    // What the verifier needs besides the commitment, such as public parameters
+    type Params;
+
+    fn commitment(&self) -> Result<Vec<u8>>;
+    fn prove(&self, index: usize) -> Result<Self::Proof>;
+    fn verify(
+        params: &Self::Params,
+        commitment: &[u8],
+        index: usize,
+        leaf: &Hash,
+        proof: &Self::Proof,
+    ) -> bool;
+}
+
+// The heap-array tree. Its root doesn't commit to the leaf count, so the verifier
+// is given the count to check the proof's position against.
+impl VectorCommitment for FastMerkleTree {
+    type Proof = Vec<(Vec<u8>, bool)>;
+    type Params = usize;
+
+    fn commitment(&self) -> Result<Vec<u8>> {
+        Ok(self.root().as_bytes().to_vec())
+    }
+
+    // Empty slots past the last leaf can be opened too, as ZERO
+    fn prove(&self, index: usize) -> Result<Self::Proof> {
+        ensure!(index < self.capacity(), "Leaf {} out of range", index);
+        Ok(self.get_merkle_proof(index))
+    }
+
+    fn verify(
+        leaf_count: &usize,
+        commitment: &[u8],
+        index: usize,
+        leaf: &Hash,
+        proof: &Self::Proof,
+    ) -> bool {
+        index < *leaf_count
+            && leaf_position(proof) == Some(index)
+            && verify_leaf_proof(leaf, proof, commitment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_tree_openings() {
+        use crate::merkletree::commitment::VectorCommitment;
+        use crate::merkletree::tree::{leaf_hash, FastMerkleTree};
+
+        let leaves: Vec<blake3::Hash> = (0..5u32).map(|i| leaf_hash(&i.to_le_bytes())).collect();
+        let tree = FastMerkleTree::from_leaves(leaves.clone());
+        let root = tree.commitment().unwrap();
+        for (i, leaf) in leaves.iter().enumerate() {
+            let proof = tree.prove(i).unwrap();
+            assert!(FastMerkleTree::verify(&5, &root, i, leaf, &proof));
+            assert!(!FastMerkleTree::verify(
+                &5,
+                &root,
+                (i + 1) % 5,
+                leaf,
+                &proof
+            ));
+        }
+        //empty slots open to ZERO but aren't leaves
+        let empty = tree.prove(5).unwrap();
+        let zero = blake3::Hash::from_bytes(crate::merkletree::tree::ZERO);
+        assert!(!FastMerkleTree::verify(&5, &root, 5, &zero, &empty));
+        assert!(tree.prove(7).is_ok());
+        assert!(tree.prove(8).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use super::tree::OFFSET_ONE;
+use anyhow::{anyhow, Result};
+use blake3::{Hash, Hasher};
+use bytes::{Bytes, BytesMut};
+use rayon::prelude::*;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::OnceLock;
+use tokio::sync::oneshot;
+
+// Above this size a single leaf is split across the pool's threads
+pub const RAYON_THRESHOLD: usize = 1 << 20;
+
+// Hashing runs on its own fixed-size rayon pool so big uploads can't starve the
+// async runtime. Size it with MERKLE_HASH_THREADS, default one thread per core.
+pub fn hash_pool() -> &'static rayon::ThreadPool {
+    static POOL: OnceLock<rayon::ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| build_pool("MERKLE_HASH_THREADS", 0, "merkle-hash"))
+}
+
+// Groth16 proofs for /prove-append take seconds each, so they get a pool of their
+// own rather than holding up the hashing uploads wait on. Size it with
+// MERKLE_PROVE_THREADS, default half the cores.
+pub fn prove_pool() -> &'static rayon::ThreadPool {
+    static POOL: OnceLock<rayon::ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        build_pool("MERKLE_PROVE_THREADS", cores.div_ceil(2), "merkle-prove")
+    })
+}
+
+fn build_pool(var: &str, default_threads: usize, name: &'static str) -> rayon::ThreadPool {
+    let threads = std::env::var(var)
+        .ok()
+        .and_then(|threads| threads.parse().ok())
+        .unwrap_or(default_threads);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(move |i| format!("{}-{}", name, i))
+        .build()
+        .expect("failed to start thread pool")
+}
+
+// Start f on the hashing pool right away and return a future for its result.
+// A panic in f comes back as an error; rayon would abort the process on it.
+pub fn spawn<T, F>(f: F) -> impl Future<Output = Result<T>>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    spawn_on(hash_pool(), "Hashing", f)
+}
+
+// The same on the proving pool
+pub fn spawn_proof<T, F>(f: F) -> impl Future<Output = Result<T>>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    spawn_on(prove_pool(), "Proving", f)
+}
+
+fn spawn_on<T, F>(
+    pool: &rayon::ThreadPool,
+    what: &'static str,
+    f: F,
+) -> impl Future<Output = Result<T>>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    pool.spawn(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        //the receiver is gone only if the caller stopped waiting
+        let _ = tx.send(result);
+    });
+    async move {
+        match rx.await {
+            Ok(Ok(value)) => Ok(value),
+            _ => Err(anyhow!("{} task panicked", what)),
+        }
+    }
+}
+
+// Leaf hash built up from an upload's chunks as they arrive. Chunks are gathered
+// until there is enough to be worth splitting, then hashed on the pool, so the
+// file is never held whole and the runtime never hashes.
+pub struct LeafHasher {
+    hasher: Option<Hasher>,
+    pending: BytesMut,
+}
+
+impl Default for LeafHasher {
+    fn default() -> Self {
+        let mut hasher = Hasher::new();
+        hasher.update(&OFFSET_ONE);
+        LeafHasher {
+            hasher: Some(hasher),
+            pending: BytesMut::new(),
+        }
+    }
+}
+
+impl LeafHasher {
+    pub async fn update(&mut self, chunk: &Bytes) -> Result<()> {
+        self.pending.extend_from_slice(chunk);
+        if self.pending.len() >= RAYON_THRESHOLD {
+            self.hash_pending().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn finalize(mut self) -> Result<Hash> {
+        self.hash_pending().await?;
+        let hasher = self
+            .hasher
+            .ok_or_else(|| anyhow!("Hashing task panicked"))?;
+        Ok(hasher.finalize())
+    }
+
+    async fn hash_pending(&mut self) -> Result<()> {
+        let data = self.pending.split().freeze();
+        let mut hasher = self
+            .hasher
+            .take()
+            .ok_or_else(|| anyhow!("Hashing task panicked"))?;
+        self.hasher = Some(
+            spawn(move || {
+                hasher.update_rayon(&data);
+                hasher
+            })
+            .await?,
+        );
+        Ok(())
+    }
+}
+
+// Leaf hash of a file on disk, memory-mapped when it is large enough to be worth it
+pub fn leaf_hash_file(path: impl AsRef<Path>) -> Result<Hash> {
+    let mut hasher = Hasher::new();
+    hasher.update(&OFFSET_ONE);
+    hasher.update_mmap_rayon(path)?;
+    Ok(hasher.finalize())
+}
+
+// Leaf hashes for a list of files, hashed in parallel on the pool
+pub fn leaf_hash_files(file_list: &[String]) -> Result<Vec<Hash>> {
+    hash_pool().install(|| file_list.par_iter().map(leaf_hash_file).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_parallel_hashes_match_leaf_hash() {
+        use crate::merkletree::hashing::{leaf_hash_file, spawn};
+        use crate::merkletree::hashing::{leaf_hash_files, RAYON_THRESHOLD};
+        use crate::merkletree::tree::leaf_hash;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut file_list = Vec::new();
+        for size in [0, 10, RAYON_THRESHOLD + 12345] {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let path = dir.path().join(format!("{}.bin", size));
+            std::fs::write(&path, &data).unwrap();
+            file_list.push(path.to_str().unwrap().to_string());
+
+            let expected = leaf_hash(&data);
+            assert_eq!(leaf_hash_file(&path).unwrap(), expected);
+            assert_eq!(spawn(move || leaf_hash(&data)).await.unwrap(), expected);
+        }
+        let hashes = leaf_hash_files(&file_list).unwrap();
+        //and in chunks, as uploads are hashed
+        let data: Vec<u8> = (0..3 * RAYON_THRESHOLD).map(|i| (i % 251) as u8).collect();
+        let mut hasher = crate::merkletree::hashing::LeafHasher::default();
+        for chunk in data.chunks(100_000) {
+            hasher
+                .update(&bytes::Bytes::copy_from_slice(chunk))
+                .await
+                .unwrap();
+        }
+        assert_eq!(hasher.finalize().await.unwrap(), leaf_hash(&data));
+        for (path, hash) in file_list.iter().zip(hashes) {
+            assert_eq!(leaf_hash(&std::fs::read(path).unwrap()), hash);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_panicking_task_returns_an_error() {
+        use crate::merkletree::hashing::spawn;
+
+        assert!(spawn(|| -> u32 { panic!("bad leaf") }).await.is_err());
+        //and the pool is still there for the next task
+        assert_eq!(spawn(|| 1 + 1).await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,207 @@
+use super::commitment::VectorCommitment;
+use anyhow::{anyhow, ensure, Result};
+use ark_bls12_381::{Bls12_381, Fr, G1Affine, G1Projective, G2Affine, G2Projective};
+use ark_ec::pairing::{Pairing, PairingOutput};
+use ark_ec::{AffineRepr, CurveGroup, PrimeGroup, ScalarMul, VariableBaseMSM};
+use ark_ff::{AdditiveGroup, Field, PrimeField, UniformRand};
+use ark_poly::{EvaluationDomain, Radix2EvaluationDomain};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use ark_std::rand::rngs::StdRng;
+use ark_std::rand::SeedableRng;
+use blake3::Hash;
+use std::sync::Arc;
+
+// Experimental KZG vector commitment over BLS12-381. Leaf i is the evaluation at
+// w^i of a polynomial over a power-of-two domain, the commitment is that
+// polynomial evaluated at the setup's secret tau in G1, and an opening is a
+// single G1 point, 48 bytes compressed however many leaves there are.
+//
+// The setup here is generated locally from a seed, so whoever knows the seed can
+// forge openings. It is only for experiments and benchmarks; real use needs the
+// powers of tau from a ceremony.
+pub struct KzgSetup {
+    powers_g1: Vec<G1Affine>,
+    tau_g2: G2Affine,
+}
+
+// What a verifier needs: the setup's tau in G2, and the domain the leaves sit on
+#[derive(Clone)]
+pub struct KzgParams {
+    pub tau_g2: G2Affine,
+    pub domain_size: usize,
+    pub leaf_count: usize,
+}
+
+pub struct KzgCommitment {
+    setup: Arc<KzgSetup>,
+    domain: Radix2EvaluationDomain<Fr>,
+    coeffs: Vec<Fr>,
+    leaf_count: usize,
+    commitment: G1Affine,
+}
+
+// Leaves go into the scalar field by reducing their hash
+pub fn leaf_scalar(leaf: &Hash) -> Fr {
+    Fr::from_le_bytes_mod_order(leaf.as_bytes())
+}
+
+impl KzgSetup {
+    // Powers of a tau drawn from the seed, enough for max_leaves leaves
+    pub fn insecure_local(max_leaves: usize, seed: u64) -> Result<Self> {
+        let size = Radix2EvaluationDomain::<Fr>::compute_size_of_domain(max_leaves.max(1))
+            .ok_or_else(|| anyhow!("No domain for {} leaves", max_leaves))?;
+        let tau = Fr::rand(&mut StdRng::seed_from_u64(seed));
+        let mut powers = Vec::with_capacity(size);
+        let mut power = Fr::ONE;
+        for _ in 0..size {
+            powers.push(power);
+            power *= tau;
+        }
+        Ok(KzgSetup {
+            powers_g1: G1Projective::generator().batch_mul(&powers),
+            tau_g2: (G2Projective::generator() * tau).into_affine(),
+        })
+    }
+
+    pub fn max_leaves(&self) -> usize {
+        self.powers_g1.len()
+    }
+
+    fn commit(&self, coeffs: &[Fr]) -> G1Affine {
+        G1Projective::msm_unchecked(&self.powers_g1[..coeffs.len()], coeffs).into_affine()
+    }
+}
+
+impl KzgCommitment {
+    pub fn new(setup: Arc<KzgSetup>, leaves: &[Hash]) -> Result<Self> {
+        ensure!(
+            leaves.len() <= setup.max_leaves(),
+            "Setup only covers {} leaves",
+            setup.max_leaves()
+        );
+        let domain = Radix2EvaluationDomain::<Fr>::new(leaves.len().max(1))
+            .ok_or_else(|| anyhow!("No domain for {} leaves", leaves.len()))?;
+        let mut evals: Vec<Fr> = leaves.iter().map(leaf_scalar).collect();
+        evals.resize(domain.size(), Fr::ZERO);
+        let coeffs = domain.ifft(&evals);
+        let commitment = setup.commit(&coeffs);
+        Ok(KzgCommitment {
+            setup,
+            domain,
+            coeffs,
+            leaf_count: leaves.len(),
+            commitment,
+        })
+    }
+
+    pub fn params(&self) -> KzgParams {
+        KzgParams {
+            tau_g2: self.setup.tau_g2,
+            domain_size: self.domain.size(),
+            leaf_count: self.leaf_count,
+        }
+    }
+}
+
+// Quotient of p(x) - p(z) by x - z, by synthetic division
+fn divide_by_linear(coeffs: &[Fr], z: Fr) -> Vec<Fr> {
+    let mut quotient = vec![Fr::ZERO; coeffs.len().saturating_sub(1)];
+    let mut carry = Fr::ZERO;
+    for i in (1..coeffs.len()).rev() {
+        carry = coeffs[i] + carry * z;
+        quotient[i - 1] = carry;
+    }
+    quotient
+}
+
+impl VectorCommitment for KzgCommitment {
+    type Proof = Vec<u8>;
+    type Params = KzgParams;
+
+    fn commitment(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.commitment.serialize_compressed(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn prove(&self, index: usize) -> Result<Vec<u8>> {
+        ensure!(index < self.leaf_count, "Leaf {} out of range", index);
+        let quotient = divide_by_linear(&self.coeffs, self.domain.element(index));
+        let mut bytes = Vec::new();
+        self.setup
+            .commit(&quotient)
+            .serialize_compressed(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    // e(C - v*G1, G2) == e(proof, tau*G2 - z*G2)
+    fn verify(
+        params: &KzgParams,
+        commitment: &[u8],
+        index: usize,
+        leaf: &Hash,
+        proof: &Vec<u8>,
+    ) -> bool {
+        let (Ok(commitment), Ok(proof)) = (
+            G1Affine::deserialize_compressed(commitment),
+            G1Affine::deserialize_compressed(proof.as_slice()),
+        ) else {
+            return false;
+        };
+        let Some(domain) = Radix2EvaluationDomain::<Fr>::new(params.domain_size) else {
+            return false;
+        };
+        if index >= params.leaf_count || domain.size() != params.domain_size {
+            return false;
+        }
+        let z = domain.element(index);
+        let g1 = G1Affine::generator();
+        let g2 = G2Affine::generator();
+        //one multi-pairing shares the final exponentiation between both sides
+        Bls12_381::multi_pairing(
+            [
+                commitment.into_group() - g1 * leaf_scalar(leaf),
+                -proof.into_group(),
+            ],
+            [g2.into_group(), params.tau_g2.into_group() - g2 * z],
+        ) == PairingOutput::ZERO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_constant_size_openings() {
+        use crate::merkletree::commitment::VectorCommitment;
+        use crate::merkletree::kzg::{KzgCommitment, KzgSetup};
+        use crate::merkletree::tree::leaf_hash;
+        use std::sync::Arc;
+
+        let setup = Arc::new(KzgSetup::insecure_local(8, 42).unwrap());
+        let leaves: Vec<blake3::Hash> = (0..6u32).map(|i| leaf_hash(&i.to_le_bytes())).collect();
+        let kzg = KzgCommitment::new(setup.clone(), &leaves).unwrap();
+        let params = kzg.params();
+        let commitment = kzg.commitment().unwrap();
+        for (i, leaf) in leaves.iter().enumerate() {
+            let proof = kzg.prove(i).unwrap();
+            assert_eq!(proof.len(), 48);
+            assert!(KzgCommitment::verify(&params, &commitment, i, leaf, &proof));
+            assert!(!KzgCommitment::verify(
+                &params,
+                &commitment,
+                (i + 1) % 6,
+                leaf,
+                &proof
+            ));
+            assert!(!KzgCommitment::verify(
+                &params,
+                &commitment,
+                i,
+                &leaves[(i + 1) % 6],
+                &proof
+            ));
+        }
+        assert!(kzg.prove(6).is_err());
+        assert!(KzgCommitment::new(setup, &[leaves[0]; 9]).is_err());
+    }
+}
@@ -0,0 +1,354 @@
+use super::commitment::VectorCommitment;
+use super::tree::inner_hash;
+use crate::storage::MmrStore;
+use anyhow::{anyhow, ensure, Result};
+use blake3::Hash;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+// Merkle mountain range: a list of perfect binary trees (mountains) stored in the
+// order their nodes are created, so appending a leaf only adds nodes and never
+// changes one. Nodes are numbered from 0 by position in that order; mmr_size is
+// the number of nodes. The root bags the mountain peaks right to left.
+//
+// A proof is the path from the leaf up to its peak plus every peak. Since nodes
+// never change, a proof stays valid against the root it was made for, and as the
+// range grows only the path above the old peak has to be added.
+pub struct MerkleMountainRange {
+    store: Arc<dyn MmrStore>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MmrProof {
+    pub mmr_size: u64,
+    // Siblings from the leaf up to its peak
+    pub path: Vec<[u8; 32]>,
+    // Every peak, left to right
+    pub peaks: Vec<[u8; 32]>,
+}
+
+// Position of the leaf with this index
+pub fn leaf_pos(index: u64) -> u64 {
+    2 * index - index.count_ones() as u64
+}
+
+// Node count once leaf_count leaves have been appended
+pub fn mmr_size(leaf_count: u64) -> u64 {
+    2 * leaf_count - leaf_count.count_ones() as u64
+}
+
+// Height of the node at pos, leaves being 0
+fn pos_height(pos: u64) -> u32 {
+    let mut pos = pos + 1;
+    //jump left until pos is the last node of a perfect tree, 2^k - 1 in 1-based numbering
+    while !(pos + 1).is_power_of_two() {
+        let most_significant_bit = 1 << (63 - pos.leading_zeros());
+        pos -= most_significant_bit - 1;
+    }
+    63 - pos.leading_zeros()
+}
+
+fn parent_offset(height: u32) -> u64 {
+    2 << height
+}
+
+fn sibling_offset(height: u32) -> u64 {
+    (2 << height) - 1
+}
+
+// Peak positions left to right for a range of mmr_size nodes
+fn peak_positions(mmr_size: u64) -> Vec<u64> {
+    let mut peaks = Vec::new();
+    let mut offset = 0;
+    let mut remaining = mmr_size;
+    while remaining > 0 {
+        //largest perfect tree that fits in what is left
+        let size = (1u64 << (64 - remaining.leading_zeros())) - 1;
+        let size = if size > remaining { size >> 1 } else { size };
+        peaks.push(offset + size - 1);
+        offset += size;
+        remaining -= size;
+    }
+    peaks
+}
+
+// Whether mmr_size is a node count some number of appends can reach
+fn is_valid_size(mmr_size: u64) -> bool {
+    let peaks = peak_positions(mmr_size);
+    peaks
+        .windows(2)
+        .all(|pair| pos_height(pair[0]) > pos_height(pair[1]))
+}
+
+pub fn bag_peaks(peaks: &[Hash]) -> Option<Hash> {
+    let mut peaks = peaks.iter().rev();
+    let last = *peaks.next()?;
+    Some(peaks.fold(last, |bag, peak| inner_hash(peak, &bag)))
+}
+
+// Climb from pos until a peak of the range is reached, calling step with each
+// sibling's position and whether it is on the left. Returns the peak position.
+fn climb(
+    mut pos: u64,
+    mmr_size: u64,
+    mut step: impl FnMut(u64, bool) -> Result<()>,
+) -> Result<u64> {
+    let peaks = peak_positions(mmr_size);
+    let mut height = pos_height(pos);
+    while !peaks.contains(&pos) {
+        ensure!(pos < mmr_size, "Position {} outside the range", pos);
+        if pos_height(pos + 1) > height {
+            //right child, sibling on the left
+            step(pos - sibling_offset(height), true)?;
+            pos += 1;
+        } else {
+            step(pos + sibling_offset(height), false)?;
+            pos += parent_offset(height);
+        }
+        height += 1;
+    }
+    Ok(pos)
+}
+
+impl MerkleMountainRange {
+    pub fn new(store: Arc<dyn MmrStore>) -> Self {
+        MerkleMountainRange { store }
+    }
+
+    pub fn mmr_size(&self) -> Result<u64> {
+        self.store.size()
+    }
+
+    pub fn leaf_count(&self) -> Result<u64> {
+        //a peak of height h covers 2^h leaves
+        let mmr_size = self.mmr_size()?;
+        let peaks = peak_positions(mmr_size);
+        Ok(peaks.iter().map(|peak| 1 << pos_height(*peak)).sum())
+    }
+
+    fn node(&self, pos: u64) -> Result<Hash> {
+        self.store
+            .node(pos)?
+            .ok_or_else(|| anyhow!("Missing mmr node {}", pos))
+    }
+
+    // Append leaves and the parents they complete in one store write, returning
+    // the index of the first new leaf
+    pub fn append(&self, leaves: &[Hash]) -> Result<u64> {
+        let start = self.mmr_size()?;
+        let first_index = self.leaf_count()?;
+        let mut added: Vec<Hash> = Vec::new();
+        let node = |pos: u64, added: &[Hash]| match pos.checked_sub(start) {
+            Some(offset) => Ok(added[offset as usize]),
+            None => self.node(pos),
+        };
+        for leaf in leaves {
+            let mut pos = start + added.len() as u64;
+            added.push(*leaf);
+            let mut height = 0;
+            while pos_height(pos + 1) > height {
+                pos += 1;
+                let left = pos - parent_offset(height);
+                let right = left + sibling_offset(height);
+                added.push(inner_hash(&node(left, &added)?, &node(right, &added)?));
+                height += 1;
+            }
+        }
+        self.store.append(start, &added)?;
+        Ok(first_index)
+    }
+
+    pub fn root(&self) -> Result<Option<Hash>> {
+        self.root_at(self.mmr_size()?)
+    }
+
+    // Root when the range had mmr_size nodes; earlier roots stay computable
+    // because nodes are never rewritten
+    pub fn root_at(&self, mmr_size: u64) -> Result<Option<Hash>> {
+        ensure!(
+            mmr_size <= self.mmr_size()? && is_valid_size(mmr_size),
+            "Invalid mmr size {}",
+            mmr_size
+        );
+        let peaks = self.peaks(mmr_size)?;
+        Ok(bag_peaks(&peaks))
+    }
+
+    fn peaks(&self, mmr_size: u64) -> Result<Vec<Hash>> {
+        peak_positions(mmr_size)
+            .into_iter()
+            .map(|pos| self.node(pos))
+            .collect()
+    }
+
+    pub fn get_proof(&self, index: u64) -> Result<MmrProof> {
+        let mmr_size = self.mmr_size()?;
+        let pos = leaf_pos(index);
+        ensure!(pos < mmr_size, "Leaf {} out of range", index);
+        self.proof_above(pos, Vec::new(), mmr_size)
+    }
+
+    // Bring a proof made against an earlier size up to the current one. The path up
+    // to the old peak is kept and only the nodes above it are read.
+    pub fn update_proof(&self, index: u64, proof: &MmrProof) -> Result<MmrProof> {
+        let mmr_size = self.mmr_size()?;
+        let pos = leaf_pos(index);
+        ensure!(
+            pos < proof.mmr_size && proof.mmr_size <= mmr_size && is_valid_size(proof.mmr_size),
+            "Proof is not for an earlier size of this range"
+        );
+        let mut steps = 0;
+        let old_peak = climb(pos, proof.mmr_size, |_, _| {
+            steps += 1;
+            Ok(())
+        })?;
+        ensure!(
+            steps == proof.path.len(),
+            "Proof path doesn't match its size"
+        );
+        self.proof_above(old_peak, proof.path.clone(), mmr_size)
+    }
+
+    // Finish a path that has reached pos and add the current peaks
+    fn proof_above(&self, pos: u64, mut path: Vec<[u8; 32]>, mmr_size: u64) -> Result<MmrProof> {
+        climb(pos, mmr_size, |sibling, _| {
+            path.push(*self.node(sibling)?.as_bytes());
+            Ok(())
+        })?;
+        let peaks = self.peaks(mmr_size)?;
+        Ok(MmrProof {
+            mmr_size,
+            path,
+            peaks: peaks.iter().map(|peak| *peak.as_bytes()).collect(),
+        })
+    }
+}
+
+impl MmrProof {
+    // Root the proof leads to from this leaf, checking it against the peaks
+    pub fn compute_root(&self, index: u64, leaf: &Hash) -> Result<Hash> {
+        ensure!(is_valid_size(self.mmr_size), "Invalid mmr size");
+        let positions = peak_positions(self.mmr_size);
+        ensure!(positions.len() == self.peaks.len(), "Wrong number of peaks");
+
+        let mut path = self.path.iter();
+        let mut current = *leaf;
+        let peak = climb(leaf_pos(index), self.mmr_size, |_, sibling_is_left| {
+            let sibling = Hash::from_bytes(*path.next().ok_or_else(|| anyhow!("Path too short"))?);
+            current = match sibling_is_left {
+                true => inner_hash(&sibling, &current),
+                false => inner_hash(&current, &sibling),
+            };
+            Ok(())
+        })?;
+        ensure!(path.next().is_none(), "Path too long");
+
+        let peaks: Vec<Hash> = self
+            .peaks
+            .iter()
+            .map(|peak| Hash::from_bytes(*peak))
+            .collect();
+        let at = positions.iter().position(|pos| *pos == peak);
+        ensure!(
+            at.is_some_and(|at| peaks[at] == current),
+            "Leaf doesn't lead to its peak"
+        );
+        bag_peaks(&peaks).ok_or_else(|| anyhow!("No peaks"))
+    }
+}
+
+impl VectorCommitment for MerkleMountainRange {
+    type Proof = MmrProof;
+    type Params = ();
+
+    fn commitment(&self) -> Result<Vec<u8>> {
+        let root = self.root()?.ok_or_else(|| anyhow!("Empty range"))?;
+        Ok(root.as_bytes().to_vec())
+    }
+
+    fn prove(&self, index: usize) -> Result<MmrProof> {
+        self.get_proof(index as u64)
+    }
+
+    fn verify(_: &(), commitment: &[u8], index: usize, leaf: &Hash, proof: &MmrProof) -> bool {
+        proof
+            .compute_root(index as u64, leaf)
+            .is_ok_and(|root| root.as_bytes().as_slice() == commitment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn leaf(i: u64) -> blake3::Hash {
+        crate::merkletree::tree::leaf_hash(&i.to_le_bytes())
+    }
+
+    #[test]
+    fn test_proofs_stay_valid_as_range_grows() {
+        use crate::merkletree::commitment::VectorCommitment;
+        use crate::merkletree::mmr::{mmr_size, MerkleMountainRange};
+        use crate::storage::sled_mmr::SledMmrStore;
+        use std::sync::Arc;
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mmr = MerkleMountainRange::new(Arc::new(SledMmrStore::open(&db).unwrap()));
+        let mut old_proofs = Vec::new();
+        for i in 0..20 {
+            assert_eq!(mmr.append(&[leaf(i)]).unwrap(), i);
+            assert_eq!(mmr.mmr_size().unwrap(), mmr_size(i + 1));
+            assert_eq!(mmr.leaf_count().unwrap(), i + 1);
+
+            let root = mmr.commitment().unwrap();
+            for j in 0..=i {
+                let proof = mmr.prove(j as usize).unwrap();
+                assert!(MerkleMountainRange::verify(
+                    &(),
+                    &root,
+                    j as usize,
+                    &leaf(j),
+                    &proof
+                ));
+                assert!(!MerkleMountainRange::verify(
+                    &(),
+                    &root,
+                    j as usize,
+                    &leaf(j + 1),
+                    &proof
+                ));
+            }
+            old_proofs.push((i, mmr.prove(i as usize).unwrap()));
+        }
+        let root = mmr.commitment().unwrap();
+
+        //old proofs still match the old roots and extend to the new one
+        for (i, old_proof) in &old_proofs {
+            let old_root = mmr.root_at(old_proof.mmr_size).unwrap().unwrap();
+            let index = *i as usize;
+            assert!(MerkleMountainRange::verify(
+                &(),
+                old_root.as_bytes(),
+                index,
+                &leaf(*i),
+                old_proof
+            ));
+            let updated = mmr.update_proof(*i, old_proof).unwrap();
+            assert!(updated.path.starts_with(&old_proof.path));
+            assert_eq!(updated, mmr.prove(index).unwrap());
+            assert!(MerkleMountainRange::verify(
+                &(),
+                &root,
+                index,
+                &leaf(*i),
+                &updated
+            ));
+        }
+
+        //a batch append gives the same range as one at a time
+        let batch =
+            MerkleMountainRange::new(Arc::new(crate::storage::memory::MemoryMmrStore::default()));
+        let leaves: Vec<blake3::Hash> = (0..20).map(leaf).collect();
+        batch.append(&leaves[..7]).unwrap();
+        batch.append(&leaves[7..]).unwrap();
+        assert_eq!(batch.commitment().unwrap(), root);
+    }
+}
@@ -1 +1,11 @@
-pub(crate) mod tree;
+pub mod absence;
+pub mod cache;
+pub mod commitment;
+pub mod hashing;
+pub mod kzg;
+pub mod mmr;
+pub mod poseidon;
+pub mod sparse;
+pub mod tree;
+pub mod witness;
+pub mod zk;
@@ -0,0 +1,202 @@
+use anyhow::{ensure, Result};
+use ark_bn254::Fr;
+use ark_ff::{AdditiveGroup, PrimeField};
+use blake3::Hash;
+use light_poseidon::parameters::bn254_x5::get_poseidon_parameters;
+use light_poseidon::{Poseidon, PoseidonHasher, PoseidonParameters};
+use std::cell::RefCell;
+use std::sync::OnceLock;
+
+// Poseidon over BN254 with circom's parameters (x^5, width 3), so two-input hashes
+// match circomlib's Poseidon(2) and can be checked inside circuits. Width is the
+// capacity element plus two inputs; the output is the first state element. The
+// circuit in zk.rs builds its constraints from these parameters.
+pub fn params() -> &'static PoseidonParameters<Fr> {
+    static PARAMS: OnceLock<PoseidonParameters<Fr>> = OnceLock::new();
+    PARAMS.get_or_init(|| get_poseidon_parameters::<Fr>(3).expect("circom poseidon parameters"))
+}
+
+pub fn hash2(left: &Fr, right: &Fr) -> Fr {
+    //the hasher keeps its state between calls, so each thread has its own
+    thread_local! {
+        static HASHER: RefCell<Poseidon<Fr>> =
+            RefCell::new(Poseidon::<Fr>::new_circom(2).expect("circom poseidon parameters"));
+    }
+    HASHER.with_borrow_mut(|hasher| {
+        hasher
+            .hash(&[*left, *right])
+            .expect("poseidon hash of two inputs")
+    })
+}
+
+// Leaves enter the field by reducing their blake3 leaf hash
+pub fn field_leaf(leaf: &Hash) -> Fr {
+    Fr::from_le_bytes_mod_order(leaf.as_bytes())
+}
+
+// Fixed-depth Poseidon tree for zk use. Leaves fill slots from the left and
+// empty slots hold zero, so the shape (and every circuit over it) depends only
+// on the depth, and appending only touches one path.
+#[derive(Clone)]
+pub struct PoseidonTree {
+    depth: usize,
+    zeros: Vec<Fr>,
+    // levels[0] holds the leaves, levels[depth] the root; only filled nodes are kept
+    levels: Vec<Vec<Fr>>,
+}
+
+// Deepest tree built from a request; each level costs a Poseidon hash per filled pair
+pub const MAX_DEPTH: usize = 32;
+
+// Hash of an empty subtree at each height
+pub fn zero_hashes(depth: usize) -> Vec<Fr> {
+    let mut zeros = vec![Fr::ZERO];
+    for height in 0..depth {
+        zeros.push(hash2(&zeros[height], &zeros[height]));
+    }
+    zeros
+}
+
+impl PoseidonTree {
+    pub fn new(depth: usize) -> Self {
+        PoseidonTree {
+            depth,
+            zeros: zero_hashes(depth),
+            levels: vec![Vec::new(); depth + 1],
+        }
+    }
+
+    // Built a level at a time, so each node is hashed once
+    pub fn from_leaves(depth: usize, leaves: &[Fr]) -> Result<Self> {
+        ensure!(
+            (1..=MAX_DEPTH).contains(&depth),
+            "Tree depth has to be between 1 and {}",
+            MAX_DEPTH
+        );
+        ensure!(
+            leaves.len() <= 1 << depth,
+            "Tree of depth {} is full",
+            depth
+        );
+        let mut tree = Self::new(depth);
+        tree.levels[0] = leaves.to_vec();
+        for height in 0..depth {
+            let level = tree.levels[height]
+                .chunks(2)
+                .map(|pair| hash2(&pair[0], pair.get(1).unwrap_or(&tree.zeros[height])))
+                .collect();
+            tree.levels[height + 1] = level;
+        }
+        Ok(tree)
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    pub fn leaves(&self) -> &[Fr] {
+        &self.levels[0]
+    }
+
+    pub fn root(&self) -> Fr {
+        match self.levels[self.depth].first() {
+            Some(root) => *root,
+            None => self.zeros[self.depth],
+        }
+    }
+
+    fn node(&self, height: usize, index: usize) -> Fr {
+        self.levels[height]
+            .get(index)
+            .copied()
+            .unwrap_or(self.zeros[height])
+    }
+
+    pub fn append(&mut self, leaf: Fr) -> Result<()> {
+        let index = self.leaf_count();
+        ensure!(
+            index < 1 << self.depth,
+            "Tree of depth {} is full",
+            self.depth
+        );
+        self.levels[0].push(leaf);
+        let mut index = index;
+        for height in 0..self.depth {
+            let parent = index / 2;
+            let left = self.node(height, parent * 2);
+            let right = self.node(height, parent * 2 + 1);
+            let hash = hash2(&left, &right);
+            match self.levels[height + 1].get_mut(parent) {
+                Some(node) => *node = hash,
+                None => self.levels[height + 1].push(hash),
+            }
+            index = parent;
+        }
+        Ok(())
+    }
+
+    // Siblings from the leaf slot up to the root; the slot may be empty
+    pub fn path(&self, index: usize) -> Result<Vec<Fr>> {
+        ensure!(index < 1 << self.depth, "Slot {} outside the tree", index);
+        Ok((0..self.depth)
+            .map(|height| self.node(height, (index >> height) ^ 1))
+            .collect())
+    }
+}
+
+// Root reached from leaf at index with the given siblings
+pub fn root_from_path(leaf: &Fr, index: usize, path: &[Fr]) -> Fr {
+    path.iter()
+        .enumerate()
+        .fold(*leaf, |node, (height, sibling)| {
+            match (index >> height) & 1 {
+                1 => hash2(sibling, &node),
+                _ => hash2(&node, sibling),
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_matches_circom_poseidon() {
+        use crate::merkletree::poseidon::{hash2, root_from_path, PoseidonTree};
+        use ark_bn254::Fr;
+        use light_poseidon::{Poseidon, PoseidonHasher};
+        use std::str::FromStr;
+
+        //circomlibjs poseidon([1, 2])
+        let expected = Fr::from_str(
+            "7853200120776062878684798364095072458815029376092732009249414926327459813530",
+        )
+        .unwrap();
+        assert_eq!(hash2(&Fr::from(1u64), &Fr::from(2u64)), expected);
+        let mut circom = Poseidon::<Fr>::new_circom(2).unwrap();
+        let (a, b) = (Fr::from(12345u64), Fr::from(67890u64));
+        assert_eq!(hash2(&a, &b), circom.hash(&[a, b]).unwrap());
+
+        let leaves: Vec<Fr> = (1..=5u64).map(Fr::from).collect();
+        let tree = PoseidonTree::from_leaves(3, &leaves).unwrap();
+        assert!(PoseidonTree::from_leaves(2, &leaves).is_err());
+        assert!(PoseidonTree::from_leaves(0, &[]).is_err());
+        assert!(PoseidonTree::from_leaves(64, &leaves).is_err());
+        let mut appended = PoseidonTree::new(3);
+        for leaf in &leaves {
+            appended.append(*leaf).unwrap();
+        }
+        assert_eq!(appended.root(), tree.root());
+        for (i, leaf) in leaves.iter().enumerate() {
+            assert_eq!(root_from_path(leaf, i, &tree.path(i).unwrap()), tree.root());
+        }
+        let empty = Fr::from(0u64);
+        assert_eq!(
+            root_from_path(&empty, 7, &tree.path(7).unwrap()),
+            tree.root()
+        );
+        assert!(PoseidonTree::from_leaves(3, &[empty; 9]).is_err());
+    }
+}
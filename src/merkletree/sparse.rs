@@ -0,0 +1,384 @@
+use super::tree::{inner_hash, ZERO};
+use anyhow::{bail, ensure, Result};
+use blake3::Hash;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+pub const KEY_BITS: usize = 256;
+
+pub type Key = [u8; 32];
+
+const OFFSET_THREE: [u8; 4] = 3u32.to_le_bytes(); //for a leaf standing in for its subtree
+
+// Sparse merkle tree over the 2^256 slots addressed by blake3(file path). A file's
+// slot never moves, so its proof only changes when the root does, and an empty slot
+// proves the file isn't in the tree. It is compact: an empty subtree hashes to
+// ZERO and a subtree holding one leaf to that leaf's node, so a file sits just
+// below where its key parts from every other key, around log2(n) levels down
+// rather than 256. Only subtrees with two or more leaves are stored.
+#[derive(Default)]
+pub struct SparseMerkleTree {
+    // (depth, key with the bits below depth cleared) -> node hash
+    nodes: HashMap<(usize, Key), Hash>,
+    leaves: BTreeMap<Key, Hash>,
+}
+
+// Siblings along the path from the slot's subtree to the root. That subtree sits
+// at depth and is empty or holds a single leaf: the slot's own for inclusion, or
+// for non-inclusion the other leaf, if any, whose key shares the same first bits.
+// Empty siblings are left out and filled back in by the verifier.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SparseProof {
+    pub key: Key,
+    pub depth: usize,
+    // (key, leaf hash) of the leaf in the slot's subtree, for non-inclusion
+    pub other: Option<(Key, Key)>,
+    // (depth of the sibling, sibling hash), deepest first
+    pub siblings: Vec<(usize, Key)>,
+}
+
+pub fn key_for(path: &str) -> Key {
+    *blake3::hash(path.as_bytes()).as_bytes()
+}
+
+// Hash of a subtree holding just this leaf
+fn leaf_node(key: &Key, leaf: &Hash) -> Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&OFFSET_THREE);
+    hasher.update(key);
+    hasher.update(leaf.as_bytes());
+    hasher.finalize()
+}
+
+// Bit i of the key, counting from the root; set means the path goes right
+fn bit(key: &Key, i: usize) -> bool {
+    key[i / 8] >> (7 - i % 8) & 1 == 1
+}
+
+// The first depth bits of the key, which name the node at that depth on its path
+fn prefix(key: &Key, depth: usize) -> Key {
+    let mut prefix = [0u8; 32];
+    prefix[..depth / 8].copy_from_slice(&key[..depth / 8]);
+    if !depth.is_multiple_of(8) {
+        prefix[depth / 8] = key[depth / 8] & (0xffu8 << (8 - depth % 8));
+    }
+    prefix
+}
+
+// The last key under the node at depth on the key's path
+fn last_under(key: &Key, depth: usize) -> Key {
+    let mut last = prefix(key, depth);
+    for (i, byte) in last.iter_mut().enumerate() {
+        let known = depth.saturating_sub(8 * i).min(8);
+        *byte |= 0xffu8.checked_shr(known as u32).unwrap_or(0);
+    }
+    last
+}
+
+fn sibling(key: &Key, depth: usize) -> Key {
+    let mut sibling = prefix(key, depth);
+    sibling[(depth - 1) / 8] ^= 1 << (7 - (depth - 1) % 8);
+    sibling
+}
+
+// How many leading bits two keys share
+fn common_bits(a: &Key, b: &Key) -> usize {
+    a.iter()
+        .zip(b)
+        .position(|(a, b)| a != b)
+        .map(|i| 8 * i + (a[i] ^ b[i]).leading_zeros() as usize)
+        .unwrap_or(KEY_BITS)
+}
+
+impl SparseMerkleTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Tree for a list of (file path, leaf hash)
+    pub fn from_files(files: impl IntoIterator<Item = (String, Hash)>) -> Self {
+        let mut tree = Self::new();
+        for (path, leaf) in files {
+            tree.insert(&path, leaf);
+        }
+        tree
+    }
+
+    pub fn root(&self) -> Hash {
+        self.node(0, &[0u8; 32])
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn get(&self, path: &str) -> Option<Hash> {
+        self.leaves.get(&key_for(path)).copied()
+    }
+
+    pub fn insert(&mut self, path: &str, leaf: Hash) {
+        let key = key_for(path);
+        self.leaves.insert(key, leaf);
+        self.set_path(&key);
+    }
+
+    pub fn remove(&mut self, path: &str) -> Option<Hash> {
+        let key = key_for(path);
+        let leaf = self.leaves.remove(&key)?;
+        self.set_path(&key);
+        Some(leaf)
+    }
+
+    // Proof for the file's slot, of inclusion if it is in the tree and of
+    // non-inclusion otherwise
+    pub fn prove(&self, path: &str) -> SparseProof {
+        let key = key_for(path);
+        //down the key's path to the first subtree with fewer than two leaves
+        let depth = (0..KEY_BITS)
+            .find(|depth| !self.nodes.contains_key(&(*depth, prefix(&key, *depth))))
+            .unwrap_or(KEY_BITS);
+        let other = self
+            .under(&key, depth)
+            .next()
+            .filter(|(other, _)| **other != key)
+            .map(|(other, leaf)| (*other, *leaf.as_bytes()));
+        let siblings = (1..=depth)
+            .rev()
+            .map(|depth| (depth, self.node(depth, &sibling(&key, depth))))
+            .filter(|(_, node)| *node != Hash::from_bytes(ZERO))
+            .map(|(depth, node)| (depth, *node.as_bytes()))
+            .collect();
+        SparseProof {
+            key,
+            depth,
+            other,
+            siblings,
+        }
+    }
+
+    // Leaves under the node at depth on the key's path, in key order
+    fn under(&self, key: &Key, depth: usize) -> impl Iterator<Item = (&Key, &Hash)> {
+        self.leaves
+            .range(prefix(key, depth)..=last_under(key, depth))
+    }
+
+    fn node(&self, depth: usize, prefix: &Key) -> Hash {
+        if let Some(node) = self.nodes.get(&(depth, *prefix)) {
+            return *node;
+        }
+        match self.under(prefix, depth).next() {
+            Some((key, leaf)) => leaf_node(key, leaf),
+            None => Hash::from_bytes(ZERO),
+        }
+    }
+
+    // Rehash the stored nodes on the key's path after its leaf came or went. Only
+    // the nodes down to where the key parts from its nearest neighbour can hold
+    // another leaf, so the rest are left alone.
+    fn set_path(&mut self, key: &Key) {
+        let before = self.leaves.range(..*key).next_back();
+        let after = self.leaves.range(*key..).find(|(other, _)| *other != key);
+        let shared = [before, after]
+            .into_iter()
+            .flatten()
+            .map(|(other, _)| common_bits(key, other))
+            .max();
+        let Some(shared) = shared else {
+            //no other leaf, so nothing is stored
+            self.nodes.clear();
+            return;
+        };
+        for depth in (0..=shared).rev() {
+            let node = (depth, prefix(key, depth));
+            if self.under(key, depth).nth(1).is_none() {
+                self.nodes.remove(&node);
+                continue;
+            }
+            let other = self.node(depth + 1, &sibling(key, depth + 1));
+            let current = self.node(depth + 1, &prefix(key, depth + 1));
+            let hash = match bit(key, depth) {
+                true => inner_hash(&other, &current),
+                false => inner_hash(&current, &other),
+            };
+            self.nodes.insert(node, hash);
+        }
+    }
+}
+
+impl SparseProof {
+    // Root reached from the slot holding leaf, or from an empty slot for None
+    pub fn compute_root(&self, leaf: Option<&Hash>) -> Result<Hash> {
+        ensure!(self.depth <= KEY_BITS, "Proof deeper than the tree");
+        let mut current = match (leaf, &self.other) {
+            (Some(leaf), None) => leaf_node(&self.key, leaf),
+            (Some(_), Some(_)) => bail!("Inclusion proof with another leaf in the slot"),
+            (None, Some((other, leaf))) => {
+                //the other leaf has to be the one whose subtree the slot falls in
+                ensure!(
+                    *other != self.key && common_bits(other, &self.key) >= self.depth,
+                    "Proof's other leaf is not in the slot's subtree"
+                );
+                leaf_node(other, &Hash::from_bytes(*leaf))
+            }
+            (None, None) => Hash::from_bytes(ZERO),
+        };
+        let mut siblings = self.siblings.iter().peekable();
+        for depth in (1..=self.depth).rev() {
+            let other = match siblings.next_if(|(sibling_depth, _)| *sibling_depth == depth) {
+                Some((_, sibling)) => Hash::from_bytes(*sibling),
+                None => Hash::from_bytes(ZERO),
+            };
+            current = match bit(&self.key, depth - 1) {
+                true => inner_hash(&other, &current),
+                false => inner_hash(&current, &other),
+            };
+        }
+        ensure!(
+            siblings.next().is_none(),
+            "Proof siblings out of order or out of range"
+        );
+        Ok(current)
+    }
+
+    // The file at path has this leaf hash under root
+    pub fn verify_inclusion(&self, root: &Hash, path: &str, leaf: &Hash) -> bool {
+        self.key == key_for(path)
+            && self
+                .compute_root(Some(leaf))
+                .is_ok_and(|computed| computed == *root)
+    }
+
+    // No file at path is under root
+    pub fn verify_non_inclusion(&self, root: &Hash, path: &str) -> bool {
+        self.key == key_for(path)
+            && self
+                .compute_root(None)
+                .is_ok_and(|computed| computed == *root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn files(n: usize) -> Vec<(String, blake3::Hash)> {
+        (0..n)
+            .map(|i| {
+                let path = format!("file{}.txt", i);
+                let leaf = crate::merkletree::tree::leaf_hash(path.as_bytes());
+                (path, leaf)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_inclusion_and_non_inclusion() {
+        use crate::merkletree::sparse::SparseMerkleTree;
+
+        let files = files(20);
+        let tree = SparseMerkleTree::from_files(files.clone());
+        let reversed = SparseMerkleTree::from_files(files.iter().rev().cloned());
+        assert_eq!(tree.root(), reversed.root());
+
+        let root = tree.root();
+        for (path, leaf) in &files {
+            let proof = tree.prove(path);
+            assert!(proof.verify_inclusion(&root, path, leaf));
+            assert!(!proof.verify_non_inclusion(&root, path));
+            assert!(!proof.verify_inclusion(&root, "other.txt", leaf));
+        }
+        let proof = tree.prove("missing.txt");
+        assert!(proof.verify_non_inclusion(&root, "missing.txt"));
+        assert!(!proof.verify_inclusion(&root, "missing.txt", &files[0].1));
+
+        //round trips through json
+        let json = serde_json::to_string(&proof).unwrap();
+        let proof: crate::merkletree::sparse::SparseProof = serde_json::from_str(&json).unwrap();
+        assert!(proof.verify_non_inclusion(&root, "missing.txt"));
+    }
+
+    #[test]
+    fn test_insert_and_remove_keep_positions() {
+        use crate::merkletree::sparse::SparseMerkleTree;
+
+        let files = files(10);
+        let mut tree = SparseMerkleTree::from_files(files[..9].to_vec());
+        let before = tree.prove(&files[0].0);
+        tree.insert(&files[9].0, files[9].1);
+        assert_eq!(tree.prove(&files[0].0).key, before.key);
+        assert_eq!(
+            tree.root(),
+            SparseMerkleTree::from_files(files.clone()).root()
+        );
+
+        for (path, _) in &files {
+            tree.remove(path);
+        }
+        assert!(tree.is_empty());
+        assert_eq!(tree.root(), SparseMerkleTree::new().root());
+        assert!(tree.nodes.is_empty());
+    }
+
+    #[test]
+    fn test_stored_nodes_match_a_rebuild() {
+        use crate::merkletree::sparse::{bit, leaf_node, Key, SparseMerkleTree};
+        use crate::merkletree::tree::{inner_hash, ZERO};
+        use blake3::Hash;
+
+        //the root straight from the sorted leaves, splitting on each bit in turn,
+        //and how many subtrees on the way hold more than one leaf
+        fn rebuild(leaves: &[(Key, Hash)], depth: usize) -> (Hash, usize) {
+            match leaves {
+                [] => (Hash::from_bytes(ZERO), 0),
+                [(key, leaf)] => (leaf_node(key, leaf), 0),
+                _ => {
+                    let split = leaves.partition_point(|(key, _)| !bit(key, depth));
+                    let (left, left_nodes) = rebuild(&leaves[..split], depth + 1);
+                    let (right, right_nodes) = rebuild(&leaves[split..], depth + 1);
+                    (inner_hash(&left, &right), left_nodes + right_nodes + 1)
+                }
+            }
+        }
+
+        let files = files(200);
+        let mut tree = SparseMerkleTree::from_files(files.clone());
+        for (path, _) in files.iter().step_by(3) {
+            tree.remove(path);
+        }
+        let leaves: Vec<(Key, Hash)> = tree.leaves.iter().map(|(k, v)| (*k, *v)).collect();
+        let (root, nodes) = rebuild(&leaves, 0);
+        assert_eq!(tree.root(), root);
+        //nothing stale left behind, and proofs only as deep as the keys need
+        assert_eq!(tree.nodes.len(), nodes);
+        assert!(nodes < 2 * leaves.len());
+        for (path, _) in &files {
+            assert!(tree.prove(path).depth < 24);
+        }
+    }
+
+    #[test]
+    fn test_forged_non_inclusion_fails() {
+        use crate::merkletree::sparse::{key_for, SparseMerkleTree};
+
+        let files = files(20);
+        let tree = SparseMerkleTree::from_files(files.clone());
+        let root = tree.root();
+
+        //a present file's leaf can't be passed off as the neighbour filling its slot
+        let mut proof = tree.prove(&files[0].0);
+        proof.other = Some((proof.key, *files[0].1.as_bytes()));
+        assert!(!proof.verify_non_inclusion(&root, &files[0].0));
+
+        //nor can a leaf from elsewhere in the tree
+        let mut proof = tree.prove("missing.txt");
+        let far = files
+            .iter()
+            .map(|(path, leaf)| (key_for(path), leaf))
+            .find(|(key, _)| key[0] >> 7 != key_for("missing.txt")[0] >> 7)
+            .unwrap();
+        proof.other = Some((far.0, *far.1.as_bytes()));
+        assert!(!proof.verify_non_inclusion(&root, "missing.txt"));
+    }
+}
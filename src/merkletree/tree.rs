@@ -1,6 +1,6 @@
+use crate::storage::TreeStore;
 use anyhow::Result;
 use blake3::{Hash, Hasher};
-use std::sync::Arc;
 
 pub struct FastMerkleTree(pub Vec<FastMerkleNode>);
 
@@ -13,57 +13,200 @@ pub const ZERO: [u8; 32] = [0; 32];
 pub const OFFSET_ONE: [u8; 4] = 1u32.to_le_bytes(); //for leaf nodes
 pub const OFFSET_TWO: [u8; 4] = 2u32.to_le_bytes(); //for inner nodes
 
-impl FastMerkleNode {
-    pub fn default() -> Self {
+impl Default for FastMerkleNode {
+    fn default() -> Self {
         FastMerkleNode {
             value: Hash::from_bytes(ZERO),
         }
     }
 }
 
-fn get_file_hashes(file_list: Vec<String>) -> Vec<Hash> {
-    //read files and return vec of file hashes
-    let mut file_hash_list: Vec<Hash> = Vec::new();
-    for file in file_list.clone() {
-        let file_content = std::fs::read(file.clone()).unwrap();
-        let mut hash = blake3::Hasher::new();
-        hash.update(&OFFSET_ONE);
-        hash.update(&file_content);
-        let hash = hash.finalize();
-        file_hash_list.push(hash);
+pub fn inner_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Hasher::new();
+    hasher.update(&OFFSET_TWO); //for inner nodes
+    hasher.update(left.as_bytes());
+    hasher.update(right.as_bytes());
+    hasher.finalize()
+}
+
+pub fn leaf_hash(file_content: &[u8]) -> Hash {
+    let mut hash = blake3::Hasher::new();
+    hash.update(&OFFSET_ONE);
+    hash.update(file_content);
+    hash.finalize()
+}
+
+// Fold the sibling hashes up from a leaf and compare against the root
+pub fn verify_leaf_proof(leaf: &Hash, proof: &[(Vec<u8>, bool)], root_hash: &[u8]) -> bool {
+    let mut current_hash = *leaf.as_bytes();
+
+    for (sibling_hash, is_left) in proof {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&OFFSET_TWO);
+        if *is_left {
+            hasher.update(sibling_hash);
+            hasher.update(&current_hash);
+        } else {
+            hasher.update(&current_hash);
+            hasher.update(sibling_hash);
+        }
+        current_hash = *hasher.finalize().as_bytes();
+    }
+    current_hash.as_slice() == root_hash
+}
+
+// Which leaf a proof is for, from the left/right turns it takes down from the root.
+// Every leaf sits at the same depth, so the proof's length gives the capacity.
+pub fn leaf_position(proof: &[(Vec<u8>, bool)]) -> Option<usize> {
+    let capacity = 1usize.checked_shl(proof.len() as u32)?;
+    let index = proof
+        .iter()
+        .rev()
+        .fold(0, |index, (_, is_left)| match is_left {
+            true => 2 * index + 2,
+            false => 2 * index + 1,
+        });
+    Some(index + 1 - capacity)
+}
+
+// Layout of the published root. Format 1 padded an odd leaf count with a copy of
+// the last leaf; format 2 pads with empty slots up to a power of two, so the same
+// files have a different root under each. Format 1 proofs are still served for
+// clients that pinned a root from before the change, see legacy_merkle_proof.
+pub const ROOT_FORMAT: u32 = 2;
+
+// Sibling hashes from a leaf up to the root, each with whether it is the left child
+pub type MerkleProof = Vec<(Vec<u8>, bool)>;
+
+// Hash of an empty subtree of each height, height 0 being an empty leaf slot
+pub fn empty_hashes(height: usize) -> Vec<Hash> {
+    let mut empty = vec![Hash::from_bytes(ZERO)];
+    for i in 0..height {
+        empty.push(inner_hash(&empty[i], &empty[i]));
     }
-    file_hash_list
+    empty
+}
+
+fn get_file_hashes(file_list: Vec<String>) -> Result<Vec<Hash>> {
+    //read and hash the files in parallel on the hashing pool
+    super::hashing::leaf_hash_files(&file_list)
 }
 
 impl FastMerkleTree {
-    // Build the Merkle tree as an array of hashes
-    pub fn build_merkle_tree(db: Arc<sled::Db>, file_list: Vec<String>) {
-        let _ = Self::store_file_list(db.clone(), file_list.clone());
+    // Build the Merkle tree for a list of files
+    pub fn build_merkle_tree(file_list: Vec<String>) -> Result<FastMerkleTree> {
+        Ok(Self::from_leaves(get_file_hashes(file_list)?))
+    }
 
-        let mut leaves = get_file_hashes(file_list);
-        let leaf_count = leaves.len();
-        //balance the tree
-        if leaf_count % 2 != 0 {
-            leaves.push(*leaves.last().unwrap());
-        }
-        let total_nodes = 2 * leaves.len() - 1;
+    // Build the Merkle tree as an array of hashes. Leaves fill the second half of
+    // the array, padded with empty slots up to a power of two so an append only
+    // rehashes the path above its slot.
+    pub fn from_leaves(leaves: Vec<Hash>) -> FastMerkleTree {
+        let capacity = leaves.len().next_power_of_two();
+        let total_nodes = 2 * capacity - 1;
         let mut tree: Vec<FastMerkleNode> = vec![FastMerkleNode::default(); total_nodes];
-        let leaf_start = leaves.len() - 1;
+        let leaf_start = capacity - 1;
         for (i, leaf_hash) in leaves.into_iter().enumerate() {
             tree[leaf_start + i] = FastMerkleNode { value: leaf_hash };
         }
         for i in (0..leaf_start).rev() {
             let left_child = &tree[2 * i + 1];
             let right_child = &tree[2 * i + 2];
-
-            let mut hasher = Hasher::new();
-            hasher.update(&OFFSET_TWO); //for inner nodes
-            hasher.update(left_child.value.as_bytes());
-            hasher.update(right_child.value.as_bytes());
-            let hash = hasher.finalize();
+            let hash = inner_hash(&left_child.value, &right_child.value);
             tree[i] = FastMerkleNode { value: hash };
         }
-        let _ = Self::store_merkle_tree(db, FastMerkleTree(tree));
+        FastMerkleTree(tree)
+    }
+
+    pub fn root(&self) -> Hash {
+        self.0[0].value
+    }
+
+    // Leaf slots, filled or not
+    pub fn capacity(&self) -> usize {
+        self.0.len().div_ceil(2)
+    }
+
+    // Leaves in file order, without the empty slots after them
+    pub fn leaves(&self, leaf_count: usize) -> Vec<Hash> {
+        //an empty store loads as a tree with no nodes at all
+        let leaf_start = self.capacity().saturating_sub(1);
+        self.0[leaf_start..leaf_start + leaf_count]
+            .iter()
+            .map(|node| node.value)
+            .collect()
+    }
+
+    pub fn leaf(&self, file_index: usize) -> Hash {
+        self.0[self.capacity() - 1 + file_index].value
+    }
+
+    // Overwrite one leaf slot and rehash its path to the root, returning the indices
+    // of every node that changed
+    pub fn set_leaf(&mut self, file_index: usize, leaf: Hash) -> Vec<usize> {
+        let mut index = self.capacity() - 1 + file_index;
+        let mut changed = vec![index];
+        self.0[index].value = leaf;
+        while index > 0 {
+            index = (index - 1) / 2;
+            self.0[index].value =
+                inner_hash(&self.0[2 * index + 1].value, &self.0[2 * index + 2].value);
+            changed.push(index);
+        }
+        changed
+    }
+
+    // Double the capacity: the tree becomes the left half of a new root with an
+    // empty right half. Leaves keep their file index but every node moves in the
+    // array, so this is O(n) once per doubling and appends stay O(log n) amortized.
+    pub fn grow(&mut self) {
+        let capacity = self.capacity();
+        let empty = empty_hashes(capacity.trailing_zeros() as usize);
+        let mut tree = vec![FastMerkleNode::default(); 4 * capacity - 1];
+        //each level of the old tree moves one level down, with empty nodes to its right
+        let (mut start, mut width) = (0, 1usize);
+        while start < self.0.len() {
+            let height = empty.len() - 1 - width.trailing_zeros() as usize;
+            tree[2 * start + 1..2 * start + 1 + width]
+                .clone_from_slice(&self.0[start..start + width]);
+            tree[2 * start + 1 + width..2 * start + 1 + 2 * width].fill(FastMerkleNode {
+                value: empty[height],
+            });
+            start += width;
+            width *= 2;
+        }
+        tree[0].value = inner_hash(&tree[1].value, &tree[2].value);
+        self.0 = tree;
+    }
+
+    pub fn get_merkle_proof(&self, file_index: usize) -> Vec<(Vec<u8>, bool)> {
+        let mut index = self.capacity() - 1 + file_index;
+        let mut proof = Vec::new();
+        while index > 0 {
+            let sibling_index = if index.is_multiple_of(2) {
+                index - 1
+            } else {
+                index + 1
+            };
+            let is_left = index.is_multiple_of(2);
+            proof.push((self.0[sibling_index].value.as_bytes().to_vec(), is_left));
+            index = (index - 1) / 2
+        }
+        proof
+    }
+
+    // Replace whatever tree is in the store with this one in a single atomic step,
+    // so readers see either the old tree or the new one and never a mix. The sparse
+    // root is of the sparse tree over the same files, committed alongside.
+    pub fn commit_merkle_tree(
+        &self,
+        store: &dyn TreeStore,
+        batch: &str,
+        file_list: Vec<String>,
+        sparse_root: &Hash,
+    ) -> Result<()> {
+        let nodes: Vec<Hash> = self.0.iter().map(|node| node.value).collect();
+        store.commit(batch, &nodes, &file_list, sparse_root)
     }
 
     #[allow(dead_code)]
@@ -81,92 +224,155 @@ impl FastMerkleTree {
         pretty_proof
     }
 
-    fn store_merkle_tree(db: Arc<sled::Db>, merkle_tree: FastMerkleTree) -> Result<()> {
-        //stores tree in db
-        let mut i: usize = 0;
-        for node in merkle_tree.0 {
-            db.insert(i.to_le_bytes(), node.value.as_bytes())?;
-            i += 1;
-        }
-        let tree_size: usize = i; // merkle_tree.0.len().try_into().unwrap();
-        let _ = db.insert(b"tree_size", &tree_size.to_le_bytes());
-        Ok(())
-    }
-
-    fn store_file_list(db: Arc<sled::Db>, file_list: Vec<String>) -> Result<()> {
-        let mut i: usize = 0;
-        for filename in file_list {
-            db.insert(filename.as_bytes(), &i.to_le_bytes())?;
-            i += 1;
-        }
-        //also store number of files
-        let num_of_files: usize = i; // merkle_tree.0.len().try_into().unwrap();
-        let _ = db.insert(b"num_of_files", &num_of_files.to_le_bytes());
-        Ok(())
-    }
-
     pub fn get_merkle_proof_from_db(
-        db: Arc<sled::Db>,
+        store: &dyn TreeStore,
         filename: String,
     ) -> Option<Vec<(Vec<u8>, bool)>> {
-        let mut tree_size: usize = 0;
-        if let Some(value) = db.get(b"tree_size").unwrap() {
-            tree_size = usize::from_le_bytes(value.as_ref().try_into().unwrap());
-        }
-        let mut file_index: usize = 0;
-        if let Some(value) = db.get(filename.as_bytes()).unwrap() {
-            file_index = usize::from_le_bytes(value.as_ref().try_into().unwrap());
-        }
-        let mut leaf_count: usize = 0;
-        if let Some(value) = db.get(b"num_of_files").unwrap() {
-            leaf_count = usize::from_le_bytes(value.as_ref().try_into().unwrap());
-            if leaf_count % 2 != 0 {
-                leaf_count += 1;
-            }
-        }
-        let mut index = tree_size - leaf_count + file_index;
+        let tree_size = store.tree_size().ok()??;
+        let file_index = store.file_index(&filename).ok()??;
+        let mut index = tree_size.div_ceil(2) - 1 + file_index;
         let mut proof = Vec::new();
 
         while index > 0 {
-            let sibling_index = if index % 2 == 0 { index - 1 } else { index + 1 };
-            if let Some(value) = db.get(sibling_index.to_le_bytes()).unwrap() {
-                let node = value.to_vec();
-                let is_left = index % 2 == 0;
-                proof.push((node, is_left));
-                index = (index - 1) / 2
-            }
+            let sibling_index = if index.is_multiple_of(2) {
+                index - 1
+            } else {
+                index + 1
+            };
+            let node = store.node(sibling_index).ok()??;
+            let is_left = index.is_multiple_of(2);
+            proof.push((node.as_bytes().to_vec(), is_left));
+            index = (index - 1) / 2
         }
         Some(proof)
     }
 
-    pub fn get_root_hash_from_leaves(leaves: Vec<Hash>) -> FastMerkleNode {
-        let mut leaves = leaves;
-        let leaf_count = leaves.len();
-        //balance the tree
-        if leaf_count % 2 != 0 {
-            leaves.push(*leaves.last().unwrap());
-        }
-        let total_nodes = 2 * leaves.len() - 1;
-        // add leaf nodes
-        let mut tree: Vec<FastMerkleNode> = vec![FastMerkleNode::default(); total_nodes];
-        let leaf_start = leaves.len() - 1;
-        for (i, leaf_hash) in leaves.into_iter().enumerate() {
-            tree[leaf_start + i] = FastMerkleNode { value: leaf_hash };
+    // Root and proof for one leaf in root format 1: leaves at the end of a 2n-1
+    // node array with an odd count padded by a copy of the last, so they don't all
+    // sit at the same depth. Rebuilt from the leaves on every call.
+    pub fn legacy_merkle_proof(leaves: &[Hash], file_index: usize) -> Option<(Hash, MerkleProof)> {
+        leaves.get(file_index)?;
+        let mut padded = leaves.to_vec();
+        if !padded.len().is_multiple_of(2) {
+            padded.push(padded[padded.len() - 1]);
         }
-        //populate internal nodes
+        let leaf_start = padded.len() - 1;
+        let mut tree = vec![Hash::from_bytes(ZERO); leaf_start];
+        tree.extend(padded);
         for i in (0..leaf_start).rev() {
-            let left_child = &tree[2 * i + 1];
-            let right_child = &tree[2 * i + 2];
+            tree[i] = inner_hash(&tree[2 * i + 1], &tree[2 * i + 2]);
+        }
+        let mut index = leaf_start + file_index;
+        let mut proof = Vec::new();
+        while index > 0 {
+            let sibling_index = if index.is_multiple_of(2) {
+                index - 1
+            } else {
+                index + 1
+            };
+            proof.push((
+                tree[sibling_index].as_bytes().to_vec(),
+                index.is_multiple_of(2),
+            ));
+            index = (index - 1) / 2
+        }
+        Some((tree[0], proof))
+    }
 
-            let mut hasher = blake3::Hasher::new();
-            hasher.update(&OFFSET_TWO); //for inner nodes
-            hasher.update(left_child.value.as_bytes());
-            hasher.update(right_child.value.as_bytes());
-            let hash = hasher.finalize();
-            tree[i] = FastMerkleNode { value: hash };
+    pub fn get_root_hash_from_leaves(leaves: Vec<Hash>) -> FastMerkleNode {
+        Self::from_leaves(leaves).0[0].clone()
+    }
+}
+// Solidity-compatible mode: keccak256 with sorted pairs, as checked by
+// OpenZeppelin's MerkleProof.sol. Leaves are double hashed and sorted and the
+// tree is laid out like @openzeppelin/merkle-tree's StandardMerkleTree, so the
+// root equals StandardMerkleTree.of(values, ["bytes32"]) for the same values.
+pub struct KeccakMerkleTree(pub Vec<[u8; 32]>);
+
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+    use tiny_keccak::{Hasher as _, Keccak};
+    let mut hasher = Keccak::v256();
+    let mut output = [0u8; 32];
+    hasher.update(data);
+    hasher.finalize(&mut output);
+    output
+}
+
+// keccak256(bytes.concat(keccak256(abi.encode(value)))) for one bytes32 value
+pub fn keccak_leaf(value: &[u8; 32]) -> [u8; 32] {
+    keccak256(&keccak256(value))
+}
+
+// MerkleProof's commutative pair hash: the smaller node goes first
+pub fn keccak_hash_pair(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let (first, second) = if a < b { (a, b) } else { (b, a) };
+    let mut data = [0u8; 64];
+    data[..32].copy_from_slice(first);
+    data[32..].copy_from_slice(second);
+    keccak256(&data)
+}
+
+// MerkleProof.verify
+pub fn verify_keccak_proof(leaf: &[u8; 32], proof: &[[u8; 32]], root: &[u8; 32]) -> bool {
+    proof
+        .iter()
+        .fold(*leaf, |node, sibling| keccak_hash_pair(&node, sibling))
+        == *root
+}
+
+// A bytes32 as 0x-prefixed hex, the way ethers and viem take it
+pub fn bytes32_hex(value: &[u8; 32]) -> String {
+    format!("0x{}", hex::encode(value))
+}
+
+impl KeccakMerkleTree {
+    // Tree over file leaves, each blake3 leaf hash going in as a bytes32 value
+    pub fn from_file_leaves(leaves: &[Hash]) -> KeccakMerkleTree {
+        Self::from_leaves(
+            leaves
+                .iter()
+                .map(|leaf| keccak_leaf(leaf.as_bytes()))
+                .collect(),
+        )
+    }
+
+    // Leaves fill the end of the heap array in reverse sorted order, as
+    // StandardMerkleTree does, so any leaf count works without padding
+    pub fn from_leaves(mut leaves: Vec<[u8; 32]>) -> KeccakMerkleTree {
+        if leaves.is_empty() {
+            return KeccakMerkleTree(Vec::new());
+        }
+        leaves.sort();
+        let size = 2 * leaves.len() - 1;
+        let mut tree = vec![[0u8; 32]; size];
+        for (i, leaf) in leaves.into_iter().enumerate() {
+            tree[size - 1 - i] = leaf;
+        }
+        for i in (0..size / 2).rev() {
+            tree[i] = keccak_hash_pair(&tree[2 * i + 1], &tree[2 * i + 2]);
         }
+        KeccakMerkleTree(tree)
+    }
+
+    pub fn root(&self) -> Option<[u8; 32]> {
+        self.0.first().copied()
+    }
 
-        tree[0].clone()
+    // Siblings from the leaf up, ready for MerkleProof.verify(proof, root, leaf)
+    pub fn get_proof(&self, leaf: &[u8; 32]) -> Option<Vec<[u8; 32]>> {
+        let leaf_count = self.0.len().div_ceil(2);
+        let mut index = (self.0.len() - leaf_count..self.0.len()).find(|&i| self.0[i] == *leaf)?;
+        let mut proof = Vec::new();
+        while index > 0 {
+            let sibling = if index.is_multiple_of(2) {
+                index - 1
+            } else {
+                index + 1
+            };
+            proof.push(self.0[sibling]);
+            index = (index - 1) / 2;
+        }
+        Some(proof)
     }
 }
 
@@ -183,9 +389,103 @@ mod tests {
             serde_json::from_str(test_file_hash_list_string).unwrap();
         let file_list: Vec<String> = get_file_list(TEST_DIR);
         let file_hashes = get_file_hashes(file_list)
+            .unwrap()
             .iter()
             .map(|h| h.as_bytes().to_vec())
             .collect::<Vec<_>>();
         assert_eq!(file_hashes, file_hash_list)
     }
+
+    #[test]
+    fn test_merkle_proof_from_store() {
+        use crate::fileserver::fs::get_file_list;
+        use crate::merkletree::tree::{FastMerkleTree, OFFSET_TWO};
+        use crate::storage::memory::MemoryTreeStore;
+        const TEST_DIR: &str = "./testfiles";
+
+        let store = MemoryTreeStore::default();
+        let file_list = get_file_list(TEST_DIR)[..5].to_vec();
+        let tree = FastMerkleTree::build_merkle_tree(file_list.clone()).unwrap();
+        tree.commit_merkle_tree(&store, "test", file_list.clone(), &tree.root())
+            .unwrap();
+
+        for (i, filename) in file_list.iter().enumerate() {
+            let proof = FastMerkleTree::get_merkle_proof_from_db(&store, filename.clone()).unwrap();
+            let mut current_hash = tree.leaves(file_list.len())[i];
+            for (sibling_hash, is_left) in proof {
+                let mut hasher = blake3::Hasher::new();
+                hasher.update(&OFFSET_TWO);
+                if is_left {
+                    hasher.update(&sibling_hash);
+                    hasher.update(current_hash.as_bytes());
+                } else {
+                    hasher.update(current_hash.as_bytes());
+                    hasher.update(&sibling_hash);
+                }
+                current_hash = hasher.finalize();
+            }
+            assert_eq!(current_hash, tree.root());
+        }
+        assert!(FastMerkleTree::get_merkle_proof_from_db(&store, "missing".into()).is_none());
+    }
+
+    #[test]
+    fn test_legacy_proofs_match_format_1_roots() {
+        use crate::merkletree::tree::{inner_hash, leaf_hash, verify_leaf_proof, FastMerkleTree};
+
+        let leaves: Vec<_> = (0..5u8).map(|i| leaf_hash(&[i])).collect();
+        let [a, b, c, d, e] = leaves[..] else {
+            unreachable!()
+        };
+        //the last leaf doubled and nodes paired off from the end of the array
+        let expected = inner_hash(
+            &inner_hash(&inner_hash(&c, &d), &inner_hash(&e, &e)),
+            &inner_hash(&a, &b),
+        );
+        for (i, leaf) in leaves.iter().enumerate() {
+            let (root, proof) = FastMerkleTree::legacy_merkle_proof(&leaves, i).unwrap();
+            assert_eq!(root, expected);
+            assert!(verify_leaf_proof(leaf, &proof, root.as_bytes()));
+        }
+        assert!(FastMerkleTree::legacy_merkle_proof(&leaves, 5).is_none());
+        let (root, _) = FastMerkleTree::legacy_merkle_proof(&leaves[..1], 0).unwrap();
+        assert_eq!(root, inner_hash(&a, &a));
+    }
+
+    #[test]
+    fn test_keccak_tree_matches_openzeppelin() {
+        use crate::merkletree::tree::{keccak256, verify_keccak_proof, KeccakMerkleTree};
+
+        assert_eq!(
+            hex::encode(keccak256(b"")),
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+        //the example in @openzeppelin/merkle-tree's readme, leaves abi.encode(address, uint256)
+        let leaf = |address: u8, amount: u128| {
+            let mut encoded = [0u8; 64];
+            encoded[12..32].fill(address);
+            encoded[48..].copy_from_slice(&amount.to_be_bytes());
+            keccak256(&keccak256(&encoded))
+        };
+        let leaves = vec![
+            leaf(0x11, 5_000_000_000_000_000_000),
+            leaf(0x22, 2_500_000_000_000_000_000),
+        ];
+        let tree = KeccakMerkleTree::from_leaves(leaves.clone());
+        let root = tree.root().unwrap();
+        assert_eq!(
+            hex::encode(root),
+            "d4dee0beab2d53f2cc83e567171bd2820e49898130a22622b10ead383e90bd77"
+        );
+
+        let leaves: Vec<[u8; 32]> = (0..7u8).map(|i| keccak256(&[i])).collect();
+        let tree = KeccakMerkleTree::from_leaves(leaves.clone());
+        let root = tree.root().unwrap();
+        for leaf in &leaves {
+            let proof = tree.get_proof(leaf).unwrap();
+            assert!(verify_keccak_proof(leaf, &proof, &root));
+            assert!(!verify_keccak_proof(&keccak256(b"other"), &proof, &root));
+        }
+        assert!(tree.get_proof(&root).is_none());
+    }
 }
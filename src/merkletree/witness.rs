@@ -0,0 +1,200 @@
+use super::poseidon::{field_leaf, PoseidonTree};
+use super::tree::FastMerkleTree;
+use crate::metrics::metrics;
+use crate::storage::TreeStore;
+use anyhow::{anyhow, Result};
+use ark_bn254::Fr;
+use blake3::Hash;
+use serde::{Deserialize, Serialize};
+
+// Membership inputs in the shape circom and noir merkle circuits take. Field
+// elements are decimal strings, which snarkjs input.json and noir's Prover.toml
+// both accept. pathIndices[i] is 1 when the node at height i is a right child.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MembershipWitness<T> {
+    pub leaf: T,
+    pub path_elements: Vec<T>,
+    pub path_indices: Vec<u8>,
+    pub root: T,
+}
+
+// A blake3 hash doesn't fit in a BN254 field element, so it goes in as its
+// big-endian high and low 128 bits
+pub type Limbs = [String; 2];
+
+pub fn hash_limbs(hash: &[u8]) -> Limbs {
+    let mut high = [0u8; 16];
+    let mut low = [0u8; 16];
+    high.copy_from_slice(&hash[..16]);
+    low.copy_from_slice(&hash[16..32]);
+    [
+        u128::from_be_bytes(high).to_string(),
+        u128::from_be_bytes(low).to_string(),
+    ]
+}
+
+impl<T: Serialize> MembershipWitness<T> {
+    // Noir names circuit inputs in snake_case; JSON strings and arrays are valid TOML
+    pub fn to_prover_toml(&self) -> Result<String> {
+        Ok(format!(
+            "leaf = {}\npath_elements = {}\npath_indices = {}\nroot = {}\n",
+            serde_json::to_string(&self.leaf)?,
+            serde_json::to_string(&self.path_elements)?,
+            serde_json::to_string(&self.path_indices)?,
+            serde_json::to_string(&self.root)?,
+        ))
+    }
+}
+
+impl MembershipWitness<Limbs> {
+    // The blake3 tree as stored, from a proof as returned by get_merkle_proof_from_db
+    pub fn blake3(leaf: &Hash, proof: &[(Vec<u8>, bool)], root: &Hash) -> Self {
+        MembershipWitness {
+            leaf: hash_limbs(leaf.as_bytes()),
+            path_elements: proof.iter().map(|(node, _)| hash_limbs(node)).collect(),
+            //a sibling on the left means this node is the right child
+            path_indices: proof.iter().map(|(_, is_left)| *is_left as u8).collect(),
+            root: hash_limbs(root.as_bytes()),
+        }
+    }
+
+    pub fn blake3_from_db(store: &dyn TreeStore, filename: &str) -> Result<Option<Self>> {
+        let timer = metrics().store_proof_seconds.start_timer();
+        let proof = FastMerkleTree::get_merkle_proof_from_db(store, filename.to_string());
+        timer.observe_duration();
+        let Some(proof) = proof else {
+            return Ok(None);
+        };
+        let (Some(index), Some(tree_size)) = (store.file_index(filename)?, store.tree_size()?)
+        else {
+            return Ok(None);
+        };
+        let leaf = store.node(tree_size.div_ceil(2) - 1 + index)?;
+        let root = store.node(0)?;
+        match (leaf, root) {
+            (Some(leaf), Some(root)) => Ok(Some(Self::blake3(&leaf, &proof, &root))),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl MembershipWitness<String> {
+    // The same leaves in a Poseidon tree of fixed depth, for circuits that can't
+    // afford blake3. Each leaf is its blake3 leaf hash reduced into the field.
+    pub fn poseidon(leaves: &[Hash], index: usize, depth: usize) -> Result<Self> {
+        let field_leaves: Vec<Fr> = leaves.iter().map(field_leaf).collect();
+        let tree = PoseidonTree::from_leaves(depth, &field_leaves)?;
+        let leaf = field_leaves
+            .get(index)
+            .ok_or_else(|| anyhow!("Leaf {} out of range", index))?;
+        Ok(MembershipWitness {
+            leaf: leaf.to_string(),
+            path_elements: tree.path(index)?.iter().map(Fr::to_string).collect(),
+            path_indices: (0..depth)
+                .map(|height| ((index >> height) & 1) as u8)
+                .collect(),
+            root: tree.root().to_string(),
+        })
+    }
+
+    pub fn poseidon_from_db(
+        store: &dyn TreeStore,
+        filename: &str,
+        depth: usize,
+    ) -> Result<Option<Self>> {
+        let (Some(index), Some(leaf_count)) = (store.file_index(filename)?, store.num_of_files()?)
+        else {
+            return Ok(None);
+        };
+        let nodes = store.nodes()?;
+        let tree = FastMerkleTree(
+            nodes
+                .into_iter()
+                .map(|value| super::tree::FastMerkleNode { value })
+                .collect(),
+        );
+        Ok(Some(Self::poseidon(
+            &tree.leaves(leaf_count),
+            index,
+            depth,
+        )?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_witnesses_rebuild_the_root() {
+        use crate::merkletree::poseidon::{hash2, PoseidonTree};
+        use crate::merkletree::tree::{inner_hash, leaf_hash, FastMerkleTree};
+        use crate::merkletree::witness::{hash_limbs, MembershipWitness};
+        use crate::storage::memory::MemoryTreeStore;
+        use ark_bn254::Fr;
+        use std::str::FromStr;
+
+        let names: Vec<String> = (0..5).map(|i| format!("file{}", i)).collect();
+        let leaves: Vec<blake3::Hash> = names.iter().map(|n| leaf_hash(n.as_bytes())).collect();
+        let tree = FastMerkleTree::from_leaves(leaves.clone());
+        let store = MemoryTreeStore::default();
+        tree.commit_merkle_tree(&store, "batch", names, &tree.root())
+            .unwrap();
+
+        let poseidon = MembershipWitness::poseidon_from_db(&store, "file3", 4)
+            .unwrap()
+            .unwrap();
+        let field = |s: &String| Fr::from_str(s).unwrap();
+        let root = poseidon
+            .path_elements
+            .iter()
+            .zip(&poseidon.path_indices)
+            .fold(
+                field(&poseidon.leaf),
+                |node, (sibling, index)| match index {
+                    1 => hash2(&field(sibling), &node),
+                    _ => hash2(&node, &field(sibling)),
+                },
+            );
+        assert_eq!(root, field(&poseidon.root));
+        assert_eq!(poseidon.path_indices, vec![1, 1, 0, 0]);
+        let field_leaves: Vec<Fr> = leaves
+            .iter()
+            .map(crate::merkletree::poseidon::field_leaf)
+            .collect();
+        assert_eq!(
+            PoseidonTree::from_leaves(4, &field_leaves).unwrap().root(),
+            root
+        );
+        assert!(MembershipWitness::poseidon(&leaves, 0, 2).is_err());
+
+        let blake3 = MembershipWitness::blake3_from_db(&store, "file3")
+            .unwrap()
+            .unwrap();
+        assert_eq!(blake3.leaf, hash_limbs(leaves[3].as_bytes()));
+        assert_eq!(blake3.root, hash_limbs(tree.root().as_bytes()));
+        //file3 is the right child of (file2, file3)
+        assert_eq!(blake3.path_indices[0], 1);
+        assert_eq!(blake3.path_elements[0], hash_limbs(leaves[2].as_bytes()));
+        let unlimb = |limbs: &[String; 2]| {
+            let mut bytes = [0u8; 32];
+            bytes[..16].copy_from_slice(&limbs[0].parse::<u128>().unwrap().to_be_bytes());
+            bytes[16..].copy_from_slice(&limbs[1].parse::<u128>().unwrap().to_be_bytes());
+            blake3::Hash::from(bytes)
+        };
+        let root = blake3.path_elements.iter().zip(&blake3.path_indices).fold(
+            unlimb(&blake3.leaf),
+            |node, (sibling, index)| match index {
+                1 => inner_hash(&unlimb(sibling), &node),
+                _ => inner_hash(&node, &unlimb(sibling)),
+            },
+        );
+        assert_eq!(root, tree.root());
+        assert!(MembershipWitness::blake3_from_db(&store, "missing")
+            .unwrap()
+            .is_none());
+
+        let toml = poseidon.to_prover_toml().unwrap();
+        assert!(toml.contains("path_indices = [1,1,0,0]"));
+        assert!(toml.starts_with(&format!("leaf = \"{}\"", poseidon.leaf)));
+    }
+}
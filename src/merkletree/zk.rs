@@ -0,0 +1,374 @@
+use super::poseidon::{self, PoseidonTree, MAX_DEPTH};
+use anyhow::{anyhow, bail, ensure, Result};
+use ark_bn254::{Bn254, Fr};
+use ark_ff::AdditiveGroup;
+use ark_groth16::{Groth16, Proof, ProvingKey, VerifyingKey};
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::prelude::*;
+use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use ark_snark::SNARK;
+use ark_std::rand::rngs::StdRng;
+use ark_std::rand::{CryptoRng, RngCore, SeedableRng};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::str::FromStr;
+
+pub const ZK_USAGE: &str =
+    "Usage: cargo run zk setup <depth> <proving-key-out> <verifying-key-out>";
+
+// Prototype zero-knowledge proof that a Poseidon tree was only appended to:
+// new_root is old_root with the new leaves written into the empty slots
+// old_count, old_count + 1, ... in order. The verifier learns nothing about the
+// leaves that were already there beyond old_root.
+//
+// Groth16 needs a setup per circuit shape (tree depth and batch size), and
+// whoever runs it can forge proofs, so the verifier should run setup and hand
+// the proving key to the server.
+#[derive(Clone)]
+pub struct AppendCircuit {
+    pub depth: usize,
+    pub old_root: Fr,
+    pub new_root: Fr,
+    pub old_count: Fr,
+    pub new_leaves: Vec<Fr>,
+    // Siblings of each new leaf's slot at the moment it was written
+    pub paths: Vec<Vec<Fr>>,
+}
+
+pub struct AppendProof {
+    pub old_root: Fr,
+    pub new_root: Fr,
+    pub old_count: u64,
+    pub proof: Proof<Bn254>,
+}
+
+impl AppendCircuit {
+    // Circuit of the right shape with placeholder values, for key generation
+    pub fn blank(depth: usize, batch: usize) -> Self {
+        AppendCircuit {
+            depth,
+            old_root: Fr::ZERO,
+            new_root: Fr::ZERO,
+            old_count: Fr::ZERO,
+            new_leaves: vec![Fr::ZERO; batch],
+            paths: vec![vec![Fr::ZERO; depth]; batch],
+        }
+    }
+}
+
+// Same permutation as poseidon::hash2, as constraints
+fn hash2_var(left: &FpVar<Fr>, right: &FpVar<Fr>) -> Result<FpVar<Fr>, SynthesisError> {
+    let params = poseidon::params();
+    let mut state = [FpVar::zero(), left.clone(), right.clone()];
+    let half_rounds = params.full_rounds / 2;
+    for round in 0..params.full_rounds + params.partial_rounds {
+        for (i, element) in state.iter_mut().enumerate() {
+            *element += params.ark[round * params.width + i];
+        }
+        let full = round < half_rounds || round >= half_rounds + params.partial_rounds;
+        for element in state.iter_mut().take(if full { 3 } else { 1 }) {
+            let square = element.square()?;
+            *element = square.square()? * &*element;
+        }
+        state = std::array::from_fn(|i| {
+            (0..3).fold(FpVar::zero(), |acc, j| acc + &state[j] * params.mds[i][j])
+        });
+    }
+    let [output, _, _] = state;
+    Ok(output)
+}
+
+// Root from a leaf, the low bits of its index and its siblings
+fn root_var(
+    leaf: &FpVar<Fr>,
+    bits: &[Boolean<Fr>],
+    siblings: &[FpVar<Fr>],
+) -> Result<FpVar<Fr>, SynthesisError> {
+    let mut node = leaf.clone();
+    for (bit, sibling) in bits.iter().zip(siblings) {
+        let left = bit.select(sibling, &node)?;
+        let right = bit.select(&node, sibling)?;
+        node = hash2_var(&left, &right)?;
+    }
+    Ok(node)
+}
+
+impl ConstraintSynthesizer<Fr> for AppendCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        //public inputs: old_root, new_root, old_count, then the new leaves
+        let old_root = FpVar::new_input(cs.clone(), || Ok(self.old_root))?;
+        let new_root = FpVar::new_input(cs.clone(), || Ok(self.new_root))?;
+        let old_count = FpVar::new_input(cs.clone(), || Ok(self.old_count))?;
+        let mut leaves = Vec::new();
+        for leaf in &self.new_leaves {
+            leaves.push(FpVar::new_input(cs.clone(), || Ok(*leaf))?);
+        }
+
+        let mut root = old_root;
+        for (i, (leaf, path)) in leaves.iter().zip(&self.paths).enumerate() {
+            let index = &old_count + Fr::from(i as u64);
+            let bits = index.to_bits_le()?;
+            //the slot has to be inside the tree
+            for bit in &bits[self.depth..] {
+                bit.enforce_equal(&Boolean::FALSE)?;
+            }
+            let mut siblings = Vec::new();
+            for sibling in path {
+                siblings.push(FpVar::new_witness(cs.clone(), || Ok(*sibling))?);
+            }
+            //the slot was empty under the current root, and the leaf goes there
+            root_var(&FpVar::zero(), &bits[..self.depth], &siblings)?.enforce_equal(&root)?;
+            root = root_var(leaf, &bits[..self.depth], &siblings)?;
+        }
+        root.enforce_equal(&new_root)
+    }
+}
+
+fn public_inputs(old_root: Fr, new_root: Fr, old_count: u64, new_leaves: &[Fr]) -> Vec<Fr> {
+    let mut inputs = vec![old_root, new_root, Fr::from(old_count)];
+    inputs.extend_from_slice(new_leaves);
+    inputs
+}
+
+// Proving and verifying keys for appending batch leaves to a tree of this depth
+pub fn setup<R: RngCore + CryptoRng>(
+    depth: usize,
+    batch: usize,
+    rng: &mut R,
+) -> Result<(ProvingKey<Bn254>, VerifyingKey<Bn254>)> {
+    Groth16::<Bn254>::circuit_specific_setup(AppendCircuit::blank(depth, batch), rng)
+        .map_err(|e| anyhow!("Setup failed: {}", e))
+}
+
+// Append the leaves to the tree and prove it was done correctly
+pub fn prove_append<R: RngCore + CryptoRng>(
+    pk: &ProvingKey<Bn254>,
+    tree: &mut PoseidonTree,
+    new_leaves: &[Fr],
+    rng: &mut R,
+) -> Result<AppendProof> {
+    ensure!(
+        pk.vk.gamma_abc_g1.len() == 4 + new_leaves.len(),
+        "Proving key is for a different batch size"
+    );
+    let old_root = tree.root();
+    let old_count = tree.leaf_count();
+    let mut updated = tree.clone();
+    let mut paths = Vec::new();
+    for leaf in new_leaves {
+        paths.push(updated.path(updated.leaf_count())?);
+        updated.append(*leaf)?;
+    }
+    let circuit = AppendCircuit {
+        depth: tree.depth(),
+        old_root,
+        new_root: updated.root(),
+        old_count: Fr::from(old_count as u64),
+        new_leaves: new_leaves.to_vec(),
+        paths,
+    };
+    let proof =
+        Groth16::<Bn254>::prove(pk, circuit, rng).map_err(|e| anyhow!("Proving failed: {}", e))?;
+    *tree = updated;
+    Ok(AppendProof {
+        old_root,
+        new_root: tree.root(),
+        old_count: old_count as u64,
+        proof,
+    })
+}
+
+// Client side: check new_root is old_root with exactly these leaves appended
+pub fn verify_append(
+    vk: &VerifyingKey<Bn254>,
+    old_root: Fr,
+    new_root: Fr,
+    old_count: u64,
+    new_leaves: &[Fr],
+    proof: &Proof<Bn254>,
+) -> Result<bool> {
+    ensure!(
+        vk.gamma_abc_g1.len() == 4 + new_leaves.len(),
+        "Verifying key is for a different batch size"
+    );
+    let inputs = public_inputs(old_root, new_root, old_count, new_leaves);
+    Groth16::<Bn254>::verify(vk, &inputs, proof).map_err(|e| anyhow!("Verify failed: {}", e))
+}
+
+// Proving key for one-leaf appends to a tree of this depth, as the server loads it
+pub struct AppendKey {
+    pub depth: usize,
+    pub pk: ProvingKey<Bn254>,
+}
+
+// Appends from old_count leaves up to the current tree, one proof per leaf so a
+// single key covers any number of them. Field elements are decimal strings, as
+// in /witness, and proofs are compressed and hex encoded.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AppendChain {
+    pub depth: usize,
+    pub old_count: u64,
+    pub old_root: String,
+    pub steps: Vec<AppendStep>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AppendStep {
+    pub file: String,
+    pub leaf: String,
+    pub new_root: String,
+    pub proof: String,
+}
+
+// Groth16 randomness, seeded from the system source
+fn rng() -> Result<StdRng> {
+    let mut seed = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut seed)
+        .map_err(|_| anyhow!("System random source failed"))?;
+    Ok(StdRng::from_seed(seed))
+}
+
+fn parse_field(value: &str) -> Result<Fr> {
+    Fr::from_str(value.trim()).map_err(|_| anyhow!("Not a field element: {}", value))
+}
+
+// Keys are stored as the depth followed by the compressed key
+pub fn write_key<K: CanonicalSerialize>(path: &Path, depth: usize, key: &K) -> Result<()> {
+    let mut bytes = Vec::new();
+    (depth as u64).serialize_compressed(&mut bytes)?;
+    key.serialize_compressed(&mut bytes)?;
+    std::fs::write(path, bytes)?;
+    Ok(())
+}
+
+pub fn read_key<K: CanonicalDeserialize>(path: &Path) -> Result<(usize, K)> {
+    let bytes = std::fs::read(path)?;
+    let mut reader = bytes.as_slice();
+    let depth = u64::deserialize_compressed(&mut reader)? as usize;
+    ensure!(
+        (1..=MAX_DEPTH).contains(&depth),
+        "Key is for depth {}",
+        depth
+    );
+    Ok((depth, K::deserialize_compressed(&mut reader)?))
+}
+
+// Prove the tree of all leaves is the tree of the first old_count with the rest
+// appended, leaf by leaf
+pub fn prove_chain(
+    key: &AppendKey,
+    files: &[String],
+    leaves: &[Fr],
+    old_count: usize,
+) -> Result<AppendChain> {
+    ensure!(old_count <= leaves.len(), "Only {} files", leaves.len());
+    let mut rng = rng()?;
+    let mut tree = PoseidonTree::from_leaves(key.depth, &leaves[..old_count])?;
+    let old_root = tree.root();
+    let mut steps = Vec::new();
+    for (file, leaf) in files[old_count..].iter().zip(&leaves[old_count..]) {
+        let append = prove_append(&key.pk, &mut tree, &[*leaf], &mut rng)?;
+        let mut proof = Vec::new();
+        append.proof.serialize_compressed(&mut proof)?;
+        steps.push(AppendStep {
+            file: file.clone(),
+            leaf: leaf.to_string(),
+            new_root: append.new_root.to_string(),
+            proof: hex::encode(proof),
+        });
+    }
+    Ok(AppendChain {
+        depth: key.depth,
+        old_count: old_count as u64,
+        old_root: old_root.to_string(),
+        steps,
+    })
+}
+
+// Client side: check the chain starts at the root the client already has and
+// every step is a proven one-leaf append. Returns the new root.
+pub fn verify_chain(
+    vk: &VerifyingKey<Bn254>,
+    chain: &AppendChain,
+    old_root: &Fr,
+    old_count: u64,
+) -> Result<Fr> {
+    if parse_field(&chain.old_root)? != *old_root || chain.old_count != old_count {
+        bail!("Server started from a different tree");
+    }
+    let mut root = *old_root;
+    for (i, step) in chain.steps.iter().enumerate() {
+        let new_root = parse_field(&step.new_root)?;
+        let leaf = parse_field(&step.leaf)?;
+        let proof = Proof::<Bn254>::deserialize_compressed(hex::decode(&step.proof)?.as_slice())?;
+        let count = old_count + i as u64;
+        if !verify_append(vk, root, new_root, count, &[leaf], &proof)? {
+            bail!("Append of {} doesn't verify", step.file);
+        }
+        root = new_root;
+    }
+    Ok(root)
+}
+
+// `zk setup` makes a key pair for one-leaf appends. Whoever runs it can forge
+// proofs, so the verifier runs it and hands the proving key to the server.
+pub fn run(args: &[String]) -> Result<()> {
+    let [command, depth, pk_path, vk_path] = args else {
+        bail!(ZK_USAGE);
+    };
+    if command != "setup" {
+        bail!(ZK_USAGE);
+    }
+    let depth: usize = depth.parse()?;
+    ensure!(
+        (1..=MAX_DEPTH).contains(&depth),
+        "Depth has to be between 1 and {}",
+        MAX_DEPTH
+    );
+    let (pk, vk) = setup(depth, 1, &mut rng()?)?;
+    write_key(Path::new(pk_path), depth, &pk)?;
+    write_key(Path::new(vk_path), depth, &vk)?;
+    println!("Proving key for the server: {}", pk_path);
+    println!("Verifying key for clients: {}", vk_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_prove_and_verify_append() {
+        use crate::merkletree::poseidon::{field_leaf, PoseidonTree};
+        use crate::merkletree::tree::leaf_hash;
+        use crate::merkletree::zk::{prove_append, setup, verify_append};
+        use ark_bn254::Fr;
+        use ark_std::rand::rngs::StdRng;
+        use ark_std::rand::SeedableRng;
+
+        let leaf = |i: u32| field_leaf(&leaf_hash(&i.to_le_bytes()));
+        let mut rng = StdRng::seed_from_u64(7);
+        let (pk, vk) = setup(4, 2, &mut rng).unwrap();
+
+        let mut tree = PoseidonTree::from_leaves(4, &[leaf(0), leaf(1), leaf(2)]).unwrap();
+        let new_leaves = [leaf(3), leaf(4)];
+        let append = prove_append(&pk, &mut tree, &new_leaves, &mut rng).unwrap();
+        assert_eq!(append.new_root, tree.root());
+        assert_eq!(tree.leaf_count(), 5);
+
+        let verify = |old_root: Fr, new_root: Fr, old_count: u64, leaves: &[Fr]| {
+            verify_append(&vk, old_root, new_root, old_count, leaves, &append.proof).unwrap()
+        };
+        assert!(verify(append.old_root, append.new_root, 3, &new_leaves));
+        //any other claim about the append fails
+        assert!(!verify(append.old_root, append.new_root, 2, &new_leaves));
+        assert!(!verify(
+            append.old_root,
+            append.new_root,
+            3,
+            &[leaf(4), leaf(3)]
+        ));
+        assert!(!verify(append.new_root, append.new_root, 3, &new_leaves));
+    }
+}
@@ -0,0 +1,168 @@
+use crate::fileserver::audit::AuditLog;
+use crate::fileserver::auth::Auth;
+use crate::storage::Store;
+use anyhow::Result;
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::convert::Infallible;
+use std::sync::{Arc, OnceLock};
+use warp::http::StatusCode;
+use warp::reply::Reply;
+
+// Counters and histograms served at /metrics, all prefixed merkle_. They live in
+// one process-wide registry so the tree code can record without a handle.
+pub struct Metrics {
+    registry: Registry,
+    pub upload_bytes: IntCounter,
+    pub upload_files: Histogram,
+    pub tree_build_seconds: Histogram,
+    // proof_seconds by where the tree was read from
+    pub memory_proof_seconds: Histogram,
+    pub store_proof_seconds: Histogram,
+    pub download_bytes: IntCounter,
+    rejections: IntCounterVec,
+    responses: IntCounterVec,
+    // Set when scraped
+    pub tree_files: IntGauge,
+    pub sled_bytes: IntGaugeVec,
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("failed to register metrics"))
+}
+
+impl Metrics {
+    fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("merkle".to_string()), None)?;
+        let upload_bytes = IntCounter::new(
+            "upload_bytes_total",
+            "Bytes in uploaded batches that were committed",
+        )?;
+        let upload_files = Histogram::with_opts(
+            HistogramOpts::new("upload_batch_files", "Files per committed upload batch")
+                .buckets(exponential_buckets(1.0, 4.0, 9)?),
+        )?;
+        let tree_build_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "tree_build_seconds",
+                "Time to build a merkle tree from its leaf hashes",
+            )
+            .buckets(exponential_buckets(0.0001, 4.0, 10)?),
+        )?;
+        let proof_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "proof_seconds",
+                "Time to read an inclusion proof from the in-memory tree or the tree store",
+            )
+            .buckets(exponential_buckets(0.000001, 4.0, 10)?),
+            &["source"],
+        )?;
+        let download_bytes = IntCounter::new("download_bytes_total", "Bytes sent by downloads")?;
+        let rejections = IntCounterVec::new(
+            Opts::new("rejections_total", "Requests turned away, by reason"),
+            &["reason"],
+        )?;
+        let responses = IntCounterVec::new(
+            Opts::new("http_responses_total", "Responses sent, by status code"),
+            &["status"],
+        )?;
+        let tree_files = IntGauge::new("tree_files", "Files under the current root")?;
+        let sled_bytes = IntGaugeVec::new(
+            Opts::new("sled_size_bytes", "Disk space used by each sled database"),
+            &["db"],
+        )?;
+        registry.register(Box::new(upload_bytes.clone()))?;
+        registry.register(Box::new(upload_files.clone()))?;
+        registry.register(Box::new(tree_build_seconds.clone()))?;
+        registry.register(Box::new(proof_seconds.clone()))?;
+        registry.register(Box::new(download_bytes.clone()))?;
+        registry.register(Box::new(rejections.clone()))?;
+        registry.register(Box::new(responses.clone()))?;
+        registry.register(Box::new(tree_files.clone()))?;
+        registry.register(Box::new(sled_bytes.clone()))?;
+        Ok(Metrics {
+            registry,
+            upload_bytes,
+            upload_files,
+            tree_build_seconds,
+            //resolve the labels once, proofs from memory take well under a microsecond
+            memory_proof_seconds: proof_seconds.with_label_values(&["memory"]),
+            store_proof_seconds: proof_seconds.with_label_values(&["store"]),
+            download_bytes,
+            rejections,
+            responses,
+            tree_files,
+            sled_bytes,
+        })
+    }
+
+    pub fn reject(&self, reason: &str) {
+        self.rejections.with_label_values(&[reason]).inc();
+    }
+
+    pub fn respond(&self, status: u16) {
+        self.responses
+            .with_label_values(&[&status.to_string()])
+            .inc();
+    }
+
+    // Everything in the Prometheus text format
+    pub fn encode(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+// Refresh the gauges, then export everything
+pub async fn handle_metrics(
+    store: Arc<Store>,
+    auth: Arc<Auth>,
+    audit: Arc<AuditLog>,
+) -> Result<warp::reply::Response, Infallible> {
+    let metrics = metrics();
+    let files = store.cache.read().await.file_list().len();
+    metrics.tree_files.set(files as i64);
+    let sizes = [
+        ("tree", store.tree.size_on_disk()),
+        ("auth", auth.size_on_disk().map(Some)),
+        ("audit", audit.size_on_disk().map(Some)),
+    ];
+    for (db, size) in sizes {
+        match size {
+            Ok(Some(size)) => metrics.sled_bytes.with_label_values(&[db]).set(size as i64),
+            Ok(None) => {}
+            Err(e) => tracing::warn!(db, error = %format!("{:#}", e), "Failed to read a sled size"),
+        }
+    }
+    Ok(match metrics.encode() {
+        Ok(text) => {
+            warp::reply::with_header(text, "Content-Type", TextEncoder::new().format_type())
+                .into_response()
+        }
+        Err(e) => {
+            tracing::error!(error = %format!("{:#}", e), "Failed to encode the metrics");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_metrics_are_exported_in_text_format() {
+        use crate::metrics::metrics;
+
+        metrics().memory_proof_seconds.observe(0.0);
+        metrics().reject("root_mismatch");
+
+        let text = metrics().encode().unwrap();
+        assert!(text.contains("# TYPE merkle_tree_build_seconds histogram"));
+        assert!(text.contains("merkle_proof_seconds_count{source=\"memory\"}"));
+        assert!(text.contains("merkle_rejections_total{reason=\"root_mismatch\"}"));
+        assert!(text.contains("merkle_upload_bytes_total"));
+    }
+}
@@ -0,0 +1,40 @@
+use crate::storage::env_or;
+use std::time::Duration;
+
+// Resolves on SIGTERM or Ctrl-C, when the servers stop taking connections
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => tracing::info!("Received SIGTERM, shutting down"),
+                    _ = tokio::signal::ctrl_c() => tracing::info!("Received Ctrl-C, shutting down"),
+                }
+                return;
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "Cannot listen for SIGTERM, only Ctrl-C stops the server")
+            }
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        tracing::error!(error = %e, "Cannot listen for Ctrl-C");
+        //never resolve rather than shut down straight away
+        std::future::pending::<()>().await;
+    }
+    tracing::info!("Received Ctrl-C, shutting down");
+}
+
+// How long requests still running at shutdown get to finish, MERKLE_SHUTDOWN_GRACE_SECS.
+// The default stays under the 30 seconds Kubernetes waits before killing the process.
+pub fn grace_period() -> Duration {
+    let secs = env_or("MERKLE_SHUTDOWN_GRACE_SECS", "25")
+        .parse()
+        .unwrap_or_else(|e| {
+            tracing::warn!(error = %e, "Invalid MERKLE_SHUTDOWN_GRACE_SECS, using 25");
+            25
+        });
+    Duration::from_secs(secs)
+}
@@ -0,0 +1,328 @@
+use super::{is_valid_name, BlobReader, BlobStore};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+pub const UPLOAD_DIR: &str = "filestore/";
+pub const STAGING_DIR: &str = "filestore_staging/";
+
+// Files on the local disk. Batches are staged in a sibling folder on the same
+// filesystem so swapping one in is two directory renames.
+pub struct LocalBlobStore {
+    root: PathBuf,
+    staging: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub fn new(root: impl AsRef<Path>, staging: impl AsRef<Path>) -> Self {
+        LocalBlobStore {
+            root: root.as_ref().to_path_buf(),
+            staging: staging.as_ref().to_path_buf(),
+        }
+    }
+
+    fn staged_path(&self, batch: &str) -> PathBuf {
+        self.staging.join(batch)
+    }
+
+    fn retired_path(&self, batch: &str) -> PathBuf {
+        self.staging.join(format!("{}.old", batch))
+    }
+
+    // Holds replaced/, the live files a merge moved aside, and added/, an empty
+    // marker for each name it added
+    fn merged_path(&self, batch: &str) -> PathBuf {
+        self.staging.join(format!("{}.merged", batch))
+    }
+
+    // Names the swap or merge in progress as "swap <batch>" or "merge <batch>",
+    // written before it touches the live folder. It is the only one recover may
+    // undo; any other .old or .merged folder was left by a discard that failed.
+    fn journal_path(&self) -> PathBuf {
+        self.staging.join("journal")
+    }
+
+    async fn write_journal(&self, op: &str, batch: &str) -> Result<()> {
+        let tmp = self.staging.join("journal.tmp");
+        fs::create_dir_all(&self.staging).await?;
+        fs::write(&tmp, format!("{} {}", op, batch)).await?;
+        fs::rename(tmp, self.journal_path()).await?;
+        Ok(())
+    }
+
+    async fn journal(&self) -> Result<Option<(String, String)>> {
+        match fs::read_to_string(self.journal_path()).await {
+            Ok(journal) => Ok(journal
+                .split_once(' ')
+                .map(|(op, batch)| (op.to_string(), batch.to_string()))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn stage(&self, batch: &str, name: &str, mut data: BlobReader) -> Result<()> {
+        anyhow::ensure!(is_valid_name(name), "Invalid file name: {}", name);
+        let staged = self.staged_path(batch);
+        fs::create_dir_all(&staged).await?;
+        let mut file = fs::File::create(staged.join(name)).await?;
+        tokio::io::copy(&mut data, &mut file).await?;
+        Ok(())
+    }
+
+    async fn swap(&self, batch: &str) -> Result<()> {
+        self.write_journal("swap", batch).await?;
+        let retired = self.retired_path(batch);
+        fs::rename(&self.root, &retired).await?;
+        if let Err(e) = fs::rename(self.staged_path(batch), &self.root).await {
+            fs::rename(&retired, &self.root).await?;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    async fn restore(&self, batch: &str) -> Result<()> {
+        fs::rename(&self.root, self.staged_path(batch)).await?;
+        fs::rename(self.retired_path(batch), &self.root).await?;
+        Ok(())
+    }
+
+    async fn merge(&self, batch: &str) -> Result<()> {
+        let staged = self.staged_path(batch);
+        let merged = self.merged_path(batch);
+        self.write_journal("merge", batch).await?;
+        fs::create_dir_all(merged.join("replaced")).await?;
+        fs::create_dir_all(merged.join("added")).await?;
+        let mut entries = fs::read_dir(&staged).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let live = self.root.join(entry.file_name());
+            //record what happens to the live name before touching it, so recover can undo it
+            if fs::try_exists(&live).await? {
+                fs::rename(&live, merged.join("replaced").join(entry.file_name())).await?;
+            } else {
+                fs::write(merged.join("added").join(entry.file_name()), b"").await?;
+            }
+            fs::rename(entry.path(), &live).await?;
+        }
+        Ok(())
+    }
+
+    async fn unmerge(&self, batch: &str) -> Result<()> {
+        let merged = self.merged_path(batch);
+        let mut added = fs::read_dir(merged.join("added")).await?;
+        while let Some(entry) = added.next_entry().await? {
+            match fs::remove_file(self.root.join(entry.file_name())).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => fs::remove_file(entry.path()).await?,
+            }
+        }
+        let mut replaced = fs::read_dir(merged.join("replaced")).await?;
+        while let Some(entry) = replaced.next_entry().await? {
+            fs::rename(entry.path(), self.root.join(entry.file_name())).await?;
+        }
+        Ok(())
+    }
+
+    async fn discard(&self, batch: &str) -> Result<()> {
+        //a swap that failed and couldn't be put back, recover needs the old folder
+        if !fs::try_exists(&self.root).await? {
+            return Ok(());
+        }
+        //dropped before the folders it points at, so it never names a batch that's gone
+        if matches!(self.journal().await?, Some((_, journal)) if journal == batch) {
+            fs::remove_file(self.journal_path()).await?;
+        }
+        for path in [
+            self.staged_path(batch),
+            self.retired_path(batch),
+            self.merged_path(batch),
+        ] {
+            if fs::try_exists(&path).await? {
+                fs::remove_dir_all(path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn recover(&self, committed_batch: Option<&str>) -> Result<()> {
+        fs::create_dir_all(&self.staging).await?;
+        match self.journal().await? {
+            Some((_, batch)) if Some(batch.as_str()) == committed_batch => (),
+            //crashed before the tree was committed, the old files still match the old tree
+            Some((op, batch)) if op == "swap" => {
+                let retired = self.retired_path(&batch);
+                if fs::try_exists(&retired).await? {
+                    if fs::try_exists(&self.root).await? {
+                        fs::remove_dir_all(&self.root).await?;
+                    }
+                    fs::rename(&retired, &self.root).await?;
+                }
+            }
+            Some((_, batch)) => {
+                let merged = self.merged_path(&batch);
+                if fs::try_exists(&merged).await? {
+                    fs::create_dir_all(&self.root).await?;
+                    fs::create_dir_all(merged.join("replaced")).await?;
+                    fs::create_dir_all(merged.join("added")).await?;
+                    self.unmerge(&batch).await?;
+                }
+            }
+            None => (),
+        }
+        let mut entries = fs::read_dir(&self.staging).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                fs::remove_dir_all(entry.path()).await?;
+            } else {
+                fs::remove_file(entry.path()).await?;
+            }
+        }
+        fs::create_dir_all(&self.root).await?;
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut files = Vec::new();
+        let mut entries = fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Ok(filename) = entry.file_name().into_string() {
+                files.push(filename);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    async fn open(&self, name: &str) -> Result<Option<BlobReader>> {
+        if !is_valid_name(name) {
+            return Ok(None);
+        }
+        match fs::File::open(self.root.join(name)).await {
+            Ok(file) => Ok(Some(Box::pin(file))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_swap_and_restore() {
+        use crate::storage::local::LocalBlobStore;
+        use crate::storage::BlobStore;
+        use tokio::io::AsyncReadExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalBlobStore::new(dir.path().join("live"), dir.path().join("staging"));
+        store.recover(None).await.unwrap();
+        store
+            .stage("one", "a.txt", Box::pin(&b"first"[..]))
+            .await
+            .unwrap();
+        store.swap("one").await.unwrap();
+        store.discard("one").await.unwrap();
+
+        store
+            .stage("two", "b.txt", Box::pin(&b"second"[..]))
+            .await
+            .unwrap();
+        store.swap("two").await.unwrap();
+        assert_eq!(store.list().await.unwrap(), vec!["b.txt"]);
+        store.restore("two").await.unwrap();
+        store.discard("two").await.unwrap();
+
+        assert_eq!(store.list().await.unwrap(), vec!["a.txt"]);
+        let mut contents = String::new();
+        let mut reader = store.open("a.txt").await.unwrap().unwrap();
+        reader.read_to_string(&mut contents).await.unwrap();
+        assert_eq!(contents, "first");
+        assert!(store.open("../live").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_merge_and_recover() {
+        use crate::storage::local::LocalBlobStore;
+        use crate::storage::BlobStore;
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalBlobStore::new(dir.path().join("live"), dir.path().join("staging"));
+        store.recover(None).await.unwrap();
+        for name in ["a.txt", "b.txt"] {
+            store
+                .stage("one", name, Box::pin(&b"first"[..]))
+                .await
+                .unwrap();
+        }
+        store.swap("one").await.unwrap();
+        store.discard("one").await.unwrap();
+
+        store
+            .stage("two", "c.txt", Box::pin(&b"second"[..]))
+            .await
+            .unwrap();
+        store.merge("two").await.unwrap();
+        assert_eq!(store.list().await.unwrap(), vec!["a.txt", "b.txt", "c.txt"]);
+        store.unmerge("two").await.unwrap();
+        store.discard("two").await.unwrap();
+        assert_eq!(store.list().await.unwrap(), vec!["a.txt", "b.txt"]);
+
+        //a merge the tree never committed is undone on startup
+        store
+            .stage("three", "a.txt", Box::pin(&b"third"[..]))
+            .await
+            .unwrap();
+        store
+            .stage("three", "d.txt", Box::pin(&b"third"[..]))
+            .await
+            .unwrap();
+        store.merge("three").await.unwrap();
+        store.recover(Some("one")).await.unwrap();
+        assert_eq!(store.list().await.unwrap(), vec!["a.txt", "b.txt"]);
+        let contents = tokio::fs::read(dir.path().join("live/a.txt"))
+            .await
+            .unwrap();
+        assert_eq!(contents, b"first");
+    }
+
+    #[tokio::test]
+    async fn test_recover_only_undoes_the_interrupted_swap() {
+        use crate::storage::local::LocalBlobStore;
+        use crate::storage::BlobStore;
+
+        let dir = tempfile::tempdir().unwrap();
+        let live = dir.path().join("live");
+        let staging = dir.path().join("staging");
+        let store = LocalBlobStore::new(&live, &staging);
+        store.recover(None).await.unwrap();
+        for (batch, name) in [("one", "a.txt"), ("two", "b.txt")] {
+            store
+                .stage(batch, name, Box::pin(&b"first"[..]))
+                .await
+                .unwrap();
+            store.swap(batch).await.unwrap();
+        }
+        //batch one's discard failed and left its old folder, two was committed
+        store.discard("two").await.unwrap();
+        store.recover(Some("two")).await.unwrap();
+        assert_eq!(store.list().await.unwrap(), vec!["b.txt"]);
+        assert!(!tokio::fs::try_exists(staging.join("one.old"))
+            .await
+            .unwrap());
+
+        //crash between the two renames of a swap
+        store
+            .stage("three", "c.txt", Box::pin(&b"third"[..]))
+            .await
+            .unwrap();
+        store.write_journal("swap", "three").await.unwrap();
+        tokio::fs::rename(&live, staging.join("three.old"))
+            .await
+            .unwrap();
+        store.recover(Some("two")).await.unwrap();
+        assert_eq!(store.list().await.unwrap(), vec!["b.txt"]);
+    }
+}
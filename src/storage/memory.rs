@@ -0,0 +1,259 @@
+use super::{is_valid_name, BlobReader, BlobStore, MmrStore, TreeStore};
+use anyhow::{anyhow, ensure, Result};
+use async_trait::async_trait;
+use blake3::Hash;
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Cursor;
+use std::sync::{Mutex, RwLock};
+use tokio::io::AsyncReadExt;
+
+type Blobs = BTreeMap<String, Bytes>;
+
+#[derive(Default)]
+struct MemoryBlobs {
+    live: Blobs,
+    staged: HashMap<String, Blobs>,
+    retired: HashMap<String, Blobs>,
+    // Names a merge added and the live files it replaced
+    merged: HashMap<String, (Vec<String>, Blobs)>,
+}
+
+// Blobs kept in memory, for tests and throwaway servers
+#[derive(Default)]
+pub struct MemoryBlobStore(Mutex<MemoryBlobs>);
+
+#[async_trait]
+impl BlobStore for MemoryBlobStore {
+    async fn stage(&self, batch: &str, name: &str, mut data: BlobReader) -> Result<()> {
+        ensure!(is_valid_name(name), "Invalid file name: {}", name);
+        let mut contents = Vec::new();
+        data.read_to_end(&mut contents).await?;
+        let data = Bytes::from(contents);
+        let mut blobs = self.0.lock().unwrap();
+        blobs
+            .staged
+            .entry(batch.to_string())
+            .or_default()
+            .insert(name.to_string(), data);
+        Ok(())
+    }
+
+    async fn swap(&self, batch: &str) -> Result<()> {
+        let mut blobs = self.0.lock().unwrap();
+        let staged = blobs.staged.remove(batch).unwrap_or_default();
+        let retired = std::mem::replace(&mut blobs.live, staged);
+        blobs.retired.insert(batch.to_string(), retired);
+        Ok(())
+    }
+
+    async fn restore(&self, batch: &str) -> Result<()> {
+        let mut blobs = self.0.lock().unwrap();
+        let retired = blobs
+            .retired
+            .remove(batch)
+            .ok_or_else(|| anyhow!("Nothing to restore for batch {}", batch))?;
+        let staged = std::mem::replace(&mut blobs.live, retired);
+        blobs.staged.insert(batch.to_string(), staged);
+        Ok(())
+    }
+
+    async fn merge(&self, batch: &str) -> Result<()> {
+        let mut blobs = self.0.lock().unwrap();
+        let staged = blobs.staged.remove(batch).unwrap_or_default();
+        let (mut added, mut replaced) = (Vec::new(), Blobs::new());
+        for (name, data) in staged {
+            match blobs.live.insert(name.clone(), data) {
+                Some(old) => {
+                    replaced.insert(name, old);
+                }
+                None => added.push(name),
+            }
+        }
+        blobs.merged.insert(batch.to_string(), (added, replaced));
+        Ok(())
+    }
+
+    async fn unmerge(&self, batch: &str) -> Result<()> {
+        let mut blobs = self.0.lock().unwrap();
+        let (added, replaced) = blobs
+            .merged
+            .remove(batch)
+            .ok_or_else(|| anyhow!("Nothing to unmerge for batch {}", batch))?;
+        for name in added {
+            blobs.live.remove(&name);
+        }
+        blobs.live.extend(replaced);
+        Ok(())
+    }
+
+    async fn discard(&self, batch: &str) -> Result<()> {
+        let mut blobs = self.0.lock().unwrap();
+        blobs.staged.remove(batch);
+        blobs.retired.remove(batch);
+        blobs.merged.remove(batch);
+        Ok(())
+    }
+
+    async fn recover(&self, _committed_batch: Option<&str>) -> Result<()> {
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        Ok(self.0.lock().unwrap().live.keys().cloned().collect())
+    }
+
+    async fn open(&self, name: &str) -> Result<Option<BlobReader>> {
+        let blobs = self.0.lock().unwrap();
+        Ok(blobs
+            .live
+            .get(name)
+            .map(|data| Box::pin(Cursor::new(data.clone())) as BlobReader))
+    }
+}
+
+#[derive(Default)]
+struct MemoryTree {
+    batch: String,
+    nodes: Vec<Hash>,
+    files: HashMap<String, usize>,
+    sparse_root: Option<Hash>,
+}
+
+// Tree kept in memory, for tests and throwaway servers
+#[derive(Default)]
+pub struct MemoryTreeStore(RwLock<Option<MemoryTree>>);
+
+impl TreeStore for MemoryTreeStore {
+    fn commit(
+        &self,
+        batch: &str,
+        nodes: &[Hash],
+        file_list: &[String],
+        sparse_root: &Hash,
+    ) -> Result<()> {
+        let files = file_list
+            .iter()
+            .enumerate()
+            .map(|(i, filename)| (filename.clone(), i))
+            .collect();
+        *self.0.write().unwrap() = Some(MemoryTree {
+            batch: batch.to_string(),
+            nodes: nodes.to_vec(),
+            files,
+            sparse_root: Some(*sparse_root),
+        });
+        Ok(())
+    }
+
+    fn update(
+        &self,
+        batch: &str,
+        nodes: &[(usize, Hash)],
+        new_files: &[String],
+        sparse_root: &Hash,
+    ) -> Result<()> {
+        let mut tree = self.0.write().unwrap();
+        let tree = tree.as_mut().ok_or_else(|| anyhow!("No tree to update"))?;
+        tree.batch = batch.to_string();
+        tree.sparse_root = Some(*sparse_root);
+        for (index, node) in nodes {
+            *tree
+                .nodes
+                .get_mut(*index)
+                .ok_or_else(|| anyhow!("Node {} out of range", index))? = *node;
+        }
+        for filename in new_files {
+            let index = tree.files.len();
+            tree.files.insert(filename.clone(), index);
+        }
+        Ok(())
+    }
+
+    fn batch(&self) -> Result<Option<String>> {
+        Ok(self
+            .0
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|tree| tree.batch.clone()))
+    }
+
+    fn sparse_root(&self) -> Result<Option<Hash>> {
+        Ok(self
+            .0
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|tree| tree.sparse_root))
+    }
+
+    fn node(&self, index: usize) -> Result<Option<Hash>> {
+        let tree = self.0.read().unwrap();
+        Ok(tree
+            .as_ref()
+            .and_then(|tree| tree.nodes.get(index).copied()))
+    }
+
+    fn nodes(&self) -> Result<Vec<Hash>> {
+        let tree = self.0.read().unwrap();
+        Ok(tree
+            .as_ref()
+            .map(|tree| tree.nodes.clone())
+            .unwrap_or_default())
+    }
+
+    fn file_list(&self) -> Result<Vec<String>> {
+        let tree = self.0.read().unwrap();
+        let mut files: Vec<(&String, &usize)> = tree
+            .as_ref()
+            .map(|tree| tree.files.iter().collect())
+            .unwrap_or_default();
+        files.sort_by_key(|(_, index)| **index);
+        Ok(files
+            .into_iter()
+            .map(|(filename, _)| filename.clone())
+            .collect())
+    }
+
+    fn file_index(&self, filename: &str) -> Result<Option<usize>> {
+        let tree = self.0.read().unwrap();
+        Ok(tree
+            .as_ref()
+            .and_then(|tree| tree.files.get(filename).copied()))
+    }
+
+    fn tree_size(&self) -> Result<Option<usize>> {
+        Ok(self.0.read().unwrap().as_ref().map(|tree| tree.nodes.len()))
+    }
+
+    fn num_of_files(&self) -> Result<Option<usize>> {
+        Ok(self.0.read().unwrap().as_ref().map(|tree| tree.files.len()))
+    }
+}
+
+// Mountain range nodes kept in memory, for tests
+#[derive(Default)]
+pub struct MemoryMmrStore(RwLock<Vec<Hash>>);
+
+impl MmrStore for MemoryMmrStore {
+    fn size(&self) -> Result<u64> {
+        Ok(self.0.read().unwrap().len() as u64)
+    }
+
+    fn node(&self, pos: u64) -> Result<Option<Hash>> {
+        Ok(self.0.read().unwrap().get(pos as usize).copied())
+    }
+
+    fn append(&self, start: u64, nodes: &[Hash]) -> Result<()> {
+        let mut stored = self.0.write().unwrap();
+        ensure!(
+            stored.len() as u64 == start,
+            "Append at {} but size is {}",
+            start,
+            stored.len()
+        );
+        stored.extend_from_slice(nodes);
+        Ok(())
+    }
+}
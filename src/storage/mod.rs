@@ -0,0 +1,196 @@
+pub mod local;
+pub mod memory;
+pub mod s3;
+pub mod sled_mmr;
+pub mod sled_store;
+
+use crate::merkletree::cache::TreeCache;
+use crate::merkletree::sparse::SparseMerkleTree;
+use crate::merkletree::tree::FastMerkleTree;
+use crate::merkletree::zk::{read_key, AppendKey};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use blake3::Hash;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::AsyncRead;
+use tokio::sync::RwLock;
+
+pub type BlobReader = Pin<Box<dyn AsyncRead + Send>>;
+
+// Where uploaded file contents live. Uploads write into a staging batch that
+// readers can't see, then swap the whole batch in for the previous files.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    // Write a file into the batch as it is read, without holding it whole
+    async fn stage(&self, batch: &str, name: &str, data: BlobReader) -> Result<()>;
+    // Make the staged batch the live set, keeping the old live set aside until discard
+    async fn swap(&self, batch: &str) -> Result<()>;
+    // Undo a swap, putting the old live set back
+    async fn restore(&self, batch: &str) -> Result<()>;
+    // Move the staged files into the live set next to the files already there,
+    // keeping any live files they replace aside until discard
+    async fn merge(&self, batch: &str) -> Result<()>;
+    // Undo a merge, taking the batch's files out and putting the ones they replaced back
+    async fn unmerge(&self, batch: &str) -> Result<()>;
+    // Drop whatever the batch left behind, staged or swapped out
+    async fn discard(&self, batch: &str) -> Result<()>;
+    // Get back to the live set matching the tree after a crash mid-upload, given
+    // the batch the tree store last committed, by undoing any later swap or merge
+    async fn recover(&self, committed_batch: Option<&str>) -> Result<()>;
+    async fn list(&self) -> Result<Vec<String>>;
+    async fn open(&self, name: &str) -> Result<Option<BlobReader>>;
+}
+
+// Where the merkle tree nodes and the file index live
+pub trait TreeStore: Send + Sync {
+    // Replace the stored tree and file list in one atomic step, recording the
+    // upload batch they came from and the root of the sparse tree over the same files
+    fn commit(
+        &self,
+        batch: &str,
+        nodes: &[Hash],
+        file_list: &[String],
+        sparse_root: &Hash,
+    ) -> Result<()>;
+    // Overwrite some nodes and add files after the existing ones in one atomic
+    // step, for changes that keep the tree layout, recording the upload batch
+    fn update(
+        &self,
+        batch: &str,
+        nodes: &[(usize, Hash)],
+        new_files: &[String],
+        sparse_root: &Hash,
+    ) -> Result<()>;
+    fn batch(&self) -> Result<Option<String>>;
+    // Root of the sparse merkle tree keyed by file name, committed with the tree
+    fn sparse_root(&self) -> Result<Option<Hash>>;
+    fn node(&self, index: usize) -> Result<Option<Hash>>;
+    // Whole tree in index order, for loading it into memory
+    fn nodes(&self) -> Result<Vec<Hash>>;
+    fn file_list(&self) -> Result<Vec<String>>;
+    fn file_index(&self, filename: &str) -> Result<Option<usize>>;
+    fn tree_size(&self) -> Result<Option<usize>>;
+    fn num_of_files(&self) -> Result<Option<usize>>;
+    // Disk space used, for stores that keep the tree on disk
+    fn size_on_disk(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+    // Write out anything buffered, before the process exits
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Append-only node log for a merkle mountain range
+pub trait MmrStore: Send + Sync {
+    // Number of nodes stored
+    fn size(&self) -> Result<u64>;
+    fn node(&self, pos: u64) -> Result<Option<Hash>>;
+    // Add nodes at positions start.. in one atomic step; start has to be the current size
+    fn append(&self, start: u64, nodes: &[Hash]) -> Result<()>;
+}
+
+// Files and tree together, with the in-memory copy of the tree that serves proofs
+pub struct Store {
+    pub blobs: Arc<dyn BlobStore>,
+    pub tree: Arc<dyn TreeStore>,
+    // Held for writing while an upload swaps its files and tree in, and for reading
+    // by anything that looks at files and proofs together
+    pub cache: RwLock<TreeCache>,
+    // Key for proving adds only appended to the Poseidon tree, if the server has one
+    pub append_key: Option<Arc<AppendKey>>,
+}
+
+impl Store {
+    pub fn new(blobs: Arc<dyn BlobStore>, tree: Arc<dyn TreeStore>) -> Result<Self> {
+        let cache = TreeCache::load(tree.as_ref())?;
+        Ok(Store {
+            blobs,
+            tree,
+            cache: RwLock::new(cache),
+            append_key: None,
+        })
+    }
+
+    // Open the backends picked by MERKLE_BLOB_STORE (local, memory or s3) and
+    // MERKLE_TREE_STORE (sled or memory)
+    pub async fn from_env() -> Result<Self> {
+        let blobs: Arc<dyn BlobStore> = match env_or("MERKLE_BLOB_STORE", "local").as_str() {
+            "local" => Arc::new(local::LocalBlobStore::new(
+                local::UPLOAD_DIR,
+                local::STAGING_DIR,
+            )),
+            "memory" => Arc::new(memory::MemoryBlobStore::default()),
+            "s3" => Arc::new(s3::S3BlobStore::from_env()?),
+            other => bail!("Unknown blob store: {}", other),
+        };
+        let tree: Arc<dyn TreeStore> = match env_or("MERKLE_TREE_STORE", "sled").as_str() {
+            "sled" => {
+                let path = env_or("MERKLE_SLED_PATH", "merkle_tree_db");
+                Arc::new(sled_store::SledTreeStore::open(&sled::open(path)?)?)
+            }
+            "memory" => Arc::new(memory::MemoryTreeStore::default()),
+            other => bail!("Unknown tree store: {}", other),
+        };
+        blobs.recover(tree.batch()?.as_deref()).await?;
+        let mut store = Store::new(blobs, tree)?;
+        //MERKLE_ZK_KEY is a proving key from `cargo run zk setup`
+        if let Ok(path) = std::env::var("MERKLE_ZK_KEY") {
+            let (depth, pk) = read_key(std::path::Path::new(&path))?;
+            store.append_key = Some(Arc::new(AppendKey { depth, pk }));
+        }
+        Ok(store)
+    }
+
+    // Takes the cache from a write lock on self.cache
+    pub async fn swap_in(
+        &self,
+        cache: &mut TreeCache,
+        batch: &str,
+        merkle_tree: FastMerkleTree,
+        file_list: Vec<String>,
+        sparse: SparseMerkleTree,
+    ) -> Result<()> {
+        self.blobs.swap(batch).await?;
+        if let Err(e) = merkle_tree.commit_merkle_tree(
+            self.tree.as_ref(),
+            batch,
+            file_list.clone(),
+            &sparse.root(),
+        ) {
+            //put the old files back so they still match the old tree
+            self.blobs.restore(batch).await?;
+            return Err(e);
+        }
+        *cache = TreeCache::new(batch, merkle_tree, file_list, sparse);
+        Ok(())
+    }
+
+    // Add one file to the live set, or replace the one with its name, updating the
+    // tree in place. Takes the cache from a write lock on self.cache.
+    pub async fn merge_in(
+        &self,
+        cache: &mut TreeCache,
+        batch: &str,
+        filename: &str,
+        leaf: Hash,
+    ) -> Result<()> {
+        self.blobs.merge(batch).await?;
+        if let Err(e) = cache.append_leaf(self.tree.as_ref(), batch, filename, leaf) {
+            //the live files go back to matching the unchanged tree
+            self.blobs.unmerge(batch).await?;
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn env_or(key: &str, default: &str) -> String {
+    std::env::var(key).unwrap_or_else(|_| default.to_string())
+}
+
+// Blob names are plain file names, never paths
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
@@ -0,0 +1,737 @@
+use super::{env_or, is_valid_name, BlobReader, BlobStore};
+use anyhow::{anyhow, ensure, Result};
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use s3::creds::Credentials;
+use s3::error::S3Error;
+use s3::{Bucket, Region};
+use serde::{Deserialize, Serialize};
+use tokio_util::io::StreamReader;
+
+// Blobs in an S3-compatible bucket (AWS, MinIO, ...). Object stores can't rename,
+// so a swap copies objects between the live/, staging/ and retired/ prefixes.
+// That isn't atomic on the bucket side; the store lock keeps readers in this
+// process from seeing it half done, and the journal lets recover finish or undo it.
+pub struct S3BlobStore {
+    bucket: Box<Bucket>,
+    prefix: String,
+}
+
+// What the last swap or merge was doing, rewritten before each of its steps so
+// recover knows which batch was interrupted and how far it got
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "phase", rename_all = "snake_case")]
+enum Journal {
+    // Moving the live objects to retired/<batch>/
+    Retire { batch: String },
+    // Moving these staged names into live/
+    Promote { batch: String, names: Vec<String> },
+    // Every staged name is in live/ and the old objects are in retired/<batch>/
+    Swapped { batch: String, names: Vec<String> },
+    // The staged names are out of live/ again, retired/<batch>/ is moving back
+    Restore { batch: String },
+    // Moving staged objects into live/, recording each under merged/<batch>/ first
+    Merge { batch: String },
+    Merged { batch: String },
+}
+
+impl Journal {
+    fn batch(&self) -> &str {
+        match self {
+            Journal::Retire { batch }
+            | Journal::Promote { batch, .. }
+            | Journal::Swapped { batch, .. }
+            | Journal::Restore { batch }
+            | Journal::Merge { batch }
+            | Journal::Merged { batch } => batch,
+        }
+    }
+
+    // Whether the operation got to the end, so what it moved aside can go
+    fn finished(&self) -> bool {
+        matches!(self, Journal::Swapped { .. } | Journal::Merged { .. })
+    }
+}
+
+impl S3BlobStore {
+    pub fn new(bucket: Box<Bucket>, prefix: &str) -> Self {
+        S3BlobStore {
+            bucket,
+            prefix: prefix.to_string(),
+        }
+    }
+
+    // Configured by MERKLE_S3_BUCKET, MERKLE_S3_ENDPOINT, MERKLE_S3_REGION and
+    // MERKLE_S3_PREFIX, with credentials from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY
+    pub fn from_env() -> Result<Self> {
+        let name =
+            std::env::var("MERKLE_S3_BUCKET").map_err(|_| anyhow!("MERKLE_S3_BUCKET not set"))?;
+        let region = env_or("MERKLE_S3_REGION", "us-east-1");
+        let region = match std::env::var("MERKLE_S3_ENDPOINT") {
+            Ok(endpoint) => Region::Custom { region, endpoint },
+            Err(_) => region.parse()?,
+        };
+        let credentials = Credentials::new(
+            std::env::var("AWS_ACCESS_KEY_ID").ok().as_deref(),
+            std::env::var("AWS_SECRET_ACCESS_KEY").ok().as_deref(),
+            None,
+            None,
+            None,
+        )?;
+        let bucket = Bucket::new(&name, region, credentials)?.with_path_style();
+        Ok(S3BlobStore::new(bucket, &env_or("MERKLE_S3_PREFIX", "")))
+    }
+
+    fn live_prefix(&self) -> String {
+        format!("{}live/", self.prefix)
+    }
+
+    fn staged_prefix(&self, batch: &str) -> String {
+        format!("{}staging/{}/", self.prefix, batch)
+    }
+
+    fn retired_prefix(&self, batch: &str) -> String {
+        format!("{}retired/{}/", self.prefix, batch)
+    }
+
+    // Under it replaced/ holds the live objects a merge moved aside and added/ an
+    // empty marker for each name it added
+    fn merged_prefix(&self, batch: &str) -> String {
+        format!("{}merged/{}/", self.prefix, batch)
+    }
+
+    fn journal_key(&self) -> String {
+        format!("{}journal", self.prefix)
+    }
+
+    async fn journal(&self) -> Result<Option<Journal>> {
+        match self.bucket.get_object(self.journal_key()).await {
+            Ok(response) if response.status_code() == 404 => Ok(None),
+            Ok(response) => Ok(Some(serde_json::from_slice(response.as_slice())?)),
+            Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn write_journal(&self, journal: &Journal) -> Result<()> {
+        self.put(&self.journal_key(), &serde_json::to_vec(journal)?)
+            .await
+    }
+
+    async fn clear_journal(&self) -> Result<()> {
+        self.delete(&self.journal_key()).await
+    }
+
+    // The wrappers below check the status as well as the error, so a copy that the
+    // bucket refused never goes on to delete its source
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let status = self.bucket.put_object(key, data).await?.status_code();
+        ensure!(
+            succeeded(status),
+            "Writing {} failed with status {}",
+            key,
+            status
+        );
+        Ok(())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        let status = self.bucket.copy_object_internal(from, to).await?;
+        ensure!(
+            succeeded(status),
+            "Copying {} to {} failed with status {}",
+            from,
+            to,
+            status
+        );
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let status = self.bucket.delete_object(key).await?.status_code();
+        ensure!(
+            succeeded(status),
+            "Deleting {} failed with status {}",
+            key,
+            status
+        );
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        match self.bucket.head_object(key).await {
+            Ok((_, 404)) | Err(S3Error::HttpFailWithBody(404, _)) => Ok(false),
+            Ok((_, status)) => {
+                ensure!(
+                    succeeded(status),
+                    "Checking {} failed with status {}",
+                    key,
+                    status
+                );
+                Ok(true)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Put the live set back the way it was before a swap, from whatever phase it
+    // reached. Only names that came from staging are deleted from live/, and the
+    // objects in retired/<batch>/ are moved back over them.
+    async fn undo_swap(&self, journal: Journal) -> Result<()> {
+        let batch = journal.batch().to_string();
+        if let Journal::Promote { names, .. } | Journal::Swapped { names, .. } = journal {
+            //marked unfinished first, so a discard after a failed undo keeps retired/
+            self.write_journal(&Journal::Promote {
+                batch: batch.clone(),
+                names: names.clone(),
+            })
+            .await?;
+            for name in names {
+                self.delete(&format!("{}{}", self.live_prefix(), name))
+                    .await?;
+            }
+        }
+        //from here on live/ only holds old objects, so rerunning just moves the rest back
+        self.write_journal(&Journal::Restore {
+            batch: batch.clone(),
+        })
+        .await?;
+        self.move_all(&self.retired_prefix(&batch), &self.live_prefix())
+            .await?;
+        self.clear_journal().await
+    }
+
+    // Undo whichever operation the journal describes
+    async fn undo(&self, journal: Journal) -> Result<()> {
+        match journal {
+            Journal::Merge { batch } | Journal::Merged { batch } => self.unmerge(&batch).await,
+            journal => self.undo_swap(journal).await,
+        }
+    }
+
+    // A swap or merge that failed and couldn't be undone has to be before the next
+    // one overwrites the journal
+    async fn undo_unfinished(&self) -> Result<()> {
+        match self.journal().await? {
+            Some(journal) if !journal.finished() => self.undo(journal).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn swap_steps(&self, batch: &str) -> Result<()> {
+        self.write_journal(&Journal::Retire {
+            batch: batch.to_string(),
+        })
+        .await?;
+        self.move_all(&self.live_prefix(), &self.retired_prefix(batch))
+            .await?;
+        let staged = self.staged_prefix(batch);
+        let names: Vec<String> = self
+            .keys(&staged)
+            .await?
+            .iter()
+            .map(|key| key.trim_start_matches(&staged).to_string())
+            .collect();
+        self.write_journal(&Journal::Promote {
+            batch: batch.to_string(),
+            names: names.clone(),
+        })
+        .await?;
+        self.move_all(&staged, &self.live_prefix()).await?;
+        self.write_journal(&Journal::Swapped {
+            batch: batch.to_string(),
+            names,
+        })
+        .await
+    }
+
+    // Every batch id with something left under root/
+    async fn batches(&self, root: &str) -> Result<Vec<String>> {
+        let mut batches: Vec<String> = self
+            .keys(root)
+            .await?
+            .iter()
+            .filter_map(|key| Some(key.trim_start_matches(root).split_once('/')?.0))
+            .map(|batch| batch.to_string())
+            .collect();
+        batches.dedup();
+        Ok(batches)
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for page in self.bucket.list(prefix.to_string(), None).await? {
+            keys.extend(page.contents.into_iter().map(|object| object.key));
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    // Move every object under one prefix to the same name under another
+    async fn move_all(&self, from: &str, to: &str) -> Result<()> {
+        for key in self.keys(from).await? {
+            let name = key.trim_start_matches(from);
+            self.copy(&key, &format!("{}{}", to, name)).await?;
+            self.delete(&key).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_all(&self, prefix: &str) -> Result<()> {
+        for key in self.keys(prefix).await? {
+            self.delete(&key).await?;
+        }
+        Ok(())
+    }
+}
+
+fn succeeded(status: u16) -> bool {
+    (200..300).contains(&status)
+}
+
+#[async_trait]
+impl BlobStore for S3BlobStore {
+    async fn stage(&self, batch: &str, name: &str, mut data: BlobReader) -> Result<()> {
+        ensure!(is_valid_name(name), "Invalid file name: {}", name);
+        let key = format!("{}{}", self.staged_prefix(batch), name);
+        //sent as a multipart upload in chunks, so large files aren't buffered here
+        let status = self
+            .bucket
+            .put_object_stream(&mut data, &key)
+            .await?
+            .status_code();
+        ensure!(
+            succeeded(status),
+            "Staging {} failed with status {}",
+            key,
+            status
+        );
+        Ok(())
+    }
+
+    async fn swap(&self, batch: &str) -> Result<()> {
+        self.undo_unfinished().await?;
+        if let Err(e) = self.swap_steps(batch).await {
+            //put the old objects back now, or leave the journal for recover to
+            match self.journal().await? {
+                Some(journal) if journal.batch() == batch => self.undo_swap(journal).await?,
+                _ => (),
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn restore(&self, batch: &str) -> Result<()> {
+        match self.journal().await? {
+            Some(journal) if journal.batch() == batch => self.undo_swap(journal).await,
+            _ => Err(anyhow!("No swap of batch {} to restore", batch)),
+        }
+    }
+
+    async fn merge(&self, batch: &str) -> Result<()> {
+        let staged = self.staged_prefix(batch);
+        let merged = self.merged_prefix(batch);
+        let live = self.live_prefix();
+        self.undo_unfinished().await?;
+        self.write_journal(&Journal::Merge {
+            batch: batch.to_string(),
+        })
+        .await?;
+        for key in self.keys(&staged).await? {
+            let name = key.trim_start_matches(&staged);
+            let live_key = format!("{}{}", live, name);
+            //record what happens to the live name before touching it, so recover can undo it
+            if self.exists(&live_key).await? {
+                self.copy(&live_key, &format!("{}replaced/{}", merged, name))
+                    .await?;
+            } else {
+                self.put(&format!("{}added/{}", merged, name), b"").await?;
+            }
+            self.copy(&key, &live_key).await?;
+            self.delete(&key).await?;
+        }
+        self.write_journal(&Journal::Merged {
+            batch: batch.to_string(),
+        })
+        .await
+    }
+
+    async fn unmerge(&self, batch: &str) -> Result<()> {
+        //marked unfinished first, so a discard after a failed unmerge keeps merged/
+        self.write_journal(&Journal::Merge {
+            batch: batch.to_string(),
+        })
+        .await?;
+        let merged = self.merged_prefix(batch);
+        let added = format!("{}added/", merged);
+        for key in self.keys(&added).await? {
+            let name = key.trim_start_matches(&added);
+            self.delete(&format!("{}{}", self.live_prefix(), name))
+                .await?;
+            self.delete(&key).await?;
+        }
+        self.move_all(&format!("{}replaced/", merged), &self.live_prefix())
+            .await?;
+        self.clear_journal().await
+    }
+
+    async fn discard(&self, batch: &str) -> Result<()> {
+        self.delete_all(&self.staged_prefix(batch)).await?;
+        match self.journal().await? {
+            //a swap or merge that failed and couldn't be undone, recover needs what it moved aside
+            Some(journal) if journal.batch() == batch && !journal.finished() => return Ok(()),
+            //dropped before the objects it points at, so it never names a batch that's gone
+            Some(journal) if journal.batch() == batch => self.clear_journal().await?,
+            _ => (),
+        }
+        self.delete_all(&self.retired_prefix(batch)).await?;
+        self.delete_all(&self.merged_prefix(batch)).await
+    }
+
+    async fn recover(&self, committed_batch: Option<&str>) -> Result<()> {
+        //only the swap or merge in the journal can have been interrupted; anything
+        //else under retired/ or merged/ was left by a discard that failed
+        match self.journal().await? {
+            Some(journal) if Some(journal.batch()) == committed_batch => {
+                self.clear_journal().await?
+            }
+            //crashed before the tree was committed, the old files still match the old tree
+            Some(journal) => self.undo(journal).await?,
+            None => (),
+        }
+        for batch in self.batches(&format!("{}retired/", self.prefix)).await? {
+            self.delete_all(&self.retired_prefix(&batch)).await?;
+        }
+        for batch in self.batches(&format!("{}merged/", self.prefix)).await? {
+            self.delete_all(&self.merged_prefix(&batch)).await?;
+        }
+        self.delete_all(&format!("{}staging/", self.prefix)).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let live = self.live_prefix();
+        let keys = self.keys(&live).await?;
+        Ok(keys
+            .iter()
+            .map(|key| key.trim_start_matches(&live).to_string())
+            .collect())
+    }
+
+    async fn open(&self, name: &str) -> Result<Option<BlobReader>> {
+        if !is_valid_name(name) {
+            return Ok(None);
+        }
+        let key = format!("{}{}", self.live_prefix(), name);
+        match self.bucket.get_object_stream(key).await {
+            Ok(response) if response.status_code == 404 => Ok(None),
+            Ok(response) => {
+                let stream = response.bytes.map_err(std::io::Error::other);
+                Ok(Some(Box::pin(StreamReader::new(stream))))
+            }
+            Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Runs against a local MinIO, e.g.
+    //   minio server /tmp/minio & mc mb local/merkle-test
+    //   MERKLE_S3_BUCKET=merkle-test MERKLE_S3_ENDPOINT=http://127.0.0.1:9000 \
+    //   AWS_ACCESS_KEY_ID=minioadmin AWS_SECRET_ACCESS_KEY=minioadmin cargo test -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn test_swap_and_restore_against_minio() {
+        use crate::storage::s3::S3BlobStore;
+        use crate::storage::BlobStore;
+        use tokio::io::AsyncReadExt;
+
+        std::env::set_var("MERKLE_S3_PREFIX", "merkle-fileserver-test/");
+        let store = S3BlobStore::from_env().unwrap();
+        store.recover(None).await.unwrap();
+        store
+            .stage("one", "a.txt", Box::pin(&b"first"[..]))
+            .await
+            .unwrap();
+        store.swap("one").await.unwrap();
+        store.discard("one").await.unwrap();
+
+        store
+            .stage("two", "b.txt", Box::pin(&b"second"[..]))
+            .await
+            .unwrap();
+        store.swap("two").await.unwrap();
+        assert_eq!(store.list().await.unwrap(), vec!["b.txt"]);
+        store.restore("two").await.unwrap();
+        store.discard("two").await.unwrap();
+
+        assert_eq!(store.list().await.unwrap(), vec!["a.txt"]);
+        let mut contents = String::new();
+        let mut reader = store.open("a.txt").await.unwrap().unwrap();
+        reader.read_to_string(&mut contents).await.unwrap();
+        assert_eq!(contents, "first");
+        assert!(store.open("missing.txt").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_recover_after_interrupted_swap_against_minio() {
+        use crate::storage::s3::{Journal, S3BlobStore};
+        use crate::storage::BlobStore;
+
+        std::env::set_var("MERKLE_S3_PREFIX", "merkle-fileserver-interrupt-test/");
+        let store = S3BlobStore::from_env().unwrap();
+        store.recover(None).await.unwrap();
+        let old: Vec<String> = (0..5).map(|i| format!("{}.txt", i)).collect();
+        for name in &old {
+            store
+                .stage("one", name, Box::pin(&b"first"[..]))
+                .await
+                .unwrap();
+        }
+        store.swap("one").await.unwrap();
+        store.discard("one").await.unwrap();
+
+        //crash after moving the first N live objects to retired/
+        for moved in [0, 2, 5] {
+            store
+                .stage("two", "new.txt", Box::pin(&b"second"[..]))
+                .await
+                .unwrap();
+            store
+                .write_journal(&Journal::Retire {
+                    batch: "two".to_string(),
+                })
+                .await
+                .unwrap();
+            let live = store.live_prefix();
+            for key in store.keys(&live).await.unwrap().iter().take(moved) {
+                let name = key.trim_start_matches(&live);
+                store
+                    .bucket
+                    .copy_object_internal(key, format!("{}{}", store.retired_prefix("two"), name))
+                    .await
+                    .unwrap();
+                store.bucket.delete_object(key).await.unwrap();
+            }
+            store.recover(Some("one")).await.unwrap();
+            assert_eq!(store.list().await.unwrap(), old);
+        }
+
+        //crash partway through moving the staged objects into live/
+        for name in ["0.txt", "new.txt"] {
+            store
+                .stage("three", name, Box::pin(&b"third"[..]))
+                .await
+                .unwrap();
+        }
+        store
+            .write_journal(&Journal::Retire {
+                batch: "three".to_string(),
+            })
+            .await
+            .unwrap();
+        store
+            .move_all(&store.live_prefix(), &store.retired_prefix("three"))
+            .await
+            .unwrap();
+        store
+            .write_journal(&Journal::Promote {
+                batch: "three".to_string(),
+                names: vec!["0.txt".to_string(), "new.txt".to_string()],
+            })
+            .await
+            .unwrap();
+        store
+            .bucket
+            .copy_object_internal(
+                format!("{}new.txt", store.staged_prefix("three")),
+                format!("{}new.txt", store.live_prefix()),
+            )
+            .await
+            .unwrap();
+        store.recover(Some("one")).await.unwrap();
+        assert_eq!(store.list().await.unwrap(), old);
+        let contents = store
+            .bucket
+            .get_object(format!("{}0.txt", store.live_prefix()))
+            .await
+            .unwrap();
+        assert_eq!(contents.as_slice(), b"first");
+    }
+
+    // An in-memory bucket that speaks just enough of the S3 API for the store,
+    // failing any request whose method and key match one of the given rules
+    type Objects = std::sync::Arc<std::sync::Mutex<std::collections::BTreeMap<String, Vec<u8>>>>;
+
+    async fn mock_s3(
+        failures: Vec<(&'static str, &'static str, u16)>,
+    ) -> (super::S3BlobStore, Objects) {
+        use s3::creds::Credentials;
+        use s3::{Bucket, Region};
+        use std::collections::HashMap;
+        use warp::http::{Method, Response};
+        use warp::Filter;
+
+        let objects = Objects::default();
+        let state = objects.clone();
+        let routes = warp::method()
+            .and(warp::path::full())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::header::optional::<String>("x-amz-copy-source"))
+            .and(warp::body::bytes())
+            .map(
+                move |method: Method,
+                      path: warp::path::FullPath,
+                      query: HashMap<String, String>,
+                      copy_source: Option<String>,
+                      body: bytes::Bytes| {
+                    let key = path.as_str().trim_start_matches("/test/").to_string();
+                    let mut objects = state.lock().unwrap();
+                    let reply = |status: u16, body: Vec<u8>| {
+                        Response::builder().status(status).body(body).unwrap()
+                    };
+                    if let Some((_, _, status)) = failures
+                        .iter()
+                        .find(|(m, prefix, _)| method.as_str() == *m && key.starts_with(prefix))
+                    {
+                        return reply(*status, b"<Error><Code>Injected</Code></Error>".to_vec());
+                    }
+                    match method {
+                        Method::GET if key.is_empty() => {
+                            let prefix = query.get("prefix").cloned().unwrap_or_default();
+                            let contents: String = objects
+                                .iter()
+                                .filter(|(key, _)| key.starts_with(&prefix))
+                                .map(|(key, data)| {
+                                    format!(
+                                        "<Contents><Key>{}</Key><LastModified>2024-01-01T00:00:00.000Z</LastModified>\
+                                         <ETag>\"x\"</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                                        key,
+                                        data.len()
+                                    )
+                                })
+                                .collect();
+                            let xml = format!(
+                                "<?xml version=\"1.0\" encoding=\"UTF-8\"?><ListBucketResult><Name>test</Name>\
+                                 <Prefix>{}</Prefix><MaxKeys>1000</MaxKeys><IsTruncated>false</IsTruncated>{}</ListBucketResult>",
+                                prefix, contents
+                            );
+                            reply(200, xml.into_bytes())
+                        }
+                        Method::GET | Method::HEAD => match objects.get(&key) {
+                            Some(data) if method == Method::GET => reply(200, data.clone()),
+                            Some(_) => reply(200, Vec::new()),
+                            None => reply(404, b"<Error><Code>NoSuchKey</Code></Error>".to_vec()),
+                        },
+                        Method::PUT => match copy_source {
+                            Some(source) => {
+                                let source = source.trim_start_matches('/').trim_start_matches("test/");
+                                match objects.get(source).cloned() {
+                                    Some(data) => {
+                                        objects.insert(key, data);
+                                        reply(
+                                            200,
+                                            b"<?xml version=\"1.0\" encoding=\"UTF-8\"?><CopyObjectResult>\
+                                              <LastModified>2024-01-01T00:00:00.000Z</LastModified>\
+                                              <ETag>\"x\"</ETag></CopyObjectResult>"
+                                                .to_vec(),
+                                        )
+                                    }
+                                    None => reply(404, b"<Error><Code>NoSuchKey</Code></Error>".to_vec()),
+                                }
+                            }
+                            None => {
+                                objects.insert(key, body.to_vec());
+                                reply(200, Vec::new())
+                            }
+                        },
+                        Method::DELETE => {
+                            objects.remove(&key);
+                            reply(204, Vec::new())
+                        }
+                        _ => reply(405, Vec::new()),
+                    }
+                },
+            );
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let region = Region::Custom {
+            region: "us-east-1".to_string(),
+            endpoint: format!("http://{}", addr),
+        };
+        let credentials = Credentials::new(Some("test"), Some("test"), None, None, None).unwrap();
+        let bucket = Bucket::new("test", region, credentials)
+            .unwrap()
+            .with_path_style();
+        (super::S3BlobStore::new(bucket, ""), objects)
+    }
+
+    #[tokio::test]
+    async fn test_unmerge_removes_added_names_against_mock_s3() {
+        use crate::storage::BlobStore;
+
+        let (store, objects) = mock_s3(Vec::new()).await;
+        store
+            .stage("one", "a.txt", Box::pin(&b"first"[..]))
+            .await
+            .unwrap();
+        store.swap("one").await.unwrap();
+        store.discard("one").await.unwrap();
+
+        //b.txt isn't live yet, so its HEAD is a 404 and the merge records it as added
+        store
+            .stage("two", "a.txt", Box::pin(&b"second"[..]))
+            .await
+            .unwrap();
+        store
+            .stage("two", "b.txt", Box::pin(&b"second"[..]))
+            .await
+            .unwrap();
+        store.merge("two").await.unwrap();
+        assert_eq!(store.list().await.unwrap(), vec!["a.txt", "b.txt"]);
+        assert!(objects
+            .lock()
+            .unwrap()
+            .contains_key("merged/two/added/b.txt"));
+
+        store.unmerge("two").await.unwrap();
+        assert_eq!(store.list().await.unwrap(), vec!["a.txt"]);
+        assert_eq!(objects.lock().unwrap()["live/a.txt"], b"first");
+    }
+
+    #[tokio::test]
+    async fn test_failed_copy_keeps_live_objects_against_mock_s3() {
+        use crate::storage::BlobStore;
+
+        let (store, objects) = mock_s3(vec![("PUT", "retired/", 500)]).await;
+        objects
+            .lock()
+            .unwrap()
+            .insert("live/a.txt".to_string(), b"first".to_vec());
+        store
+            .stage("two", "b.txt", Box::pin(&b"second"[..]))
+            .await
+            .unwrap();
+
+        //the copy into retired/ fails, so the live object must not be deleted
+        assert!(store.swap("two").await.is_err());
+        assert_eq!(store.list().await.unwrap(), vec!["a.txt"]);
+        assert_eq!(objects.lock().unwrap()["live/a.txt"], b"first");
+    }
+
+    #[tokio::test]
+    async fn test_refused_writes_fail_against_mock_s3() {
+        use crate::storage::BlobStore;
+
+        let (store, _) = mock_s3(vec![("PUT", "staging/", 403), ("PUT", "journal", 500)]).await;
+        assert!(store
+            .stage("one", "a.txt", Box::pin(&b"first"[..]))
+            .await
+            .is_err());
+        assert!(store.merge("one").await.is_err());
+    }
+}
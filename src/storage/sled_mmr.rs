@@ -0,0 +1,63 @@
+use super::MmrStore;
+use anyhow::{anyhow, ensure, Result};
+use blake3::Hash;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+
+// Mountain range nodes in their own sled tree, keyed by position (u64 big-endian).
+// Nodes are only ever added, so the size is one past the last key. Appends aren't
+// flushed here; the database's owner decides when they reach disk.
+const MMR_NODES_TREE: &str = "mmr_nodes";
+
+#[derive(Clone)]
+pub struct SledMmrStore {
+    nodes: sled::Tree,
+}
+
+impl SledMmrStore {
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        Ok(SledMmrStore {
+            nodes: db.open_tree(MMR_NODES_TREE)?,
+        })
+    }
+}
+
+fn size_of(last: Option<sled::IVec>) -> Result<u64> {
+    match last {
+        Some(key) => Ok(u64::from_be_bytes(key.as_ref().try_into()?) + 1),
+        None => Ok(0),
+    }
+}
+
+impl MmrStore for SledMmrStore {
+    fn size(&self) -> Result<u64> {
+        size_of(self.nodes.last()?.map(|(key, _)| key))
+    }
+
+    fn node(&self, pos: u64) -> Result<Option<Hash>> {
+        let node = self.nodes.get(pos.to_be_bytes())?;
+        Ok(node.and_then(|value| Some(Hash::from_bytes(value.as_ref().try_into().ok()?))))
+    }
+
+    fn append(&self, start: u64, nodes: &[Hash]) -> Result<()> {
+        ensure!(
+            self.size()? == start,
+            "Append at {} is not at the end",
+            start
+        );
+        let mut batch = sled::Batch::default();
+        for (i, node) in nodes.iter().enumerate() {
+            batch.insert(&(start + i as u64).to_be_bytes(), node.as_bytes());
+        }
+        //nodes are never overwritten: refuse if another writer got there first
+        self.nodes
+            .transaction(|tx_nodes| {
+                if tx_nodes.get(start.to_be_bytes())?.is_some() {
+                    return Err(ConflictableTransactionError::Abort(()));
+                }
+                tx_nodes.apply_batch(&batch)?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError<()>| anyhow!("Mmr append failed: {:?}", e))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+use super::TreeStore;
+use crate::merkletree::sparse::SparseMerkleTree;
+use crate::merkletree::tree::{FastMerkleNode, FastMerkleTree};
+use anyhow::{anyhow, bail, ensure, Result};
+use blake3::Hash;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::Transactional;
+
+// Layout of the merkle tree in sled:
+//   nodes: node index (u64 big-endian) -> node hash
+//   files: file name -> leaf index (u64 big-endian)
+//   meta:  tree_size, num_of_files, schema_version -> u64 big-endian
+//          batch -> id of the upload the tree was last changed by
+//          sparse_root -> root of the sparse tree over the same files
+// Version 2 pads the leaves to a power of two with empty slots, version 1 to an
+// even count with a copy of the last leaf. Version 3 adds sparse_root, and
+// version 4 takes it from the compact sparse tree.
+pub const SCHEMA_VERSION: u64 = 4;
+
+const NODES_TREE: &str = "nodes";
+const FILES_TREE: &str = "files";
+const META_TREE: &str = "meta";
+
+pub const TREE_SIZE_KEY: &[u8] = b"tree_size";
+pub const NUM_OF_FILES_KEY: &[u8] = b"num_of_files";
+pub const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+pub const BATCH_KEY: &[u8] = b"batch";
+pub const SPARSE_ROOT_KEY: &[u8] = b"sparse_root";
+
+// Before the schema existed everything lived in the default tree with the
+// upload folder prefixed to file names
+const LEGACY_FILE_PREFIX: &str = "./filestore/";
+
+#[derive(Clone)]
+pub struct SledTreeStore {
+    nodes: sled::Tree,
+    files: sled::Tree,
+    meta: sled::Tree,
+    db: sled::Db,
+}
+
+pub fn index_key(index: usize) -> [u8; 8] {
+    (index as u64).to_be_bytes()
+}
+
+pub fn decode_index(value: &[u8]) -> Option<usize> {
+    Some(u64::from_be_bytes(value.try_into().ok()?) as usize)
+}
+
+impl SledTreeStore {
+    // Open the schema trees, migrating an older layout if one is found
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        let store = SledTreeStore {
+            nodes: db.open_tree(NODES_TREE)?,
+            files: db.open_tree(FILES_TREE)?,
+            meta: db.open_tree(META_TREE)?,
+            db: db.clone(),
+        };
+        match store.get_meta(SCHEMA_VERSION_KEY)? {
+            Some(version) if version == SCHEMA_VERSION as usize => {}
+            Some(2) | Some(3) => store.migrate_sparse_root()?,
+            Some(1) => store.migrate_padding()?,
+            Some(version) => bail!("Unsupported tree schema version {}", version),
+            None => {
+                store.migrate_legacy()?;
+                store.migrate_padding()?;
+            }
+        }
+        Ok(store)
+    }
+
+    fn get_meta(&self, key: &[u8]) -> Result<Option<usize>> {
+        Ok(self.meta.get(key)?.and_then(|value| decode_index(&value)))
+    }
+
+    // Apply one batch per tree in a single transaction
+    fn apply(&self, nodes: sled::Batch, files: sled::Batch, meta: sled::Batch) -> Result<()> {
+        (&self.nodes, &self.files, &self.meta)
+            .transaction(|(tx_nodes, tx_files, tx_meta)| {
+                tx_nodes.apply_batch(&nodes)?;
+                tx_files.apply_batch(&files)?;
+                tx_meta.apply_batch(&meta)?;
+                Ok::<(), ConflictableTransactionError>(())
+            })
+            .map_err(|e: TransactionError| anyhow::anyhow!("Tree commit failed: {:?}", e))?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // Move entries written by the unversioned layout out of the default tree:
+    // node keys were usize::to_le_bytes with 32 byte hashes as values, file names and
+    // the tree_size/num_of_files keys held usize::to_le_bytes values
+    fn migrate_legacy(&self) -> Result<()> {
+        let mut nodes = sled::Batch::default();
+        let mut files = sled::Batch::default();
+        let mut meta = sled::Batch::default();
+        let mut legacy = sled::Batch::default();
+        for result in self.db.iter() {
+            let (key, value) = result?;
+            legacy.remove(key.clone());
+            if value.len() == blake3::OUT_LEN && key.len() == 8 {
+                let index = usize::from_le_bytes(key.as_ref().try_into()?);
+                nodes.insert(&index_key(index), value);
+                continue;
+            }
+            let index = usize::from_le_bytes(value.as_ref().try_into()?);
+            match key.as_ref() {
+                TREE_SIZE_KEY | NUM_OF_FILES_KEY => meta.insert(key, &index_key(index)),
+                _ => {
+                    let filename = String::from_utf8(key.to_vec())?;
+                    let filename = filename.trim_start_matches(LEGACY_FILE_PREFIX);
+                    files.insert(filename.as_bytes(), &index_key(index));
+                }
+            }
+        }
+        meta.insert(SCHEMA_VERSION_KEY, &index_key(1));
+
+        (
+            &*self.db as &sled::Tree,
+            &self.nodes,
+            &self.files,
+            &self.meta,
+        )
+            .transaction(|(tx_legacy, tx_nodes, tx_files, tx_meta)| {
+                tx_legacy.apply_batch(&legacy)?;
+                tx_nodes.apply_batch(&nodes)?;
+                tx_files.apply_batch(&files)?;
+                tx_meta.apply_batch(&meta)?;
+                Ok::<(), ConflictableTransactionError>(())
+            })
+            .map_err(|e: TransactionError| anyhow::anyhow!("Schema migration failed: {:?}", e))?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // Rebuild a version 1 tree from its leaves in the current layout. The commit
+    // writes the new version along with the tree.
+    fn migrate_padding(&self) -> Result<()> {
+        let nodes = self.nodes()?;
+        let leaf_count = self.num_of_files()?.unwrap_or_default();
+        let padded = leaf_count + leaf_count % 2;
+        ensure!(nodes.len() >= padded, "Tree has fewer nodes than files");
+        let leaves = &nodes[nodes.len() - padded..][..leaf_count];
+        let tree = match leaf_count {
+            0 => FastMerkleTree(Vec::new()),
+            _ => FastMerkleTree::from_leaves(leaves.to_vec()),
+        };
+        let batch = self.batch()?.unwrap_or_default();
+        let file_list = self.file_list()?;
+        let sparse = SparseMerkleTree::from_files(file_list.iter().cloned().zip(leaves.to_vec()));
+        tree.commit_merkle_tree(self, &batch, file_list, &sparse.root())?;
+        //same files, new padding: roots published before no longer match
+        let old_root = nodes.first().map(|root| root.to_hex().to_string());
+        let new_root = tree.0.first().map(|root| root.value.to_hex().to_string());
+        if old_root != new_root {
+            tracing::warn!(
+                old_root = old_root.unwrap_or_default(),
+                new_root = new_root.unwrap_or_default(),
+                files = leaf_count,
+                "Tree rebuilt with empty-slot padding, its root has changed"
+            );
+        }
+        Ok(())
+    }
+
+    // Work out the sparse root of a version 2 or 3 tree from its files and leaves
+    fn migrate_sparse_root(&self) -> Result<()> {
+        let tree = FastMerkleTree(
+            self.nodes()?
+                .into_iter()
+                .map(|value| FastMerkleNode { value })
+                .collect(),
+        );
+        let file_list = self.file_list()?;
+        let leaves = tree.leaves(file_list.len());
+        let sparse = SparseMerkleTree::from_files(file_list.into_iter().zip(leaves));
+        let mut meta = sled::Batch::default();
+        meta.insert(SPARSE_ROOT_KEY, sparse.root().as_bytes());
+        meta.insert(SCHEMA_VERSION_KEY, &index_key(SCHEMA_VERSION as usize));
+        self.apply(sled::Batch::default(), sled::Batch::default(), meta)
+    }
+}
+
+impl TreeStore for SledTreeStore {
+    fn commit(
+        &self,
+        batch: &str,
+        nodes: &[Hash],
+        file_list: &[String],
+        sparse_root: &Hash,
+    ) -> Result<()> {
+        let mut node_batch = sled::Batch::default();
+        let mut file_batch = sled::Batch::default();
+        let mut meta_batch = sled::Batch::default();
+        //clear old entries before adding new ones
+        for key in self.nodes.iter().keys() {
+            node_batch.remove(key?);
+        }
+        for key in self.files.iter().keys() {
+            file_batch.remove(key?);
+        }
+        for (i, node) in nodes.iter().enumerate() {
+            node_batch.insert(&index_key(i), node.as_bytes());
+        }
+        for (i, filename) in file_list.iter().enumerate() {
+            file_batch.insert(filename.as_bytes(), &index_key(i));
+        }
+        meta_batch.insert(TREE_SIZE_KEY, &index_key(nodes.len()));
+        meta_batch.insert(NUM_OF_FILES_KEY, &index_key(file_list.len()));
+        meta_batch.insert(BATCH_KEY, batch.as_bytes());
+        meta_batch.insert(SPARSE_ROOT_KEY, sparse_root.as_bytes());
+        meta_batch.insert(SCHEMA_VERSION_KEY, &index_key(SCHEMA_VERSION as usize));
+        self.apply(node_batch, file_batch, meta_batch)
+    }
+
+    fn update(
+        &self,
+        batch: &str,
+        nodes: &[(usize, Hash)],
+        new_files: &[String],
+        sparse_root: &Hash,
+    ) -> Result<()> {
+        let mut node_batch = sled::Batch::default();
+        let mut file_batch = sled::Batch::default();
+        let mut meta_batch = sled::Batch::default();
+        for (index, node) in nodes {
+            node_batch.insert(&index_key(*index), node.as_bytes());
+        }
+        let num_of_files = self.num_of_files()?.unwrap_or_default();
+        for (i, filename) in new_files.iter().enumerate() {
+            file_batch.insert(filename.as_bytes(), &index_key(num_of_files + i));
+        }
+        meta_batch.insert(NUM_OF_FILES_KEY, &index_key(num_of_files + new_files.len()));
+        meta_batch.insert(BATCH_KEY, batch.as_bytes());
+        meta_batch.insert(SPARSE_ROOT_KEY, sparse_root.as_bytes());
+        self.apply(node_batch, file_batch, meta_batch)
+    }
+
+    fn batch(&self) -> Result<Option<String>> {
+        let batch = self.meta.get(BATCH_KEY)?;
+        Ok(batch.and_then(|value| String::from_utf8(value.to_vec()).ok()))
+    }
+
+    fn sparse_root(&self) -> Result<Option<Hash>> {
+        let root = self.meta.get(SPARSE_ROOT_KEY)?;
+        Ok(root.and_then(|value| Some(Hash::from_bytes(value.as_ref().try_into().ok()?))))
+    }
+
+    fn node(&self, index: usize) -> Result<Option<Hash>> {
+        let node = self.nodes.get(index_key(index))?;
+        Ok(node.and_then(|value| Some(Hash::from_bytes(value.as_ref().try_into().ok()?))))
+    }
+
+    fn nodes(&self) -> Result<Vec<Hash>> {
+        //big-endian keys iterate in index order
+        let mut nodes = Vec::new();
+        for value in self.nodes.iter().values() {
+            nodes.push(Hash::from_bytes(value?.as_ref().try_into()?));
+        }
+        Ok(nodes)
+    }
+
+    fn file_list(&self) -> Result<Vec<String>> {
+        let mut files = Vec::new();
+        for result in self.files.iter() {
+            let (filename, index) = result?;
+            let index = decode_index(&index).ok_or_else(|| anyhow!("Bad file index"))?;
+            files.push((index, String::from_utf8(filename.to_vec())?));
+        }
+        files.sort();
+        Ok(files.into_iter().map(|(_, filename)| filename).collect())
+    }
+
+    fn file_index(&self, filename: &str) -> Result<Option<usize>> {
+        let index = self.files.get(filename.as_bytes())?;
+        Ok(index.and_then(|value| decode_index(&value)))
+    }
+
+    fn tree_size(&self) -> Result<Option<usize>> {
+        self.get_meta(TREE_SIZE_KEY)
+    }
+
+    fn num_of_files(&self) -> Result<Option<usize>> {
+        self.get_meta(NUM_OF_FILES_KEY)
+    }
+
+    fn size_on_disk(&self) -> Result<Option<u64>> {
+        Ok(Some(self.db.size_on_disk()?))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_migrate_legacy_layout() {
+        use crate::merkletree::tree::inner_hash;
+        use crate::storage::sled_store::{SledTreeStore, SCHEMA_VERSION, SCHEMA_VERSION_KEY};
+        use crate::storage::TreeStore;
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        for i in 0..3usize {
+            db.insert(i.to_le_bytes(), &[i as u8; 32]).unwrap();
+        }
+        db.insert(b"tree_size", &3usize.to_le_bytes()).unwrap();
+        db.insert(b"./filestore/a.txt", &0usize.to_le_bytes())
+            .unwrap();
+        db.insert(b"./filestore/b.txt", &1usize.to_le_bytes())
+            .unwrap();
+        db.insert(b"num_of_files", &2usize.to_le_bytes()).unwrap();
+
+        let store = SledTreeStore::open(&db).unwrap();
+        assert!(db.is_empty());
+        assert_eq!(
+            store.get_meta(SCHEMA_VERSION_KEY).unwrap(),
+            Some(SCHEMA_VERSION as usize)
+        );
+        assert_eq!(store.tree_size().unwrap(), Some(3));
+        assert_eq!(store.num_of_files().unwrap(), Some(2));
+        assert_eq!(store.file_index("b.txt").unwrap(), Some(1));
+        assert_eq!(store.node(2).unwrap().unwrap().as_bytes(), &[2u8; 32]);
+        //rebuilt from the leaves in the current layout
+        let leaves = [1u8, 2].map(|i| blake3::Hash::from_bytes([i; 32]));
+        assert_eq!(
+            store.node(0).unwrap(),
+            Some(inner_hash(&leaves[0], &leaves[1]))
+        );
+    }
+
+    #[test]
+    fn test_commit_replaces_old_tree() {
+        use crate::storage::sled_store::SledTreeStore;
+        use crate::storage::TreeStore;
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = SledTreeStore::open(&db).unwrap();
+        let big_list: Vec<String> = (0..4).map(|i| format!("f{}.txt", i)).collect();
+        let big_nodes = vec![blake3::hash(b"big"); 7];
+        store
+            .commit("big", &big_nodes, &big_list, &big_nodes[0])
+            .unwrap();
+
+        let small_nodes = vec![blake3::hash(b"small"); 3];
+        store
+            .commit("small", &small_nodes, &big_list[..2], &small_nodes[0])
+            .unwrap();
+
+        //nothing left from the big tree
+        assert_eq!(store.nodes.len(), 3);
+        assert_eq!(store.files.len(), 2);
+        assert_eq!(store.file_index("f2.txt").unwrap(), None);
+        assert_eq!(store.node(0).unwrap(), Some(small_nodes[0]));
+        assert_eq!(store.batch().unwrap().as_deref(), Some("small"));
+        assert_eq!(store.sparse_root().unwrap(), Some(small_nodes[0]));
+    }
+}